@@ -0,0 +1,7 @@
+#include <stdlib.h>
+
+// Exits with the status given in DEET_EXIT_STATUS, or 100 if it isn't set
+int main() {
+    const char *status = getenv("DEET_EXIT_STATUS");
+    return status ? atoi(status) : 100;
+}
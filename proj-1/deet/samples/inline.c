@@ -0,0 +1,14 @@
+#include <stdio.h>
+
+static inline __attribute__((always_inline)) int square(int x) {
+    int result = x * x;
+    return result;
+}
+
+int main() {
+    int a = square(3);
+    printf("square(3) = %d\n", a);
+    int b = square(4);
+    printf("square(4) = %d\n", b);
+    return 0;
+}
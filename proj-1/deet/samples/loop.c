@@ -0,0 +1,11 @@
+// A loop that doesn't call anything, so every iteration runs exactly the same instructions
+
+int iterations = 10;
+
+int main() {
+    int total = 0;
+    for (int i = 0; i < iterations; i++) {
+        total += i;
+    }
+    return total;
+}
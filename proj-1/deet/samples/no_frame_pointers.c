@@ -0,0 +1,24 @@
+#include <stdio.h>
+
+// Built with -fomit-frame-pointer (see the Makefile), so rbp doesn't point to a chain of frames
+// and backtraces from inside func2 can't walk back to main. Set a breakpoint on func2 and run
+// "bt" to see how deet copes.
+
+int global = 5;
+
+int func2(int a) {
+    int b = a * global;
+    printf("func2(%d) = %d\n", a, b);
+    return b;
+}
+
+int func1(int a) {
+    int result = func2(a + 1);
+    printf("func1(%d) = %d\n", a, result);
+    return result;
+}
+
+int main() {
+    func1(42);
+    return 0;
+}
@@ -0,0 +1,11 @@
+fn checked_divide(dividend: u32, divisor: u32) -> u32 {
+    if divisor == 0 {
+        panic!("attempted to divide {} by zero", dividend);
+    }
+    dividend / divisor
+}
+
+fn main() {
+    println!("10 / 2 = {}", checked_divide(10, 2));
+    println!("1 / 0 = {}", checked_divide(1, 0));
+}
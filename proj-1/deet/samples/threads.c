@@ -0,0 +1,24 @@
+#include <pthread.h>
+#include <stdio.h>
+#include <unistd.h>
+
+void *worker(void *arg) {
+    int id = *(int *)arg;
+    for (int i = 0; i < 3; i++) {
+        printf("worker %d: %d\n", id, i);
+        sleep(1);
+    }
+    return NULL;
+}
+
+int main() {
+    pthread_t thread;
+    int id = 1;
+    pthread_create(&thread, NULL, worker, &id);
+    for (int i = 0; i < 3; i++) {
+        printf("main: %d\n", i);
+        sleep(1);
+    }
+    pthread_join(thread, NULL);
+    return 0;
+}
@@ -0,0 +1,137 @@
+//! Conditions on breakpoints, like `i == 10`: a comparison between a variable and an integer.
+
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    LessThan,
+    LessOrEqual,
+    GreaterThan,
+    GreaterOrEqual,
+}
+
+/// The operators we understand, along with what they mean. Two-character operators come first, so
+/// that `<=` isn't taken for `<`.
+const OPERATORS: [(&str, Comparison); 6] = [
+    ("==", Comparison::Equal),
+    ("!=", Comparison::NotEqual),
+    ("<=", Comparison::LessOrEqual),
+    (">=", Comparison::GreaterOrEqual),
+    ("<", Comparison::LessThan),
+    (">", Comparison::GreaterThan),
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Condition {
+    pub variable: String,
+    pub comparison: Comparison,
+    pub value: i128,
+}
+
+/// Parses a decimal or 0x-prefixed hex integer, which may be negative.
+pub fn parse_integer(text: &str) -> Option<i128> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let magnitude = match digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+    {
+        Some(hex) => i128::from_str_radix(hex, 16).ok()?,
+        None => digits.parse::<i128>().ok()?,
+    };
+    Some(if negative { -magnitude } else { magnitude })
+}
+
+impl Condition {
+    pub fn parse(expr: &str) -> Result<Condition, String> {
+        let invalid = || {
+            format!(
+                "Invalid condition {} (expected a variable compared to an integer, e.g. i == 10)",
+                expr
+            )
+        };
+        let (symbol, comparison) = OPERATORS
+            .iter()
+            .find(|(symbol, _)| expr.contains(symbol))
+            .ok_or_else(invalid)?;
+        let (variable, value) = expr.split_once(symbol).unwrap();
+        let variable = variable.trim();
+        if variable.is_empty()
+            || !variable
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_')
+        {
+            return Err(invalid());
+        }
+        Ok(Condition {
+            variable: variable.to_string(),
+            comparison: *comparison,
+            value: parse_integer(value.trim()).ok_or_else(invalid)?,
+        })
+    }
+
+    /// Returns whether the condition holds when the variable has the value `value`.
+    pub fn holds(&self, value: i128) -> bool {
+        match self.comparison {
+            Comparison::Equal => value == self.value,
+            Comparison::NotEqual => value != self.value,
+            Comparison::LessThan => value < self.value,
+            Comparison::LessOrEqual => value <= self.value,
+            Comparison::GreaterThan => value > self.value,
+            Comparison::GreaterOrEqual => value >= self.value,
+        }
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let symbol = OPERATORS
+            .iter()
+            .find(|(_, comparison)| *comparison == self.comparison)
+            .unwrap()
+            .0;
+        write!(f, "{} {} {}", self.variable, symbol, self.value)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let condition = Condition::parse("i == 10").unwrap();
+        assert_eq!(condition.variable, "i");
+        assert_eq!(condition.comparison, Comparison::Equal);
+        assert_eq!(condition.value, 10);
+        assert_eq!(condition.to_string(), "i == 10");
+
+        let condition = Condition::parse("num_seconds<=0x20").unwrap();
+        assert_eq!(condition.comparison, Comparison::LessOrEqual);
+        assert_eq!(condition.value, 32);
+        assert_eq!(
+            Condition::parse("x > -3").unwrap().comparison,
+            Comparison::GreaterThan
+        );
+        assert_eq!(Condition::parse("x != -3").unwrap().value, -3);
+
+        assert!(Condition::parse("i").is_err());
+        assert!(Condition::parse("== 3").is_err());
+        assert!(Condition::parse("i == j").is_err());
+        assert!(Condition::parse("i + 1 == 3").is_err());
+    }
+
+    #[test]
+    fn test_holds() {
+        let condition = Condition::parse("i < 3").unwrap();
+        assert!(condition.holds(2));
+        assert!(!condition.holds(3));
+        assert!(Condition::parse("i >= 3").unwrap().holds(3));
+        assert!(Condition::parse("i != 3").unwrap().holds(4));
+        assert!(!Condition::parse("i == 3").unwrap().holds(4));
+    }
+}
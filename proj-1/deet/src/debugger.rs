@@ -1,8 +1,32 @@
+use crate::condition::{self, Condition};
 use crate::debugger_command::DebuggerCommand;
 use crate::dwarf_data::{DwarfData, Error as DwarfError};
+use crate::examine::{self, Format};
 use crate::inferior::{Inferior, Status};
+use crate::listing;
+use crate::panic;
+use crate::registers;
+use nix::sys::ptrace;
+use nix::sys::signal::Signal;
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::os::unix::fs::MetadataExt;
+
+/// How many lines of source to show either side of the line the inferior stopped at (or a panic
+/// came from)
+const SOURCE_CONTEXT_LINES: usize = 2;
+
+/// Upper bound on the number of instructions we'll single-step through when counting instructions
+/// on `continue`, so that a long-running program can't keep us stepping forever.
+const MAX_COUNTED_STEPS: usize = 1_000_000;
+
+/// Commands in this file (looked for in the current directory, then in $HOME) are run at startup
+const INIT_FILE_NAME: &str = ".deetinit";
+
+/// Most commands we'll queue up to run. Going over this almost certainly means a user-defined
+/// command is invoking itself.
+const MAX_PENDING_COMMANDS: usize = 10_000;
 
 pub struct Debugger {
     target: String,
@@ -10,7 +34,26 @@ pub struct Debugger {
     readline: Editor<()>,
     inferior: Option<Inferior>,
     debug_data: DwarfData,
-    breakpoints: Vec<usize>,
+    /// Breakpoints by number. Deleted breakpoints leave a None behind, so that the others keep
+    /// their numbers.
+    breakpoints: Vec<Option<usize>>,
+    /// Conditions on breakpoints, by address. A breakpoint with a condition only stops the
+    /// inferior when the condition holds.
+    breakpoint_conditions: HashMap<usize, Condition>,
+    /// Where we stop the inferior to report a Rust panic, if the target has a panic runtime
+    panic_breakpoint: Option<usize>,
+    count_instructions: bool,
+    /// Environment changes applied to the next inferior we start (None means unset the variable)
+    env_overrides: BTreeMap<String, Option<String>>,
+    /// Lines read by `source` that haven't been run yet. These are run before we prompt again.
+    pending_commands: VecDeque<String>,
+    /// Commands created with `define`, by name. Each one is a list of lines to run.
+    user_commands: HashMap<String, Vec<String>>,
+    /// Abbreviations created with `alias`, mapping each name to what it stands for
+    aliases: HashMap<String, String>,
+    /// Source files we've shown lines from, by path, so that they're only read once. Files that
+    /// couldn't be read map to None.
+    source_files: HashMap<String, Option<String>>,
 }
 
 fn parse_address(addr: &str) -> Option<usize> {
@@ -22,9 +65,21 @@ fn parse_address(addr: &str) -> Option<usize> {
     usize::from_str_radix(addr_without_0x, 16).ok()
 }
 
+// An init file is only trusted if it belongs to the user and nobody else can change it
+fn check_init_file(path: &str) -> Result<(), String> {
+    let metadata = std::fs::metadata(path).map_err(|e| e.to_string())?;
+    if metadata.uid() != nix::unistd::getuid().as_raw() {
+        return Err("it is owned by another user".to_string());
+    }
+    if metadata.mode() & 0o022 != 0 {
+        return Err("other users can write to it".to_string());
+    }
+    Ok(())
+}
+
 impl Debugger {
     /// Initializes the debugger.
-    pub fn new(target: &str) -> Debugger {
+    pub fn new(target: &str, count_instructions: bool) -> Debugger {
         // TODO (milestone 3): initialize the DwarfData
 
         let history_path = format!("{}/.deet_history", std::env::var("HOME").unwrap());
@@ -47,6 +102,9 @@ impl Debugger {
         };
 
         debug_data.print();
+        let panic_breakpoint = panic::PANIC_ENTRY_POINTS
+            .iter()
+            .find_map(|name| debug_data.get_addr_for_function(None, name));
 
         Debugger {
             target: target.to_string(),
@@ -55,130 +113,971 @@ impl Debugger {
             inferior: None,
             debug_data,
             breakpoints: Vec::new(),
+            breakpoint_conditions: HashMap::new(),
+            panic_breakpoint,
+            count_instructions,
+            env_overrides: BTreeMap::new(),
+            pending_commands: VecDeque::new(),
+            user_commands: HashMap::new(),
+            aliases: HashMap::new(),
+            source_files: HashMap::new(),
+        }
+    }
+
+    /// Queues up the commands in .deetinit, if there is one, so that they run before the first
+    /// prompt. Since they run without asking, a .deetinit that someone else could have written
+    /// (in a directory full of downloaded code, say) is skipped.
+    pub fn source_init_file(&mut self) {
+        let mut candidates = vec![INIT_FILE_NAME.to_string()];
+        if let Ok(home) = std::env::var("HOME") {
+            candidates.push(format!("{}/{}", home, INIT_FILE_NAME));
+        }
+        if let Some(path) = candidates
+            .iter()
+            .find(|path| std::path::Path::new(path).is_file())
+        {
+            self.source_init_file_at(path);
+        }
+    }
+
+    fn source_init_file_at(&mut self, path: &str) {
+        match check_init_file(path) {
+            Ok(()) => self.source_file(path),
+            Err(reason) => println!("Not running the commands in {}: {}", path, reason),
+        }
+    }
+
+    // Queue up the commands in a file, ahead of any that are already queued, so that a `source`
+    // inside a sourced file runs the nested file's commands right away
+    fn source_file(&mut self, path: &str) {
+        match std::fs::read_to_string(path) {
+            Ok(contents) => {
+                for line in contents.lines().rev() {
+                    self.pending_commands.push_front(line.to_string());
+                }
+            }
+            Err(e) => println!("Unable to read {}: {}", path, e),
         }
     }
 
     pub fn run(&mut self) {
         loop {
-            match self.get_next_command() {
-                DebuggerCommand::Run(args) => {
-                    self.kill_inferior();
-                    if let Some(inferior) = Inferior::new(&self.target, &args, &self.breakpoints) {
-                        // Create the inferior
-                        self.inferior = Some(inferior);
-                        // TODO (milestone 1): make the inferior run
-                        // You may use self.inferior.as_mut().unwrap() to get a mutable reference
-                        // to the Inferior object
-                        self.continue_inferior();
-                    } else {
-                        println!("Error starting subprocess");
+            let command = self.get_next_command();
+            if !self.run_command(command) {
+                return;
+            }
+        }
+    }
+
+    // Carry out a single command. Returns false once it's time to quit.
+    fn run_command(&mut self, command: DebuggerCommand) -> bool {
+        match command {
+            DebuggerCommand::Run(args) => {
+                self.kill_inferior();
+                let mut breakpoints: Vec<usize> =
+                    self.breakpoints.iter().flatten().copied().collect();
+                breakpoints.extend(self.panic_breakpoint);
+                if let Some(mut inferior) =
+                    Inferior::new(&self.target, &args, &breakpoints, &self.env_overrides)
+                {
+                    for (addr, condition) in &self.breakpoint_conditions {
+                        inferior.set_breakpoint_condition(*addr, Some(condition.clone()));
                     }
+                    // Create the inferior
+                    self.inferior = Some(inferior);
+                    // TODO (milestone 1): make the inferior run
+                    // You may use self.inferior.as_mut().unwrap() to get a mutable reference
+                    // to the Inferior object
+                    self.continue_inferior();
+                } else {
+                    println!("Error starting subprocess");
+                }
+            }
+            DebuggerCommand::Continue => {
+                if self.inferior.is_none() {
+                    println!("No inferior running");
+                } else {
+                    self.continue_inferior();
                 }
-                DebuggerCommand::Continue => {
-                    if self.inferior.is_none() {
-                        println!("No inferior running");
-                    } else {
-                        self.continue_inferior();
+            }
+            DebuggerCommand::Next => {
+                if self.inferior.is_none() {
+                    println!("No inferior running");
+                } else {
+                    let status = self.inferior.as_mut().unwrap().next_line(&self.debug_data);
+                    self.report_status(status);
+                }
+            }
+            DebuggerCommand::Step => {
+                if self.inferior.is_none() {
+                    println!("No inferior running");
+                } else {
+                    let status = self.inferior.as_mut().unwrap().step_line(&self.debug_data);
+                    self.report_status(status);
+                }
+            }
+            DebuggerCommand::StepInstruction(count) => {
+                if self.inferior.is_none() {
+                    println!("No inferior running");
+                } else {
+                    self.step_and_count(count);
+                }
+            }
+            DebuggerCommand::Finish => {
+                if self.inferior.is_none() {
+                    println!("No inferior running");
+                } else {
+                    self.finish_function();
+                }
+            }
+            DebuggerCommand::Backtrace => {
+                if self.inferior.is_none() {
+                    println!("No inferior running");
+                } else {
+                    self.inferior
+                        .as_ref()
+                        .unwrap()
+                        .print_backtrace(&self.debug_data);
+                }
+            }
+            DebuggerCommand::Breakpoint(location, condition) => {
+                let condition = match condition.map(|expr| Condition::parse(&expr)) {
+                    Some(Ok(condition)) => Some(condition),
+                    Some(Err(e)) => {
+                        println!("{}", e);
+                        return true;
                     }
+                    None => None,
+                };
+                if let Some(addr) = location.strip_prefix('*') {
+                    match self.parse_breakpoint_address(addr) {
+                        Some(bp) => self.add_breakpoint(bp, condition.as_ref()),
+                        None => println!("Unable to parse address {}", location),
+                    }
+                } else if location
+                    .rsplit(':')
+                    .next()
+                    .unwrap()
+                    .parse::<usize>()
+                    .is_ok()
+                {
+                    self.add_line_breakpoints(&location, condition.as_ref());
+                } else {
+                    self.add_function_breakpoint(&location, condition.as_ref());
                 }
-                DebuggerCommand::Backtrace => {
-                    if self.inferior.is_none() {
-                        println!("No inferior running");
-                    } else {
-                        self.inferior
-                            .as_ref()
-                            .unwrap()
-                            .print_backtrace(&self.debug_data);
+            }
+            DebuggerCommand::Delete(which) => self.delete_breakpoint(&which),
+            DebuggerCommand::InfoBreakpoints => self.print_breakpoints(),
+            DebuggerCommand::SetEnv(key, value) => {
+                self.env_overrides.insert(key, Some(value));
+            }
+            DebuggerCommand::SetVariable(name, value) => self.set_variable(&name, &value),
+            DebuggerCommand::UnsetEnv(key) => {
+                self.env_overrides.insert(key, None);
+            }
+            DebuggerCommand::ShowEnv => {
+                if self.env_overrides.is_empty() {
+                    println!("No environment changes (the inferior inherits deet's)");
+                }
+                for (key, value) in &self.env_overrides {
+                    match value {
+                        Some(value) => println!("{}={}", key, value),
+                        None => println!("{} (unset)", key),
+                    }
+                }
+            }
+            DebuggerCommand::Checkpoint => {
+                if self.inferior.is_none() {
+                    println!("No inferior running");
+                } else {
+                    match self.inferior.as_mut().unwrap().checkpoint() {
+                        Ok(saved_bytes) => println!(
+                            "Saved checkpoint ({} bytes of writable memory)",
+                            saved_bytes
+                        ),
+                        Err(e) => println!("Failed to save checkpoint: {}", e),
                     }
                 }
-                DebuggerCommand::Breakpoint(addr) => match parse_address(&addr[1..]) {
-                    Some(bp) => {
-                        self.breakpoints.push(bp);
-                        if self.inferior.is_some() {
-                            self.inferior
-                                .as_mut()
-                                .unwrap()
-                                .set_breakpoint(bp, self.breakpoints.len() - 1);
+            }
+            DebuggerCommand::Restart => {
+                if self.inferior.is_none() {
+                    println!("No inferior running");
+                } else {
+                    let inferior = self.inferior.as_mut().unwrap();
+                    match inferior.restore_checkpoint() {
+                        Ok(skipped) => {
+                            if skipped > 0 {
+                                println!(
+                                    "Warning: {} memory regions are no longer mapped and were \
+                                         not restored",
+                                    skipped
+                                );
+                            }
+                            match inferior.get_rip() {
+                                Ok(rip) => self.print_stop_location(rip),
+                                Err(e) => println!("Unable to get register value {}", e),
+                            }
                         }
+                        Err(e) => println!("Failed to restore checkpoint: {}", e),
                     }
-                    None => println!("Unable to parse address {}", addr),
-                },
-                DebuggerCommand::Quit => {
-                    self.kill_inferior();
-                    return;
                 }
             }
+            DebuggerCommand::InfoRegisters(name) => self.print_registers(name.as_deref()),
+            DebuggerCommand::Print(expr) => {
+                if expr.starts_with('$') {
+                    self.print_registers(Some(&expr));
+                } else {
+                    self.print_variable(&expr);
+                }
+            }
+            DebuggerCommand::DumpMemory(path, start, end) => self.dump_memory(&path, &start, &end),
+            DebuggerCommand::Examine(count, format, addr) => {
+                self.examine_memory(count, format, &addr)
+            }
+            DebuggerCommand::Restore(path, addr) => self.restore_memory(&path, &addr),
+            DebuggerCommand::Source(path) => self.source_file(&path),
+            DebuggerCommand::Define(name) => self.define_command(name),
+            DebuggerCommand::Alias(name, expansion) => {
+                self.aliases.insert(name, expansion);
+            }
+            DebuggerCommand::InfoThreads => self.print_threads(),
+            DebuggerCommand::Thread(number) => self.switch_thread(number),
+            DebuggerCommand::Quit => {
+                self.kill_inferior();
+                return false;
+            }
         }
+        true
     }
 
     /// This function prompts the user to enter a command, and continues re-prompting until the user
-    /// enters a valid command. It uses DebuggerCommand::from_tokens to do the command parsing.
-    ///
-    /// You don't need to read, understand, or modify this function.
+    /// enters a valid command. It uses parse_line to do the command parsing.
     fn get_next_command(&mut self) -> DebuggerCommand {
+        loop {
+            let line = match self.read_line("(deet) ") {
+                Some(line) => line,
+                // User pressed ctrl+d, which is the equivalent of "quit" for our purposes
+                None => return DebuggerCommand::Quit,
+            };
+            if let Some(cmd) = self.parse_line(&line) {
+                return cmd;
+            }
+        }
+    }
+
+    // Turn a line of input into a command, using DebuggerCommand::from_tokens for built-in
+    // commands. Aliases and user-defined commands are looked up first, so they can replace
+    // built-in ones. A user-defined command has its body queued up to run next, and gives None,
+    // as do comments, blank lines and unrecognized commands.
+    fn parse_line(&mut self, line: &str) -> Option<DebuggerCommand> {
+        let expanded;
+        let mut tokens: Vec<&str> = line.split_whitespace().collect();
+        // Lines starting with # are comments
+        if tokens.is_empty() || tokens[0].starts_with('#') {
+            return None;
+        }
+        // An alias stands for the start of a command; anything after it is passed along
+        if let Some(expansion) = self.aliases.get(tokens[0]) {
+            expanded = format!("{} {}", expansion, tokens[1..].join(" "));
+            tokens = expanded.split_whitespace().collect();
+        }
+        if let Some(body) = self.user_commands.get(tokens[0]) {
+            if self.pending_commands.len() + body.len() > MAX_PENDING_COMMANDS {
+                println!(
+                    "Too many commands queued up; does {} invoke itself?",
+                    tokens[0]
+                );
+                self.pending_commands.clear();
+                return None;
+            }
+            for body_line in body.iter().rev() {
+                self.pending_commands.push_front(body_line.clone());
+            }
+            return None;
+        }
+        let cmd = DebuggerCommand::from_tokens(&tokens);
+        if cmd.is_none() {
+            println!("Unrecognized command: {}", line.trim());
+        }
+        cmd
+    }
+
+    // Get the next line to run: one queued up by `source` or a user-defined command if there is
+    // one, and otherwise one typed by the user. Returns None if the user presses ctrl+d.
+    fn read_line(&mut self, prompt: &str) -> Option<String> {
+        if let Some(line) = self.pending_commands.pop_front() {
+            return Some(line);
+        }
         loop {
             // Print prompt and get next line of user input
-            match self.readline.readline("(deet) ") {
+            match self.readline.readline(prompt) {
                 Err(ReadlineError::Interrupted) => {
                     // User pressed ctrl+c. We're going to ignore it
                     println!("Type \"quit\" to exit");
                 }
-                Err(ReadlineError::Eof) => {
-                    // User pressed ctrl+d, which is the equivalent of "quit" for our purposes
-                    return DebuggerCommand::Quit;
-                }
+                Err(ReadlineError::Eof) => return None,
                 Err(err) => {
                     panic!("Unexpected I/O error: {:?}", err);
                 }
                 Ok(line) => {
-                    if line.trim().len() == 0 {
-                        continue;
-                    }
-                    self.readline.add_history_entry(line.as_str());
-                    if let Err(err) = self.readline.save_history(&self.history_path) {
-                        println!(
-                            "Warning: failed to save history file at {}: {}",
-                            self.history_path, err
-                        );
-                    }
-                    let tokens: Vec<&str> = line.split_whitespace().collect();
-                    if let Some(cmd) = DebuggerCommand::from_tokens(&tokens) {
-                        return cmd;
-                    } else {
-                        println!("Unrecognized command.");
+                    if line.trim().len() > 0 {
+                        self.readline.add_history_entry(line.as_str());
+                        if let Err(err) = self.readline.save_history(&self.history_path) {
+                            println!(
+                                "Warning: failed to save history file at {}: {}",
+                                self.history_path, err
+                            );
+                        }
                     }
+                    return Some(line);
                 }
             }
         }
     }
 
-    // Continue the inferior and handle the status returned
-    fn continue_inferior(&mut self) {
-        /*
-        match self.inferior.as_mut().unwrap().continue_process() {
-            Ok(status) => match status {
-                Status::Exited(code) => {
-                    println!("Child exited (status {})", code);
-                    self.inferior = None;
-                }
-                Status::Stopped(signal, rip) => {
-                    println!("Child stopped with {}", signal);
-                    self.inferior
-                        .as_ref()
-                        .unwrap()
-                        .print_stopped_instruction(&self.debug_data, rip);
+    // Read the body of a user-defined command, up to a line saying "end", and save it under
+    // `name`. Running the command runs each line of the body in turn.
+    fn define_command(&mut self, name: String) {
+        if self.pending_commands.is_empty() {
+            println!(
+                "Type commands for {}, one per line. End with a line saying \"end\".",
+                name
+            );
+        }
+        let mut body = Vec::new();
+        loop {
+            match self.read_line(">") {
+                Some(line) if line.trim() == "end" => break,
+                Some(line) => body.push(line),
+                None => {
+                    println!("Definition of {} was not finished", name);
+                    return;
+                }
+            }
+        }
+        self.user_commands.insert(name, body);
+    }
+
+    // Record a new breakpoint, installing it right away if the inferior is running. Breakpoints
+    // are numbered in the order they were added.
+    fn add_breakpoint(&mut self, addr: usize, condition: Option<&Condition>) {
+        if let Some(condition) = condition {
+            if self
+                .debug_data
+                .get_variable(addr, &condition.variable)
+                .is_none()
+            {
+                println!(
+                    "No variable {} in the context of {:#x}",
+                    condition.variable, addr
+                );
+                return;
+            }
+        }
+        let existing = self.breakpoints.iter().position(|bp| *bp == Some(addr));
+        if existing.is_none() {
+            if let Some(inferior) = self.inferior.as_mut() {
+                if let Err(e) = inferior.set_breakpoint(addr) {
+                    println!(
+                        "Error setting breakpoint at {:#x}: {}",
+                        inferior.runtime_addr(addr),
+                        e
+                    );
+                    return;
+                }
+            }
+        }
+        // Setting an existing breakpoint again can add a condition to it (or change the one it
+        // has), but not remove it
+        if let Some(condition) = condition {
+            self.breakpoint_conditions.insert(addr, condition.clone());
+            if let Some(inferior) = self.inferior.as_mut() {
+                inferior.set_breakpoint_condition(addr, Some(condition.clone()));
+            }
+        }
+        let index = match existing {
+            Some(index) if condition.is_none() => {
+                println!(
+                    "Breakpoint {} is already set at {:#x}",
+                    index,
+                    self.shown_addr(addr)
+                );
+                return;
+            }
+            Some(index) => index,
+            None => {
+                self.breakpoints.push(Some(addr));
+                self.breakpoints.len() - 1
+            }
+        };
+        let shown_addr = self.shown_addr(addr);
+        match condition {
+            Some(condition) => println!(
+                "Set breakpoint {} at {:#x} if {}",
+                index, shown_addr, condition
+            ),
+            None => println!("Set breakpoint {} at {:#x}", index, shown_addr),
+        }
+    }
+
+    // Breakpoints are kept at their addresses in the executable, but once the inferior is running
+    // we show and accept the addresses it's been loaded at, as in backtraces and registers. The
+    // two are only different for position-independent executables.
+    fn parse_breakpoint_address(&self, addr: &str) -> Option<usize> {
+        let addr = parse_address(addr)?;
+        Some(match self.inferior.as_ref() {
+            Some(inferior) => inferior.dwarf_addr(addr),
+            None => addr,
+        })
+    }
+
+    fn shown_addr(&self, addr: usize) -> usize {
+        match self.inferior.as_ref() {
+            Some(inferior) => inferior.runtime_addr(addr),
+            None => addr,
+        }
+    }
+
+    // Delete a breakpoint, given its number or *address
+    fn delete_breakpoint(&mut self, which: &str) {
+        let index = if let Some(addr) = which.strip_prefix('*') {
+            self.parse_breakpoint_address(addr)
+                .and_then(|addr| self.breakpoints.iter().position(|bp| *bp == Some(addr)))
+        } else {
+            which
+                .parse::<usize>()
+                .ok()
+                .filter(|index| matches!(self.breakpoints.get(*index), Some(Some(_))))
+        };
+        let index = match index {
+            Some(index) => index,
+            None => {
+                println!("No breakpoint {}", which);
+                return;
+            }
+        };
+        let addr = self.breakpoints[index].take().unwrap();
+        let shown_addr = self.shown_addr(addr);
+        self.breakpoint_conditions.remove(&addr);
+        if let Some(inferior) = self.inferior.as_mut() {
+            if let Err(e) = inferior.remove_breakpoint(addr) {
+                println!("Error removing breakpoint at {:#x}: {}", shown_addr, e);
+            }
+        }
+        println!("Deleted breakpoint {} at {:#x}", index, shown_addr);
+    }
+
+    // List the breakpoints, with their numbers and where they are. Breakpoints are "enabled" when
+    // they're in the running inferior, and "pending" when they'll be put in on the next run.
+    fn print_breakpoints(&self) {
+        if self.breakpoints.iter().all(Option::is_none) {
+            println!("No breakpoints");
+            return;
+        }
+        println!("{:<4}{:<14}{:<10}What", "Num", "Address", "State");
+        for (index, addr) in self.breakpoints.iter().enumerate() {
+            if let Some(addr) = addr {
+                let state = match self.inferior.as_ref() {
+                    Some(inferior) if inferior.has_breakpoint(*addr) => "enabled",
+                    // Inserting it failed when the inferior was started
+                    Some(_) => "failed",
+                    None => "pending",
+                };
+                let function = self
+                    .debug_data
+                    .get_function_from_addr(*addr)
+                    .unwrap_or("??".to_string());
+                let line = self
+                    .debug_data
+                    .get_line_from_addr(*addr)
+                    .unwrap_or_default();
+                let condition = match self.breakpoint_conditions.get(addr) {
+                    Some(condition) => format!(" if {}", condition),
+                    None => String::new(),
+                };
+                println!(
+                    "{:<4}{:<14}{:<10}in {} ({}:{}){}",
+                    index,
+                    format!("{:#x}", self.shown_addr(*addr)),
+                    state,
+                    function,
+                    line.file,
+                    line.number,
+                    condition
+                );
+            }
+        }
+    }
+
+    // Set a breakpoint at a [file:]line. If the line was inlined in several places, every inlined
+    // instance gets its own breakpoint.
+    fn add_line_breakpoints(&mut self, location: &str, condition: Option<&Condition>) {
+        let (file, line) = match location.rsplit_once(':') {
+            Some((file, line)) => (Some(file), line),
+            None => (None, location),
+        };
+        let line_number = match line.parse::<usize>() {
+            Ok(line_number) => line_number,
+            Err(_) => {
+                println!("Unable to parse breakpoint location {}", location);
+                return;
+            }
+        };
+        if let Some(file) = file {
+            if !self.debug_data.has_file(file) {
+                println!("No source file named {}", file);
+                return;
+            }
+        }
+        // Lines without code (say, blank lines or comments) get the breakpoint on the next line
+        // that does have some, like gdb does
+        let code_line = match self.debug_data.next_line_with_code(file, line_number) {
+            Some(code_line) => code_line,
+            None => {
+                println!("No code found at or after line {}", location);
+                return;
+            }
+        };
+        if code_line != line_number {
+            println!(
+                "Line {} has no code; using line {} instead",
+                line_number, code_line
+            );
+        }
+        let addrs = self.debug_data.get_addrs_for_line(file, code_line);
+        if addrs.len() > 1 {
+            println!(
+                "Line {} was inlined in {} places; setting a breakpoint at each",
+                location,
+                addrs.len()
+            );
+        }
+        for addr in addrs {
+            self.add_breakpoint(addr, condition);
+        }
+    }
+
+    // Set a breakpoint on entry to a [file:]function. It goes just past the prologue, so that the
+    // function's frame is set up (and its arguments can be printed) when it's hit.
+    fn add_function_breakpoint(&mut self, location: &str, condition: Option<&Condition>) {
+        let (file, name) = match location.rsplit_once(':') {
+            Some((file, name)) => (Some(file), name),
+            None => (None, location),
+        };
+        let functions = self.debug_data.get_functions_named(file, name);
+        match functions.len() {
+            0 => println!("No function named {}", location),
+            1 => {
+                let (file, func) = functions[0];
+                let addr = func.body_address(file);
+                self.add_breakpoint(addr, condition);
+            }
+            _ => {
+                println!(
+                    "There's more than one function named {}; use file:function to pick one:",
+                    name
+                );
+                for (file, func) in functions {
+                    println!("  {}:{} (line {})", file.name, name, func.line_number);
                 }
-                Status::Signaled(signal) => {
-                    println!("Child signaled with {}", signal)
+            }
+        }
+    }
+
+    // Print a single register, or all of them if `name` is None
+    fn print_registers(&self, name: Option<&str>) {
+        let inferior = match self.inferior.as_ref() {
+            Some(inferior) => inferior,
+            None => {
+                println!("The program is not being run");
+                return;
+            }
+        };
+        let regs = match ptrace::getregs(inferior.pid()) {
+            Ok(regs) => regs,
+            Err(e) => {
+                println!("Unable to read registers: {}", e);
+                return;
+            }
+        };
+        // rip is shown along with where it is in the program
+        let describe = |name: &str, value: u64| {
+            let formatted = registers::format_register(name, value);
+            if name.strip_prefix('$').unwrap_or(name) != "rip" {
+                return formatted;
+            }
+            let addr = inferior.dwarf_addr(value as usize);
+            match self.debug_data.get_function_from_addr(addr) {
+                Some(function) => {
+                    let line = self.debug_data.get_line_from_addr(addr).unwrap_or_default();
+                    format!(
+                        "{} in {} ({}:{})",
+                        formatted, function, line.file, line.number
+                    )
                 }
+                None => formatted,
+            }
+        };
+        match name {
+            Some(name) => match registers::get_register(&regs, name) {
+                Some(value) => println!("{} = {}", name, describe(name, value)),
+                None => println!("Invalid register {}", name),
             },
-            Err(e) => println!("Child errored {}", e),
+            None => {
+                for name in registers::REGISTER_NAMES.iter() {
+                    let value = registers::get_register(&regs, name).unwrap();
+                    println!("{:<8}{}", name, describe(name, value));
+                }
+            }
+        }
+    }
+
+    // Print the value of a variable in the current function (or a global variable), read from the
+    // inferior's memory and formatted according to its type
+    fn print_variable(&self, name: &str) {
+        let inferior = match self.inferior.as_ref() {
+            Some(inferior) => inferior,
+            None => {
+                println!("The program is not being run");
+                return;
+            }
+        };
+        match inferior
+            .read_variable(&self.debug_data, name)
+            .and_then(|(variable, bytes)| variable.entity_type.format_value(&bytes))
+        {
+            Ok(value) => println!("{} = {}", name, value),
+            Err(e) => println!("Unable to print {}: {}", name, e),
+        }
+    }
+
+    // Set an integer variable in the current frame (or a global) to `value`
+    fn set_variable(&mut self, name: &str, value: &str) {
+        let inferior = match self.inferior.as_mut() {
+            Some(inferior) => inferior,
+            None => {
+                println!("The program is not being run");
+                return;
+            }
+        };
+        let value = match condition::parse_integer(value) {
+            Some(value) => value,
+            None => {
+                println!("Invalid value {} (expected an integer)", value);
+                return;
+            }
+        };
+        match inferior.write_variable(&self.debug_data, name, value) {
+            Ok(()) => println!("{} = {}", name, value),
+            Err(e) => println!("Unable to set {}: {}", name, e),
+        }
+    }
+
+    // List the inferior's threads, numbered from 1 in the order they were created, marking the
+    // selected one
+    fn print_threads(&self) {
+        let inferior = match self.inferior.as_ref() {
+            Some(inferior) => inferior,
+            None => {
+                println!("No inferior running");
+                return;
+            }
+        };
+        for (i, tid) in inferior.threads().iter().enumerate() {
+            let marker = if *tid == inferior.pid() { '*' } else { ' ' };
+            match inferior.get_thread_rip(*tid) {
+                Ok(rip) => {
+                    let addr = inferior.dwarf_addr(rip);
+                    let function = self
+                        .debug_data
+                        .get_function_from_addr(addr)
+                        .unwrap_or("??".to_string());
+                    let line = self.debug_data.get_line_from_addr(addr).unwrap_or_default();
+                    println!(
+                        "{} {:<3} Thread {} in {} ({}:{})",
+                        marker,
+                        i + 1,
+                        tid,
+                        function,
+                        line.file,
+                        line.number
+                    );
+                }
+                Err(e) => println!(
+                    "{} {:<3} Thread {} (unable to read registers: {})",
+                    marker,
+                    i + 1,
+                    tid,
+                    e
+                ),
+            }
+        }
+    }
+
+    // Make thread `number` (as listed by `info threads`) the one that subsequent commands inspect
+    fn switch_thread(&mut self, number: usize) {
+        let inferior = match self.inferior.as_mut() {
+            Some(inferior) => inferior,
+            None => {
+                println!("No inferior running");
+                return;
+            }
+        };
+        let tid = match inferior.threads().get(number - 1) {
+            Some(tid) => *tid,
+            None => {
+                println!("No thread {}", number);
+                return;
+            }
+        };
+        inferior.select_thread(tid);
+        println!("Switching to thread {} ({})", number, tid);
+        match inferior.get_rip() {
+            Ok(rip) => self.print_stop_location(rip),
+            Err(e) => println!("Unable to get register value {}", e),
+        }
+    }
+
+    // Print `count` words of the inferior's memory, starting at `addr` (an address or a register
+    // holding one, like $rsp)
+    fn examine_memory(&self, count: usize, format: Format, addr: &str) {
+        let inferior = match self.inferior.as_ref() {
+            Some(inferior) => inferior,
+            None => {
+                println!("No inferior running");
+                return;
+            }
+        };
+        let start = if addr.starts_with('$') {
+            match ptrace::getregs(inferior.pid()) {
+                Ok(regs) => registers::get_register(&regs, addr).map(|value| value as usize),
+                Err(e) => {
+                    println!("Unable to read registers: {}", e);
+                    return;
+                }
+            }
+        } else {
+            parse_address(addr)
+        };
+        let start = match start {
+            Some(start) => start,
+            None => {
+                println!("Unable to parse address {}", addr);
+                return;
+            }
+        };
+        for (word_addr, word) in inferior.read_words(start, count) {
+            match word {
+                Ok(word) => println!("{:#x}: {}", word_addr, examine::format_word(word, format)),
+                Err(e) => println!("{:#x}: Unable to read memory: {}", word_addr, e),
+            }
+        }
+    }
+
+    // Write the inferior's memory from `start` up to (but not including) `end` to a file
+    fn dump_memory(&self, path: &str, start: &str, end: &str) {
+        let inferior = match self.inferior.as_ref() {
+            Some(inferior) => inferior,
+            None => {
+                println!("No inferior running");
+                return;
+            }
+        };
+        let (start, end) = match (parse_address(start), parse_address(end)) {
+            (Some(start), Some(end)) if start < end => (start, end),
+            _ => {
+                println!("Unable to parse address range {} {}", start, end);
+                return;
+            }
+        };
+        match inferior.dump_memory(start, end, path) {
+            Ok(len) => println!("Wrote {} bytes from {:#x} to {}", len, start, path),
+            Err(e) => println!("Unable to dump memory at {:#x} to {}: {}", start, path, e),
         }
-        */
-        self.inferior
+    }
+
+    // Load the contents of a file into the inferior's memory, starting at `addr`
+    fn restore_memory(&mut self, path: &str, addr: &str) {
+        let inferior = match self.inferior.as_mut() {
+            Some(inferior) => inferior,
+            None => {
+                println!("No inferior running");
+                return;
+            }
+        };
+        let addr = match parse_address(addr) {
+            Some(addr) => addr,
+            None => {
+                println!("Unable to parse address {}", addr);
+                return;
+            }
+        };
+        match inferior.restore_memory(path, addr) {
+            Ok(len) => println!("Restored {} bytes from {} to {:#x}", len, path, addr),
+            Err(e) => println!("Unable to restore {} to {:#x}: {}", path, addr, e),
+        }
+    }
+
+    // Run until the current function returns, and report which function that was and where we
+    // ended up
+    fn finish_function(&mut self) {
+        let inferior = self.inferior.as_mut().unwrap();
+        let rip = match inferior.get_rip() {
+            Ok(rip) => rip,
+            Err(e) => {
+                println!("Unable to get register value {}", e);
+                return;
+            }
+        };
+        let function = self
+            .debug_data
+            .get_function_from_addr(inferior.dwarf_addr(rip))
+            .unwrap_or("??".to_string());
+        if function == "main" {
+            println!("\"finish\" doesn't make sense in main, the outermost frame");
+            return;
+        }
+        let return_addr = match inferior.return_address(&self.debug_data) {
+            Ok(return_addr) => return_addr,
+            Err(e) => {
+                println!("Unable to find where {} returns to: {}", function, e);
+                return;
+            }
+        };
+        println!("Running until {} returns to {:#x}", function, return_addr);
+        let status = inferior.run_to_return(return_addr);
+        // We might have stopped somewhere else first, e.g. at a breakpoint
+        if let Ok(Status::Stopped(Signal::SIGTRAP, rip)) = status {
+            if rip == return_addr {
+                println!("Finished {}", function);
+            }
+        }
+        self.report_status(status);
+    }
+
+    // Single-step the inferior up to `max_steps` times, reporting how many instructions were
+    // executed before it stopped
+    fn step_and_count(&mut self, max_steps: usize) {
+        let result = self
+            .inferior
             .as_mut()
             .unwrap()
-            .continue_child(&self.debug_data);
+            .step_instructions(max_steps, &self.debug_data);
+        match result {
+            Ok((status, steps, hit_limit)) => {
+                println!("Executed {} instructions", steps);
+                if hit_limit && max_steps == MAX_COUNTED_STEPS {
+                    println!(
+                        "Stopped counting after reaching the limit of {} instructions, before the \
+                         program reached a breakpoint or exited",
+                        MAX_COUNTED_STEPS
+                    );
+                }
+                self.report_status(Ok(status));
+            }
+            Err(e) => self.report_status(Err(e)),
+        }
+    }
+
+    // Continue the inferior and handle the status returned
+    fn continue_inferior(&mut self) {
+        if self.count_instructions {
+            self.step_and_count(MAX_COUNTED_STEPS);
+            return;
+        }
+        let status = self
+            .inferior
+            .as_mut()
+            .unwrap()
+            .continue_running(&self.debug_data);
+        self.report_status(status);
+    }
+
+    // Print the status the inferior stopped with, forgetting about it if it's no longer running
+    fn report_status(&mut self, status: Result<Status, nix::Error>) {
+        match status {
+            Ok(Status::Exited(code)) => {
+                println!("Child exited (status {})", code);
+                self.inferior = None;
+            }
+            Ok(Status::Signaled(signal)) => {
+                println!("Child signaled with {}", signal);
+                self.inferior = None;
+            }
+            Ok(Status::Stopped(Signal::SIGTRAP, rip))
+                if self
+                    .panic_breakpoint
+                    .map(|addr| self.inferior.as_ref().unwrap().runtime_addr(addr))
+                    == Some(rip) =>
+            {
+                self.report_panic();
+            }
+            Ok(Status::Stopped(signal, rip)) => {
+                if signal != Signal::SIGTRAP {
+                    println!("Child stopped with {}", signal);
+                }
+                self.print_stop_location(rip);
+            }
+            Err(e) => println!("Child errored {}", e),
+        }
+    }
+
+    // Report a Rust panic the inferior has stopped in: where in the program it came from, with the
+    // surrounding source
+    fn report_panic(&mut self) {
+        let inferior = self.inferior.as_ref().unwrap();
+        match inferior.read_panic_message(&self.debug_data) {
+            Some(message) => println!("Child panicked: {}", message),
+            None => println!("Child panicked (the panic message is in the program's output above)"),
+        }
+        let call_site = match inferior.find_panic_call_site(&self.debug_data) {
+            Some(call_site) => inferior.dwarf_addr(call_site),
+            None => {
+                println!("Unable to find where in the program the panic came from");
+                return;
+            }
+        };
+        let function = self
+            .debug_data
+            .get_function_from_addr(call_site)
+            .unwrap_or("Unable to get function name".to_string());
+        let line = self
+            .debug_data
+            .get_line_from_addr(call_site)
+            .unwrap_or_default();
+        println!("Panicked in {} ({}:{})", function, line.file, line.number);
+        self.print_source_context(&line.file, line.number);
+    }
+
+    // Print the function and line the inferior is stopped at, followed by the source around it
+    fn print_stop_location(&mut self, rip: usize) {
+        let inferior = self.inferior.as_ref().unwrap();
+        inferior.print_stopped_instruction(&self.debug_data, rip);
+        if let Some(line) = self.debug_data.get_line_from_addr(inferior.dwarf_addr(rip)) {
+            self.print_source_context(&line.file, line.number);
+        }
+    }
+
+    // Print the lines of `file` around line `number`, marking that line with an arrow. We only try
+    // to read each file once, so a missing file is only complained about the first time.
+    fn print_source_context(&mut self, file: &str, number: usize) {
+        let source = self
+            .source_files
+            .entry(file.to_string())
+            .or_insert_with(|| match std::fs::read_to_string(file) {
+                Ok(source) => Some(source),
+                Err(e) => {
+                    println!("Unable to read source file {}: {}", file, e);
+                    None
+                }
+            });
+        if let Some(source) = source {
+            for (line_number, text) in listing::source_context(source, number, SOURCE_CONTEXT_LINES)
+            {
+                let marker = if line_number == number { "->" } else { "  " };
+                println!("{} {:>4} {}", marker, line_number, text);
+            }
+        }
     }
 
     // Kill any inferior running
@@ -186,10 +1085,106 @@ impl Debugger {
         if self.inferior.is_some() {
             println!(
                 "Killing running inferior (pid {})",
-                self.inferior.as_ref().unwrap().pid()
+                self.inferior.as_ref().unwrap().process_id()
             );
             self.inferior.as_mut().unwrap().kill();
             self.inferior = None;
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Runs each line in turn, as if it had been typed at the prompt, along with anything those
+    /// lines queue up
+    fn run_lines(debugger: &mut Debugger, lines: &[&str]) {
+        for line in lines.iter().rev() {
+            debugger.pending_commands.push_front(line.to_string());
+        }
+        while let Some(line) = debugger.pending_commands.pop_front() {
+            if let Some(cmd) = debugger.parse_line(&line) {
+                assert!(debugger.run_command(cmd));
+            }
+        }
+    }
+
+    #[test]
+    fn test_user_defined_command() {
+        // Needs the samples to have been built (run `make` first)
+        let mut debugger = Debugger::new("samples/function_calls", false);
+        run_lines(
+            &mut debugger,
+            &[
+                "define breakboth",
+                "break func2",
+                "# Comments are skipped",
+                "break func3",
+                "end",
+                "alias bb = breakboth",
+            ],
+        );
+        assert!(debugger.breakpoints.is_empty());
+
+        // Both lines of the body run, including when the command is called through an alias
+        run_lines(&mut debugger, &["bb"]);
+        assert_eq!(debugger.breakpoints.len(), 2);
+        assert_ne!(debugger.breakpoints[0], debugger.breakpoints[1]);
+    }
+
+    #[test]
+    fn test_init_file() {
+        use std::os::unix::fs::PermissionsExt;
+        // Needs the samples to have been built (run `make` first)
+        let mut debugger = Debugger::new("samples/function_calls", false);
+        let path = std::env::temp_dir().join(format!("deetinit-test-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        std::fs::write(path, "# Stop in func3\nbreak func3\n").unwrap();
+
+        // Skipped while anyone can write to it
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o666)).unwrap();
+        debugger.source_init_file_at(path);
+        assert!(debugger.pending_commands.is_empty());
+
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        debugger.source_init_file_at(path);
+        std::fs::remove_file(path).unwrap();
+        // The queued commands run before the first prompt, and so before anything typed there
+        run_lines(&mut debugger, &[]);
+        assert_eq!(debugger.breakpoints.len(), 1);
+        run_lines(&mut debugger, &["run"]);
+        let (file, func) = debugger.debug_data.get_functions_named(None, "func3")[0];
+        let addr = func.body_address(file);
+        let inferior = debugger
+            .inferior
+            .as_ref()
+            .expect("The inferior should be stopped");
+        assert_eq!(inferior.get_rip().unwrap(), inferior.runtime_addr(addr));
+        debugger.kill_inferior();
+    }
+
+    #[test]
+    fn test_break_at_runtime_address() {
+        // Needs the samples to have been built (run `make` first)
+        let mut debugger = Debugger::new("samples/function_calls_pie", false);
+        run_lines(&mut debugger, &["break func1", "run"]);
+        let (file, func) = debugger.debug_data.get_functions_named(None, "func3")[0];
+        let addr = func.body_address(file);
+        let runtime_addr = debugger.inferior.as_ref().unwrap().runtime_addr(addr);
+        assert_ne!(runtime_addr, addr);
+
+        // The address is where func3 has been loaded, but the breakpoint is kept at its address in
+        // the executable, like `break func3` would be
+        let break_command = format!("break *{:#x}", runtime_addr);
+        run_lines(&mut debugger, &[&break_command, "continue"]);
+        assert_eq!(debugger.breakpoints[1], Some(addr));
+        let inferior = debugger.inferior.as_ref().unwrap();
+        assert_eq!(inferior.get_rip().unwrap(), runtime_addr);
+
+        let delete_command = format!("delete *{:#x}", runtime_addr);
+        run_lines(&mut debugger, &[&delete_command]);
+        assert_eq!(debugger.breakpoints[1], None);
+        debugger.kill_inferior();
+    }
+}
@@ -1,9 +1,33 @@
+use crate::examine::{self, Format};
+
 pub enum DebuggerCommand {
     Quit,
     Continue,
+    Next,
+    Step,
+    Finish,
+    StepInstruction(usize),
     Backtrace,
-    Breakpoint(String),
+    Breakpoint(String, Option<String>),
+    Delete(String),
+    InfoBreakpoints,
     Run(Vec<String>),
+    SetEnv(String, String),
+    SetVariable(String, String),
+    UnsetEnv(String),
+    ShowEnv,
+    Checkpoint,
+    Restart,
+    InfoRegisters(Option<String>),
+    Print(String),
+    DumpMemory(String, String, String),
+    Examine(usize, Format, String),
+    Restore(String, String),
+    InfoThreads,
+    Thread(usize),
+    Source(String),
+    Define(String),
+    Alias(String, String),
 }
 
 impl DebuggerCommand {
@@ -17,8 +41,116 @@ impl DebuggerCommand {
                 ))
             }
             "c" | "cont" | "continue" => Some(DebuggerCommand::Continue),
+            "n" | "next" => Some(DebuggerCommand::Next),
+            "s" | "step" => Some(DebuggerCommand::Step),
+            "fin" | "finish" => Some(DebuggerCommand::Finish),
+            "si" | "stepi" => match tokens.get(1) {
+                Some(count) => match count.parse::<usize>() {
+                    Ok(count) if count > 0 => Some(DebuggerCommand::StepInstruction(count)),
+                    _ => None,
+                },
+                None => Some(DebuggerCommand::StepInstruction(1)),
+            },
             "bt" | "back" | "backtrace" => Some(DebuggerCommand::Backtrace),
-            "b" | "break" => Some(DebuggerCommand::Breakpoint(tokens[1].to_string())),
+            "b" | "break" if tokens.len() >= 2 => {
+                // break <location> [if <condition>]
+                let condition = match tokens.get(2) {
+                    None => None,
+                    Some(&"if") if tokens.len() > 3 => Some(tokens[3..].join(" ")),
+                    Some(_) => return None,
+                };
+                Some(DebuggerCommand::Breakpoint(
+                    tokens[1].to_string(),
+                    condition,
+                ))
+            }
+            "d" | "delete" if tokens.len() == 2 => {
+                Some(DebuggerCommand::Delete(tokens[1].to_string()))
+            }
+            "set" if tokens.get(1) == Some(&"env") => {
+                let assignment = tokens[2..].join(" ");
+                let (key, value) = assignment.split_once('=')?;
+                if key.is_empty() {
+                    return None;
+                }
+                Some(DebuggerCommand::SetEnv(key.to_string(), value.to_string()))
+            }
+            // set <variable> = <value>
+            "set" if tokens.len() >= 2 => {
+                let assignment = tokens[1..].join(" ");
+                let (name, value) = assignment.split_once('=')?;
+                let (name, value) = (name.trim(), value.trim());
+                if name.is_empty() || name.contains(' ') || value.is_empty() {
+                    return None;
+                }
+                Some(DebuggerCommand::SetVariable(
+                    name.to_string(),
+                    value.to_string(),
+                ))
+            }
+            "unset" if tokens.get(1) == Some(&"env") && tokens.len() == 3 => {
+                Some(DebuggerCommand::UnsetEnv(tokens[2].to_string()))
+            }
+            "show" if tokens.get(1) == Some(&"env") => Some(DebuggerCommand::ShowEnv),
+            "checkpoint" => Some(DebuggerCommand::Checkpoint),
+            "restart" => Some(DebuggerCommand::Restart),
+            "i" | "info" if matches!(tokens.get(1), Some(&"r") | Some(&"registers")) => Some(
+                DebuggerCommand::InfoRegisters(tokens.get(2).map(|reg| reg.to_string())),
+            ),
+            "regs" => Some(DebuggerCommand::InfoRegisters(
+                tokens.get(1).map(|reg| reg.to_string()),
+            )),
+            "i" | "info" if tokens.get(1) == Some(&"threads") => Some(DebuggerCommand::InfoThreads),
+            "i" | "info"
+                if matches!(
+                    tokens.get(1),
+                    Some(&"b") | Some(&"break") | Some(&"breakpoints")
+                ) =>
+            {
+                Some(DebuggerCommand::InfoBreakpoints)
+            }
+            "breakpoints" => Some(DebuggerCommand::InfoBreakpoints),
+            "thread" if tokens.len() == 2 => match tokens[1].parse::<usize>() {
+                Ok(number) if number > 0 => Some(DebuggerCommand::Thread(number)),
+                _ => None,
+            },
+            "p" | "print" if tokens.len() == 2 => {
+                Some(DebuggerCommand::Print(tokens[1].to_string()))
+            }
+            "dump" if tokens.get(1) == Some(&"memory") && tokens.len() == 5 => {
+                Some(DebuggerCommand::DumpMemory(
+                    tokens[2].to_string(),
+                    tokens[3].to_string(),
+                    tokens[4].to_string(),
+                ))
+            }
+            // x/<count><format> <address>
+            cmd if (cmd == "x" || cmd.starts_with("x/")) && tokens.len() == 2 => {
+                let (count, format) = examine::parse_spec(cmd.strip_prefix("x/").unwrap_or(""))?;
+                Some(DebuggerCommand::Examine(
+                    count,
+                    format,
+                    tokens[1].to_string(),
+                ))
+            }
+            "restore" if tokens.len() == 3 => Some(DebuggerCommand::Restore(
+                tokens[1].to_string(),
+                tokens[2].to_string(),
+            )),
+            "source" if tokens.len() == 2 => Some(DebuggerCommand::Source(tokens[1].to_string())),
+            "define" if tokens.len() == 2 => Some(DebuggerCommand::Define(tokens[1].to_string())),
+            "alias" => {
+                let assignment = tokens[1..].join(" ");
+                let (name, expansion) = assignment.split_once('=')?;
+                let (name, expansion) = (name.trim(), expansion.trim());
+                if name.is_empty() || name.contains(' ') || expansion.is_empty() {
+                    return None;
+                }
+                Some(DebuggerCommand::Alias(
+                    name.to_string(),
+                    expansion.to_string(),
+                ))
+            }
             // Default case:
             _ => None,
         }
@@ -66,6 +66,103 @@ impl DwarfData {
         )
     }
 
+    /// Returns true if there's debugging information for a source file called `file`.
+    pub fn has_file(&self, file: &str) -> bool {
+        self.get_target_file(file).is_some()
+    }
+
+    /// Returns `line_number` if there's code for that line, or otherwise the next line after it
+    /// that has code (e.g. for a blank line or a comment). Returns None if there's no code at or
+    /// after the line.
+    pub fn next_line_with_code(&self, file: Option<&str>, line_number: usize) -> Option<usize> {
+        let target_file = match file {
+            Some(filename) => self.get_target_file(filename)?,
+            None => self.files.first()?,
+        };
+        target_file
+            .lines
+            .iter()
+            .map(|line| line.number)
+            .filter(|number| *number >= line_number)
+            .min()
+    }
+
+    /// Returns the first address of every instance of a line. A line normally has a single
+    /// instance, but a line in a function that was inlined in several places has one per
+    /// inlining site.
+    pub fn get_addrs_for_line(&self, file: Option<&str>, line_number: usize) -> Vec<usize> {
+        let target_file = match file {
+            Some(filename) => self.get_target_file(filename),
+            None => self.files.first(),
+        };
+        let target_file = match target_file {
+            Some(target_file) => target_file,
+            None => return Vec::new(),
+        };
+        // Addresses with the same inlining chain belong to the same instance of the line
+        let mut instances: Vec<(Vec<String>, usize)> = Vec::new();
+        for line in target_file
+            .lines
+            .iter()
+            .filter(|line| line.number == line_number)
+        {
+            let chain = self.get_inline_chain(line.address).unwrap_or_default();
+            match instances.iter_mut().find(|(other, _)| *other == chain) {
+                Some(instance) => instance.1 = instance.1.min(line.address),
+                None => instances.push((chain, line.address)),
+            }
+        }
+        let mut addrs: Vec<usize> = instances.into_iter().map(|(_, addr)| addr).collect();
+        addrs.sort_unstable();
+        addrs
+    }
+
+    /// Returns the functions that an address is nested in, along with the call sites that were
+    /// inlined to get there. The innermost frame's own location is left out, since that's just the
+    /// line at `curr_addr`.
+    fn get_inline_chain(&self, curr_addr: usize) -> Option<Vec<String>> {
+        let mut frames = self
+            .addr2line
+            .find_frames(curr_addr.try_into().unwrap())
+            .ok()?;
+        let mut chain = Vec::new();
+        let mut innermost = true;
+        while let Some(frame) = frames.next().ok()? {
+            if let Some(function) = &frame.function {
+                chain.push(function.raw_name().ok()?.to_string());
+            }
+            if !innermost {
+                if let Some(location) = &frame.location {
+                    chain.push(format!(
+                        "{}:{}",
+                        location.file.unwrap_or("<unknown>"),
+                        location.line.unwrap_or(0)
+                    ));
+                }
+            }
+            innermost = false;
+        }
+        Some(chain)
+    }
+
+    /// Returns the functions called `name` (in `file`, if one is given), along with the files
+    /// they're in. There can be more than one, e.g. static functions in different files.
+    pub fn get_functions_named(&self, file: Option<&str>, name: &str) -> Vec<(&File, &Function)> {
+        let files: Vec<&File> = match file {
+            Some(filename) => self.get_target_file(filename).into_iter().collect(),
+            None => self.files.iter().collect(),
+        };
+        files
+            .into_iter()
+            .flat_map(|file| {
+                file.functions
+                    .iter()
+                    .filter(move |func| func.name == name)
+                    .map(move |func| (file, func))
+            })
+            .collect()
+    }
+
     #[allow(dead_code)]
     pub fn get_addr_for_function(&self, file: Option<&str>, func_name: &str) -> Option<usize> {
         match file {
@@ -87,6 +184,23 @@ impl DwarfData {
         }
     }
 
+    /// Returns the variable called `name` that's visible from `addr`: a local variable or
+    /// parameter of the function containing `addr` if there is one, and otherwise a global.
+    pub fn get_variable(&self, addr: usize, name: &str) -> Option<&Variable> {
+        let local = self
+            .files
+            .iter()
+            .flat_map(|file| file.functions.iter())
+            .find(|func| func.address <= addr && addr < func.address + func.text_length)
+            .and_then(|func| func.variables.iter().find(|var| var.name == name));
+        local.or_else(|| {
+            self.files
+                .iter()
+                .flat_map(|file| file.global_variables.iter())
+                .find(|var| var.name == name)
+        })
+    }
+
     #[allow(dead_code)]
     pub fn get_line_from_addr(&self, curr_addr: usize) -> Option<Line> {
         let location = self
@@ -148,19 +262,141 @@ impl DwarfData {
     }
 }
 
+/// How the bytes of a value are to be interpreted (DWARF's DW_AT_encoding for base types, plus
+/// pointers)
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Encoding {
+    Signed,
+    Unsigned,
+    SignedChar,
+    UnsignedChar,
+    /// A Rust char, which is a 4-byte Unicode scalar value
+    Utf,
+    Boolean,
+    Float,
+    Pointer,
+    #[default]
+    Unsupported,
+}
+
 #[derive(Debug, Clone, Default)]
 pub struct Type {
     pub name: String,
     pub size: usize,
+    pub encoding: Encoding,
 }
 
 impl Type {
-    pub fn new(name: String, size: usize) -> Self {
+    pub fn new(name: String, size: usize, encoding: Encoding) -> Self {
         Type {
             name: name,
             size: size,
+            encoding: encoding,
+        }
+    }
+
+    /// Returns the value in `bytes` both as an unsigned and as a sign-extended integer, or None if
+    /// it isn't `size` bytes long or is too big for that.
+    fn decode(&self, bytes: &[u8]) -> Option<(u64, i64)> {
+        if bytes.len() != self.size || self.size == 0 || self.size > 8 {
+            return None;
+        }
+        // Values are little-endian on x86-64
+        let raw = bytes
+            .iter()
+            .rev()
+            .fold(0_u64, |value, byte| (value << 8) | *byte as u64);
+        let shift = 64 - 8 * self.size;
+        Some((raw, ((raw << shift) as i64) >> shift))
+    }
+
+    /// Returns the value of an integer (or char or bool) of this type, given its bytes, or None if
+    /// this isn't an integer type.
+    pub fn integer_value(&self, bytes: &[u8]) -> Option<i128> {
+        let (raw, signed) = self.decode(bytes)?;
+        match self.encoding {
+            Encoding::Signed | Encoding::SignedChar => Some(signed as i128),
+            Encoding::Unsigned | Encoding::UnsignedChar | Encoding::Boolean | Encoding::Utf => {
+                Some(raw as i128)
+            }
+            _ => None,
         }
     }
+
+    /// Returns the bytes of an integer (or char or bool) of this type with the value `value`, for
+    /// writing to the inferior's memory. Returns an error if this isn't an integer type or the
+    /// value doesn't fit in it.
+    pub fn encode_integer(&self, value: i128) -> Result<Vec<u8>, String> {
+        let signed = match self.encoding {
+            Encoding::Signed | Encoding::SignedChar => true,
+            Encoding::Unsigned | Encoding::UnsignedChar | Encoding::Boolean | Encoding::Utf => {
+                false
+            }
+            _ => {
+                return Err(format!(
+                    "setting values of type {} isn't supported",
+                    self.name
+                ))
+            }
+        };
+        if self.size == 0 || self.size > 8 {
+            return Err(format!(
+                "setting values of type {} isn't supported",
+                self.name
+            ));
+        }
+        let bits = 8 * self.size;
+        let (min, max) = if signed {
+            (-(1_i128 << (bits - 1)), (1_i128 << (bits - 1)) - 1)
+        } else {
+            (0, (1_i128 << bits) - 1)
+        };
+        if value < min || value > max {
+            return Err(format!("{} doesn't fit in a {}", value, self.name));
+        }
+        // Truncating the two's complement little-endian bytes works for negative values too
+        Ok(value.to_le_bytes()[..self.size].to_vec())
+    }
+
+    /// Formats a value of this type, given its bytes as read from the inferior's memory (which must
+    /// be `size` bytes long). Returns an error for types we don't know how to print.
+    pub fn format_value(&self, bytes: &[u8]) -> Result<String, String> {
+        let unsupported = || {
+            Err(format!(
+                "printing values of type {} isn't supported",
+                self.name
+            ))
+        };
+        let (raw, signed) = match self.decode(bytes) {
+            Some(decoded) => decoded,
+            None => return unsupported(),
+        };
+        Ok(match self.encoding {
+            Encoding::Signed => signed.to_string(),
+            Encoding::Unsigned => raw.to_string(),
+            Encoding::SignedChar | Encoding::UnsignedChar if self.size == 1 => {
+                let number = if self.encoding == Encoding::SignedChar {
+                    signed.to_string()
+                } else {
+                    raw.to_string()
+                };
+                if raw < 0x80 {
+                    format!("{} {:?}", number, raw as u8 as char)
+                } else {
+                    format!("{} '\\x{:02x}'", number, raw)
+                }
+            }
+            Encoding::Utf if self.size == 4 => match std::char::from_u32(raw as u32) {
+                Some(c) => format!("{:?}", c),
+                None => format!("{:#x} (invalid char)", raw),
+            },
+            Encoding::Boolean => (raw != 0).to_string(),
+            Encoding::Float if self.size == 4 => f32::from_bits(raw as u32).to_string(),
+            Encoding::Float if self.size == 8 => f64::from_bits(raw).to_string(),
+            Encoding::Pointer => format!("{:#x}", raw),
+            _ => return unsupported(),
+        })
+    }
 }
 
 #[derive(Clone)]
@@ -202,12 +438,40 @@ pub struct Function {
     pub variables: Vec<Variable>,
 }
 
+impl Function {
+    /// Returns the address of the first instruction after the function's prologue (which sets up
+    /// the stack frame), given the file it's in. A breakpoint there sees a valid rbp, so that
+    /// backtraces and variables work. If the compiler marked where the prologue ends, we use that;
+    /// otherwise we rely on the prologue being attributed to the line the function starts on, so
+    /// that the body starts at the function's second row in the line table.
+    pub fn body_address(&self, file: &File) -> usize {
+        let in_function =
+            |addr: &usize| *addr > self.address && *addr < self.address + self.text_length;
+        file.prologue_ends
+            .iter()
+            .copied()
+            .filter(in_function)
+            .min()
+            .or_else(|| {
+                file.lines
+                    .iter()
+                    .map(|line| line.address)
+                    .filter(in_function)
+                    .min()
+            })
+            .unwrap_or(self.address)
+    }
+}
+
 #[derive(Debug, Default, Clone)]
 pub struct File {
     pub name: String,
     pub global_variables: Vec<Variable>,
     pub functions: Vec<Function>,
     pub lines: Vec<Line>,
+    /// Addresses the compiler marked as the end of a function's prologue (clang does this; gcc
+    /// doesn't)
+    pub prologue_ends: Vec<usize>,
 }
 
 #[derive(Debug, Clone, PartialEq, Default)]
@@ -222,3 +486,111 @@ impl fmt::Display for Line {
         write!(f, "{}:{}", self.file, self.number)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_format_value() {
+        let int = Type::new("int".to_string(), 4, Encoding::Signed);
+        assert_eq!(int.format_value(&(-42_i32).to_le_bytes()).unwrap(), "-42");
+        let unsigned = Type::new("unsigned int".to_string(), 4, Encoding::Unsigned);
+        assert_eq!(unsigned.format_value(&[0xff; 4]).unwrap(), "4294967295");
+        let long = Type::new("long int".to_string(), 8, Encoding::Signed);
+        assert_eq!(
+            long.format_value(&(1_i64 << 40).to_le_bytes()).unwrap(),
+            "1099511627776"
+        );
+
+        let c = Type::new("char".to_string(), 1, Encoding::SignedChar);
+        assert_eq!(c.format_value(b"A").unwrap(), "65 'A'");
+        assert_eq!(c.format_value(b"\n").unwrap(), "10 '\\n'");
+        assert_eq!(c.format_value(&[0xe9]).unwrap(), "-23 '\\xe9'");
+
+        let boolean = Type::new("_Bool".to_string(), 1, Encoding::Boolean);
+        assert_eq!(boolean.format_value(&[1]).unwrap(), "true");
+        assert_eq!(boolean.format_value(&[0]).unwrap(), "false");
+
+        let double = Type::new("double".to_string(), 8, Encoding::Float);
+        assert_eq!(double.format_value(&1.5_f64.to_le_bytes()).unwrap(), "1.5");
+
+        let pointer = Type::new("char *".to_string(), 8, Encoding::Pointer);
+        assert_eq!(
+            pointer.format_value(&0x7ffe1234_u64.to_le_bytes()).unwrap(),
+            "0x7ffe1234"
+        );
+    }
+
+    #[test]
+    fn test_encode_integer() {
+        let int = Type::new("int".to_string(), 4, Encoding::Signed);
+        assert_eq!(int.encode_integer(-42).unwrap(), (-42_i32).to_le_bytes());
+        assert_eq!(
+            int.encode_integer(i32::MAX as i128).unwrap(),
+            i32::MAX.to_le_bytes()
+        );
+        assert!(int.encode_integer(1 << 31).is_err());
+        let unsigned = Type::new("unsigned long".to_string(), 8, Encoding::Unsigned);
+        assert_eq!(
+            unsigned.encode_integer(u64::MAX as i128).unwrap(),
+            [0xff; 8]
+        );
+        assert!(unsigned.encode_integer(-1).is_err());
+        let c = Type::new("char".to_string(), 1, Encoding::SignedChar);
+        assert_eq!(c.encode_integer(65).unwrap(), b"A");
+
+        let double = Type::new("double".to_string(), 8, Encoding::Float);
+        assert!(double.encode_integer(1).is_err());
+    }
+
+    #[test]
+    fn test_next_line_with_code() {
+        // Needs the samples to have been built (run `make` first)
+        let debug_data =
+            DwarfData::from_file("samples/function_calls").expect("Run make to build the samples");
+        assert_eq!(debug_data.next_line_with_code(None, 6), Some(6));
+        // Neither the blank line nor the global variable above func3 has any code
+        assert_eq!(debug_data.next_line_with_code(None, 3), Some(5));
+        assert_eq!(
+            debug_data.next_line_with_code(Some("function_calls.c"), 22),
+            Some(23)
+        );
+        assert_eq!(debug_data.next_line_with_code(None, 100), None);
+        assert!(debug_data.has_file("function_calls.c"));
+        assert!(!debug_data.has_file("nonexistent.c"));
+    }
+
+    #[test]
+    fn test_get_functions_named() {
+        // Needs the samples to have been built (run `make` first)
+        let debug_data =
+            DwarfData::from_file("samples/function_calls").expect("Run make to build the samples");
+        let functions = debug_data.get_functions_named(None, "func2");
+        assert_eq!(functions.len(), 1);
+        let (file, func) = functions[0];
+        // Past the prologue, we're on the first line of the body
+        let addr = func.body_address(file);
+        assert!(addr > func.address);
+        assert_eq!(debug_data.get_line_from_addr(addr).unwrap().number, 10);
+
+        assert_eq!(
+            debug_data
+                .get_functions_named(Some("function_calls.c"), "func2")
+                .len(),
+            1
+        );
+        assert!(debug_data.get_functions_named(None, "func4").is_empty());
+        assert!(debug_data
+            .get_functions_named(Some("nonexistent.c"), "func2")
+            .is_empty());
+    }
+
+    #[test]
+    fn test_format_unsupported_value() {
+        let complex = Type::new("complex float".to_string(), 8, Encoding::Unsupported);
+        assert!(complex.format_value(&[0; 8]).is_err());
+        let long_double = Type::new("long double".to_string(), 16, Encoding::Float);
+        assert!(long_double.format_value(&[0; 16]).is_err());
+    }
+}
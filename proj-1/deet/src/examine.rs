@@ -0,0 +1,74 @@
+//! Formatting raw memory for the `x` (examine) command.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Hex,
+    Decimal,
+    /// The word's bytes, in the order they're in memory
+    Bytes,
+}
+
+/// Parses what comes after the slash in `x/<count><format>`, where the format is `x` (hex), `d`
+/// (decimal) or `i` (bytes). Either part can be left out: the count defaults to 1 word and the
+/// format to hex.
+pub fn parse_spec(spec: &str) -> Option<(usize, Format)> {
+    let digits_len = spec
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(spec.len());
+    let count = match &spec[..digits_len] {
+        "" => 1,
+        digits => digits.parse::<usize>().ok()?,
+    };
+    let format = match &spec[digits_len..] {
+        "" | "x" => Format::Hex,
+        "d" => Format::Decimal,
+        "i" => Format::Bytes,
+        _ => return None,
+    };
+    if count == 0 {
+        return None;
+    }
+    Some((count, format))
+}
+
+/// Formats a word read from the inferior. Decimal words are taken to be signed.
+pub fn format_word(word: u64, format: Format) -> String {
+    match format {
+        Format::Hex => format!("{:#018x}", word),
+        Format::Decimal => (word as i64).to_string(),
+        Format::Bytes => word
+            .to_le_bytes()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<Vec<String>>()
+            .join(" "),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_spec() {
+        assert_eq!(parse_spec(""), Some((1, Format::Hex)));
+        assert_eq!(parse_spec("4x"), Some((4, Format::Hex)));
+        assert_eq!(parse_spec("d"), Some((1, Format::Decimal)));
+        assert_eq!(parse_spec("16i"), Some((16, Format::Bytes)));
+        assert_eq!(parse_spec("8"), Some((8, Format::Hex)));
+        assert_eq!(parse_spec("0x"), None);
+        assert_eq!(parse_spec("4s"), None);
+        assert_eq!(parse_spec("x4"), None);
+    }
+
+    #[test]
+    fn test_format_word() {
+        assert_eq!(format_word(0x2a, Format::Hex), "0x000000000000002a");
+        assert_eq!(format_word(42, Format::Decimal), "42");
+        assert_eq!(format_word(u64::MAX, Format::Decimal), "-1");
+        assert_eq!(
+            format_word(0x0807060504030201, Format::Bytes),
+            "01 02 03 04 05 06 07 08"
+        );
+    }
+}
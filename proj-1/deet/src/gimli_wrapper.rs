@@ -10,7 +10,7 @@ use gimli::{UnitOffset, UnitSectionOffset};
 use object::Object;
 use std::borrow;
 //use std::io::{BufWriter, Write};
-use crate::dwarf_data::{File, Function, Line, Location, Type, Variable};
+use crate::dwarf_data::{Encoding, File, Function, Line, Location, Type, Variable};
 use std::collections::HashMap;
 use std::convert::TryInto;
 use std::fmt::Write;
@@ -72,6 +72,7 @@ pub fn load_file(object: &object::File, endian: gimli::RunTimeEndian) -> Result<
                         global_variables: Vec::new(),
                         functions: Vec::new(),
                         lines: Vec::new(),
+                        prologue_ends: Vec::new(),
                     });
                 }
                 gimli::DW_TAG_base_type => {
@@ -97,9 +98,57 @@ pub fn load_file(object: &object::File, endian: gimli::RunTimeEndian) -> Result<
                         // TODO: report error?
                         0
                     };
+                    let encoding = if let Ok(Some(gimli::AttributeValue::Encoding(encoding))) =
+                        entry.attr_value(gimli::DW_AT_encoding)
+                    {
+                        match encoding {
+                            gimli::DW_ATE_signed => Encoding::Signed,
+                            gimli::DW_ATE_unsigned => Encoding::Unsigned,
+                            gimli::DW_ATE_signed_char => Encoding::SignedChar,
+                            gimli::DW_ATE_unsigned_char => Encoding::UnsignedChar,
+                            gimli::DW_ATE_UTF => Encoding::Utf,
+                            gimli::DW_ATE_boolean => Encoding::Boolean,
+                            gimli::DW_ATE_float => Encoding::Float,
+                            _ => Encoding::Unsupported,
+                        }
+                    } else {
+                        Encoding::Unsupported
+                    };
                     let type_offset = entry.offset().0;
-                    offset_to_type
-                        .insert(type_offset, Type::new(name, byte_size.try_into().unwrap()));
+                    offset_to_type.insert(
+                        type_offset,
+                        Type::new(name, byte_size.try_into().unwrap(), encoding),
+                    );
+                }
+                gimli::DW_TAG_pointer_type => {
+                    // Pointers don't have names of their own, so we name them after what they point
+                    // to (if we've seen that type yet)
+                    let name = if let Ok(Some(attr)) = entry.attr(gimli::DW_AT_type) {
+                        match get_attr_value(&attr, &unit, &dwarf) {
+                            Ok(DebugValue::Size(offset)) => match offset_to_type.get(&offset) {
+                                Some(pointee) => format!("{} *", pointee.name),
+                                None => "<unknown> *".to_string(),
+                            },
+                            _ => "<unknown> *".to_string(),
+                        }
+                    } else {
+                        "void *".to_string()
+                    };
+                    let byte_size = if let Ok(Some(attr)) = entry.attr(gimli::DW_AT_byte_size) {
+                        if let Ok(DebugValue::Uint(byte_size)) =
+                            get_attr_value(&attr, &unit, &dwarf)
+                        {
+                            byte_size
+                        } else {
+                            8
+                        }
+                    } else {
+                        8
+                    };
+                    offset_to_type.insert(
+                        entry.offset().0,
+                        Type::new(name, byte_size.try_into().unwrap(), Encoding::Pointer),
+                    );
                 }
                 gimli::DW_TAG_subprogram => {
                     let mut func: Function = Default::default();
@@ -235,6 +284,9 @@ pub fn load_file(object: &object::File, endian: gimli::RunTimeEndian) -> Result<
                             number: line.try_into().unwrap(),
                             address: row.address().try_into().unwrap(),
                         });
+                        if row.prologue_end() {
+                            file.prologue_ends.push(row.address().try_into().unwrap());
+                        }
                     }
                 }
             }
@@ -1,17 +1,36 @@
-use crate::dwarf_data::DwarfData;
+use crate::condition::Condition;
+use crate::dwarf_data::{DwarfData, Location, Variable};
+use crate::instruction;
+use crate::panic;
 use nix::sys::ptrace;
 use nix::sys::signal;
+use nix::sys::uio::{process_vm_readv, process_vm_writev, IoVec, RemoteIoVec};
 use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
 use nix::unistd::Pid;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryInto;
+use std::io::Read;
 use std::mem::size_of;
 use std::os::unix::process::CommandExt;
 use std::process::{Child, Command};
 
+/// How many words up the stack we'll look for a return address into the program's own code when
+/// working out where a Rust panic came from.
+const MAX_STACK_SCAN_WORDS: usize = 4096;
+
+/// Largest panic payload, and longest panic message, that we'll read out of the inferior
+const MAX_PANIC_PAYLOAD_SIZE: usize = 256;
+const MAX_PANIC_MESSAGE_LEN: usize = 4096;
+
+/// The ELF file type of shared libraries, which position-independent executables also have.
+const ET_DYN: u16 = 3;
+
 #[derive(Debug, Clone)]
 struct Breakpoint {
     addr: usize,
     orig_byte: u8,
+    /// If there's a condition, we only stop here when it holds
+    condition: Option<Condition>,
 }
 
 pub enum Status {
@@ -36,34 +55,137 @@ fn child_traceme() -> Result<(), std::io::Error> {
     )))
 }
 
+/// A snapshot of the inferior's registers and writable memory, taken by the `checkpoint` command.
+///
+/// This is nowhere near a full snapshot of the process. Only memory that was writable when the
+/// checkpoint was taken is saved, and only regions that are still mapped get restored. Nothing on
+/// the kernel's side is rolled back: open files and their offsets, pipes, child processes, output
+/// that was already printed, and mappings created or destroyed since the checkpoint all stay as
+/// they are. Multithreaded programs will only have the selected thread's registers restored.
+pub struct Checkpoint {
+    regs: libc::user_regs_struct,
+    regions: Vec<(usize, Vec<u8>)>,
+}
+
 pub struct Inferior {
     child: Child,
     breakpoints_map: HashMap<usize, Breakpoint>,
+    checkpoint: Option<Checkpoint>,
+    /// Ids of the inferior's threads, in the order they were created (the main thread first)
+    threads: Vec<Pid>,
+    /// The thread that registers are read from and single-stepping applies to
+    selected_thread: Pid,
+    /// Whether threads other than the selected one were resumed and still need to be stopped
+    other_threads_running: bool,
+    /// Where a position-independent executable was loaded, or 0 for one that wasn't. The
+    /// addresses in DwarfData are relative to this.
+    load_base: usize,
+}
+
+/// Puts back the original bytes of any breakpoints that fall within `bytes`, which were read from
+/// the inferior starting at `addr`.
+fn hide_breakpoints(bytes: &mut [u8], addr: usize, breakpoints: &HashMap<usize, Breakpoint>) {
+    for bp in breakpoints.values() {
+        if bp.addr >= addr && bp.addr < addr + bytes.len() {
+            bytes[bp.addr - addr] = bp.orig_byte;
+        }
+    }
+}
+
+/// Returns true if `base_ptr` could be a saved frame pointer for a frame above the one whose stack
+/// pointer (or frame pointer) is `stack_ptr`. Frames are word-aligned, and since the stack grows
+/// down, each caller's frame is at a higher address than its callee's.
+fn is_plausible_frame_pointer(stack_ptr: usize, base_ptr: usize) -> bool {
+    align_addr_to_word(base_ptr) == base_ptr && base_ptr > stack_ptr
 }
 
 fn align_addr_to_word(addr: usize) -> usize {
     addr & (-(size_of::<usize>() as isize) as usize)
 }
 
+/// Parses the contents of /proc/<pid>/maps, returning the (start, end) address ranges of the
+/// regions that are both readable and writable.
+fn parse_writable_regions(maps: &str) -> Vec<(usize, usize)> {
+    maps.lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let range = fields.next()?;
+            if !fields.next()?.starts_with("rw") {
+                return None;
+            }
+            let (start, end) = range.split_once('-')?;
+            Some((
+                usize::from_str_radix(start, 16).ok()?,
+                usize::from_str_radix(end, 16).ok()?,
+            ))
+        })
+        .collect()
+}
+
+/// Parses the contents of /proc/<pid>/maps, returning the address that the start of the file
+/// `exe` is mapped at.
+fn parse_load_base(maps: &str, exe: &str) -> Option<usize> {
+    maps.lines().find_map(|line| {
+        // The path comes after the inode, padded with spaces
+        let fields: Vec<&str> = line.splitn(6, ' ').collect();
+        if fields.len() != 6
+            || fields[5].trim() != exe
+            || usize::from_str_radix(fields[2], 16).ok()? != 0
+        {
+            return None;
+        }
+        let (start, _) = fields[0].split_once('-')?;
+        usize::from_str_radix(start, 16).ok()
+    })
+}
+
 impl Inferior {
     /// Attempts to start a new inferior process. Returns Some(Inferior) if successful, or None if
-    /// an error is encountered.
-    pub fn new(target: &str, args: &Vec<String>, breakpoints: &Vec<usize>) -> Option<Inferior> {
+    /// an error is encountered. `env` holds changes to the environment inherited from deet: a
+    /// value of Some sets the variable, and None removes it.
+    pub fn new(
+        target: &str,
+        args: &Vec<String>,
+        breakpoints: &Vec<usize>,
+        env: &BTreeMap<String, Option<String>>,
+    ) -> Option<Inferior> {
         // TODO: implement me!
         let mut cmd = Command::new(target);
         cmd.args(args);
+        for (key, value) in env {
+            match value {
+                Some(value) => cmd.env(key, value),
+                None => cmd.env_remove(key),
+            };
+        }
         unsafe {
             cmd.pre_exec(child_traceme);
         }
         let child = cmd.spawn().expect("Failed to spawn child process");
+        let pid = Pid::from_raw(child.id() as i32);
         let mut inferior = Inferior {
             child,
             breakpoints_map: HashMap::new(),
+            checkpoint: None,
+            threads: vec![pid],
+            selected_thread: pid,
+            other_threads_running: false,
+            load_base: 0,
         };
         match inferior.wait(None) {
             Ok(_) => {
-                for (i, bp) in breakpoints.iter().enumerate() {
-                    inferior.set_breakpoint(*bp, i);
+                // Have the kernel stop new threads and tell us about them, so that we can trace them
+                if let Err(e) = ptrace::setoptions(pid, ptrace::Options::PTRACE_O_TRACECLONE) {
+                    println!("Error enabling thread tracing: {}", e);
+                }
+                match inferior.find_load_base() {
+                    Ok(load_base) => inferior.load_base = load_base,
+                    Err(e) => println!("Error finding where the program was loaded: {}", e),
+                }
+                for bp in breakpoints {
+                    if let Err(e) = inferior.set_breakpoint(*bp) {
+                        println!("Error setting breakpoint at {:#x}: {}", bp, e);
+                    }
                 }
                 Some(inferior)
             }
@@ -71,121 +193,911 @@ impl Inferior {
         }
     }
 
-    /// Returns the pid of this inferior.
+    /// Returns the id of the selected thread, which is what ptrace requests should be made on. This
+    /// is the inferior's pid unless another thread has been selected.
     pub fn pid(&self) -> Pid {
-        nix::unistd::Pid::from_raw(self.child.id() as i32)
+        self.selected_thread
     }
 
-    /// Calls waitpid on this inferior and returns a Status to indicate the state of the process
-    /// after the waitpid call.
-    pub fn wait(&self, options: Option<WaitPidFlag>) -> Result<Status, nix::Error> {
-        Ok(match waitpid(self.pid(), options)? {
-            WaitStatus::Exited(_pid, exit_code) => Status::Exited(exit_code),
-            WaitStatus::Signaled(_pid, signal, _core_dumped) => Status::Signaled(signal),
-            WaitStatus::Stopped(_pid, signal) => {
-                let regs = ptrace::getregs(self.pid())?;
-                Status::Stopped(signal, regs.rip as usize)
-            }
-            other => panic!("waitpid returned unexpected status: {:?}", other),
+    /// Returns the pid of the inferior process (which is also the id of its main thread).
+    pub fn process_id(&self) -> Pid {
+        Pid::from_raw(self.child.id() as i32)
+    }
+
+    /// Works out where the executable was loaded. The kernel maps it (at a random address, with
+    /// ASLR) when it's exec'ed, so this works as soon as the inferior has started. Executables
+    /// that aren't position-independent are loaded at the addresses they were linked for.
+    fn find_load_base(&self) -> Result<usize, std::io::Error> {
+        let exe = format!("/proc/{}/exe", self.process_id());
+        let mut header = [0_u8; 18];
+        std::fs::File::open(&exe)?.read_exact(&mut header)?;
+        // e_type comes right after the 16-byte e_ident (and we only support little-endian x86-64)
+        if u16::from_le_bytes([header[16], header[17]]) != ET_DYN {
+            return Ok(0);
+        }
+        let path = std::fs::read_link(&exe)?;
+        let maps = std::fs::read_to_string(format!("/proc/{}/maps", self.process_id()))?;
+        parse_load_base(&maps, &path.to_string_lossy()).ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::NotFound, "executable isn't mapped")
         })
     }
 
-    pub fn continue_child(&mut self, debug_data: &DwarfData) {
-        match ptrace::cont(self.pid(), None) {
-            Ok(()) => {}
-            Err(e) => println!("Child errorred while continuing {}", e),
+    /// Converts an address in the inferior to the address DwarfData knows it by.
+    pub fn dwarf_addr(&self, addr: usize) -> usize {
+        addr.wrapping_sub(self.load_base)
+    }
+
+    /// Converts an address from DwarfData to where it is in the inferior.
+    pub fn runtime_addr(&self, addr: usize) -> usize {
+        addr + self.load_base
+    }
+
+    /// Returns the ids of the inferior's threads, in the order they were created.
+    pub fn threads(&self) -> &[Pid] {
+        &self.threads
+    }
+
+    /// Makes `tid` the thread that registers, backtraces and single-stepping apply to.
+    pub fn select_thread(&mut self, tid: Pid) {
+        self.selected_thread = tid;
+    }
+
+    /// Returns where a (stopped) thread is stopped.
+    pub fn get_thread_rip(&self, tid: Pid) -> Result<usize, nix::Error> {
+        Ok(ptrace::getregs(tid)?.rip as usize)
+    }
+
+    /// Calls waitpid on this inferior and returns a Status to indicate the state of the process
+    /// after the waitpid call. Thread creation and exit are handled here without being reported.
+    /// When any thread stops, it becomes the selected thread, and the rest of the threads are
+    /// stopped too so that they can be inspected.
+    pub fn wait(&mut self, options: Option<WaitPidFlag>) -> Result<Status, nix::Error> {
+        // waitpid(-1) would otherwise also reap children that other threads of deet started,
+        // since Linux treats a thread group's children as shared. Only the thread that started an
+        // inferior can ptrace it, so an event we took from another thread's inferior would be
+        // lost to that thread, and we'd mistake it for one of ours. (cargo test runs tests on
+        // several threads at once, each tracing its own inferior.) __WNOTHREAD limits the wait to
+        // this thread's own children.
+        let flags = options.unwrap_or(WaitPidFlag::empty())
+            | WaitPidFlag::__WALL
+            | WaitPidFlag::__WNOTHREAD;
+        loop {
+            match waitpid(Pid::from_raw(-1), Some(flags))? {
+                WaitStatus::PtraceEvent(tid, _, libc::PTRACE_EVENT_CLONE) => {
+                    let new_tid = Pid::from_raw(ptrace::getevent(tid)? as i32);
+                    if !self.threads.contains(&new_tid) {
+                        // The new thread starts out with a SIGSTOP, which we swallow
+                        waitpid(new_tid, Some(WaitPidFlag::__WALL))?;
+                        self.threads.push(new_tid);
+                        ptrace::cont(new_tid, None)?;
+                    }
+                    ptrace::cont(tid, None)?;
+                }
+                // A new thread's SIGSTOP can arrive before we hear about the clone
+                WaitStatus::Stopped(tid, signal::Signal::SIGSTOP)
+                    if !self.threads.contains(&tid) =>
+                {
+                    self.threads.push(tid);
+                    ptrace::cont(tid, None)?;
+                }
+                WaitStatus::Exited(tid, _) | WaitStatus::Signaled(tid, _, _)
+                    if tid != self.process_id() =>
+                {
+                    self.forget_thread(tid);
+                }
+                WaitStatus::Exited(_pid, exit_code) => return Ok(Status::Exited(exit_code)),
+                WaitStatus::Signaled(_pid, signal, _core_dumped) => {
+                    return Ok(Status::Signaled(signal))
+                }
+                WaitStatus::Stopped(tid, signal) => {
+                    self.selected_thread = tid;
+                    self.stop_other_threads()?;
+                    let regs = ptrace::getregs(tid)?;
+                    return Ok(Status::Stopped(signal, regs.rip as usize));
+                }
+                other => panic!("waitpid returned unexpected status: {:?}", other),
+            }
+        }
+    }
+
+    fn forget_thread(&mut self, tid: Pid) {
+        self.threads.retain(|thread| *thread != tid);
+        if self.selected_thread == tid {
+            self.selected_thread = self.process_id();
+        }
+    }
+
+    /// Stops every thread but the selected one (which has already stopped). A thread that hits a
+    /// breakpoint before our SIGSTOP reaches it gets its rip rewound onto the breakpoint, so that
+    /// it will hit the breakpoint again once it's resumed.
+    fn stop_other_threads(&mut self) -> Result<(), nix::Error> {
+        if !self.other_threads_running {
+            return Ok(());
+        }
+        self.other_threads_running = false;
+        let others: Vec<Pid> = self
+            .threads
+            .iter()
+            .copied()
+            .filter(|tid| *tid != self.selected_thread)
+            .collect();
+        for tid in others {
+            unsafe {
+                libc::syscall(
+                    libc::SYS_tgkill,
+                    self.process_id().as_raw(),
+                    tid.as_raw(),
+                    libc::SIGSTOP,
+                );
+            }
+            loop {
+                match waitpid(tid, Some(WaitPidFlag::__WALL)) {
+                    Ok(WaitStatus::Stopped(_, signal::Signal::SIGSTOP)) => break,
+                    Ok(WaitStatus::Stopped(_, signal::Signal::SIGTRAP)) => {
+                        let mut regs = ptrace::getregs(tid)?;
+                        if self.breakpoints_map.contains_key(&(regs.rip as usize - 1)) {
+                            regs.rip -= 1;
+                            ptrace::setregs(tid, regs)?;
+                        }
+                        // The SIGSTOP is still pending, so the thread stops again right away
+                        ptrace::cont(tid, None)?;
+                    }
+                    Ok(WaitStatus::Stopped(_, signal)) => ptrace::cont(tid, signal)?,
+                    Ok(WaitStatus::PtraceEvent(..)) => ptrace::cont(tid, None)?,
+                    // The thread exited before it could be stopped
+                    Ok(_) | Err(_) => {
+                        self.forget_thread(tid);
+                        break;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Resumes every thread. The selected thread is resumed last.
+    fn cont_all_threads(&mut self) -> Result<(), nix::Error> {
+        self.other_threads_running = true;
+        for tid in &self.threads {
+            if *tid != self.selected_thread {
+                ptrace::cont(*tid, None)?;
+            }
+        }
+        ptrace::cont(self.selected_thread, None)
+    }
+
+    pub fn get_rip(&self) -> Result<usize, nix::Error> {
+        Ok(ptrace::getregs(self.pid())?.rip as usize)
+    }
+
+    fn set_rip(&self, rip: usize) -> Result<(), nix::Error> {
+        let mut regs = ptrace::getregs(self.pid())?;
+        regs.rip = rip as u64;
+        ptrace::setregs(self.pid(), regs)
+    }
+
+    /// Resumes the inferior until it next stops. If we're sitting on a breakpoint, we step past it
+    /// first; if the inferior stops on a breakpoint, rip is rewound so that it points at the
+    /// breakpoint's address rather than the byte after the 0xcc.
+    pub fn resume(&mut self) -> Result<Status, nix::Error> {
+        if self.breakpoints_map.contains_key(&self.get_rip()?) {
+            match self.step_instruction()? {
+                Status::Stopped(signal::Signal::SIGTRAP, _) => {}
+                status => return Ok(status),
+            }
+        }
+        self.cont_all_threads()?;
+        match self.wait(None)? {
+            Status::Stopped(signal::Signal::SIGTRAP, rip)
+                if self.breakpoints_map.contains_key(&(rip - 1)) =>
+            {
+                self.set_rip(rip - 1)?;
+                Ok(Status::Stopped(signal::Signal::SIGTRAP, rip - 1))
+            }
+            status => Ok(status),
+        }
+    }
+
+    /// Executes a single instruction. If rip sits on one of our breakpoints, the original byte is
+    /// put back for the duration of the step and the breakpoint is re-armed afterwards.
+    pub fn step_instruction(&mut self) -> Result<Status, nix::Error> {
+        let rip = self.get_rip()?;
+        let orig_byte = self.breakpoints_map.get(&rip).map(|bp| bp.orig_byte);
+        if let Some(orig_byte) = orig_byte {
+            self.write_byte(rip, orig_byte)?;
+        }
+        ptrace::step(self.pid(), None)?;
+        let status = self.wait(None)?;
+        if let (Some(_), Status::Stopped(..)) = (orig_byte, &status) {
+            self.write_byte(rip, 0xcc)?;
+        }
+        Ok(status)
+    }
+
+    /// Single-steps up to `max_steps` instructions, stopping early if the inferior exits, receives a
+    /// signal, or arrives at a breakpoint whose condition (if it has one) holds, as in
+    /// continue_running. Returns the final status along with the number of instructions that were
+    /// executed, and whether we stopped because we ran out of steps.
+    pub fn step_instructions(
+        &mut self,
+        max_steps: usize,
+        debug_data: &DwarfData,
+    ) -> Result<(Status, usize, bool), nix::Error> {
+        let mut steps = 0;
+        loop {
+            let status = self.step_instruction()?;
+            steps += 1;
+            match status {
+                Status::Stopped(signal::Signal::SIGTRAP, rip)
+                    if !self.breakpoint_stops(rip, debug_data) =>
+                {
+                    if steps == max_steps {
+                        return Ok((status, steps, true));
+                    }
+                }
+                _ => return Ok((status, steps, false)),
+            }
+        }
+    }
+
+    /// Returns the address that the current function will return to. Once the prologue has run, the
+    /// return address sits just above the saved rbp, but if the function hasn't gotten that far, rbp
+    /// is still the caller's: right at the start of the function the return address is at the top
+    /// of the stack, and after `push %rbp` it's just below the top.
+    pub fn return_address(&self, debug_data: &DwarfData) -> Result<usize, nix::Error> {
+        let regs = ptrace::getregs(self.pid())?;
+        let rip = regs.rip as usize;
+        let function_start = debug_data
+            .get_function_from_addr(self.dwarf_addr(rip))
+            .and_then(|name| debug_data.get_addr_for_function(None, &name))
+            .map(|start| self.runtime_addr(start));
+        let push_rbp = match function_start {
+            Some(start) => self
+                .read_memory(start, instruction::MAX_INSTRUCTION_LEN)
+                .ok()
+                .and_then(|bytes| instruction::push_rbp_offset(&bytes))
+                .map(|offset| start + offset),
+            None => None,
+        };
+        let slot = match push_rbp {
+            Some(push_rbp) if rip <= push_rbp => regs.rsp as usize,
+            Some(push_rbp) if rip == push_rbp + 1 => regs.rsp as usize + 8,
+            _ => regs.rbp as usize + 8,
         };
-        match self.wait(None) {
-            Ok(status) => match status {
-                Status::Exited(code) => {
-                    println!("Child exited (status {})", code);
-                    // self.inferior = None;
+        Ok(ptrace::read(self.pid(), slot as ptrace::AddressType)? as usize)
+    }
+
+    /// Runs until the current call returns to `return_addr` (either the call we're about to make,
+    /// or the function we're in), using a temporary breakpoint. Hits of the breakpoint from deeper
+    /// (recursive) frames are ignored.
+    pub fn run_to_return(&mut self, return_addr: usize) -> Result<Status, nix::Error> {
+        let call_rsp = ptrace::getregs(self.pid())?.rsp;
+        let temporary = !self.breakpoints_map.contains_key(&return_addr);
+        if temporary {
+            let orig_byte = self.write_byte(return_addr, 0xcc)?;
+            self.breakpoints_map.insert(
+                return_addr,
+                Breakpoint {
+                    addr: return_addr,
+                    orig_byte,
+                    condition: None,
+                },
+            );
+        }
+        let status = loop {
+            match self.resume()? {
+                Status::Stopped(signal::Signal::SIGTRAP, rip)
+                    if rip == return_addr && ptrace::getregs(self.pid())?.rsp < call_rsp =>
+                {
+                    continue
                 }
-                Status::Signaled(signal) => {
-                    println!("Child signaled with {}", signal)
+                status => break status,
+            }
+        };
+        if temporary {
+            let bp = self.breakpoints_map.remove(&return_addr).unwrap();
+            if let Status::Stopped(..) = status {
+                self.write_byte(bp.addr, bp.orig_byte)?;
+            }
+        }
+        Ok(status)
+    }
+
+    /// Like resume(), but passes over conditional breakpoints whose condition doesn't hold.
+    pub fn continue_running(&mut self, debug_data: &DwarfData) -> Result<Status, nix::Error> {
+        loop {
+            match self.resume()? {
+                Status::Stopped(signal::Signal::SIGTRAP, rip)
+                    if self.breakpoints_map.contains_key(&rip)
+                        && !self.breakpoint_stops(rip, debug_data) =>
+                {
+                    continue
                 }
-                Status::Stopped(signal, rip) => {
-                    println!("Child stopped with {}", signal);
-                    self.print_stopped_instruction(debug_data, rip);
+                status => return Ok(status),
+            }
+        }
+    }
+
+    /// Returns true if there's a breakpoint at `rip` that we should stop at: one without a
+    /// condition, or whose condition holds. If a condition can't be evaluated (say, because its
+    /// variable isn't in scope), we stop there.
+    fn breakpoint_stops(&self, rip: usize, debug_data: &DwarfData) -> bool {
+        let condition = match self.breakpoints_map.get(&rip) {
+            Some(bp) => bp.condition.clone(),
+            None => return false,
+        };
+        match condition {
+            Some(condition) => match self.condition_holds(&condition, debug_data) {
+                Ok(holds) => holds,
+                Err(e) => {
+                    println!(
+                        "Unable to evaluate breakpoint condition {}: {}",
+                        condition, e
+                    );
+                    true
                 }
             },
-            Err(e) => println!("Child errorred while waiting {}", e),
+            None => true,
+        }
+    }
+
+    fn condition_holds(
+        &self,
+        condition: &Condition,
+        debug_data: &DwarfData,
+    ) -> Result<bool, String> {
+        let (variable, bytes) = self.read_variable(debug_data, &condition.variable)?;
+        match variable.entity_type.integer_value(&bytes) {
+            Some(value) => Ok(condition.holds(value)),
+            None => Err(format!(
+                "{} is a {}, not an integer",
+                condition.variable, variable.entity_type.name
+            )),
+        }
+    }
+
+    /// Finds the variable called `name`, as seen from where the selected thread is stopped (see
+    /// DwarfData::get_variable), returning it along with its address.
+    fn find_variable<'a>(
+        &self,
+        debug_data: &'a DwarfData,
+        name: &str,
+    ) -> Result<(&'a Variable, usize), String> {
+        let regs =
+            ptrace::getregs(self.pid()).map_err(|e| format!("unable to read registers: {}", e))?;
+        let variable = debug_data
+            .get_variable(self.dwarf_addr(regs.rip as usize), name)
+            .ok_or_else(|| format!("no variable {} in the current context", name))?;
+        let addr = match variable.location {
+            Location::Address(addr) => self.runtime_addr(addr),
+            // Offsets are from the frame base, which gcc makes the canonical frame address: the
+            // value rsp had before the call. The return address and saved rbp sit between that and
+            // rbp.
+            Location::FramePointerOffset(offset) => (regs.rbp as isize + 16 + offset) as usize,
         };
+        Ok((variable, addr))
     }
 
-    pub fn continue_process(&mut self) -> Result<Status, nix::Error> {
-        ptrace::cont(self.pid(), None)?;
-        match self.wait(None) {
-            Ok(status) => match status {
-                Status::Stopped(signal::Signal::SIGTRAP, rip) => {
-                    if self.breakpoints_map.contains_key(&(rip - 1)) {
-                        self.continue_from_breakpoint(rip - 1)
-                    } else {
-                        Ok(status)
-                    }
+    /// Reads the variable called `name` (see find_variable), returning it along with its bytes.
+    pub fn read_variable<'a>(
+        &self,
+        debug_data: &'a DwarfData,
+        name: &str,
+    ) -> Result<(&'a Variable, Vec<u8>), String> {
+        let (variable, addr) = self.find_variable(debug_data, name)?;
+        let bytes = self
+            .read_memory(addr, variable.entity_type.size)
+            .map_err(|e| format!("unable to read {} at {:#x}: {}", name, addr, e))?;
+        Ok((variable, bytes))
+    }
+
+    /// Sets the integer variable called `name` (see find_variable) to `value`. We refuse to write
+    /// outside of writable memory: ptrace would happily change read-only data, like constants.
+    pub fn write_variable(
+        &mut self,
+        debug_data: &DwarfData,
+        name: &str,
+        value: i128,
+    ) -> Result<(), String> {
+        let (variable, addr) = self.find_variable(debug_data, name)?;
+        let bytes = variable.entity_type.encode_integer(value)?;
+        let writable = self
+            .writable_regions()
+            .map_err(|e| format!("unable to read the memory map: {}", e))?
+            .iter()
+            .any(|(start, end)| *start <= addr && addr + bytes.len() <= *end);
+        if !writable {
+            return Err(format!("{} at {:#x} isn't in writable memory", name, addr));
+        }
+        self.write_memory(addr, &bytes)
+            .map_err(|e| format!("unable to write {} at {:#x}: {}", name, addr, e))
+    }
+
+    /// Steps to the next source line of the current function without descending into any calls.
+    /// We decode the instruction at rip: calls are run to completion using a breakpoint on the
+    /// return address, and anything else is single-stepped. Returns early if the inferior stops
+    /// for some other reason (a breakpoint, a signal, or exiting), or if the function returns.
+    pub fn next_line(&mut self, debug_data: &DwarfData) -> Result<Status, nix::Error> {
+        let start_rip = self.get_rip()?;
+        let start_function = debug_data.get_function_from_addr(self.dwarf_addr(start_rip));
+        let start_line = debug_data
+            .get_line_from_addr(self.dwarf_addr(start_rip))
+            .map(|line| (line.file, line.number));
+        loop {
+            let rip = self.get_rip()?;
+            let instruction = self.read_memory(rip, instruction::MAX_INSTRUCTION_LEN)?;
+            let status = match instruction::call_length(&instruction) {
+                Some(len) => self.run_to_return(rip + len)?,
+                None => self.step_instruction()?,
+            };
+            let rip = match status {
+                Status::Stopped(signal::Signal::SIGTRAP, rip) => rip,
+                _ => return Ok(status),
+            };
+            if self.breakpoints_map.contains_key(&rip)
+                || debug_data.get_function_from_addr(self.dwarf_addr(rip)) != start_function
+            {
+                return Ok(status);
+            }
+            let line = debug_data
+                .get_line_from_addr(self.dwarf_addr(rip))
+                .map(|line| (line.file, line.number));
+            if line.is_some() && line != start_line {
+                return Ok(status);
+            }
+        }
+    }
+
+    /// Steps to the next source line, descending into any function that's called. When we enter a
+    /// function, we keep going past its prologue, which is attributed to the line the function
+    /// starts on. Calls into code we have no line information for (e.g. library functions) are run
+    /// to completion rather than stepped through.
+    pub fn step_line(&mut self, debug_data: &DwarfData) -> Result<Status, nix::Error> {
+        let load_base = self.load_base;
+        let line_at = |rip: usize| {
+            debug_data
+                .get_line_from_addr(rip.wrapping_sub(load_base))
+                .map(|line| (line.file, line.number))
+        };
+        let mut start_line = line_at(self.get_rip()?);
+        loop {
+            let rip = self.get_rip()?;
+            let instruction = self.read_memory(rip, instruction::MAX_INSTRUCTION_LEN)?;
+            let call_len = instruction::call_length(&instruction);
+            let mut status = self.step_instruction()?;
+            let mut entered_function = false;
+            if let (Some(len), Status::Stopped(signal::Signal::SIGTRAP, new_rip)) =
+                (call_len, &status)
+            {
+                if line_at(*new_rip).is_some() {
+                    start_line = line_at(*new_rip);
+                    entered_function = true;
+                } else {
+                    status = self.run_to_return(rip + len)?;
                 }
-                _ => Ok(status),
-            },
-            Err(e) => Err(e),
+            }
+            let rip = match status {
+                Status::Stopped(signal::Signal::SIGTRAP, rip) => rip,
+                _ => return Ok(status),
+            };
+            if self.breakpoints_map.contains_key(&rip) {
+                return Ok(status);
+            }
+            let line = line_at(rip);
+            if !entered_function && line.is_some() && line != start_line {
+                return Ok(status);
+            }
         }
     }
 
+    /// Kills the inferior and reaps all of its threads. Child::wait would hang on a multithreaded
+    /// inferior: the kernel doesn't report a traced thread group leader's exit until its tracer
+    /// has reaped every other thread in the group, and Child::wait only waits for the leader.
     pub fn kill(&mut self) {
         match self.child.kill() {
             Err(e) => println!("Error killing child {}", e),
             Ok(()) => println!("Killed child successfully"),
         }
-        match self.child.wait() {
-            Ok(status) => println!("Child exited with status {}", status),
-            Err(e) => println!("Error exiting child {}", e),
+        // As in wait(), __WNOTHREAD keeps us from reaping other deet threads' inferiors
+        let flags = WaitPidFlag::__WALL | WaitPidFlag::__WNOTHREAD;
+        loop {
+            match waitpid(Pid::from_raw(-1), Some(flags)) {
+                Ok(WaitStatus::Exited(tid, code)) if tid == self.process_id() => {
+                    println!("Child exited with status {}", code);
+                    break;
+                }
+                Ok(WaitStatus::Signaled(tid, signal, _)) if tid == self.process_id() => {
+                    println!("Child exited with signal {}", signal);
+                    break;
+                }
+                // Another thread exiting, or stopping before the SIGKILL reached it
+                Ok(_) => {}
+                Err(e) => {
+                    println!("Error exiting child {}", e);
+                    break;
+                }
+            }
         }
     }
 
     pub fn print_backtrace(&self, debug_data: &DwarfData) {
         match ptrace::getregs(self.pid()) {
             Ok(regs) => {
-                let mut instruction_ptr = regs.rip as usize;
-                let mut base_ptr = regs.rbp as usize;
-                loop {
-                    let function = debug_data
-                        .get_function_from_addr(instruction_ptr)
-                        .unwrap_or("Unable to get function name".to_string());
-                    let line = debug_data
-                        .get_line_from_addr(instruction_ptr)
+                for line in self.backtrace(debug_data, &regs) {
+                    println!("{}", line);
+                }
+            }
+            Err(e) => println!("Unable to get register value {}", e),
+        }
+    }
+
+    /// Describes each frame of the backtrace, innermost first, the way print_backtrace shows them.
+    fn backtrace(&self, debug_data: &DwarfData, regs: &libc::user_regs_struct) -> Vec<String> {
+        let (frames, complete) = self.collect_frames(
+            debug_data,
+            regs.rip as usize,
+            regs.rsp as usize,
+            regs.rbp as usize,
+        );
+        let mut lines = Vec::new();
+        for (i, pc) in frames.iter().enumerate() {
+            // Every frame but the innermost is stopped at a return address, which points just
+            // past the call and may be on the line after it
+            let lookup_addr = self.dwarf_addr(if i == 0 { *pc } else { pc - 1 });
+            let function = debug_data
+                .get_function_from_addr(lookup_addr)
+                .unwrap_or("Unable to get function name".to_string());
+            let line = debug_data
+                .get_line_from_addr(lookup_addr)
+                .unwrap_or_default();
+            match frames.get(i + 1) {
+                Some(return_addr) => {
+                    let call_site = debug_data
+                        .get_line_from_addr(self.dwarf_addr(return_addr - 1))
                         .unwrap_or_default();
-                    println!("{} ({}:{})", function, line.file, line.number);
-                    if function == "main" {
-                        break;
-                    }
-                    instruction_ptr =
-                        match ptrace::read(self.pid(), (base_ptr + 8) as ptrace::AddressType) {
-                            Ok(iptr) => iptr as usize,
-                            Err(e) => {
-                                println!("Unable to read rip memory at {} {}", base_ptr + 8, e);
-                                break;
-                            }
-                        };
-                    base_ptr = match ptrace::read(self.pid(), base_ptr as ptrace::AddressType) {
-                        Ok(bptr) => bptr as usize,
-                        Err(e) => {
-                            println!("Unable to read rbp memory at {} {}", base_ptr, e);
-                            break;
-                        }
+                    lines.push(format!(
+                        "{} ({}:{}), returns to {:#x}, called from {}:{}",
+                        function,
+                        line.file,
+                        line.number,
+                        return_addr,
+                        call_site.file,
+                        call_site.number
+                    ));
+                }
+                None => lines.push(format!("{} ({}:{})", function, line.file, line.number)),
+            }
+        }
+        // When the innermost frame isn't in the program's own code (say, a thread waiting in the C
+        // library), it's the library's missing frame pointers that stopped us, and recompiling the
+        // program won't help
+        let in_own_code = debug_data
+            .get_function_from_addr(self.dwarf_addr(frames[0]))
+            .is_some();
+        if !complete && in_own_code {
+            lines.push(
+                "Frame-pointer-based unwind failed; recompile with -fno-omit-frame-pointer to \
+                 see the rest of the backtrace"
+                    .to_string(),
+            );
+        }
+        lines
+    }
+
+    /// Walks the chain of saved frame pointers up to main, returning the program counter of each
+    /// frame, innermost first. For every frame but the innermost, this is the return address that
+    /// the frame below it will return to.
+    ///
+    /// Code compiled without frame pointers uses rbp as an ordinary register, so the chain can
+    /// lead anywhere. We stop as soon as it stops looking like a chain of frames, and return false
+    /// along with the frames we found up to that point.
+    fn collect_frames(
+        &self,
+        debug_data: &DwarfData,
+        rip: usize,
+        rsp: usize,
+        rbp: usize,
+    ) -> (Vec<usize>, bool) {
+        let mut frames = vec![rip];
+        let mut instruction_ptr = rip;
+        let mut base_ptr = rbp;
+        // The frame at base_ptr has to be further up the stack than this
+        let mut stack_ptr = rsp;
+        loop {
+            let lookup_addr = self.dwarf_addr(if frames.len() == 1 {
+                instruction_ptr
+            } else {
+                instruction_ptr - 1
+            });
+            if debug_data.get_function_from_addr(lookup_addr).as_deref() == Some("main") {
+                return (frames, true);
+            }
+            if !is_plausible_frame_pointer(stack_ptr, base_ptr) {
+                return (frames, false);
+            }
+            instruction_ptr = match ptrace::read(self.pid(), (base_ptr + 8) as ptrace::AddressType)
+            {
+                Ok(iptr) => iptr as usize,
+                Err(_) => return (frames, false),
+            };
+            // A real return address points just past a call in a function we know about
+            if instruction_ptr == 0
+                || debug_data
+                    .get_function_from_addr(self.dwarf_addr(instruction_ptr - 1))
+                    .is_none()
+            {
+                return (frames, false);
+            }
+            stack_ptr = base_ptr;
+            base_ptr = match ptrace::read(self.pid(), base_ptr as ptrace::AddressType) {
+                Ok(bptr) => bptr as usize,
+                Err(_) => return (frames, false),
+            };
+            frames.push(instruction_ptr);
+        }
+    }
+
+    /// Finds the call in the program's own code that led to a Rust panic, returning its address.
+    /// The standard library isn't built with frame pointers, so we can't walk the rbp chain out of
+    /// the panic runtime. Instead, we scan up the stack for the first word that looks like a return
+    /// address into code outside the standard library: it has to map to a source line, and the
+    /// bytes before it have to be a call instruction.
+    pub fn find_panic_call_site(&self, debug_data: &DwarfData) -> Option<usize> {
+        let rsp = ptrace::getregs(self.pid()).ok()?.rsp as usize;
+        for i in 0..MAX_STACK_SCAN_WORDS {
+            // Reads fail once we run off the top of the stack
+            let word =
+                ptrace::read(self.pid(), (rsp + i * 8) as ptrace::AddressType).ok()? as usize;
+            if word < instruction::MAX_INSTRUCTION_LEN {
+                continue;
+            }
+            match debug_data.get_line_from_addr(self.dwarf_addr(word - 1)) {
+                Some(line) if !panic::is_runtime_source(&line.file) => {}
+                _ => continue,
+            }
+            for call_len in 2..=instruction::MAX_INSTRUCTION_LEN {
+                if let Ok(bytes) = self.read_memory(word - call_len, call_len) {
+                    if instruction::call_length(&bytes) == Some(call_len) {
+                        return Some(word - call_len);
                     }
                 }
             }
-            Err(e) => println!("Unable to get register value {}", e),
         }
+        None
+    }
+
+    /// Reads the message of a Rust panic, if we're stopped at the start of `rust_panic`. Its
+    /// argument is a `&mut dyn PanicPayload` (in rdi and rsi), pointing at either the &str passed
+    /// to panic! or a struct holding the message formatted as a String, which the panic hook has
+    /// filled in by now. Neither layout is stable, so we look through the payload for a pointer
+    /// followed by a length that together lead to printable text.
+    pub fn read_panic_message(&self, debug_data: &DwarfData) -> Option<String> {
+        let regs = ptrace::getregs(self.pid()).ok()?;
+        if debug_data.get_addr_for_function(None, "rust_panic")
+            != Some(self.dwarf_addr(regs.rip as usize))
+        {
+            return None;
+        }
+        // A vtable starts with the type's drop function, followed by its size
+        let size = ptrace::read(self.pid(), (regs.rsi + 8) as ptrace::AddressType).ok()? as usize;
+        if size > MAX_PANIC_PAYLOAD_SIZE {
+            return None;
+        }
+        let payload = self.read_memory(regs.rdi as usize, size).ok()?;
+        let words: Vec<usize> = payload
+            .chunks_exact(size_of::<usize>())
+            .map(|word| usize::from_le_bytes(word.try_into().unwrap()))
+            .collect();
+        words.windows(2).find_map(|pair| {
+            let (ptr, len) = (pair[0], pair[1]);
+            if len == 0 || len > MAX_PANIC_MESSAGE_LEN {
+                return None;
+            }
+            panic::message_text(self.read_memory(ptr, len).ok()?)
+        })
     }
 
     pub fn print_stopped_instruction(&self, debug_data: &DwarfData, rip: usize) {
+        let addr = self.dwarf_addr(rip);
         let function = debug_data
-            .get_function_from_addr(rip)
+            .get_function_from_addr(addr)
             .unwrap_or("Unable to get function name".to_string());
-        let line = debug_data.get_line_from_addr(rip).unwrap_or_default();
+        let line = debug_data.get_line_from_addr(addr).unwrap_or_default();
         println!("Stopped at {} ({}:{})", function, line.file, line.number);
     }
 
+    /// Reads `len` bytes of the inferior's memory starting at `addr`. Bytes that we've replaced with
+    /// 0xcc to install breakpoints are reported with their original values.
+    ///
+    /// The whole range is copied with a single process_vm_readv call if possible, which is much
+    /// faster than going a word at a time with ptrace. We fall back to ptrace if that fails (e.g.
+    /// because part of the range isn't mapped, in which case ptrace reports the error).
+    pub fn read_memory(&self, addr: usize, len: usize) -> Result<Vec<u8>, nix::Error> {
+        let mut bytes = vec![0_u8; len];
+        let copied = process_vm_readv(
+            self.process_id(),
+            &[IoVec::from_mut_slice(&mut bytes)],
+            &[RemoteIoVec { base: addr, len }],
+        );
+        if copied == Ok(len) {
+            hide_breakpoints(&mut bytes, addr, &self.breakpoints_map);
+            return Ok(bytes);
+        }
+        self.read_memory_with_ptrace(addr, len)
+    }
+
+    /// Reads `count` words of the inferior's memory, starting with the word that `addr` is in.
+    /// Returns the address of each word along with its value (with breakpoints hidden, as in
+    /// read_memory) or the error we got reading it. Failing to read one word doesn't stop us from
+    /// trying the rest.
+    pub fn read_words(&self, addr: usize, count: usize) -> Vec<(usize, Result<u64, nix::Error>)> {
+        let start = align_addr_to_word(addr);
+        (0..count)
+            .map(|i| {
+                let word_addr = start + i * size_of::<usize>();
+                let word = ptrace::read(self.pid(), word_addr as ptrace::AddressType).map(|word| {
+                    let mut bytes = (word as u64).to_le_bytes();
+                    hide_breakpoints(&mut bytes, word_addr, &self.breakpoints_map);
+                    u64::from_le_bytes(bytes)
+                });
+                (word_addr, word)
+            })
+            .collect()
+    }
+
+    fn read_memory_with_ptrace(&self, addr: usize, len: usize) -> Result<Vec<u8>, nix::Error> {
+        let mut bytes = Vec::with_capacity(len);
+        let mut word_addr = align_addr_to_word(addr);
+        while word_addr < addr + len {
+            let word = ptrace::read(self.pid(), word_addr as ptrace::AddressType)? as u64;
+            for i in 0..size_of::<usize>() {
+                let byte_addr = word_addr + i;
+                if byte_addr < addr || byte_addr >= addr + len {
+                    continue;
+                }
+                bytes.push(match self.breakpoints_map.get(&byte_addr) {
+                    Some(bp) => bp.orig_byte,
+                    None => (word >> (8 * i)) as u8,
+                });
+            }
+            word_addr += size_of::<usize>();
+        }
+        Ok(bytes)
+    }
+
+    /// Writes `bytes` into the inferior's memory starting at `addr`. If one of our breakpoints lies
+    /// in the range, the 0xcc stays in place and the new byte becomes the breakpoint's original
+    /// byte, so that the breakpoint keeps working.
+    ///
+    /// Like read_memory, this uses a single process_vm_writev call when it can. That only works
+    /// for writable memory, though (ptrace can write to code), and doesn't know about
+    /// breakpoints, so anything else goes through ptrace.
+    pub fn write_memory(&mut self, addr: usize, bytes: &[u8]) -> Result<(), nix::Error> {
+        let end = addr + bytes.len();
+        let has_breakpoints = self
+            .breakpoints_map
+            .keys()
+            .any(|bp_addr| *bp_addr >= addr && *bp_addr < end);
+        if !has_breakpoints {
+            let copied = process_vm_writev(
+                self.process_id(),
+                &[IoVec::from_slice(bytes)],
+                &[RemoteIoVec {
+                    base: addr,
+                    len: bytes.len(),
+                }],
+            );
+            if copied == Ok(bytes.len()) {
+                return Ok(());
+            }
+        }
+        self.write_memory_with_ptrace(addr, bytes)
+    }
+
+    fn write_memory_with_ptrace(&mut self, addr: usize, bytes: &[u8]) -> Result<(), nix::Error> {
+        let end = addr + bytes.len();
+        let mut word_addr = align_addr_to_word(addr);
+        while word_addr < end {
+            // Only words that we're partially overwriting need to be read first
+            let mut word = if word_addr >= addr && word_addr + size_of::<usize>() <= end {
+                0
+            } else {
+                ptrace::read(self.pid(), word_addr as ptrace::AddressType)? as u64
+            };
+            for i in 0..size_of::<usize>() {
+                let byte_addr = word_addr + i;
+                if byte_addr < addr || byte_addr >= end {
+                    continue;
+                }
+                let mut byte = bytes[byte_addr - addr];
+                if let Some(bp) = self.breakpoints_map.get_mut(&byte_addr) {
+                    bp.orig_byte = byte;
+                    byte = 0xcc;
+                }
+                word = (word & !(0xff << (8 * i))) | ((byte as u64) << (8 * i));
+            }
+            ptrace::write(
+                self.pid(),
+                word_addr as ptrace::AddressType,
+                word as *mut std::ffi::c_void,
+            )?;
+            word_addr += size_of::<usize>();
+        }
+        Ok(())
+    }
+
+    /// Writes the inferior's memory from `start` up to (but not including) `end` to the file at
+    /// `path`. Returns the number of bytes written.
+    pub fn dump_memory(
+        &self,
+        start: usize,
+        end: usize,
+        path: &str,
+    ) -> Result<usize, Box<dyn std::error::Error>> {
+        let bytes = self.read_memory(start, end - start)?;
+        std::fs::write(path, &bytes)?;
+        Ok(bytes.len())
+    }
+
+    /// Loads the contents of the file at `path` into the inferior's memory, starting at `addr`.
+    /// Returns the number of bytes loaded.
+    pub fn restore_memory(
+        &mut self,
+        path: &str,
+        addr: usize,
+    ) -> Result<usize, Box<dyn std::error::Error>> {
+        let bytes = std::fs::read(path)?;
+        self.write_memory(addr, &bytes)?;
+        Ok(bytes.len())
+    }
+
+    fn writable_regions(&self) -> Result<Vec<(usize, usize)>, std::io::Error> {
+        let maps = std::fs::read_to_string(format!("/proc/{}/maps", self.process_id()))?;
+        Ok(parse_writable_regions(&maps))
+    }
+
+    /// Saves the registers and all writable memory of the inferior, replacing any previous
+    /// checkpoint. Returns the number of bytes of memory saved.
+    pub fn checkpoint(&mut self) -> Result<usize, Box<dyn std::error::Error>> {
+        let regs = ptrace::getregs(self.pid())?;
+        let mut regions = Vec::new();
+        for (start, end) in self.writable_regions()? {
+            regions.push((start, self.read_memory(start, end - start)?));
+        }
+        let saved_bytes = regions.iter().map(|(_, bytes)| bytes.len()).sum();
+        self.checkpoint = Some(Checkpoint { regs, regions });
+        Ok(saved_bytes)
+    }
+
+    /// Restores the registers and memory saved by the last checkpoint (see Checkpoint for what
+    /// that does and doesn't cover). Regions that are no longer mapped are skipped; their count
+    /// is returned.
+    pub fn restore_checkpoint(&mut self) -> Result<usize, Box<dyn std::error::Error>> {
+        let checkpoint = match self.checkpoint.take() {
+            Some(checkpoint) => checkpoint,
+            None => return Err("no checkpoint has been saved".into()),
+        };
+        let current_regions = self.writable_regions()?;
+        let mut skipped = 0;
+        let mut result = Ok(());
+        for (start, bytes) in &checkpoint.regions {
+            let end = start + bytes.len();
+            if current_regions
+                .iter()
+                .any(|(region_start, region_end)| region_start <= start && end <= *region_end)
+            {
+                result = result.and(self.write_memory(*start, bytes));
+            } else {
+                skipped += 1;
+            }
+        }
+        let result = result.and(ptrace::setregs(self.pid(), checkpoint.regs));
+        // Keep the checkpoint around so that we can go back to it again
+        self.checkpoint = Some(checkpoint);
+        result?;
+        Ok(skipped)
+    }
+
     fn write_byte(&mut self, addr: usize, val: u8) -> Result<u8, nix::Error> {
         let aligned_addr = align_addr_to_word(addr);
         let byte_offset = addr - aligned_addr;
@@ -201,47 +1113,824 @@ impl Inferior {
         Ok(orig_byte as u8)
     }
 
-    pub fn set_breakpoint(&mut self, addr: usize, i: usize) {
-        match self.write_byte(addr, 0xcc) {
-            Ok(orig_byte) => {
-                self.breakpoints_map
-                    .insert(addr, Breakpoint { addr, orig_byte });
-                println!("Set breakpoint {} at {}", i, addr);
-            }
-            Err(e) => println!("Error adding breakpoint {}", e),
-        }
-    }
-
-    pub fn continue_from_breakpoint(&mut self, bp: usize) -> Result<Status, nix::Error> {
-        let _ = ptrace::step(self.pid(), signal::Signal::SIGTRAP);
-        match self.wait(None) {
-            Ok(status) => match status {
-                Status::Stopped(signal::Signal::SIGTRAP, _) => {
-                    self.write_byte(bp, 0xcc)
-                        .expect("Error setting back break point");
-                    ptrace::cont(self.pid(), None)?;
-                    match self.wait(None) {
-                        Ok(status) => match status {
-                            Status::Stopped(signal::Signal::SIGTRAP, rip) => {
-                                if self.breakpoints_map.contains_key(&(rip - 1)) {
-                                    self.write_byte(
-                                        rip - 1,
-                                        self.breakpoints_map.get(&(rip - 1)).unwrap().orig_byte,
-                                    )
-                                    .unwrap();
-                                    ptrace::getregs(self.pid()).unwrap().rip = (rip - 1) as u64;
-                                }
-                                ptrace::cont(self.pid(), None)?;
-                                self.wait(None)
-                            }
-                            _ => Ok(status),
-                        },
-                        Err(e) => Err(e),
-                    }
-                }
-                _ => Ok(status),
+    /// Installs a breakpoint at `addr`. Installing a breakpoint where there already is one does
+    /// nothing; writing 0xcc a second time would make us remember 0xcc as the original byte.
+    ///
+    /// Like the other breakpoint methods, this takes an address from DwarfData (see dwarf_addr).
+    pub fn set_breakpoint(&mut self, addr: usize) -> Result<(), nix::Error> {
+        let addr = self.runtime_addr(addr);
+        if self.breakpoints_map.contains_key(&addr) {
+            return Ok(());
+        }
+        let orig_byte = self.write_byte(addr, 0xcc)?;
+        self.breakpoints_map.insert(
+            addr,
+            Breakpoint {
+                addr,
+                orig_byte,
+                condition: None,
             },
-            Err(e) => Err(e),
+        );
+        Ok(())
+    }
+
+    /// Sets (or with None, clears) the condition on the breakpoint at `addr`, if there is one.
+    pub fn set_breakpoint_condition(&mut self, addr: usize, condition: Option<Condition>) {
+        let addr = self.runtime_addr(addr);
+        if let Some(bp) = self.breakpoints_map.get_mut(&addr) {
+            bp.condition = condition;
         }
     }
+
+    /// Returns true if there's a breakpoint at `addr`.
+    pub fn has_breakpoint(&self, addr: usize) -> bool {
+        self.breakpoints_map.contains_key(&self.runtime_addr(addr))
+    }
+
+    /// Removes a breakpoint, putting back the byte it replaced. Does nothing if there's no
+    /// breakpoint at `addr`.
+    pub fn remove_breakpoint(&mut self, addr: usize) -> Result<(), nix::Error> {
+        if let Some(bp) = self.breakpoints_map.remove(&self.runtime_addr(addr)) {
+            self.write_byte(bp.addr, bp.orig_byte)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Instant;
+
+    /// Loads the debugging information of one of the samples, which need to have been built (run
+    /// `make` first).
+    fn sample_debug_data(target: &str) -> DwarfData {
+        DwarfData::from_file(target).expect("Run make to build the samples")
+    }
+
+    /// Starts `target` with a breakpoint at `addr` (an address from DwarfData) and runs it until it
+    /// stops there.
+    fn run_to_breakpoint(target: &str, args: &[&str], addr: usize) -> Inferior {
+        let args = args.iter().map(|arg| arg.to_string()).collect();
+        let mut inferior = Inferior::new(target, &args, &vec![addr], &BTreeMap::new()).unwrap();
+        match inferior.resume().unwrap() {
+            Status::Stopped(signal::Signal::SIGTRAP, rip) => {
+                assert_eq!(rip, inferior.runtime_addr(addr))
+            }
+            _ => panic!("The inferior didn't stop at the breakpoint"),
+        }
+        inferior
+    }
+
+    #[test]
+    fn test_parse_writable_regions() {
+        let maps = "\
+00400000-00401000 r--p 00000000 08:01 1234                       /deet/samples/count
+00401000-00402000 r-xp 00001000 08:01 1234                       /deet/samples/count
+00404000-00405000 rw-p 00003000 08:01 1234                       /deet/samples/count
+01e5c000-01e7d000 rw-p 00000000 00:00 0                          [heap]
+7ffd4c9a1000-7ffd4c9c2000 rw-p 00000000 00:00 0                  [stack]
+7ffd4c9f3000-7ffd4c9f7000 r--p 00000000 00:00 0                  [vvar]
+ffffffffff600000-ffffffffff601000 --xp 00000000 00:00 0          [vsyscall]
+";
+        assert_eq!(
+            parse_writable_regions(maps),
+            vec![
+                (0x404000, 0x405000),
+                (0x1e5c000, 0x1e7d000),
+                (0x7ffd4c9a1000, 0x7ffd4c9c2000)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_load_base() {
+        let maps = "\
+55d0c6a3e000-55d0c6a3f000 r--p 00000000 08:01 1234                       /deet/samples/function_calls_pie
+55d0c6a3f000-55d0c6a40000 r-xp 00001000 08:01 1234                       /deet/samples/function_calls_pie
+7f26e1c00000-7f26e1c28000 r--p 00000000 08:01 5678                       /usr/lib/x86_64-linux-gnu/libc.so.6
+7ffd4c9a1000-7ffd4c9c2000 rw-p 00000000 00:00 0                          [stack]
+";
+        assert_eq!(
+            parse_load_base(maps, "/deet/samples/function_calls_pie"),
+            Some(0x55d0c6a3e000)
+        );
+        assert_eq!(
+            parse_load_base(maps, "/usr/lib/x86_64-linux-gnu/libc.so.6"),
+            Some(0x7f26e1c00000)
+        );
+        assert_eq!(parse_load_base(maps, "/deet/samples/count"), None);
+    }
+
+    #[test]
+    fn test_hide_breakpoints() {
+        let mut breakpoints = HashMap::new();
+        for (addr, orig_byte) in [(0x1000, 0x55), (0x1003, 0x48), (0x2000, 0x90)] {
+            breakpoints.insert(
+                addr,
+                Breakpoint {
+                    addr,
+                    orig_byte,
+                    condition: None,
+                },
+            );
+        }
+        let mut bytes = vec![0xcc, 0x01, 0x02, 0xcc];
+        hide_breakpoints(&mut bytes, 0x1000, &breakpoints);
+        assert_eq!(bytes, vec![0x55, 0x01, 0x02, 0x48]);
+
+        let mut bytes = vec![0x01, 0x02, 0xcc];
+        hide_breakpoints(&mut bytes, 0x1001, &breakpoints);
+        assert_eq!(bytes, vec![0x01, 0x02, 0x48]);
+    }
+
+    #[test]
+    fn test_is_plausible_frame_pointer() {
+        assert!(is_plausible_frame_pointer(0x7ffe0000, 0x7ffe0040));
+        // Pointing back down the stack, or not moving at all
+        assert!(!is_plausible_frame_pointer(0x7ffe0040, 0x7ffe0000));
+        assert!(!is_plausible_frame_pointer(0x7ffe0040, 0x7ffe0040));
+        // rbp used as a general purpose register
+        assert!(!is_plausible_frame_pointer(0x7ffe0000, 0));
+        assert!(!is_plausible_frame_pointer(0x7ffe0000, 0x7ffe0043));
+    }
+
+    #[test]
+    fn test_breakpoint_in_loop_is_hit_every_iteration() {
+        let target = "samples/sleepy_print";
+        let debug_data = sample_debug_data(target);
+        // The printf inside the loop
+        let addr = debug_data.get_addr_for_line(None, 12).unwrap();
+        let mut inferior = Inferior::new(
+            target,
+            &vec!["3".to_string()],
+            &vec![addr],
+            &BTreeMap::new(),
+        )
+        .unwrap();
+
+        let mut status = inferior.resume().unwrap();
+        let mut hits = 0;
+        while let Status::Stopped(signal::Signal::SIGTRAP, rip) = status {
+            assert_eq!(rip, addr);
+            hits += 1;
+            status = inferior.resume().unwrap();
+        }
+        // If rip weren't rewound, the inferior would have run from the middle of an instruction
+        // and crashed rather than running the loop to completion
+        match status {
+            Status::Exited(code) => assert_eq!(code, 0),
+            _ => panic!("The inferior didn't run the loop to completion"),
+        }
+        assert_eq!(hits, 3);
+    }
+
+    #[test]
+    fn test_next_steps_over_calls() {
+        let target = "samples/function_calls";
+        let debug_data = sample_debug_data(target);
+        // The first line of func1
+        let addr = debug_data.get_addr_for_line(None, 17).unwrap();
+        let mut inferior = run_to_breakpoint(target, &[], addr);
+
+        // Each call (to printf, func2 and func3) is stepped over rather than into
+        for expected_line in 18..=20 {
+            let rip = match inferior.next_line(&debug_data).unwrap() {
+                Status::Stopped(signal::Signal::SIGTRAP, rip) => rip,
+                _ => panic!("The inferior didn't stop after next"),
+            };
+            assert_eq!(
+                debug_data.get_function_from_addr(rip).as_deref(),
+                Some("func1")
+            );
+            assert_eq!(
+                debug_data.get_line_from_addr(rip).unwrap().number,
+                expected_line
+            );
+        }
+        match inferior.resume().unwrap() {
+            Status::Exited(code) => assert_eq!(code, 0),
+            _ => panic!("The inferior didn't run to completion"),
+        }
+    }
+
+    #[test]
+    fn test_step_enters_calls() {
+        let target = "samples/function_calls";
+        let debug_data = sample_debug_data(target);
+        // The call to func2
+        let addr = debug_data.get_addr_for_line(None, 18).unwrap();
+        let mut inferior = run_to_breakpoint(target, &[], addr);
+
+        // We end up on the first line of func2's body, past its prologue, and then step over the
+        // call to printf (which has no line information)
+        for expected_line in 10..=11 {
+            let rip = match inferior.step_line(&debug_data).unwrap() {
+                Status::Stopped(signal::Signal::SIGTRAP, rip) => rip,
+                _ => panic!("The inferior didn't stop after step"),
+            };
+            assert_eq!(
+                debug_data.get_function_from_addr(rip).as_deref(),
+                Some("func2")
+            );
+            assert_eq!(
+                debug_data.get_line_from_addr(rip).unwrap().number,
+                expected_line
+            );
+        }
+        match inferior.resume().unwrap() {
+            Status::Exited(code) => assert_eq!(code, 0),
+            _ => panic!("The inferior didn't run to completion"),
+        }
+    }
+
+    #[test]
+    fn test_finish_from_function_entry() {
+        let target = "samples/function_calls";
+        let debug_data = sample_debug_data(target);
+        // Stopping on func3's first instruction means the prologue hasn't set up rbp yet
+        let addr = debug_data.get_addr_for_function(None, "func3").unwrap();
+        let mut inferior = run_to_breakpoint(target, &[], addr);
+
+        // func3 is first called by func2, on line 13
+        let return_addr = inferior.return_address(&debug_data).unwrap();
+        match inferior.run_to_return(return_addr).unwrap() {
+            Status::Stopped(signal::Signal::SIGTRAP, rip) => {
+                assert_eq!(rip, return_addr);
+                assert_eq!(
+                    debug_data.get_function_from_addr(rip).as_deref(),
+                    Some("func2")
+                );
+                assert_eq!(debug_data.get_line_from_addr(rip - 1).unwrap().number, 13);
+            }
+            _ => panic!("The inferior didn't stop after func3 returned"),
+        }
+        inferior.kill();
+    }
+
+    #[test]
+    fn test_conditional_breakpoint() {
+        let target = "samples/sleepy_print";
+        let debug_data = sample_debug_data(target);
+        // The printf inside the loop, which runs with i = 0, 1, 2
+        let addr = debug_data.get_addr_for_line(None, 12).unwrap();
+        let mut inferior = Inferior::new(
+            target,
+            &vec!["3".to_string()],
+            &vec![addr],
+            &BTreeMap::new(),
+        )
+        .unwrap();
+        inferior.set_breakpoint_condition(addr, Some(Condition::parse("i == 1").unwrap()));
+
+        match inferior.continue_running(&debug_data).unwrap() {
+            Status::Stopped(signal::Signal::SIGTRAP, rip) => assert_eq!(rip, addr),
+            _ => panic!("The inferior didn't stop at the breakpoint"),
+        }
+        let (variable, bytes) = inferior.read_variable(&debug_data, "i").unwrap();
+        assert_eq!(variable.entity_type.integer_value(&bytes), Some(1));
+        match inferior.continue_running(&debug_data).unwrap() {
+            Status::Exited(code) => assert_eq!(code, 0),
+            _ => panic!("The inferior stopped when the condition didn't hold"),
+        }
+    }
+
+    #[test]
+    fn test_backtrace_at_function_breakpoint() {
+        let target = "samples/function_calls";
+        let debug_data = sample_debug_data(target);
+        // Where `break func3` puts its breakpoint: past the prologue, so rbp is func3's own
+        let functions = debug_data.get_functions_named(None, "func3");
+        let (file, func) = functions[0];
+        let addr = func.body_address(file);
+        let mut inferior = run_to_breakpoint(target, &[], addr);
+
+        let regs = ptrace::getregs(inferior.pid()).unwrap();
+        let (frames, complete) = inferior.collect_frames(
+            &debug_data,
+            regs.rip as usize,
+            regs.rsp as usize,
+            regs.rbp as usize,
+        );
+        assert!(complete);
+        let names: Vec<String> = frames
+            .iter()
+            .enumerate()
+            .map(|(i, pc)| {
+                let lookup_addr = if i == 0 { *pc } else { *pc - 1 };
+                debug_data.get_function_from_addr(lookup_addr).unwrap()
+            })
+            .collect();
+        assert_eq!(names, vec!["func3", "func2", "func1", "main"]);
+        inferior.kill();
+    }
+
+    #[test]
+    fn test_position_independent_executable() {
+        let target = "samples/function_calls_pie";
+        let debug_data = sample_debug_data(target);
+        let functions = debug_data.get_functions_named(None, "func3");
+        let (file, func) = functions[0];
+        let addr = func.body_address(file);
+        let mut inferior = run_to_breakpoint(target, &[], addr);
+        assert_ne!(inferior.load_base, 0);
+        let rip = inferior.get_rip().unwrap();
+        assert_eq!(
+            debug_data
+                .get_function_from_addr(inferior.dwarf_addr(rip))
+                .as_deref(),
+            Some("func3")
+        );
+
+        // Globals are at fixed addresses, which have to be moved by the load base too
+        let (variable, bytes) = inferior.read_variable(&debug_data, "global").unwrap();
+        assert_eq!(variable.entity_type.integer_value(&bytes), Some(5));
+
+        let regs = ptrace::getregs(inferior.pid()).unwrap();
+        let (frames, complete) = inferior.collect_frames(
+            &debug_data,
+            regs.rip as usize,
+            regs.rsp as usize,
+            regs.rbp as usize,
+        );
+        assert!(complete);
+        assert_eq!(frames.len(), 4);
+        inferior.kill();
+    }
+
+    #[test]
+    fn test_read_words() {
+        let target = "samples/function_calls";
+        let debug_data = sample_debug_data(target);
+        let addr = debug_data.get_addr_for_function(None, "func3").unwrap();
+        let mut inferior = Inferior::new(target, &vec![], &vec![addr], &BTreeMap::new()).unwrap();
+
+        // Reads start at the word the address is in, and don't show the breakpoint's 0xcc
+        let start = align_addr_to_word(addr);
+        let words = inferior.read_words(addr, 2);
+        assert_eq!(words[0].0, start);
+        assert_eq!(words[1].0, start + 8);
+        let bytes: Vec<u8> = words
+            .iter()
+            .flat_map(|(_, word)| word.as_ref().unwrap().to_le_bytes().to_vec())
+            .collect();
+        assert_eq!(bytes, inferior.read_memory(start, 16).unwrap());
+
+        assert!(inferior.read_words(0, 1)[0].1.is_err());
+        inferior.kill();
+    }
+
+    #[test]
+    fn test_write_variable() {
+        let target = "samples/sleepy_print";
+        let debug_data = sample_debug_data(target);
+        // The printf inside the loop, which would run with i = 0, 1, 2, 3, 4
+        let addr = debug_data.get_addr_for_line(None, 12).unwrap();
+        let mut inferior = run_to_breakpoint(target, &["5"], addr);
+
+        // Skipping ahead to the last iteration means we don't stop here again
+        inferior.write_variable(&debug_data, "i", 4).unwrap();
+        let (variable, bytes) = inferior.read_variable(&debug_data, "i").unwrap();
+        assert_eq!(variable.entity_type.integer_value(&bytes), Some(4));
+        assert!(inferior.write_variable(&debug_data, "i", -1).is_err());
+        match inferior.resume().unwrap() {
+            Status::Exited(code) => assert_eq!(code, 0),
+            _ => panic!("The inferior stopped again after the loop counter was changed"),
+        }
+    }
+
+    #[test]
+    fn test_restore_checkpoint() {
+        let target = "samples/function_calls";
+        let debug_data = sample_debug_data(target);
+        let addr = debug_data.get_addr_for_function(None, "func3").unwrap();
+        let mut inferior = run_to_breakpoint(target, &[], addr);
+        assert!(inferior.restore_checkpoint().is_err());
+
+        assert!(inferior.checkpoint().unwrap() > 0);
+        inferior.write_variable(&debug_data, "global", 60).unwrap();
+        inferior.step_instruction().unwrap();
+        assert_ne!(inferior.get_rip().unwrap(), addr);
+
+        // Both memory and registers go back to how they were
+        inferior.restore_checkpoint().unwrap();
+        let (variable, bytes) = inferior.read_variable(&debug_data, "global").unwrap();
+        assert_eq!(variable.entity_type.integer_value(&bytes), Some(5));
+        assert_eq!(inferior.get_rip().unwrap(), addr);
+        inferior.kill();
+    }
+
+    #[test]
+    fn test_backtrace_of_other_thread() {
+        let target = "samples/threads";
+        let debug_data = sample_debug_data(target);
+        // The printf in worker, which only the second thread runs
+        let addr = debug_data.get_addr_for_line(None, 8).unwrap();
+        let mut inferior = run_to_breakpoint(target, &[], addr);
+
+        // The thread that hit the breakpoint is selected, and isn't the main thread
+        let threads = inferior.threads().to_vec();
+        assert_eq!(threads.len(), 2);
+        assert_eq!(threads[0], inferior.process_id());
+        let worker = inferior.pid();
+        assert_eq!(worker, threads[1]);
+
+        inferior.select_thread(threads[0]);
+        assert_ne!(inferior.get_rip().unwrap(), addr);
+        // The main thread is waiting for the worker in the threading library, which has no frame
+        // pointers, but recompiling the program wouldn't change that
+        let regs = ptrace::getregs(inferior.pid()).unwrap();
+        let lines = inferior.backtrace(&debug_data, &regs);
+        assert!(
+            lines.iter().all(|line| !line.contains("recompile")),
+            "{:?}",
+            lines
+        );
+        inferior.select_thread(worker);
+        let regs = ptrace::getregs(inferior.pid()).unwrap();
+        assert_eq!(regs.rip as usize, addr);
+        // The worker's stack starts in the threading library, without ever passing through main
+        let (frames, complete) = inferior.collect_frames(
+            &debug_data,
+            regs.rip as usize,
+            regs.rsp as usize,
+            regs.rbp as usize,
+        );
+        assert!(!complete);
+        assert_eq!(frames, vec![addr]);
+        assert_eq!(
+            debug_data.get_function_from_addr(frames[0]).as_deref(),
+            Some("worker")
+        );
+        inferior.kill();
+    }
+
+    #[test]
+    fn test_breakpoint_in_inlined_function() {
+        let target = "samples/inline";
+        let debug_data = sample_debug_data(target);
+        // The first line of square's body, which was inlined into main twice
+        let addrs = debug_data.get_addrs_for_line(None, 4);
+        assert_eq!(addrs.len(), 2);
+        let mut inferior = Inferior::new(target, &vec![], &addrs, &BTreeMap::new()).unwrap();
+
+        // square(3) is inlined before the printf on line 10, and square(4) after it
+        let first_printf = debug_data.get_addr_for_line(None, 10).unwrap();
+        for (i, addr) in addrs.iter().enumerate() {
+            match inferior.resume().unwrap() {
+                Status::Stopped(signal::Signal::SIGTRAP, rip) => {
+                    assert_eq!(rip, *addr);
+                    assert_eq!(debug_data.get_line_from_addr(rip).unwrap().number, 4);
+                    assert_eq!(rip > first_printf, i == 1);
+                }
+                _ => panic!("The inferior didn't stop at inlined call site {}", i + 1),
+            }
+        }
+        match inferior.resume().unwrap() {
+            Status::Exited(code) => assert_eq!(code, 0),
+            _ => panic!("The inferior didn't run to completion"),
+        }
+    }
+
+    #[test]
+    fn test_find_panic_call_site() {
+        let target = "samples/panic";
+        let debug_data = sample_debug_data(target);
+        // Where the debugger stops to report a panic
+        let addr = panic::PANIC_ENTRY_POINTS
+            .iter()
+            .find_map(|name| debug_data.get_addr_for_function(None, name))
+            .unwrap();
+        let mut inferior = run_to_breakpoint(target, &[], addr);
+
+        // The panic! in checked_divide, rather than anywhere in the standard library
+        let call_site = inferior.dwarf_addr(inferior.find_panic_call_site(&debug_data).unwrap());
+        let line = debug_data.get_line_from_addr(call_site).unwrap();
+        assert!(line.file.ends_with("samples/panic.rs"), "{}", line.file);
+        assert_eq!(line.number, 3);
+        // (The name may be mangled.)
+        let function = debug_data.get_function_from_addr(call_site).unwrap();
+        assert!(function.contains("checked_divide"), "{}", function);
+        assert_eq!(
+            inferior.read_panic_message(&debug_data).as_deref(),
+            Some("attempted to divide 1 by zero")
+        );
+        inferior.kill();
+    }
+
+    #[test]
+    fn test_backtrace_without_frame_pointers() {
+        let target = "samples/no_frame_pointers";
+        let debug_data = sample_debug_data(target);
+        let functions = debug_data.get_functions_named(None, "func2");
+        let (file, func) = functions[0];
+        let addr = func.body_address(file);
+        let mut inferior = run_to_breakpoint(target, &[], addr);
+
+        // rbp doesn't lead back to main, which print_backtrace reports as a failed unwind
+        let regs = ptrace::getregs(inferior.pid()).unwrap();
+        let (frames, complete) = inferior.collect_frames(
+            &debug_data,
+            regs.rip as usize,
+            regs.rsp as usize,
+            regs.rbp as usize,
+        );
+        assert!(!complete);
+        assert_eq!(frames[0], addr);
+        let lines = inferior.backtrace(&debug_data, &regs);
+        assert!(lines.last().unwrap().contains("recompile"), "{:?}", lines);
+        inferior.kill();
+    }
+
+    #[test]
+    fn test_dump_and_restore_memory() {
+        let target = "samples/function_calls";
+        let debug_data = sample_debug_data(target);
+        let addr = debug_data.get_addr_for_function(None, "func3").unwrap();
+        let mut inferior = run_to_breakpoint(target, &[], addr);
+        let (global, _) = inferior.read_variable(&debug_data, "global").unwrap();
+        let global_addr = match global.location {
+            Location::Address(addr) => inferior.runtime_addr(addr),
+            _ => panic!("global isn't at a fixed address"),
+        };
+
+        let path = std::env::temp_dir().join(format!("deet-dump-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        assert_eq!(
+            inferior
+                .dump_memory(global_addr, global_addr + 4, path)
+                .unwrap(),
+            4
+        );
+        assert_eq!(std::fs::read(path).unwrap(), 5_i32.to_le_bytes());
+
+        std::fs::write(path, 7_i32.to_le_bytes()).unwrap();
+        assert_eq!(inferior.restore_memory(path, global_addr).unwrap(), 4);
+        let (variable, bytes) = inferior.read_variable(&debug_data, "global").unwrap();
+        assert_eq!(variable.entity_type.integer_value(&bytes), Some(7));
+
+        std::fs::remove_file(path).unwrap();
+        assert!(inferior.restore_memory(path, global_addr).is_err());
+        inferior.kill();
+    }
+
+    #[test]
+    fn test_step_instructions_through_loop() {
+        let target = "samples/loop";
+        let debug_data = sample_debug_data(target);
+        // The body of the loop, and the return after it
+        let body_addr = debug_data.get_addr_for_line(None, 8).unwrap();
+        let end_addr = debug_data.get_addr_for_line(None, 10).unwrap();
+        let mut inferior = run_to_breakpoint(target, &[], body_addr);
+        inferior.set_breakpoint(end_addr).unwrap();
+
+        // Stepping stops when we get back around to the breakpoint
+        let iteration_steps = match inferior.step_instructions(1000, &debug_data).unwrap() {
+            (Status::Stopped(signal::Signal::SIGTRAP, rip), steps, false) => {
+                assert_eq!(rip, body_addr);
+                steps
+            }
+            _ => panic!("Stepping didn't stop at the breakpoint"),
+        };
+        assert!(iteration_steps > 1);
+
+        // ...unless the breakpoint's condition doesn't hold
+        inferior.set_breakpoint_condition(body_addr, Some(Condition::parse("i == 4").unwrap()));
+        match inferior.step_instructions(1000, &debug_data).unwrap() {
+            (Status::Stopped(signal::Signal::SIGTRAP, rip), steps, false) => {
+                assert_eq!(rip, body_addr);
+                assert_eq!(steps, 3 * iteration_steps);
+            }
+            _ => panic!("Stepping didn't stop at the conditional breakpoint"),
+        }
+        let (variable, bytes) = inferior.read_variable(&debug_data, "i").unwrap();
+        assert_eq!(variable.entity_type.integer_value(&bytes), Some(4));
+
+        // The other 6 iterations each run the same instructions
+        inferior.remove_breakpoint(body_addr).unwrap();
+        match inferior.step_instructions(1000, &debug_data).unwrap() {
+            (Status::Stopped(signal::Signal::SIGTRAP, rip), steps, false) => {
+                assert_eq!(rip, end_addr);
+                assert_eq!(steps, 6 * iteration_steps);
+            }
+            _ => panic!("Stepping didn't stop at the breakpoint after the loop"),
+        }
+        match inferior.step_instructions(1, &debug_data).unwrap() {
+            (Status::Stopped(signal::Signal::SIGTRAP, _), steps, true) => assert_eq!(steps, 1),
+            _ => panic!("The inferior didn't stop after one step"),
+        }
+        match inferior.resume().unwrap() {
+            Status::Exited(code) => assert_eq!(code, 45),
+            _ => panic!("The inferior didn't run to completion"),
+        }
+    }
+
+    #[test]
+    fn test_memory_round_trip() {
+        let target = "samples/function_calls";
+        let debug_data = sample_debug_data(target);
+        let addr = debug_data.get_addr_for_function(None, "func3").unwrap();
+        let mut inferior = run_to_breakpoint(target, &[], addr);
+
+        // The far end of the stack, which nothing is using yet. Starting and ending partway
+        // through a word exercises the partial word handling of the ptrace paths.
+        let rsp = ptrace::getregs(inferior.pid()).unwrap().rsp as usize;
+        let (stack_start, _) = inferior
+            .writable_regions()
+            .unwrap()
+            .into_iter()
+            .find(|(start, end)| *start <= rsp && rsp < *end)
+            .unwrap();
+        let start = stack_start + 3;
+        let len = 3 * 4096 + 5;
+        assert!(start + len < rsp);
+        let before = inferior.read_memory(start - 3, 3).unwrap();
+        let after = inferior.read_memory(start + len, 3).unwrap();
+        assert_eq!(
+            inferior.read_memory(start, len).unwrap(),
+            inferior.read_memory_with_ptrace(start, len).unwrap()
+        );
+
+        // Reading the whole range in one process_vm_readv call is the point of the fast path, so
+        // it shouldn't be slower than peeking a word at a time. (Taking the best of several runs
+        // keeps a busy machine from skewing either side.)
+        let fastest = |read: &dyn Fn() -> Vec<u8>| {
+            (0..10)
+                .map(|_| {
+                    let start = Instant::now();
+                    read();
+                    start.elapsed()
+                })
+                .min()
+                .unwrap()
+        };
+        let fast = fastest(&|| inferior.read_memory(start, len).unwrap());
+        let slow = fastest(&|| inferior.read_memory_with_ptrace(start, len).unwrap());
+        assert!(
+            fast <= slow,
+            "read_memory took {:?}, but read_memory_with_ptrace took {:?}",
+            fast,
+            slow
+        );
+
+        // Whichever way bytes are written, they read back the same both ways
+        let pattern: Vec<u8> = (0..len).map(|i| (i * 7 % 251) as u8).collect();
+        inferior.write_memory_with_ptrace(start, &pattern).unwrap();
+        assert_eq!(inferior.read_memory(start, len).unwrap(), pattern);
+        assert_eq!(
+            inferior.read_memory_with_ptrace(start, len).unwrap(),
+            pattern
+        );
+        let pattern: Vec<u8> = pattern.iter().map(|byte| !byte).collect();
+        inferior.write_memory(start, &pattern).unwrap();
+        assert_eq!(inferior.read_memory(start, len).unwrap(), pattern);
+        assert_eq!(
+            inferior.read_memory_with_ptrace(start, len).unwrap(),
+            pattern
+        );
+        // The bytes either side are left alone
+        assert_eq!(inferior.read_memory(start - 3, 3).unwrap(), before);
+        assert_eq!(inferior.read_memory(start + len, 3).unwrap(), after);
+        inferior.kill();
+    }
+
+    #[test]
+    fn test_set_breakpoint_twice() {
+        let target = "samples/function_calls";
+        let debug_data = sample_debug_data(target);
+        let addr = debug_data.get_addr_for_function(None, "func3").unwrap();
+        let mut inferior = Inferior::new(target, &vec![], &vec![addr], &BTreeMap::new()).unwrap();
+        let raw_byte = |inferior: &Inferior| {
+            ptrace::read(inferior.pid(), addr as ptrace::AddressType).unwrap() as u8
+        };
+        assert_eq!(raw_byte(&inferior), 0xcc);
+        let orig_byte = inferior.read_memory(addr, 1).unwrap()[0];
+        assert_ne!(orig_byte, 0xcc);
+
+        // The second breakpoint mustn't remember the first one's 0xcc as the original byte
+        inferior.set_breakpoint(addr).unwrap();
+        inferior.remove_breakpoint(addr).unwrap();
+        assert_eq!(raw_byte(&inferior), orig_byte);
+        match inferior.resume().unwrap() {
+            Status::Exited(code) => assert_eq!(code, 0),
+            _ => panic!("The inferior stopped at a removed breakpoint"),
+        }
+    }
+
+    #[test]
+    fn test_environment_overrides() {
+        // Needs the samples to have been built (run `make` first)
+        let target = "samples/env";
+        let mut env = BTreeMap::new();
+        env.insert("DEET_EXIT_STATUS".to_string(), Some("42".to_string()));
+        let mut inferior = Inferior::new(target, &vec![], &vec![], &env).unwrap();
+        match inferior.resume().unwrap() {
+            Status::Exited(code) => assert_eq!(code, 42),
+            _ => panic!("The inferior didn't run to completion"),
+        }
+
+        // Removing it hides it from the inferior, whether or not deet itself has it set
+        env.insert("DEET_EXIT_STATUS".to_string(), None);
+        let mut inferior = Inferior::new(target, &vec![], &vec![], &env).unwrap();
+        match inferior.resume().unwrap() {
+            Status::Exited(code) => assert_eq!(code, 100),
+            _ => panic!("The inferior didn't run to completion"),
+        }
+    }
+
+    #[test]
+    fn test_backtrace_call_sites() {
+        let target = "samples/function_calls";
+        let debug_data = sample_debug_data(target);
+        let functions = debug_data.get_functions_named(None, "func3");
+        let (file, func) = functions[0];
+        let addr = func.body_address(file);
+        let mut inferior = run_to_breakpoint(target, &[], addr);
+
+        // func3 is first called from func2, which func1 calls, which main calls
+        let regs = ptrace::getregs(inferior.pid()).unwrap();
+        let lines = inferior.backtrace(&debug_data, &regs);
+        assert_eq!(lines.len(), 4);
+        let expected = [("func3", 13), ("func2", 18), ("func1", 24)];
+        // Line lookups may give the full path of the source file, where the DWARF file name is
+        // relative to the compilation directory, so only the file names are compared
+        let file_name = std::path::Path::new(&file.name).file_name();
+        for (line, (function, call_site)) in lines.iter().zip(&expected) {
+            assert!(line.starts_with(&format!("{} (", function)), "{}", line);
+            let (_, called_from) = line.split_once("called from ").unwrap();
+            let (path, number) = called_from.rsplit_once(':').unwrap();
+            assert_eq!(
+                std::path::Path::new(path).file_name(),
+                file_name,
+                "{}",
+                line
+            );
+            assert_eq!(number, call_site.to_string(), "{}", line);
+        }
+        assert!(lines[3].starts_with("main ("), "{}", lines[3]);
+        assert!(!lines[3].contains("called from"));
+        inferior.kill();
+    }
+
+    #[test]
+    fn test_next_over_plain_lines_and_calls() {
+        let target = "samples/function_calls";
+        let debug_data = sample_debug_data(target);
+        // The first line of func2, which mixes calls with a line of plain arithmetic
+        let addr = debug_data.get_addr_for_line(None, 10).unwrap();
+        let mut inferior = run_to_breakpoint(target, &[], addr);
+
+        // Line 11 has no call in it, and line 13's call to func3 is stepped over
+        for expected_line in 11..=14 {
+            let rip = match inferior.next_line(&debug_data).unwrap() {
+                Status::Stopped(signal::Signal::SIGTRAP, rip) => rip,
+                _ => panic!("The inferior didn't stop after next"),
+            };
+            assert_eq!(
+                debug_data.get_function_from_addr(rip).as_deref(),
+                Some("func2")
+            );
+            assert_eq!(
+                debug_data.get_line_from_addr(rip).unwrap().number,
+                expected_line
+            );
+        }
+        inferior.kill();
+    }
+
+    #[test]
+    fn test_wait_ignores_other_threads_inferiors() {
+        let target = "samples/function_calls";
+        let debug_data = sample_debug_data(target);
+        let addr = debug_data.get_addr_for_line(None, 17).unwrap();
+
+        // Another thread starts an inferior and lets it exit, but doesn't reap it until we say so
+        let (pid_sender, pid_receiver) = std::sync::mpsc::channel();
+        let (reap_sender, reap_receiver) = std::sync::mpsc::channel();
+        let other_thread = std::thread::spawn(move || {
+            let mut other = Inferior::new(target, &vec![], &vec![], &BTreeMap::new()).unwrap();
+            ptrace::cont(other.pid(), None).unwrap();
+            pid_sender.send(other.pid()).unwrap();
+            reap_receiver.recv().unwrap();
+            match other.wait(None).unwrap() {
+                Status::Exited(code) => assert_eq!(code, 0),
+                _ => panic!("The other thread's inferior didn't exit"),
+            }
+        });
+        let other_pid = pid_receiver.recv().unwrap();
+        let stat_path = format!("/proc/{}/stat", other_pid);
+        while !std::fs::read_to_string(&stat_path)
+            .unwrap()
+            .contains(") Z ")
+        {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+
+        // With the other inferior's exit waiting to be collected, ours should still only see
+        // its own stops
+        let mut inferior = run_to_breakpoint(target, &[], addr);
+        reap_sender.send(()).unwrap();
+        other_thread.join().unwrap();
+        inferior.kill();
+    }
 }
@@ -0,0 +1,130 @@
+//! A tiny x86-64 decoder. We don't need a real disassembler; we only need to know whether the
+//! instruction at some address is a `call`, and if so, how long it is (so that we know where the
+//! call will return to).
+
+/// The longest an x86-64 instruction can be.
+pub const MAX_INSTRUCTION_LEN: usize = 15;
+
+fn is_legacy_prefix(byte: u8) -> bool {
+    matches!(
+        byte,
+        0x66 | 0x67 | 0xf0 | 0xf2 | 0xf3 | 0x2e | 0x36 | 0x3e | 0x26 | 0x64 | 0x65
+    )
+}
+
+fn is_rex_prefix(byte: u8) -> bool {
+    byte & 0xf0 == 0x40
+}
+
+/// Returns the number of bytes taken up by a ModRM byte and whatever SIB byte/displacement follows
+/// it, or None if `bytes` is too short to tell.
+fn modrm_len(bytes: &[u8]) -> Option<usize> {
+    let modrm = *bytes.first()?;
+    let mode = modrm >> 6;
+    let rm = modrm & 0x7;
+    let mut len = 1;
+    if mode != 3 && rm == 4 {
+        // A SIB byte follows. A base of 5 with mod 0 means a disp32 with no base register.
+        let sib = *bytes.get(1)?;
+        len += 1;
+        if mode == 0 && sib & 0x7 == 5 {
+            len += 4;
+        }
+    }
+    len += match mode {
+        0 if rm == 5 => 4, // rip-relative
+        1 => 1,
+        2 => 4,
+        _ => 0,
+    };
+    Some(len)
+}
+
+/// If `bytes` starts with a call instruction, returns the length of that instruction (i.e. the
+/// offset of the return address from the start of the call). Returns None for anything else.
+pub fn call_length(bytes: &[u8]) -> Option<usize> {
+    let mut offset = 0;
+    while offset < bytes.len() && is_legacy_prefix(bytes[offset]) {
+        offset += 1;
+    }
+    if offset < bytes.len() && is_rex_prefix(bytes[offset]) {
+        offset += 1;
+    }
+    match *bytes.get(offset)? {
+        // call rel32
+        0xe8 => Some(offset + 5),
+        // call r/m64 (/2) or far call m16:64 (/3)
+        0xff => {
+            let reg = (*bytes.get(offset + 1)? >> 3) & 0x7;
+            if reg == 2 || reg == 3 {
+                Some(offset + 1 + modrm_len(&bytes[offset + 1..])?)
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+/// endbr64, which functions start with when compiled with control-flow protection
+const ENDBR64: [u8; 4] = [0xf3, 0x0f, 0x1e, 0xfa];
+
+/// If `bytes` (the start of a function) begins with the usual `push %rbp` that saves the caller's
+/// frame pointer, possibly after an endbr64, returns the offset of the push. Returns None if the
+/// function doesn't set up a frame pointer that way.
+pub fn push_rbp_offset(bytes: &[u8]) -> Option<usize> {
+    let offset = if bytes.starts_with(&ENDBR64) {
+        ENDBR64.len()
+    } else {
+        0
+    };
+    if *bytes.get(offset)? == 0x55 {
+        Some(offset)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_call_length() {
+        // The body of a small function: only the calls should be recognized
+        let instructions: Vec<(&[u8], Option<usize>)> = vec![
+            (&[0x55], None),                                  // push %rbp
+            (&[0x48, 0x89, 0xe5], None),                      // mov %rsp,%rbp
+            (&[0x89, 0x7d, 0xfc], None),                      // mov %edi,-0x4(%rbp)
+            (&[0xbf, 0x64, 0x00, 0x00, 0x00], None),          // mov $0x64,%edi
+            (&[0xe8, 0x10, 0xff, 0xff, 0xff], Some(5)),       // call func3
+            (&[0xff, 0xd0], Some(2)),                         // call *%rax
+            (&[0x41, 0xff, 0xd4], Some(3)),                   // call *%r12
+            (&[0xff, 0x15, 0x00, 0x10, 0x00, 0x00], Some(6)), // call *0x1000(%rip)
+            (&[0xff, 0x54, 0x24, 0x08], Some(4)),             // call *0x8(%rsp)
+            (&[0xff, 0x25, 0x00, 0x10, 0x00, 0x00], None),    // jmp *0x1000(%rip)
+            (&[0xeb, 0xfe], None),                            // jmp .
+            (&[0xc9], None),                                  // leave
+            (&[0xc3], None),                                  // ret
+        ];
+        for (bytes, expected) in instructions {
+            assert_eq!(call_length(bytes), expected, "decoding {:x?}", bytes);
+        }
+    }
+
+    #[test]
+    fn test_call_length_truncated() {
+        assert_eq!(call_length(&[]), None);
+        assert_eq!(call_length(&[0xff]), None);
+        assert_eq!(call_length(&[0xff, 0x14]), None);
+    }
+
+    #[test]
+    fn test_push_rbp_offset() {
+        assert_eq!(push_rbp_offset(&[0x55, 0x48, 0x89, 0xe5]), Some(0));
+        assert_eq!(push_rbp_offset(&[0xf3, 0x0f, 0x1e, 0xfa, 0x55]), Some(4));
+        // sub $0x18,%rsp (no frame pointer)
+        assert_eq!(push_rbp_offset(&[0x48, 0x83, 0xec, 0x18]), None);
+        assert_eq!(push_rbp_offset(&[0xf3, 0x0f, 0x1e, 0xfa]), None);
+    }
+}
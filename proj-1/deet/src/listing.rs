@@ -0,0 +1,37 @@
+//! Showing the lines of source code around a location in the inferior.
+
+/// Returns the lines of `source` within `radius` lines of `line` (counting from 1), along with
+/// their line numbers.
+pub fn source_context(source: &str, line: usize, radius: usize) -> Vec<(usize, &str)> {
+    let first = line.saturating_sub(radius).max(1);
+    source
+        .lines()
+        .enumerate()
+        .map(|(i, text)| (i + 1, text))
+        .skip(first - 1)
+        .take_while(|(number, _)| *number <= line + radius)
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_source_context() {
+        let source = "one\ntwo\nthree\nfour\nfive\n";
+        assert_eq!(
+            source_context(source, 3, 1),
+            vec![(2, "two"), (3, "three"), (4, "four")]
+        );
+        assert_eq!(
+            source_context(source, 1, 2),
+            vec![(1, "one"), (2, "two"), (3, "three")]
+        );
+        assert_eq!(
+            source_context(source, 5, 2),
+            vec![(3, "three"), (4, "four"), (5, "five")]
+        );
+        assert_eq!(source_context(source, 9, 1), vec![]);
+    }
+}
@@ -1,8 +1,14 @@
+mod condition;
 mod debugger;
 mod debugger_command;
 mod dwarf_data;
+mod examine;
 mod gimli_wrapper;
 mod inferior;
+mod instruction;
+mod listing;
+mod panic;
+mod registers;
 
 use crate::debugger::Debugger;
 use nix::sys::signal::{signal, SigHandler, Signal};
@@ -10,15 +16,29 @@ use std::env;
 
 fn main() {
     let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        println!("Usage: {} <target program>", args[0]);
+    let count_instructions = args[1..].iter().any(|arg| arg == "--count-instructions");
+    // Like gdb's -nx, skip running .deetinit
+    let skip_init_file = args[1..].iter().any(|arg| arg == "--nx");
+    let positional: Vec<&String> = args[1..]
+        .iter()
+        .filter(|arg| !arg.starts_with("--"))
+        .collect();
+    if positional.len() != 1 {
+        println!(
+            "Usage: {} [--count-instructions] [--nx] <target program>",
+            args[0]
+        );
         std::process::exit(1);
     }
-    let target = &args[1];
+    let target = positional[0];
 
     // Disable handling of ctrl+c in this process (so that ctrl+c only gets delivered to child
     // processes)
     unsafe { signal(Signal::SIGINT, SigHandler::SigIgn) }.expect("Error disabling SIGINT handling");
 
-    Debugger::new(target).run();
+    let mut debugger = Debugger::new(target, count_instructions);
+    if !skip_init_file {
+        debugger.source_init_file();
+    }
+    debugger.run();
 }
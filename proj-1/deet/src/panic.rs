@@ -0,0 +1,56 @@
+//! Recognizing Rust panics in the inferior.
+
+/// Functions in the Rust panic runtime that we stop at to catch a panic, most preferred first.
+/// `rust_panic` is called once the panic hook has printed the panic message, whether the program
+/// goes on to unwind or abort.
+pub const PANIC_ENTRY_POINTS: [&str; 2] = ["rust_panic", "rust_begin_unwind"];
+
+/// Returns true if a source file belongs to the Rust standard library (core, alloc, std and the
+/// panic runtime) rather than to the program being debugged. The standard library is built with
+/// its sources under /rustc/<commit hash>/, or under library/ when built locally.
+pub fn is_runtime_source(file: &str) -> bool {
+    file.starts_with("/rustc/")
+        || ["core", "alloc", "std", "panic_unwind", "panic_abort"]
+            .iter()
+            .any(|krate| file.contains(&format!("library/{}/src/", krate)))
+}
+
+/// Returns `bytes` as a string if they look like a panic message: valid UTF-8 without control
+/// characters, other than line breaks and tabs.
+pub fn message_text(bytes: Vec<u8>) -> Option<String> {
+    String::from_utf8(bytes).ok().filter(|text| {
+        text.chars()
+            .all(|c| !c.is_control() || c == '\n' || c == '\t')
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_is_runtime_source() {
+        assert!(is_runtime_source(
+            "/rustc/a8314ef7d0ec7b75c336af2c9857bfaf43002bfc/library/core/src/panicking.rs"
+        ));
+        assert!(is_runtime_source(
+            "/home/me/rust/library/std/src/panicking.rs"
+        ));
+        assert!(!is_runtime_source("samples/panic.rs"));
+    }
+
+    #[test]
+    fn test_message_text() {
+        assert_eq!(
+            message_text(b"attempted to divide 1 by zero".to_vec()).as_deref(),
+            Some("attempted to divide 1 by zero")
+        );
+        assert_eq!(
+            message_text(b"line one\n\tline two".to_vec()).as_deref(),
+            Some("line one\n\tline two")
+        );
+        // Pointers and lengths, rather than text
+        assert_eq!(message_text(vec![0x10, 0x52, 0x55, 0x55, 0, 0, 0, 0]), None);
+        assert_eq!(message_text(vec![0xff, 0x7f]), None);
+    }
+}
@@ -0,0 +1,107 @@
+//! Looking up and formatting the inferior's registers (as returned by `ptrace::getregs`).
+
+/// The registers that `info registers` prints, in the order it prints them.
+pub const REGISTER_NAMES: [&str; 24] = [
+    "rax", "rbx", "rcx", "rdx", "rsi", "rdi", "rbp", "rsp", "r8", "r9", "r10", "r11", "r12", "r13",
+    "r14", "r15", "rip", "eflags", "cs", "ss", "ds", "es", "fs", "gs",
+];
+
+/// Bits of the flags register we know how to name, along with their names.
+const FLAGS: [(u64, &str); 9] = [
+    (0, "CF"),
+    (2, "PF"),
+    (4, "AF"),
+    (6, "ZF"),
+    (7, "SF"),
+    (8, "TF"),
+    (9, "IF"),
+    (10, "DF"),
+    (11, "OF"),
+];
+
+/// Returns the value of the register with the given name (with or without a leading `$`), or None
+/// if there's no such register.
+pub fn get_register(regs: &libc::user_regs_struct, name: &str) -> Option<u64> {
+    let name = name.strip_prefix('$').unwrap_or(name);
+    Some(match name {
+        "rax" => regs.rax,
+        "rbx" => regs.rbx,
+        "rcx" => regs.rcx,
+        "rdx" => regs.rdx,
+        "rsi" => regs.rsi,
+        "rdi" => regs.rdi,
+        "rbp" => regs.rbp,
+        "rsp" => regs.rsp,
+        "r8" => regs.r8,
+        "r9" => regs.r9,
+        "r10" => regs.r10,
+        "r11" => regs.r11,
+        "r12" => regs.r12,
+        "r13" => regs.r13,
+        "r14" => regs.r14,
+        "r15" => regs.r15,
+        "rip" => regs.rip,
+        "eflags" | "rflags" => regs.eflags,
+        "cs" => regs.cs,
+        "ss" => regs.ss,
+        "ds" => regs.ds,
+        "es" => regs.es,
+        "fs" => regs.fs,
+        "gs" => regs.gs,
+        "fs_base" => regs.fs_base,
+        "gs_base" => regs.gs_base,
+        "orig_rax" => regs.orig_rax,
+        _ => return None,
+    })
+}
+
+/// Returns the names of the condition/control flags that are set in a flags register value.
+pub fn decode_flags(flags: u64) -> Vec<&'static str> {
+    FLAGS
+        .iter()
+        .filter(|(bit, _)| flags & (1 << bit) != 0)
+        .map(|(_, name)| *name)
+        .collect()
+}
+
+/// Formats a register's value for display. The flags register is shown along with its decoded
+/// flags, e.g. `0x246 [ PF ZF IF ]`.
+pub fn format_register(name: &str, value: u64) -> String {
+    let name = name.strip_prefix('$').unwrap_or(name);
+    if name == "eflags" || name == "rflags" {
+        format!("{:#x} [ {} ]", value, decode_flags(value).join(" "))
+    } else {
+        format!("{:#x}", value)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn test_regs() -> libc::user_regs_struct {
+        let mut regs: libc::user_regs_struct = unsafe { std::mem::zeroed() };
+        regs.rax = 42;
+        regs.rip = 0x401126;
+        regs.eflags = 0x246;
+        regs
+    }
+
+    #[test]
+    fn test_get_register() {
+        let regs = test_regs();
+        assert_eq!(get_register(&regs, "rax"), Some(42));
+        assert_eq!(get_register(&regs, "$rip"), Some(0x401126));
+        assert_eq!(get_register(&regs, "rflags"), Some(0x246));
+        assert_eq!(get_register(&regs, "xmm0"), None);
+    }
+
+    #[test]
+    fn test_decode_flags() {
+        assert_eq!(decode_flags(0x246), vec!["PF", "ZF", "IF"]);
+        assert_eq!(decode_flags(0x893), vec!["CF", "AF", "SF", "OF"]);
+        assert_eq!(decode_flags(0), Vec::<&str>::new());
+        assert_eq!(format_register("eflags", 0x246), "0x246 [ PF ZF IF ]");
+        assert_eq!(format_register("rax", 42), "0x2a");
+    }
+}
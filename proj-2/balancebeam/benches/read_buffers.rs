@@ -0,0 +1,202 @@
+//! Counts the allocations made reading requests, and compares request::read_from_stream against
+//! the way it read them before its buffers came from a BufferPool. (Responses are read into
+//! buffers on the stack, so there's nothing for a pool to save there.)
+//!
+//! Run with `cargo bench --bench read_buffers`.
+
+// Option::is_some_and is newer than the toolchain this assignment targets
+#![allow(unknown_lints, clippy::unnecessary_map_or)]
+
+// The reading code lives in the binary, so it's compiled in here too
+#[allow(dead_code, unused_imports)]
+#[path = "../src/bufpool.rs"]
+mod bufpool;
+#[allow(dead_code, unused_imports)]
+#[path = "../src/request.rs"]
+mod request;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::hint::black_box;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+const ITERATIONS: usize = 10_000;
+
+/// request::read_headers and request::read_body as they were before they borrowed their buffers
+/// from the pool: the header buffer was a new Vec for every request, and the body was read through
+/// a new Vec each time around the loop.
+mod before_pooling {
+    use crate::request::{
+        check_header_sizes, get_content_length, parse_request, read_until, Error,
+    };
+    use std::cmp::min;
+    use tokio::io::AsyncRead;
+    use tokio::time::Instant;
+
+    async fn read_headers<S: AsyncRead + Unpin>(
+        stream: &mut S,
+        max_request_line_size: usize,
+        max_headers_size: usize,
+        deadline: Option<Instant>,
+    ) -> Result<http::Request<Vec<u8>>, Error> {
+        let max_buffer_size = max_request_line_size + max_headers_size + 5;
+        let mut request_buffer: Vec<u8> = Vec::new();
+        let mut chunk = [0_u8; 1024];
+        loop {
+            let chunk_size = min(chunk.len(), max_buffer_size - request_buffer.len());
+            let new_bytes = read_until(
+                stream,
+                &mut chunk[..chunk_size],
+                deadline,
+                !request_buffer.is_empty(),
+            )
+            .await?;
+            if new_bytes == 0 {
+                return Err(Error::IncompleteRequest(request_buffer.len()));
+            }
+            request_buffer.extend_from_slice(&chunk[..new_bytes]);
+            let bytes_read = request_buffer.len();
+
+            let parsed = parse_request(&request_buffer)?;
+            check_header_sizes(
+                &request_buffer,
+                parsed.as_ref().map(|(_, headers_len)| *headers_len),
+                max_request_line_size,
+                max_headers_size,
+            )?;
+            if let Some((mut request, headers_len)) = parsed {
+                request
+                    .body_mut()
+                    .extend_from_slice(&request_buffer[headers_len..bytes_read]);
+                return Ok(request);
+            }
+        }
+    }
+
+    async fn read_body<S: AsyncRead + Unpin>(
+        stream: &mut S,
+        request: &mut http::Request<Vec<u8>>,
+        content_length: usize,
+        deadline: Option<Instant>,
+    ) -> Result<(), Error> {
+        while request.body().len() < content_length {
+            let mut buffer = vec![0_u8; min(512, content_length)];
+            let bytes_read = read_until(stream, &mut buffer, deadline, true).await?;
+            if bytes_read == 0 || request.body().len() + bytes_read > content_length {
+                return Err(Error::ContentLengthMismatch);
+            }
+            request.body_mut().extend_from_slice(&buffer[..bytes_read]);
+        }
+        Ok(())
+    }
+
+    pub async fn read_from_stream<S: AsyncRead + Unpin>(
+        stream: &mut S,
+        max_body_size: Option<usize>,
+    ) -> Result<http::Request<Vec<u8>>, Error> {
+        let mut request = read_headers(
+            stream,
+            crate::request::MAX_REQUEST_LINE_SIZE,
+            crate::request::MAX_HEADERS_SIZE,
+            None,
+        )
+        .await?;
+        if let Some(content_length) = get_content_length(&request)? {
+            if max_body_size.map_or(false, |max_body_size| content_length > max_body_size) {
+                return Err(Error::RequestBodyTooLarge);
+            }
+            read_body(stream, &mut request, content_length, None).await?;
+        }
+        Ok(request)
+    }
+}
+
+/// Passes everything through to the system allocator, counting allocations as it goes.
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Runs `f` ITERATIONS times, returning the allocations made and the time taken per iteration.
+fn measure<F: FnMut()>(mut f: F) -> (f64, Duration) {
+    // Warm up, so that the pool is filled before we start counting
+    f();
+    let allocations_before = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        f();
+    }
+    let elapsed = start.elapsed();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations_before;
+    (
+        allocations as f64 / ITERATIONS as f64,
+        elapsed / ITERATIONS as u32,
+    )
+}
+
+fn report(name: &str, (allocations, elapsed): (f64, Duration)) {
+    println!(
+        "{:<50} {:>6.1} allocations {:>10?}",
+        name, allocations, elapsed
+    );
+}
+
+fn main() {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()
+        .unwrap();
+    let get: &[u8] = b"GET /index.html HTTP/1.1\r\nHost: example.com\r\nUser-Agent: bench\r\n\r\n";
+    let mut post =
+        b"POST /upload HTTP/1.1\r\nHost: example.com\r\nContent-Length: 4000\r\n\r\n".to_vec();
+    post.resize(post.len() + 4000, b'x');
+
+    for (name, bytes) in [("GET", get), ("POST, 4000-byte body", &post[..])] {
+        report(
+            &format!("{}, before pooling", name),
+            measure(|| {
+                let mut stream = bytes;
+                black_box(
+                    runtime
+                        .block_on(before_pooling::read_from_stream(
+                            &mut stream,
+                            Some(request::MAX_BODY_SIZE),
+                        ))
+                        .unwrap(),
+                );
+            }),
+        );
+        report(
+            &format!("{}, request::read_from_stream", name),
+            measure(|| {
+                let mut stream = bytes;
+                black_box(
+                    runtime
+                        .block_on(request::read_from_stream(
+                            &mut stream,
+                            Some(request::MAX_BODY_SIZE),
+                        ))
+                        .unwrap(),
+                );
+            }),
+        );
+    }
+}
@@ -0,0 +1,75 @@
+//! Compares picking upstreams with a freshly seeded StdRng for every selection (how
+//! get_live_upstream used to do it) against drawing from one StdRng seeded at startup and shared
+//! behind a mutex (how ProxyState::gen_range does it now), on one thread and on several threads
+//! picking at once.
+//!
+//! Run with `cargo bench --bench upstream_selection`.
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::hint::black_box;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const SELECTIONS_PER_THREAD: usize = 100_000;
+const NUM_UPSTREAMS: usize = 8;
+const NUM_THREADS: usize = 8;
+
+fn select_with_new_rng() -> usize {
+    StdRng::from_entropy().gen_range(0..NUM_UPSTREAMS)
+}
+
+fn select_with_shared_rng(rng: &Mutex<StdRng>) -> usize {
+    rng.lock().unwrap().gen_range(0..NUM_UPSTREAMS)
+}
+
+/// Runs `select` SELECTIONS_PER_THREAD times on each of `num_threads` threads at once, returning
+/// how long that took.
+fn time_selections<F: Fn() -> usize + Send + Sync + 'static>(
+    num_threads: usize,
+    select: F,
+) -> Duration {
+    let select = Arc::new(select);
+    let start = Instant::now();
+    let threads: Vec<_> = (0..num_threads)
+        .map(|_| {
+            let select = select.clone();
+            thread::spawn(move || {
+                for _ in 0..SELECTIONS_PER_THREAD {
+                    black_box(select());
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    start.elapsed()
+}
+
+fn report(name: &str, num_threads: usize, elapsed: Duration) {
+    let selections = (num_threads * SELECTIONS_PER_THREAD) as f64;
+    println!(
+        "{:<40} {:>10.0} selections/s {:>8.1} ns/selection",
+        format!("{} ({} thread(s))", name, num_threads),
+        selections / elapsed.as_secs_f64(),
+        elapsed.as_nanos() as f64 / selections
+    );
+}
+
+fn main() {
+    for &num_threads in &[1, NUM_THREADS] {
+        report(
+            "new StdRng per selection",
+            num_threads,
+            time_selections(num_threads, select_with_new_rng),
+        );
+        let shared_rng = Mutex::new(StdRng::from_entropy());
+        report(
+            "shared StdRng",
+            num_threads,
+            time_selections(num_threads, move || select_with_shared_rng(&shared_rng)),
+        );
+    }
+}
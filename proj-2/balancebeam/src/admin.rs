@@ -0,0 +1,226 @@
+use crate::{mark_upstream_status, request, response, ProxyState, UpstreamAddress};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+
+/// Escapes a string for use inside a JSON string literal.
+pub fn json_escape(value: &str) -> String {
+    let mut escaped = String::new();
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Renders an upstream's health check history as JSON.
+fn render_health_history(addr: &UpstreamAddress) -> String {
+    let results: Vec<String> = addr
+        .health_history
+        .0
+        .iter()
+        .map(|result| {
+            let timestamp = result
+                .time
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default();
+            format!(
+                "{{\"timestamp_ms\":{},\"alive\":{},\"latency_ms\":{},\"error\":{}}}",
+                timestamp.as_millis(),
+                result.alive,
+                result.latency.as_millis(),
+                match &result.error {
+                    Some(error) => format!("\"{}\"", json_escape(error)),
+                    None => "null".to_string(),
+                }
+            )
+        })
+        .collect();
+    format!(
+        "{{\"upstream\":\"{}\",\"alive\":{},\"history\":[{}]}}",
+        json_escape(&addr.address),
+        addr.alive,
+        results.join(",")
+    )
+}
+
+/// Renders an upstream's current status as JSON.
+fn render_upstream_status(addr: &UpstreamAddress) -> String {
+    format!(
+        "{{\"upstream\":\"{}\",\"alive\":{},\"drained\":{},\"weight\":{},\
+         \"active_connections\":{}}}",
+        json_escape(&addr.address),
+        addr.alive,
+        addr.drained,
+        addr.weight,
+        addr.active_connections.load(Ordering::SeqCst)
+    )
+}
+
+/// Renders the status of every upstream (in any pool) as a JSON list.
+async fn render_upstream_statuses(state: &ProxyState) -> String {
+    let mut seen: Vec<String> = Vec::new();
+    let mut statuses: Vec<String> = Vec::new();
+    for pool in state.pools() {
+        for addr in pool.addresses.read().await.iter() {
+            if !seen.contains(&addr.address) {
+                seen.push(addr.address.clone());
+                statuses.push(render_upstream_status(addr));
+            }
+        }
+    }
+    format!("[{}]", statuses.join(","))
+}
+
+/// Drains an upstream (or enables it again), returning its new status as JSON, or None if there's
+/// no such upstream.
+async fn set_upstream_drained(state: &ProxyState, address: &str, drained: bool) -> Option<String> {
+    let mut status = None;
+    for pool in state.pools() {
+        let mut addresses = pool.addresses.write().await;
+        if let Some(addr) = addresses.iter_mut().find(|addr| addr.address == address) {
+            addr.drained = drained;
+            status = Some(render_upstream_status(addr));
+        }
+    }
+    let status = status?;
+    log::info!(
+        "Upstream {} {} through the admin API",
+        address,
+        if drained { "drained" } else { "enabled" }
+    );
+    // An enabled upstream is given the benefit of the doubt until its next health check
+    mark_upstream_status(state, address.to_string(), !drained).await;
+    Some(status)
+}
+
+/// Returns a 200 response with a JSON body.
+fn make_json_response(body: String) -> http::Response<Vec<u8>> {
+    let body = body.into_bytes();
+    http::Response::builder()
+        .status(http::StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .header("Content-Length", body.len().to_string())
+        .version(http::Version::HTTP_11)
+        .body(body)
+        .unwrap()
+}
+
+/// Answers one admin API request.
+async fn handle_admin_request(
+    request: &http::Request<Vec<u8>>,
+    state: &ProxyState,
+) -> http::Response<Vec<u8>> {
+    let path = request.uri().path();
+    // (Unix socket addresses contain slashes of their own, so the action is whatever follows the
+    // last one.)
+    let upstream_action = path
+        .strip_prefix("/upstreams/")
+        .and_then(|rest| rest.rsplit_once('/'));
+    let body = match (request.method(), path, upstream_action) {
+        (&http::Method::GET, "/upstreams", _) => Some(render_upstream_statuses(state).await),
+        (&http::Method::GET, _, Some((address, "history"))) => {
+            let mut history = None;
+            for pool in state.pools() {
+                let addresses = pool.addresses.read().await;
+                if let Some(addr) = addresses.iter().find(|addr| addr.address == address) {
+                    history = Some(render_health_history(addr));
+                    break;
+                }
+            }
+            history
+        }
+        (&http::Method::POST, _, Some((address, "drain"))) => {
+            set_upstream_drained(state, address, true).await
+        }
+        (&http::Method::POST, _, Some((address, "enable"))) => {
+            set_upstream_drained(state, address, false).await
+        }
+        _ => None,
+    };
+    match body {
+        Some(body) => make_json_response(body),
+        None => response::make_http_error(http::StatusCode::NOT_FOUND),
+    }
+}
+
+/// Serves the admin API. Like the metrics listener, this never proxies anything.
+pub async fn serve_admin(listener: TcpListener, state: Arc<ProxyState>) {
+    loop {
+        let (mut socket, _) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(error) => {
+                log::warn!("Failed to accept admin connection: {}", error);
+                continue;
+            }
+        };
+        let state = state.clone();
+        tokio::spawn(async move {
+            while let Ok(request) =
+                request::read_from_stream(&mut socket, Some(request::MAX_BODY_SIZE)).await
+            {
+                let response = handle_admin_request(&request, &state).await;
+                if let Err(error) = response::write_to_stream(&response, &mut socket).await {
+                    log::debug!("Failed to send admin response: {}", error);
+                    return;
+                }
+            }
+        });
+    }
+}
+
+/// Serves the metrics endpoint. This listener never proxies anything; it only answers GET /metrics.
+pub async fn serve_metrics(listener: TcpListener, state: Arc<ProxyState>) {
+    loop {
+        let (mut socket, _) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(error) => {
+                log::warn!("Failed to accept metrics connection: {}", error);
+                continue;
+            }
+        };
+        let state = state.clone();
+        tokio::spawn(async move {
+            while let Ok(request) =
+                request::read_from_stream(&mut socket, Some(request::MAX_BODY_SIZE)).await
+            {
+                let response = if request.method() == http::Method::GET
+                    && request.uri().path() == "/metrics"
+                {
+                    let body = state.metrics.render().into_bytes();
+                    http::Response::builder()
+                        .status(http::StatusCode::OK)
+                        .header("Content-Type", "text/plain; version=0.0.4")
+                        .header("Content-Length", body.len().to_string())
+                        .version(http::Version::HTTP_11)
+                        .body(body)
+                        .unwrap()
+                } else {
+                    response::make_http_error(http::StatusCode::NOT_FOUND)
+                };
+                if let Err(error) = response::write_to_stream(&response, &mut socket).await {
+                    log::debug!("Failed to send metrics response: {}", error);
+                    return;
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_json_escape() {
+        assert_eq!(json_escape("127.0.0.1:8080"), "127.0.0.1:8080");
+        assert_eq!(json_escape("say \"hi\""), "say \\\"hi\\\"");
+        assert_eq!(json_escape("C:\\upstream"), "C:\\\\upstream");
+        assert_eq!(json_escape("line\nbreak\t"), "line\\u000abreak\\u0009");
+        assert_eq!(json_escape("caf\u{e9}"), "caf\u{e9}");
+    }
+}
@@ -0,0 +1,119 @@
+use parking_lot::{const_mutex, Mutex};
+use std::ops::{Deref, DerefMut};
+
+/// Most spare buffers the shared pool holds on to. Any more that get returned are freed.
+const MAX_POOLED_BUFFERS: usize = 64;
+/// Buffers that have grown bigger than this (e.g. to read one huge request) are freed rather than
+/// returned to the shared pool, so that they don't keep all that memory around forever.
+const MAX_POOLED_CAPACITY: usize = 64 * 1024;
+
+/// The pool that request::read_from_stream reads into, shared by every connection. (It's a
+/// parking_lot mutex because std's can't be created in a static.)
+static SHARED: BufferPool = BufferPool::new(MAX_POOLED_BUFFERS, MAX_POOLED_CAPACITY);
+
+/// Spare read buffers that can be reused instead of allocating new ones for every request we
+/// read.
+#[derive(Debug)]
+pub struct BufferPool {
+    max_buffers: usize,
+    max_capacity: usize,
+    spare: Mutex<Vec<Vec<u8>>>,
+}
+
+/// A buffer borrowed from a BufferPool. It starts out empty, and goes back to the pool when it's
+/// dropped.
+#[derive(Debug)]
+pub struct PooledBuffer<'a> {
+    pool: &'a BufferPool,
+    buffer: Vec<u8>,
+}
+
+impl BufferPool {
+    pub const fn new(max_buffers: usize, max_capacity: usize) -> BufferPool {
+        BufferPool {
+            max_buffers,
+            max_capacity,
+            spare: const_mutex(Vec::new()),
+        }
+    }
+
+    /// Borrows a spare buffer, or a new one if there aren't any.
+    pub fn take(&self) -> PooledBuffer<'_> {
+        PooledBuffer {
+            pool: self,
+            buffer: self.spare.lock().pop().unwrap_or_default(),
+        }
+    }
+
+    fn give_back(&self, mut buffer: Vec<u8>) {
+        if buffer.capacity() == 0 || buffer.capacity() > self.max_capacity {
+            return;
+        }
+        buffer.clear();
+        let mut spare = self.spare.lock();
+        if spare.len() < self.max_buffers {
+            spare.push(buffer);
+        }
+    }
+}
+
+/// Borrows a buffer from the pool shared by every connection.
+pub fn take() -> PooledBuffer<'static> {
+    SHARED.take()
+}
+
+impl Deref for PooledBuffer<'_> {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> {
+        &self.buffer
+    }
+}
+
+impl DerefMut for PooledBuffer<'_> {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.buffer
+    }
+}
+
+impl Drop for PooledBuffer<'_> {
+    fn drop(&mut self) {
+        self.pool.give_back(std::mem::take(&mut self.buffer));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_buffers_are_reused() {
+        let pool = BufferPool::new(2, 1024);
+        let mut buffer = pool.take();
+        buffer.extend_from_slice(b"hello");
+        let address = buffer.as_ptr();
+        drop(buffer);
+
+        // We get the same allocation back, emptied out
+        let buffer = pool.take();
+        assert!(buffer.is_empty());
+        assert!(buffer.capacity() >= 5);
+        assert_eq!(buffer.as_ptr(), address);
+    }
+
+    #[test]
+    fn test_pool_is_capped() {
+        let pool = BufferPool::new(2, 1024);
+        let mut huge = pool.take();
+        huge.resize(2048, 0);
+        drop(huge);
+        assert!(pool.spare.lock().is_empty());
+
+        let mut buffers: Vec<PooledBuffer> = (0..3).map(|_| pool.take()).collect();
+        for buffer in buffers.iter_mut() {
+            buffer.push(0);
+        }
+        drop(buffers);
+        assert_eq!(pool.spare.lock().len(), 2);
+    }
+}
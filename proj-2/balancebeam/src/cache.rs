@@ -0,0 +1,302 @@
+use crate::response;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Cache-Control directives that mean a message mustn't be served from a shared cache like ours
+const UNCACHEABLE_DIRECTIVES: [&str; 3] = ["no-store", "no-cache", "private"];
+
+/// Cache-Control directives a response needs at least one of before we'll store it. Without them,
+/// the upstream hasn't said anything about how long (or whether) its response stays fresh.
+const CACHEABLE_DIRECTIVES: [&str; 3] = ["public", "max-age", "s-maxage"];
+
+/// Upstream responses to GET requests, kept for a while so that repeat requests can be answered
+/// without bothering an upstream. Once the cache is full, the least recently used response makes
+/// way for new ones.
+#[derive(Debug)]
+pub struct ResponseCache {
+    ttl: Duration,
+    max_entries: usize,
+    entries: HashMap<String, CacheEntry>,
+    /// Counts lookups and stores, so entries can tell which was used least recently
+    uses: u64,
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    response: http::Response<Vec<u8>>,
+    stored_at: Instant,
+    /// How long the entry stays fresh: the cache's TTL, or less if the upstream said so
+    ttl: Duration,
+    last_used: u64,
+}
+
+/// Returns true if a message's Cache-Control header has any of `directives` (ignoring their
+/// values).
+fn has_directive(headers: &http::HeaderMap, directives: &[&str]) -> bool {
+    headers
+        .get_all("cache-control")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|directive| {
+            let name = directive.split('=').next().unwrap_or("").trim();
+            directives
+                .iter()
+                .any(|wanted| name.eq_ignore_ascii_case(wanted))
+        })
+}
+
+/// Returns how long the upstream said a response stays fresh for, if it did. s-maxage is meant for
+/// shared caches like ours, so it takes precedence over max-age.
+fn max_age(headers: &http::HeaderMap) -> Option<Duration> {
+    let directive_value = |wanted: &str| {
+        headers
+            .get_all("cache-control")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .find_map(|directive| {
+                let (name, value) = directive.split_once('=')?;
+                if !name.trim().eq_ignore_ascii_case(wanted) {
+                    return None;
+                }
+                value.trim().trim_matches('"').parse::<u64>().ok()
+            })
+    };
+    directive_value("s-maxage")
+        .or_else(|| directive_value("max-age"))
+        .map(Duration::from_secs)
+}
+
+/// Returns true if a message's Cache-Control header forbids caching it.
+fn forbids_caching(headers: &http::HeaderMap) -> bool {
+    has_directive(headers, &UNCACHEABLE_DIRECTIVES)
+}
+
+/// Returns the key to cache the response to `request` under (its method, host and path), or None
+/// if the request shouldn't be answered from the cache at all.
+pub fn cache_key(request: &http::Request<Vec<u8>>) -> Option<String> {
+    // Responses to authorized (or cookie-carrying) requests are only meant for whoever sent them
+    if request.method() != http::Method::GET
+        || request.headers().contains_key("authorization")
+        || request.headers().contains_key("cookie")
+        || forbids_caching(request.headers())
+    {
+        return None;
+    }
+    let host = request
+        .headers()
+        .get("host")
+        .and_then(|host| host.to_str().ok())
+        .unwrap_or("")
+        .to_ascii_lowercase();
+    let path = request
+        .uri()
+        .path_and_query()
+        .map_or("/", |path| path.as_str());
+    Some(format!("{} {}{}", request.method(), host, path))
+}
+
+/// Returns true if a response can be stored and handed to other clients. Only responses that
+/// explicitly allow it (with public, max-age or s-maxage) are.
+fn is_cacheable(response: &http::Response<Vec<u8>>) -> bool {
+    // We'd have to key on more than the URL to cache responses that vary by request headers
+    response.status() == http::StatusCode::OK
+        && has_directive(response.headers(), &CACHEABLE_DIRECTIVES)
+        && !forbids_caching(response.headers())
+        && !response.headers().contains_key("set-cookie")
+        && !response.headers().contains_key("vary")
+        && response.extensions().get::<response::Truncated>().is_none()
+}
+
+impl ResponseCache {
+    pub fn new(ttl: Duration, max_entries: usize) -> ResponseCache {
+        ResponseCache {
+            ttl,
+            max_entries,
+            entries: HashMap::new(),
+            uses: 0,
+        }
+    }
+
+    /// Returns a copy of the response stored under `key` and how long ago it was stored, unless
+    /// there isn't one or it's older than its TTL.
+    pub fn get(&mut self, key: &str, now: Instant) -> Option<(http::Response<Vec<u8>>, Duration)> {
+        let entry = self.entries.get(key)?;
+        let age = now.saturating_duration_since(entry.stored_at);
+        if age >= entry.ttl {
+            self.entries.remove(key);
+            return None;
+        }
+        self.uses += 1;
+        let entry = self.entries.get_mut(key).unwrap();
+        entry.last_used = self.uses;
+        Some((response::clone_response(&entry.response), age))
+    }
+
+    /// Stores a copy of `response` under `key`, if it's cacheable, evicting the least recently
+    /// used response if the cache is full. It's kept for the cache's TTL, or for as long as the
+    /// upstream said it stays fresh if that's shorter.
+    pub fn store(&mut self, key: String, response: &http::Response<Vec<u8>>, now: Instant) {
+        let ttl = match max_age(response.headers()) {
+            Some(max_age) => max_age.min(self.ttl),
+            None => self.ttl,
+        };
+        if self.max_entries == 0 || ttl == Duration::from_secs(0) || !is_cacheable(response) {
+            return;
+        }
+        if !self.entries.contains_key(&key) && self.entries.len() >= self.max_entries {
+            // Expired entries are the first to go
+            self.entries
+                .retain(|_, entry| now.saturating_duration_since(entry.stored_at) < entry.ttl);
+            if self.entries.len() >= self.max_entries {
+                let least_recently_used = self
+                    .entries
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.last_used)
+                    .map(|(key, _)| key.clone())
+                    .unwrap();
+                self.entries.remove(&least_recently_used);
+            }
+        }
+        self.uses += 1;
+        self.entries.insert(
+            key,
+            CacheEntry {
+                response: response::clone_response(response),
+                stored_at: now,
+                ttl,
+                last_used: self.uses,
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn response_with_cache_control(cache_control: Option<&str>) -> http::Response<Vec<u8>> {
+        let mut builder = http::Response::builder().status(200);
+        if let Some(cache_control) = cache_control {
+            builder = builder.header("cache-control", cache_control);
+        }
+        builder.body(b"cached".to_vec()).unwrap()
+    }
+
+    #[test]
+    fn test_expiry() {
+        let start = Instant::now();
+        let mut cache = ResponseCache::new(Duration::from_secs(10), 10);
+        assert!(cache.get("GET example.com/", start).is_none());
+        cache.store(
+            "GET example.com/".to_string(),
+            &response_with_cache_control(Some("max-age=60")),
+            start,
+        );
+        let (response, age) = cache
+            .get("GET example.com/", start + Duration::from_secs(9))
+            .unwrap();
+        assert_eq!(response.body(), b"cached");
+        assert_eq!(age, Duration::from_secs(9));
+        assert!(cache
+            .get("GET example.com/", start + Duration::from_secs(10))
+            .is_none());
+    }
+
+    #[test]
+    fn test_respects_max_age() {
+        let start = Instant::now();
+        let mut cache = ResponseCache::new(Duration::from_secs(60), 10);
+        cache.store(
+            "short".to_string(),
+            &response_with_cache_control(Some("public, max-age=5")),
+            start,
+        );
+        assert!(cache.get("short", start + Duration::from_secs(4)).is_some());
+        assert!(cache.get("short", start + Duration::from_secs(5)).is_none());
+
+        // s-maxage is the one meant for us
+        cache.store(
+            "shared".to_string(),
+            &response_with_cache_control(Some("max-age=30, s-maxage=5")),
+            start,
+        );
+        assert!(cache
+            .get("shared", start + Duration::from_secs(5))
+            .is_none());
+
+        // A longer max-age doesn't outlast our TTL
+        cache.store(
+            "long".to_string(),
+            &response_with_cache_control(Some("max-age=3600")),
+            start,
+        );
+        assert!(cache.get("long", start + Duration::from_secs(59)).is_some());
+        assert!(cache.get("long", start + Duration::from_secs(60)).is_none());
+
+        cache.store(
+            "stale".to_string(),
+            &response_with_cache_control(Some("max-age=0")),
+            start,
+        );
+        assert!(cache.get("stale", start).is_none());
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let start = Instant::now();
+        let mut cache = ResponseCache::new(Duration::from_secs(60), 2);
+        let response = response_with_cache_control(Some("public, max-age=60"));
+        cache.store("a".to_string(), &response, start);
+        cache.store("b".to_string(), &response, start);
+        // Using a makes b the least recently used
+        assert!(cache.get("a", start).is_some());
+        cache.store("c".to_string(), &response, start);
+        assert!(cache.get("a", start).is_some());
+        assert!(cache.get("b", start).is_none());
+        assert!(cache.get("c", start).is_some());
+    }
+
+    #[test]
+    fn test_respects_cache_control() {
+        let start = Instant::now();
+        let mut cache = ResponseCache::new(Duration::from_secs(60), 10);
+        // Responses that don't say they can be cached aren't
+        cache.store("key".to_string(), &response_with_cache_control(None), start);
+        assert!(cache.get("key", start).is_none());
+        for cache_control in &[
+            "no-store",
+            "private, max-age=60",
+            "No-Cache",
+            "must-revalidate",
+        ] {
+            cache.store(
+                "key".to_string(),
+                &response_with_cache_control(Some(cache_control)),
+                start,
+            );
+            assert!(cache.get("key", start).is_none(), "{}", cache_control);
+        }
+
+        let request = |cache_control: Option<&str>| {
+            let mut builder = http::Request::builder()
+                .uri("/path?query")
+                .header("host", "Example.com");
+            if let Some(cache_control) = cache_control {
+                builder = builder.header("cache-control", cache_control);
+            }
+            builder.body(Vec::new()).unwrap()
+        };
+        assert_eq!(
+            cache_key(&request(None)).as_deref(),
+            Some("GET example.com/path?query")
+        );
+        assert!(cache_key(&request(Some("no-cache"))).is_none());
+        let mut with_cookie = request(None);
+        with_cookie
+            .headers_mut()
+            .insert("cookie", "session=1".parse().unwrap());
+        assert!(cache_key(&with_cookie).is_none());
+    }
+}
@@ -0,0 +1,78 @@
+use std::net::IpAddr;
+
+/// A block of IP addresses, written in CIDR notation (e.g. 10.0.0.0/8 or fd00::/8). A bare
+/// address is a block containing just that address.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix_len: u32,
+}
+
+impl Cidr {
+    pub fn parse(cidr: &str) -> Result<Cidr, String> {
+        let invalid = || format!("Invalid CIDR block {}", cidr);
+        let (address, prefix_len) = match cidr.split_once('/') {
+            Some((address, prefix_len)) => (address, Some(prefix_len)),
+            None => (cidr, None),
+        };
+        let network = address.trim().parse::<IpAddr>().map_err(|_| invalid())?;
+        let max_len = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => match prefix_len.trim().parse::<u32>() {
+                Ok(prefix_len) if prefix_len <= max_len => prefix_len,
+                _ => return Err(invalid()),
+            },
+            None => max_len,
+        };
+        Ok(Cidr {
+            network,
+            prefix_len,
+        })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_len).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_len).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_contains() {
+        let block = Cidr::parse("10.1.0.0/16").unwrap();
+        assert!(block.contains("10.1.2.3".parse().unwrap()));
+        assert!(!block.contains("10.2.0.1".parse().unwrap()));
+        assert!(!block.contains("::1".parse().unwrap()));
+
+        let single = Cidr::parse("192.168.0.5").unwrap();
+        assert!(single.contains("192.168.0.5".parse().unwrap()));
+        assert!(!single.contains("192.168.0.6".parse().unwrap()));
+
+        let everything = Cidr::parse("0.0.0.0/0").unwrap();
+        assert!(everything.contains("203.0.113.9".parse().unwrap()));
+
+        let v6 = Cidr::parse("fd00::/8").unwrap();
+        assert!(v6.contains("fd12:3456::1".parse().unwrap()));
+        assert!(!v6.contains("fe80::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_parse_rejects_invalid_blocks() {
+        assert!(Cidr::parse("10.0.0.0/33").is_err());
+        assert!(Cidr::parse("10.0.0/8").is_err());
+        assert!(Cidr::parse("10.0.0.0/abc").is_err());
+        assert!(Cidr::parse("").is_err());
+    }
+}
@@ -0,0 +1,138 @@
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use http::header::HeaderValue;
+use std::io::Write;
+
+/// Bodies smaller than this aren't worth compressing; the gzip header and the time spent would
+/// outweigh whatever we save
+const MIN_BODY_SIZE: usize = 1024;
+
+/// Non-text content types that compress well
+const COMPRESSIBLE_TYPES: [&str; 5] = [
+    "application/json",
+    "application/javascript",
+    "application/xml",
+    "application/xhtml+xml",
+    "image/svg+xml",
+];
+
+/// Returns true if the client's Accept-Encoding header says it can take a gzipped response (i.e.
+/// it lists gzip or *, and not with q=0).
+pub fn client_accepts_gzip(request: &http::Request<Vec<u8>>) -> bool {
+    request
+        .headers()
+        .get_all("accept-encoding")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|coding| {
+            let mut params = coding.split(';');
+            let name = params.next().unwrap_or("").trim();
+            let refused = params.any(|param| {
+                param
+                    .trim()
+                    .strip_prefix("q=")
+                    .and_then(|q| q.trim().parse::<f32>().ok())
+                    .map_or(false, |q| q == 0.0)
+            });
+            (name.eq_ignore_ascii_case("gzip") || name == "*") && !refused
+        })
+}
+
+/// Returns true if a response is worth compressing: it has a textual content type, a body over
+/// MIN_BODY_SIZE, and hasn't been encoded by the upstream already.
+fn is_compressible(response: &http::Response<Vec<u8>>) -> bool {
+    if response.headers().contains_key("content-encoding")
+        || response.body().len() < MIN_BODY_SIZE
+        // Ranges refer to the bytes of the uncompressed body
+        || response.status() == http::StatusCode::PARTIAL_CONTENT
+    {
+        return false;
+    }
+    let content_type = match response
+        .headers()
+        .get("content-type")
+        .and_then(|value| value.to_str().ok())
+    {
+        Some(content_type) => content_type,
+        None => return false,
+    };
+    let mime_type = content_type
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_ascii_lowercase();
+    mime_type.starts_with("text/") || COMPRESSIBLE_TYPES.contains(&mime_type.as_str())
+}
+
+/// Gzips a response's body if it's worth compressing, updating Content-Encoding and
+/// Content-Length to match. Returns true if the body was compressed.
+pub fn compress_response(response: &mut http::Response<Vec<u8>>) -> bool {
+    if !is_compressible(response) {
+        return false;
+    }
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    let compressed = match encoder
+        .write_all(response.body())
+        .and_then(|_| encoder.finish())
+    {
+        Ok(compressed) => compressed,
+        Err(error) => {
+            log::warn!("Failed to compress response: {}", error);
+            return false;
+        }
+    };
+    let headers = response.headers_mut();
+    headers.insert("content-encoding", HeaderValue::from_static("gzip"));
+    headers.insert("content-length", HeaderValue::from(compressed.len()));
+    // Caches need to know that what we send depends on Accept-Encoding
+    headers.append("vary", HeaderValue::from_static("accept-encoding"));
+    *response.body_mut() = compressed;
+    true
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn request_accepting(accept_encoding: &str) -> http::Request<Vec<u8>> {
+        http::Request::builder()
+            .header("accept-encoding", accept_encoding)
+            .body(Vec::new())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_client_accepts_gzip() {
+        assert!(client_accepts_gzip(&request_accepting("gzip")));
+        assert!(client_accepts_gzip(&request_accepting(
+            "deflate, GZIP;q=0.5"
+        )));
+        assert!(client_accepts_gzip(&request_accepting("*")));
+        assert!(!client_accepts_gzip(&request_accepting("br, deflate")));
+        assert!(!client_accepts_gzip(&request_accepting("gzip;q=0")));
+        assert!(!client_accepts_gzip(
+            &http::Request::builder().body(Vec::new()).unwrap()
+        ));
+    }
+
+    #[test]
+    fn test_is_compressible() {
+        let response = |content_type: &str, body_len: usize| {
+            http::Response::builder()
+                .header("content-type", content_type)
+                .body(vec![b'a'; body_len])
+                .unwrap()
+        };
+        assert!(is_compressible(&response("text/html; charset=utf-8", 2000)));
+        assert!(is_compressible(&response("application/json", 2000)));
+        assert!(!is_compressible(&response("text/plain", 100)));
+        assert!(!is_compressible(&response("image/png", 2000)));
+        let mut encoded = response("text/plain", 2000);
+        encoded
+            .headers_mut()
+            .insert("content-encoding", HeaderValue::from_static("br"));
+        assert!(!is_compressible(&encoded));
+    }
+}
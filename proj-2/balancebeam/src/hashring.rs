@@ -0,0 +1,109 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::hash::{Hash, Hasher};
+
+/// How many positions each node gets on the ring. With only one position per node, the share of
+/// keys each node gets would depend heavily on where its position happened to land.
+const VIRTUAL_NODES_PER_NODE: usize = 100;
+
+fn hash<T: Hash + ?Sized>(value: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// A consistent-hash ring. Nodes (upstream addresses) and keys (client IPs) are both hashed onto
+/// the ring, and each key belongs to the first node found going clockwise from it. Adding or
+/// removing a node only moves the keys next to that node's positions, rather than reshuffling
+/// nearly every key the way hashing modulo the number of nodes does.
+#[derive(Debug, Default)]
+pub struct HashRing {
+    /// Node names, keyed by their positions on the ring
+    ring: BTreeMap<u64, String>,
+}
+
+impl HashRing {
+    pub fn new<'a>(nodes: impl IntoIterator<Item = &'a str>) -> HashRing {
+        let mut ring = HashRing::default();
+        for node in nodes {
+            ring.add(node);
+        }
+        ring
+    }
+
+    pub fn add(&mut self, node: &str) {
+        for i in 0..VIRTUAL_NODES_PER_NODE {
+            self.ring.insert(hash(&(node, i)), node.to_string());
+        }
+    }
+
+    pub fn remove(&mut self, node: &str) {
+        self.ring.retain(|_, name| name != node);
+    }
+
+    /// Returns the node that `key` belongs to, or None if the ring is empty.
+    #[allow(dead_code)]
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.get_matching(key, |_| true)
+    }
+
+    /// Returns the first node clockwise from `key` that `is_usable` accepts. Skipping over nodes
+    /// this way (e.g. because they're dead) moves only their keys, and only to their neighbours.
+    pub fn get_matching<F: Fn(&str) -> bool>(&self, key: &str, is_usable: F) -> Option<&str> {
+        let position = hash(key);
+        self.ring
+            .range(position..)
+            .chain(self.ring.range(..position))
+            .map(|(_, node)| node.as_str())
+            .find(|node| is_usable(node))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_removing_node_moves_few_keys() {
+        let nodes = ["10.0.0.1:80", "10.0.0.2:80", "10.0.0.3:80", "10.0.0.4:80"];
+        let mut ring = HashRing::new(nodes.iter().copied());
+        let keys: Vec<String> = (0..10000)
+            .map(|i| format!("192.168.{}.{}", i / 256, i % 256))
+            .collect();
+        let before: Vec<String> = keys
+            .iter()
+            .map(|key| ring.get(key).unwrap().to_string())
+            .collect();
+
+        ring.remove(nodes[3]);
+        let mut moved = 0;
+        for (key, old_node) in keys.iter().zip(before.iter()) {
+            let new_node = ring.get(key).unwrap();
+            assert_ne!(new_node, nodes[3]);
+            if new_node != old_node {
+                // Only keys that belonged to the removed node should have moved
+                assert_eq!(old_node, nodes[3]);
+                moved += 1;
+            }
+        }
+        let moved_fraction = moved as f64 / keys.len() as f64;
+        assert!(
+            moved_fraction > 0.15 && moved_fraction < 0.35,
+            "{:.0}% of keys moved after removing 1 of 4 nodes",
+            moved_fraction * 100.0
+        );
+    }
+
+    #[test]
+    fn test_get_matching_skips_unusable_nodes() {
+        let ring = HashRing::new(vec!["a", "b", "c"]);
+        assert_eq!(HashRing::default().get("key"), None);
+        for i in 0..100 {
+            let key = i.to_string();
+            let node = ring.get(&key).unwrap();
+            let fallback = ring.get_matching(&key, |name| name != node).unwrap();
+            assert_ne!(fallback, node);
+            assert_eq!(ring.get_matching(&key, |_| false), None);
+        }
+    }
+}
@@ -0,0 +1,81 @@
+use http::header::{HeaderMap, HeaderName, HeaderValue};
+
+/// Recommended security headers, added to responses by --security-headers
+const SECURITY_HEADERS: [(&str, &str); 4] = [
+    (
+        "strict-transport-security",
+        "max-age=31536000; includeSubDomains",
+    ),
+    ("x-content-type-options", "nosniff"),
+    ("x-frame-options", "DENY"),
+    ("referrer-policy", "strict-origin-when-cross-origin"),
+];
+
+/// Headers to add to or remove from every message passing through balancebeam.
+#[derive(Debug, Default)]
+pub struct HeaderRules {
+    /// Added only if the message doesn't already have a value for the header
+    defaults: Vec<(HeaderName, HeaderValue)>,
+    /// Always added, replacing any existing values
+    set: Vec<(HeaderName, HeaderValue)>,
+    /// Removed after everything else has been applied
+    remove: Vec<HeaderName>,
+}
+
+/// Parses a header given on the command line as NAME=VALUE.
+fn parse_header(header: &str) -> Result<(HeaderName, HeaderValue), String> {
+    let (name, value) = header
+        .split_once('=')
+        .ok_or_else(|| format!("{} is not of the form NAME=VALUE", header))?;
+    let name = HeaderName::from_bytes(name.trim().as_bytes())
+        .map_err(|_| format!("{} is not a valid header name", name))?;
+    let value = HeaderValue::from_str(value.trim())
+        .map_err(|_| format!("{} is not a valid header value", value))?;
+    Ok((name, value))
+}
+
+impl HeaderRules {
+    /// Builds a set of rules from NAME=VALUE headers to set and NAMEs to remove. If
+    /// `security_headers` is true, the recommended security headers are added as defaults; they
+    /// can be overridden or removed like any other header.
+    pub fn new(
+        security_headers: bool,
+        set: &[String],
+        remove: &[String],
+    ) -> Result<HeaderRules, String> {
+        let mut rules = HeaderRules::default();
+        if security_headers {
+            for (name, value) in SECURITY_HEADERS.iter() {
+                rules.defaults.push((
+                    HeaderName::from_static(name),
+                    HeaderValue::from_static(value),
+                ));
+            }
+        }
+        for header in set {
+            rules.set.push(parse_header(header)?);
+        }
+        for name in remove {
+            rules.remove.push(
+                HeaderName::from_bytes(name.trim().as_bytes())
+                    .map_err(|_| format!("{} is not a valid header name", name))?,
+            );
+        }
+        Ok(rules)
+    }
+
+    /// Applies the rules to a message's headers. Header names are case-insensitive.
+    pub fn apply(&self, headers: &mut HeaderMap) {
+        for (name, value) in &self.defaults {
+            if !headers.contains_key(name) {
+                headers.insert(name.clone(), value.clone());
+            }
+        }
+        for (name, value) in &self.set {
+            headers.insert(name.clone(), value.clone());
+        }
+        for name in &self.remove {
+            headers.remove(name);
+        }
+    }
+}
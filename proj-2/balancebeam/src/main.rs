@@ -1,12 +1,58 @@
+// Option::is_some_and and usize::div_ceil are newer than the toolchain this assignment targets
+#![allow(unknown_lints, clippy::unnecessary_map_or, clippy::manual_div_ceil)]
+
+mod admin;
+mod bufpool;
+mod cache;
+mod cidr;
+mod gzip;
+mod hashring;
+mod headers;
+mod metrics;
+mod pool;
+mod ratelimit;
 mod request;
 mod response;
+mod stream;
 
 use clap::Clap;
+use rand::distributions::uniform::{SampleRange, SampleUniform};
 use rand::{Rng, SeedableRng};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::io::BufReader;
 use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::RwLock;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{broadcast, watch, OwnedSemaphorePermit, RwLock, Semaphore};
 use tokio::time;
+use tokio::time::Instant;
+use tokio_rustls::rustls;
+use tokio_rustls::TlsAcceptor;
+
+/// How many upstream state change events can be buffered for a slow events subscriber before it
+/// starts missing events
+const EVENTS_CHANNEL_CAPACITY: usize = 64;
+
+/// How many of its most recent active health check results each upstream remembers
+const HEALTH_HISTORY_LEN: usize = 20;
+
+/// The share of its weight that an upstream gets right after it recovers, with --slow-start-seconds
+const SLOW_START_MIN_FRACTION: f64 = 0.1;
+
+/// The span of time that --max-requests-per-minute applies to
+const RATE_LIMIT_WINDOW: time::Duration = time::Duration::from_secs(60);
+
+/// The first file descriptor systemd passes sockets in on when using socket activation
+const SD_LISTEN_FDS_START: RawFd = 3;
 
 /// Contains information parsed from the command-line invocation of balancebeam. The Clap macros
 /// provide a fancy way to automatically construct a command-line argument parser.
@@ -16,12 +62,49 @@ struct CmdOptions {
     #[clap(
         short,
         long,
-        about = "IP/port to bind to",
+        about = "IP/port to bind to. Repeat it to listen on more than one address.",
         default_value = "0.0.0.0:1100"
     )]
-    bind: String,
-    #[clap(short, long, about = "Upstream host to forward requests to")]
+    bind: Vec<String>,
+    #[clap(
+        short,
+        long,
+        about = "Upstream host to forward requests to, optionally with a weight (e.g. \
+                 10.0.0.1:80@3). Weight 0 means only use it once no others are alive. Upstreams \
+                 listening on a Unix socket are given as unix:/path/to.sock."
+    )]
     upstream: Vec<String>,
+    #[clap(
+        long,
+        about = "Upstream host to only forward requests to once none of the --upstream ones are \
+                 alive (the same as giving it weight 0)"
+    )]
+    backup_upstream: Vec<String>,
+    #[clap(
+        long,
+        about = "File to read more upstreams from, one per line in the same format as --upstream. \
+                 It's read again on SIGHUP, replacing the upstream list."
+    )]
+    upstream_file: Option<String>,
+    #[clap(
+        long,
+        about = "Look up upstream host names again every health check interval, sending traffic \
+                 to each address a name resolves to and keeping up as its DNS records change"
+    )]
+    resolve_upstream_dns: bool,
+    #[clap(
+        long,
+        about = "Send requests for a host name to their own upstream instead (e.g. \
+                 example.com=10.0.0.2:80@3). Repeat it to give a host more upstreams."
+    )]
+    vhost: Vec<String>,
+    #[clap(
+        long,
+        about = "Send requests whose path starts with a prefix to their own upstreams instead \
+                 (e.g. /api=10.0.0.3:80,10.0.0.4:80). The longest matching prefix wins; --vhost \
+                 takes priority over this."
+    )]
+    route: Vec<String>,
     #[clap(
         long,
         about = "Perform active health checks on this interval (in seconds)",
@@ -34,18 +117,586 @@ struct CmdOptions {
         default_value = "/"
     )]
     active_health_check_path: String,
+    #[clap(
+        long,
+        about = "HTTP method to use for active health checks (e.g. HEAD, to skip the body)",
+        default_value = "GET"
+    )]
+    active_health_check_method: String,
+    #[clap(
+        long,
+        about = "Most active health checks to run at once",
+        default_value = "10"
+    )]
+    health_check_concurrency: usize,
+    #[clap(
+        long,
+        about = "Count an active health check as failed if the upstream hasn't answered it within \
+                 this many seconds, including connecting (0 = wait forever)",
+        default_value = "5"
+    )]
+    health_check_timeout: u64,
+    #[clap(
+        long,
+        about = "Check every upstream once before accepting connections, so that requests aren't \
+                 sent to upstreams that are already down (otherwise the first check waits for \
+                 the first interval)"
+    )]
+    health_check_on_startup: bool,
+    #[clap(
+        long,
+        about = "Statuses that active health checks count as alive, as a comma-separated list of \
+                 codes and ranges (e.g. 200,204,300-399)",
+        default_value = "200"
+    )]
+    active_health_check_expected_status: String,
     #[clap(
         long,
         about = "Maximum number of requests to accept per IP per minute (0 = unlimited)",
         default_value = "0"
     )]
     max_requests_per_minute: usize,
+    #[clap(
+        long,
+        about = "Comma-separated CIDR blocks (e.g. 10.0.0.0/8,192.168.1.5) of proxies in front of \
+                 balancebeam. For requests from these, the client IP used for logging and rate \
+                 limiting comes from X-Forwarded-For. Anyone who can connect from a trusted \
+                 address can claim to be any client, so only list proxies you control."
+    )]
+    trusted_proxies: Option<String>,
+    #[clap(
+        long,
+        about = "Upstream to send a copy of every request to (responses from it are discarded)"
+    )]
+    mirror: Option<String>,
+    #[clap(
+        long,
+        about = "Most copies of requests to have in flight to the mirror at once. Further copies \
+                 are dropped until earlier ones finish (0 = unlimited)",
+        default_value = "100"
+    )]
+    max_mirror_requests: usize,
+    #[clap(
+        long,
+        about = "Path at which to serve a Server-Sent Events stream of upstream state changes"
+    )]
+    events_path: Option<String>,
+    #[clap(
+        long,
+        about = "Close client connections after this many seconds, even if active (0 = unlimited)",
+        default_value = "0"
+    )]
+    max_connection_duration: u64,
+    #[clap(
+        long,
+        about = "Close client connections after they've made this many requests (0 = unlimited)",
+        default_value = "0"
+    )]
+    max_requests_per_connection: usize,
+    #[clap(
+        long,
+        about = "Close client connections that haven't sent a whole request within this many \
+                 seconds of the connection opening or the last response (0 = wait forever)",
+        default_value = "60"
+    )]
+    client_idle_timeout: u64,
+    #[clap(
+        long,
+        about = "Give up on a request with a 504 if no upstream has responded to it within this \
+                 many seconds of it arriving, counting connects and retries (0 = no deadline)",
+        default_value = "0"
+    )]
+    request_deadline: u64,
+    #[clap(
+        long,
+        about = "Give up on an upstream with a 504, and mark it dead, if it takes more than this \
+                 many seconds to take a request and respond to it (0 = wait forever)",
+        default_value = "120"
+    )]
+    upstream_timeout: u64,
+    #[clap(
+        long,
+        about = "Give up connecting to an upstream, and try another one, after this many seconds \
+                 (0 = leave it to the OS)",
+        default_value = "3"
+    )]
+    upstream_connect_timeout: u64,
+    #[clap(
+        long,
+        about = "Log a warning for every request that takes longer than this many milliseconds to \
+                 handle (0 = never)",
+        default_value = "0"
+    )]
+    slow_request_threshold_ms: u64,
+    #[clap(long, about = "IP/port to serve Prometheus metrics on (at /metrics)")]
+    metrics_bind: Option<String>,
+    #[clap(
+        long,
+        about = "IP/port to serve the admin API on (GET /upstreams and \
+                 /upstreams/<address>/history, POST /upstreams/<address>/drain and \
+                 /upstreams/<address>/enable)"
+    )]
+    admin_bind: Option<String>,
+    #[clap(
+        long,
+        about = "Log a warning when the fraction of recent responses that are 5xx reaches this \
+                 value (between 0 and 1)"
+    )]
+    error_rate_alert: Option<f64>,
+    #[clap(
+        long,
+        about = "Add HSTS, X-Content-Type-Options, X-Frame-Options and Referrer-Policy headers to \
+                 responses that don't already have them"
+    )]
+    security_headers: bool,
+    #[clap(
+        long,
+        about = "Set a header (NAME=VALUE) on every response, replacing any existing value"
+    )]
+    set_response_header: Vec<String>,
+    #[clap(long, about = "Remove a header from every response")]
+    remove_response_header: Vec<String>,
+    #[clap(
+        long,
+        about = "Set a header (NAME=VALUE) on every request sent to an upstream, replacing any \
+                 existing value"
+    )]
+    set_request_header: Vec<String>,
+    #[clap(long, about = "Remove a header from every request sent to an upstream")]
+    remove_request_header: Vec<String>,
+    #[clap(
+        long,
+        about = "How to choose an upstream for each connection",
+        possible_values = &[
+            "random",
+            "round-robin",
+            "least-connections",
+            "ip-hash",
+            "consistent-hash",
+        ],
+        default_value = "random"
+    )]
+    lb_algorithm: String,
+    #[clap(
+        long,
+        about = "On SIGTERM/SIGINT, wait this many seconds for connections to finish before \
+                 closing them",
+        default_value = "30"
+    )]
+    shutdown_timeout: u64,
+    #[clap(
+        long,
+        about = "Most idle keep-alive connections to keep open to each upstream for reuse",
+        default_value = "10"
+    )]
+    max_idle_per_upstream: usize,
+    #[clap(
+        long,
+        about = "Most client connections to proxy to any one upstream at once, counted separately \
+                 for each pool it's in (0 = unlimited). Connections go to other upstreams while \
+                 one is full.",
+        default_value = "0"
+    )]
+    max_conns_per_upstream: usize,
+    #[clap(
+        long,
+        about = "Ramp the weight (for random selection) of an upstream that has just come back \
+                 from being dead up from a tenth to all of it over this many seconds \
+                 (0 = give it its full weight at once)",
+        default_value = "0"
+    )]
+    slow_start_seconds: u64,
+    #[clap(
+        long,
+        about = "Retry failed GET, HEAD, PUT and DELETE requests on up to this many other upstreams",
+        default_value = "0"
+    )]
+    max_retries: usize,
+    #[clap(
+        long,
+        about = "Stop sending requests to an upstream after this many of them fail in a row \
+                 (0 = never)",
+        default_value = "0"
+    )]
+    circuit_breaker_threshold: usize,
+    #[clap(
+        long,
+        about = "Seconds to wait after the circuit breaker trips before letting one request \
+                 through to see if the upstream has recovered",
+        default_value = "30"
+    )]
+    circuit_breaker_cooldown: u64,
+    #[clap(
+        long,
+        about = "Mark an upstream dead once this many connections or requests to it have failed in \
+                 a row",
+        default_value = "1"
+    )]
+    passive_failure_threshold: usize,
+    #[clap(
+        long,
+        about = "Start in maintenance mode, where POST, PUT, PATCH and DELETE requests get a 503 \
+                 (SIGUSR1 toggles maintenance mode)"
+    )]
+    maintenance: bool,
+    #[clap(
+        long,
+        about = "Retry-After (in seconds) to send with 503s in maintenance mode",
+        default_value = "60"
+    )]
+    maintenance_retry_after: u64,
+    #[clap(
+        long,
+        about = "Maximum number of connections each IP may have open at once (0 = unlimited)",
+        default_value = "0"
+    )]
+    max_connections_per_ip: usize,
+    #[clap(
+        long,
+        about = "Maximum number of client connections to handle at once (0 = unlimited)",
+        default_value = "0"
+    )]
+    max_connections: usize,
+    #[clap(
+        long,
+        about = "What to do with new connections once --max-connections are open: wait to accept \
+                 them until one closes, or accept them and answer with a 503 right away",
+        possible_values = &["wait", "reject"],
+        default_value = "wait"
+    )]
+    connection_limit_behavior: String,
+    #[clap(
+        long,
+        about = "PEM file with the certificate chain to serve HTTPS with (requires --tls-key)"
+    )]
+    tls_cert: Option<String>,
+    #[clap(long, about = "PEM file with the private key for --tls-cert")]
+    tls_key: Option<String>,
+    #[clap(
+        long,
+        about = "Largest request body (in bytes) to accept from a client; bigger ones get a 413 \
+                 (0 = unlimited)",
+        default_value = "10000000"
+    )]
+    max_request_body_bytes: usize,
+    #[clap(
+        long,
+        about = "Longest request line (in bytes) to accept from a client; longer ones get a 431",
+        default_value = "8000"
+    )]
+    max_request_line_bytes: usize,
+    #[clap(
+        long,
+        about = "Most bytes of headers to accept from a client with a request; more get a 431",
+        default_value = "8000"
+    )]
+    max_header_bytes: usize,
+    #[clap(
+        long,
+        about = "Largest response body (in bytes) to accept from an upstream",
+        default_value = "10000000"
+    )]
+    max_response_body_bytes: usize,
+    #[clap(
+        long,
+        about = "What to do with a response body over --max-response-body-bytes: reject it with a \
+                 502, or truncate it (logging a warning)",
+        possible_values = &["reject", "truncate"],
+        default_value = "reject"
+    )]
+    oversized_response: String,
+    #[clap(
+        long,
+        about = "Whether to turn off Nagle's algorithm on client and upstream connections, so that \
+                 small responses aren't held back waiting for acknowledgements",
+        possible_values = &["on", "off"],
+        default_value = "on"
+    )]
+    tcp_nodelay: String,
+    #[clap(
+        long,
+        about = "How to log requests: as text in the regular log, or also as one JSON object per \
+                 request on stdout (with its timestamp, client IP, method, path, upstream, status \
+                 and latency)",
+        possible_values = &["text", "json"],
+        default_value = "text"
+    )]
+    access_log_format: String,
+    #[clap(
+        long,
+        about = "Gzip text responses over 1 KB for clients that accept it, unless the upstream \
+                 already encoded them"
+    )]
+    enable_gzip: bool,
+    #[clap(
+        long,
+        about = "Answer repeat GET requests from a cache of upstream responses kept for this many \
+                 seconds (0 = no caching). Only responses marked public, max-age or s-maxage (and \
+                 not no-store, no-cache or private) are cached, and requests with cookies skip the \
+                 cache.",
+        default_value = "0"
+    )]
+    cache_ttl_seconds: u64,
+    #[clap(
+        long,
+        about = "Most responses to keep in the cache, dropping the least recently used ones first",
+        default_value = "1000"
+    )]
+    cache_max_entries: usize,
+    #[clap(
+        long,
+        about = "Check the options (including that every address resolves) and exit without \
+                 starting the proxy"
+    )]
+    check_config: bool,
+}
+
+/// Ways of logging the requests we handle
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum AccessLogFormat {
+    /// Only the human-oriented lines in the regular log
+    Text,
+    /// Also a JSON object per request on stdout, for log collectors
+    Json,
+}
+
+/// Marks (as an http::Extensions entry) when we finished reading a request from the client, so
+/// that the access log can say how long we took to respond
+#[derive(Debug, Clone, Copy)]
+struct ReceivedAt(Instant);
+
+/// Ways of choosing which live upstream a new connection is sent to
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum LoadBalancingAlgorithm {
+    Random,
+    RoundRobin,
+    LeastConnections,
+    /// Each client IP sticks to one upstream for as long as that upstream is alive
+    IpHash,
+    /// Like IpHash, but adding or removing upstreams moves as few clients as possible
+    ConsistentHash,
+}
+
+impl LoadBalancingAlgorithm {
+    fn parse(name: &str) -> Option<LoadBalancingAlgorithm> {
+        match name {
+            "random" => Some(LoadBalancingAlgorithm::Random),
+            "round-robin" => Some(LoadBalancingAlgorithm::RoundRobin),
+            "least-connections" => Some(LoadBalancingAlgorithm::LeastConnections),
+            "ip-hash" => Some(LoadBalancingAlgorithm::IpHash),
+            "consistent-hash" => Some(LoadBalancingAlgorithm::ConsistentHash),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug)]
 struct UpstreamAddress {
     address: String,
     alive: bool,
+    /// Relative share of traffic this upstream gets with random selection. Upstreams with weight
+    /// 0 are only used once every other upstream is dead.
+    weight: usize,
+    /// Number of client connections currently being proxied to this upstream
+    active_connections: Arc<AtomicUsize>,
+    /// Results of the most recent active health checks, oldest first
+    health_history: HealthHistory,
+    /// Number of connections or requests to this upstream that have failed since the last one
+    /// that succeeded
+    consecutive_failures: usize,
+    /// Keeps requests away from the upstream after too many of them fail. (This is separate from
+    /// `alive`, which health checks can reset.)
+    breaker: Mutex<CircuitBreaker>,
+    /// One permit for each connection this upstream can take, if --max-conns-per-upstream is set
+    connection_slots: Option<Arc<Semaphore>>,
+    /// When the upstream was last marked alive after being dead, for --slow-start-seconds
+    recovered_at: Option<Instant>,
+    /// Set through the admin API to keep new connections away from the upstream, whatever its
+    /// health checks say, until it's enabled again
+    drained: bool,
+}
+
+impl UpstreamAddress {
+    fn new(address: String, weight: usize, max_connections: usize) -> UpstreamAddress {
+        UpstreamAddress {
+            address,
+            alive: true,
+            weight,
+            active_connections: Arc::new(AtomicUsize::new(0)),
+            health_history: HealthHistory::default(),
+            consecutive_failures: 0,
+            breaker: Mutex::new(CircuitBreaker::default()),
+            connection_slots: match max_connections {
+                0 => None,
+                max_connections => Some(Arc::new(Semaphore::new(max_connections))),
+            },
+            recovered_at: None,
+            drained: false,
+        }
+    }
+}
+
+/// The outcome of one active health check
+#[derive(Debug, Clone)]
+struct HealthCheckResult {
+    /// When the check started
+    time: std::time::SystemTime,
+    alive: bool,
+    /// How long the check took, including connecting
+    latency: time::Duration,
+    /// Why the upstream failed the check, if it did
+    error: Option<String>,
+}
+
+/// An upstream's most recent health check results, up to HEALTH_HISTORY_LEN of them. (The Debug
+/// output only gives the count, because upstreams get logged whenever one changes state.)
+#[derive(Default)]
+struct HealthHistory(VecDeque<HealthCheckResult>);
+
+impl HealthHistory {
+    fn record(&mut self, result: HealthCheckResult) {
+        if self.0.len() == HEALTH_HISTORY_LEN {
+            self.0.pop_front();
+        }
+        self.0.push_back(result);
+    }
+}
+
+impl std::fmt::Debug for HealthHistory {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "HealthHistory({} results)", self.0.len())
+    }
+}
+
+/// The states of an upstream's circuit breaker
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BreakerState {
+    /// Requests go through as usual
+    Closed,
+    /// Too many requests failed in a row, at the given time. No requests go through until the
+    /// cooldown is over.
+    Open(Instant),
+    /// The cooldown is over and a probe connection was let through at the given time. The outcome
+    /// of its first request decides whether the breaker closes or opens again.
+    HalfOpen(Instant),
+}
+
+/// Stops requests from going to an upstream for a while after too many of them fail in a row.
+#[derive(Debug)]
+struct CircuitBreaker {
+    state: BreakerState,
+    /// Number of failed requests since the last successful one
+    consecutive_failures: usize,
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> CircuitBreaker {
+        CircuitBreaker {
+            state: BreakerState::Closed,
+            consecutive_failures: 0,
+        }
+    }
+}
+
+impl CircuitBreaker {
+    /// Returns true if the upstream can be picked for a new connection. An open breaker whose
+    /// cooldown is over lets one through, but only until that one has been picked. (If the probe
+    /// connection never sends a request, another one is let through after another cooldown.)
+    fn allows_requests(&self, cooldown: time::Duration) -> bool {
+        match self.state {
+            BreakerState::Closed => true,
+            BreakerState::Open(since) | BreakerState::HalfOpen(since) => {
+                since.elapsed() >= cooldown
+            }
+        }
+    }
+
+    /// Notes that the upstream has been picked, making the connection a probe if the breaker
+    /// isn't closed.
+    fn on_picked(&mut self) {
+        if self.state != BreakerState::Closed {
+            self.state = BreakerState::HalfOpen(Instant::now());
+        }
+    }
+
+    /// Records whether a request to the upstream succeeded, returning the new state if that
+    /// changed it.
+    fn record(&mut self, succeeded: bool, threshold: usize) -> Option<BreakerState> {
+        let old_state = self.state;
+        if succeeded {
+            self.consecutive_failures = 0;
+            self.state = BreakerState::Closed;
+        } else {
+            self.consecutive_failures += 1;
+            match self.state {
+                BreakerState::Closed if self.consecutive_failures >= threshold => {
+                    self.state = BreakerState::Open(Instant::now())
+                }
+                BreakerState::HalfOpen(_) => self.state = BreakerState::Open(Instant::now()),
+                // (An open breaker can still hear about requests that were already in flight.)
+                _ => {}
+            }
+        }
+        if self.state != old_state {
+            Some(self.state)
+        } else {
+            None
+        }
+    }
+}
+
+/// A connection to an upstream, which counts towards the upstream's active connections (and holds
+/// one of its connection slots, if it has a limit) until it's dropped.
+struct UpstreamConnection {
+    address: String,
+    active_connections: Arc<AtomicUsize>,
+    #[allow(dead_code)]
+    slot: Option<OwnedSemaphorePermit>,
+}
+
+impl Drop for UpstreamConnection {
+    fn drop(&mut self) {
+        self.active_connections.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// A set of upstreams that requests are balanced across
+struct UpstreamPool {
+    /// Addresses of servers in the pool
+    addresses: RwLock<Vec<UpstreamAddress>>,
+    /// The pool's upstreams as they were given, before --resolve-upstream-dns expanded host names
+    /// into addresses
+    configured_upstreams: Mutex<Vec<(String, usize)>>,
+    /// Every upstream's positions on the ring, for consistent-hash selection
+    hash_ring: RwLock<hashring::HashRing>,
+    /// Position of the next upstream to use for round-robin, counting only live upstreams
+    round_robin_cursor: AtomicUsize,
+}
+
+impl UpstreamPool {
+    /// Creates a pool of `upstreams`, which were resolved from `configured_upstreams` (or are the
+    /// same list, if we're not resolving host names).
+    fn new(
+        configured_upstreams: Vec<(String, usize)>,
+        upstreams: Vec<(String, usize)>,
+        max_conns_per_upstream: usize,
+    ) -> UpstreamPool {
+        let hash_ring =
+            hashring::HashRing::new(upstreams.iter().map(|(address, _)| address.as_str()));
+        UpstreamPool {
+            configured_upstreams: Mutex::new(configured_upstreams),
+            addresses: RwLock::new(
+                upstreams
+                    .into_iter()
+                    .map(|(address, weight)| {
+                        UpstreamAddress::new(address, weight, max_conns_per_upstream)
+                    })
+                    .collect(),
+            ),
+            hash_ring: RwLock::new(hash_ring),
+            round_robin_cursor: AtomicUsize::new(0),
+        }
+    }
 }
 
 /// Contains information about the state of balancebeam (e.g. what servers we are currently proxying
@@ -54,174 +705,1660 @@ struct UpstreamAddress {
 /// You should add fields to this struct in later milestones.
 struct ProxyState {
     /// How frequently we check whether upstream servers are alive (Milestone 4)
-    #[allow(dead_code)]
     active_health_check_interval: usize,
     /// Where we should send requests when doing active health checks (Milestone 4)
-    #[allow(dead_code)]
     active_health_check_path: String,
+    /// Method to send active health check requests with
+    active_health_check_method: http::Method,
+    /// Most active health checks that can be in progress at once
+    health_check_concurrency: usize,
+    /// How long an upstream has to answer an active health check (None = forever)
+    health_check_timeout: Option<time::Duration>,
+    /// Response statuses that mean an upstream passed its active health check
+    active_health_check_expected_status: Vec<RangeInclusive<u16>>,
     /// Maximum number of requests an individual IP can make in a minute (Milestone 5)
-    #[allow(dead_code)]
     max_requests_per_minute: usize,
-    /// Addresses of servers that we are proxying to
-    upstream_addresses: RwLock<Vec<UpstreamAddress>>,
+    /// Recent requests from each client IP
+    rate_limiter: Mutex<ratelimit::RateLimiter>,
+    /// Proxies whose X-Forwarded-For headers we believe
+    trusted_proxies: Vec<cidr::Cidr>,
+    /// Servers that we are proxying to, unless the request's host has its own pool
+    upstreams: UpstreamPool,
+    /// Whether to keep upstream host names resolved to their current addresses
+    resolve_upstream_dns: bool,
+    /// Pools for particular host names (given with --vhost), keyed by lowercase host name
+    vhosts: HashMap<String, UpstreamPool>,
+    /// Pools for paths starting with particular prefixes (given with --route), longest prefix
+    /// first
+    routes: Vec<(String, UpstreamPool)>,
+    /// Server that receives a copy of all proxied traffic, if any
+    mirror: Option<String>,
+    /// Limits how many copies can be on their way to the mirror at once (None = unlimited)
+    mirror_slots: Option<Arc<Semaphore>>,
+    /// Path that clients can request to subscribe to upstream state changes, if any
+    events_path: Option<String>,
+    /// Upstream state changes (as JSON) are published here for the events stream
+    upstream_events: broadcast::Sender<String>,
+    /// Longest a client connection may stay open, regardless of activity (None = unlimited)
+    max_connection_duration: Option<time::Duration>,
+    /// Most requests a client may make on one connection (None = unlimited)
+    max_requests_per_connection: Option<usize>,
+    /// How long a client has to send each request (None = forever)
+    client_idle_timeout: Option<time::Duration>,
+    /// How long an upstream has to respond to each request (None = forever)
+    request_deadline: Option<time::Duration>,
+    /// How long an upstream can take over a request before we decide it's hung (None = forever).
+    /// Unlike the request deadline, this counts against the upstream.
+    upstream_timeout: Option<time::Duration>,
+    /// How long connecting to an upstream can take before we try another one (None = as long as
+    /// the OS allows)
+    upstream_connect_timeout: Option<time::Duration>,
+    /// Requests that take longer than this to handle get logged as slow (None = never)
+    slow_request_threshold: Option<time::Duration>,
+    /// Counts of the responses we've sent
+    metrics: metrics::Metrics,
+    /// Headers to add to or remove from responses before sending them to clients
+    response_headers: headers::HeaderRules,
+    /// Headers to add to or remove from requests before sending them to upstreams
+    request_headers: headers::HeaderRules,
+    /// How upstreams are chosen for new connections
+    lb_algorithm: LoadBalancingAlgorithm,
+    /// Randomness for choosing upstreams. It's seeded once and shared, since seeding a new RNG from
+    /// the OS for every connection is expensive.
+    rng: Mutex<rand::rngs::StdRng>,
+    /// Idle upstream connections that can be reused
+    connection_pool: pool::ConnectionPool,
+    /// Number of client connections currently being handled
+    active_connections: AtomicUsize,
+    /// Becomes true once we've started shutting down, so that idle connections can close
+    shutting_down: watch::Receiver<bool>,
+    /// How many other upstreams an idempotent request may be retried on if its upstream fails
+    max_retries: usize,
+    /// How many requests to an upstream have to fail in a row to trip its circuit breaker (0 =
+    /// never)
+    circuit_breaker_threshold: usize,
+    /// How long a tripped circuit breaker stays open before letting a probe request through
+    circuit_breaker_cooldown: time::Duration,
+    /// How many connections or requests to an upstream have to fail in a row before we mark it
+    /// dead
+    passive_failure_threshold: usize,
+    /// While true, requests that would change anything are turned away with a 503
+    maintenance_mode: AtomicBool,
+    /// Retry-After (in seconds) to send with maintenance mode 503s
+    maintenance_retry_after: u64,
+    /// Maximum number of connections an individual IP may have open at once (0 = unlimited)
+    max_connections_per_ip: usize,
+    /// One permit for each client connection we can handle at once, if --max-connections is set
+    connection_slots: Option<Arc<Semaphore>>,
+    /// Whether connections over --max-connections get a 503 rather than waiting to be accepted
+    reject_excess_connections: bool,
+    /// Number of connections each client IP currently has open
+    connections_per_ip: Mutex<HashMap<String, usize>>,
+    /// Performs TLS handshakes with clients, if we're serving HTTPS
+    tls_acceptor: Option<TlsAcceptor>,
+    /// Largest request body we'll read from a client (None = unlimited)
+    max_request_body_bytes: Option<usize>,
+    /// Longest request line we'll read from a client
+    max_request_line_bytes: usize,
+    /// Most bytes of headers we'll read from a client with each request
+    max_header_bytes: usize,
+    /// Largest response body we'll read from an upstream
+    max_response_body_bytes: usize,
+    /// Whether bigger response bodies are rejected or truncated
+    oversized_response: response::OversizedBody,
+    /// Whether to set TCP_NODELAY on client and upstream connections
+    tcp_nodelay: bool,
+    /// Most client connections each upstream can take at once (0 = unlimited)
+    max_conns_per_upstream: usize,
+    /// How long a recovered upstream takes to get its full weight back (None = no time at all)
+    slow_start: Option<time::Duration>,
+    /// How requests are logged
+    access_log_format: AccessLogFormat,
+    /// Whether to compress responses for clients that accept gzip
+    enable_gzip: bool,
+    /// Recent responses to GET requests, if --cache-ttl-seconds is set
+    response_cache: Option<Mutex<cache::ResponseCache>>,
 }
 
-#[tokio::main]
-async fn main() {
-    use std::sync::Arc;
-    // Initialize the logging library. You can print log messages using the `log` macros:
-    // https://docs.rs/log/0.4.8/log/ You are welcome to continue using print! statements; this
-    // just looks a little prettier.
-    if let Err(_) = std::env::var("RUST_LOG") {
-        std::env::set_var("RUST_LOG", "debug");
+impl ProxyState {
+    /// Returns every upstream pool, starting with the default one
+    fn pools(&self) -> impl Iterator<Item = &UpstreamPool> {
+        std::iter::once(&self.upstreams)
+            .chain(self.vhosts.values())
+            .chain(self.routes.iter().map(|(_, pool)| pool))
     }
-    pretty_env_logger::init();
 
-    // Parse the command line arguments passed to this program
-    let options = CmdOptions::parse();
-    if options.upstream.len() < 1 {
-        log::error!("At least one upstream server must be specified using the --upstream option.");
-        std::process::exit(1);
+    /// Returns the pool that should handle a request, based on its Host header and then its path
+    fn pool_for_request(&self, request: &http::Request<Vec<u8>>) -> &UpstreamPool {
+        let vhost = request
+            .headers()
+            .get("host")
+            .and_then(|host| host.to_str().ok())
+            .and_then(|host| {
+                // (The Host header may include a port, which we ignore.)
+                let name = match host.rsplit_once(':') {
+                    Some((name, port)) if port.chars().all(|c| c.is_ascii_digit()) => name,
+                    _ => host,
+                };
+                self.vhosts.get(&name.to_ascii_lowercase())
+            });
+        let path = request.uri().path();
+        let route = self
+            .routes
+            .iter()
+            .find(|(prefix, _)| path_has_prefix(path, prefix))
+            .map(|(_, pool)| pool);
+        vhost.or(route).unwrap_or(&self.upstreams)
     }
 
-    // Start listening for connections
-    let listener = match TcpListener::bind(&options.bind).await {
-        Ok(listener) => listener,
-        Err(err) => {
-            log::error!("Could not bind to {}: {}", options.bind, err);
+    /// Returns a random number from `range`. The RNG is only locked for as long as it takes to draw
+    /// the number, so connections picking upstreams at the same time barely wait for each other.
+    fn gen_range<T: SampleUniform, R: SampleRange<T>>(&self, range: R) -> T {
+        self.rng.lock().unwrap().gen_range(range)
+    }
+}
+
+/// Counts a client connection as active for as long as it's alive.
+struct ActiveConnection<'a> {
+    state: &'a ProxyState,
+}
+
+impl Drop for ActiveConnection<'_> {
+    fn drop(&mut self) {
+        self.state.active_connections.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Counts a client connection towards its IP's connection limit for as long as it's alive. Create
+/// one with claim_ip_connection_slot.
+struct IpConnectionSlot<'a> {
+    state: &'a ProxyState,
+    client_ip: String,
+}
+
+impl Drop for IpConnectionSlot<'_> {
+    fn drop(&mut self) {
+        let mut counts = self.state.connections_per_ip.lock().unwrap();
+        if let Some(count) = counts.get_mut(&self.client_ip) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.client_ip);
+            }
+        }
+    }
+}
+
+/// Counts a new connection from `client_ip`, returning false (without counting it) if the client
+/// already has as many connections open as it's allowed.
+fn claim_ip_connection_slot(state: &ProxyState, client_ip: &str) -> bool {
+    let mut counts = state.connections_per_ip.lock().unwrap();
+    let count = counts.entry(client_ip.to_string()).or_insert(0);
+    if state.max_connections_per_ip > 0 && *count >= state.max_connections_per_ip {
+        return false;
+    }
+    *count += 1;
+    true
+}
+
+/// Returns the listening socket passed to us through systemd socket activation, if there is one.
+/// See sd_listen_fds(3).
+fn take_systemd_listener() -> Option<std::io::Result<TcpListener>> {
+    let num_fds: usize = std::env::var("LISTEN_FDS").ok()?.parse().ok()?;
+    // The sockets are meant for whoever LISTEN_PID names, which might not be us
+    if let Ok(pid) = std::env::var("LISTEN_PID") {
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            return None;
+        }
+    }
+    if num_fds == 0 {
+        return None;
+    }
+    if num_fds > 1 {
+        log::warn!(
+            "systemd passed in {} sockets; only the first will be used",
+            num_fds
+        );
+    }
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_PID");
+    let listener = unsafe { std::net::TcpListener::from_raw_fd(SD_LISTEN_FDS_START) };
+    Some(
+        listener
+            .set_nonblocking(true)
+            .and_then(|_| TcpListener::from_std(listener)),
+    )
+}
+
+/// Parses an --upstream value, which is an address optionally followed by `@weight`.
+fn parse_upstream(upstream: &str) -> Result<(String, usize), String> {
+    match upstream.rsplit_once('@') {
+        Some((address, weight)) => match weight.parse::<usize>() {
+            Ok(weight) => Ok((address.to_string(), weight)),
+            Err(_) => Err(format!("Invalid weight in upstream {}", upstream)),
+        },
+        None => Ok((upstream.to_string(), 1)),
+    }
+}
+
+/// Returns true if `path` is `prefix` or is underneath it. (/api matches /api and /api/users, but
+/// not /apiary.)
+fn path_has_prefix(path: &str, prefix: &str) -> bool {
+    match path.strip_prefix(prefix) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/') || prefix.ends_with('/'),
+        None => false,
+    }
+}
+
+/// Parses a --route value (`prefix=upstream,upstream,...`) into the prefix and its upstreams.
+fn parse_route(route: &str) -> Result<(String, Vec<(String, usize)>), String> {
+    match route.split_once('=') {
+        Some((prefix, upstreams)) if prefix.starts_with('/') => Ok((
+            prefix.to_string(),
+            upstreams
+                .split(',')
+                .map(parse_upstream)
+                .collect::<Result<_, _>>()?,
+        )),
+        _ => Err(format!(
+            "Invalid route {} (expected /PREFIX=UPSTREAM,UPSTREAM,...)",
+            route
+        )),
+    }
+}
+
+/// Parses a --vhost value (`host=upstream`) into the lowercase host name and the upstream.
+fn parse_vhost(vhost: &str) -> Result<(String, (String, usize)), String> {
+    match vhost.split_once('=') {
+        Some((host, upstream)) if !host.is_empty() => {
+            Ok((host.to_ascii_lowercase(), parse_upstream(upstream)?))
+        }
+        _ => Err(format!("Invalid vhost {} (expected HOST=UPSTREAM)", vhost)),
+    }
+}
+
+/// Reads upstreams from a file with one `address[@weight]` per line, skipping blank lines and
+/// lines starting with #.
+fn read_upstream_file(path: &str) -> Result<Vec<(String, usize)>, String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|err| format!("Could not read upstream file {}: {}", path, err))?;
+    contents
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(parse_upstream)
+        .collect()
+}
+
+/// Parses a comma-separated list of status codes and ranges of them (e.g. `200,204,300-399`).
+fn parse_status_set(statuses: &str) -> Result<Vec<RangeInclusive<u16>>, String> {
+    let parse_status = |status: &str| match status.trim().parse::<u16>() {
+        Ok(status) if (100..=999).contains(&status) => Ok(status),
+        _ => Err(format!("Invalid status code {}", status)),
+    };
+    statuses
+        .split(',')
+        .map(|item| match item.split_once('-') {
+            Some((low, high)) => {
+                let (low, high) = (parse_status(low)?, parse_status(high)?);
+                if low > high {
+                    return Err(format!("Invalid status range {}", item));
+                }
+                Ok(low..=high)
+            }
+            None => {
+                let status = parse_status(item)?;
+                Ok(status..=status)
+            }
+        })
+        .collect()
+}
+
+/// Checks that every address we were given is a valid host:port whose host resolves, returning a
+/// description of each one that isn't.
+async fn check_addresses(options: &CmdOptions, upstreams: &[(String, usize)]) -> Vec<String> {
+    let mut addresses: Vec<(&str, &str)> = options
+        .bind
+        .iter()
+        .map(|addr| ("bind", addr.as_str()))
+        .collect();
+    addresses.extend(
+        options
+            .metrics_bind
+            .iter()
+            .map(|addr| ("metrics", addr.as_str())),
+    );
+    addresses.extend(
+        options
+            .admin_bind
+            .iter()
+            .map(|addr| ("admin", addr.as_str())),
+    );
+    addresses.extend(
+        upstreams
+            .iter()
+            .map(|(addr, _)| ("upstream", addr.as_str())),
+    );
+    addresses.extend(options.mirror.iter().map(|addr| ("mirror", addr.as_str())));
+    let mut problems = Vec::new();
+    for (kind, address) in addresses {
+        if let Some(path) = stream::unix_socket_path(address) {
+            if !std::path::Path::new(path).exists() {
+                problems.push(format!("{} socket {} does not exist", kind, path));
+            }
+            continue;
+        }
+        match tokio::net::lookup_host(address)
+            .await
+            .map(|mut addrs| addrs.next())
+        {
+            Ok(Some(_)) => {}
+            Ok(None) => problems.push(format!("{} address {} did not resolve", kind, address)),
+            Err(err) => problems.push(format!("Invalid {} address {}: {}", kind, address, err)),
+        }
+    }
+    problems
+}
+
+/// Loads a certificate chain and private key (both PEM-encoded) to terminate TLS with.
+fn load_tls_acceptor(cert_path: &str, key_path: &str) -> Result<TlsAcceptor, String> {
+    let open = |path: &str| {
+        std::fs::File::open(path)
+            .map(BufReader::new)
+            .map_err(|err| format!("Could not open {}: {}", path, err))
+    };
+    let certs = rustls::internal::pemfile::certs(&mut open(cert_path)?)
+        .map_err(|_| format!("Could not parse certificates in {}", cert_path))?;
+    if certs.is_empty() {
+        return Err(format!("No certificates found in {}", cert_path));
+    }
+    // Keys may be either PKCS#8 ("BEGIN PRIVATE KEY") or PKCS#1 ("BEGIN RSA PRIVATE KEY")
+    let mut keys = rustls::internal::pemfile::pkcs8_private_keys(&mut open(key_path)?)
+        .map_err(|_| format!("Could not parse private key in {}", key_path))?;
+    if keys.is_empty() {
+        keys = rustls::internal::pemfile::rsa_private_keys(&mut open(key_path)?)
+            .map_err(|_| format!("Could not parse private key in {}", key_path))?;
+    }
+    let key = keys
+        .into_iter()
+        .next()
+        .ok_or_else(|| format!("No private key found in {}", key_path))?;
+    let mut config = rustls::ServerConfig::new(rustls::NoClientAuth::new());
+    config
+        .set_single_cert(certs, key)
+        .map_err(|err| format!("Invalid certificate or key: {}", err))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+#[tokio::main]
+async fn main() {
+    // Initialize the logging library. You can print log messages using the `log` macros:
+    // https://docs.rs/log/0.4.8/log/ You are welcome to continue using print! statements; this
+    // just looks a little prettier.
+    if let Err(_) = std::env::var("RUST_LOG") {
+        std::env::set_var("RUST_LOG", "debug");
+    }
+    pretty_env_logger::init();
+
+    // Parse the command line arguments passed to this program
+    let options = CmdOptions::parse();
+    if let Some(threshold) = options.error_rate_alert {
+        if !(threshold > 0.0 && threshold <= 1.0) {
+            log::error!("--error-rate-alert must be between 0 and 1.");
+            std::process::exit(1);
+        }
+    }
+    if options.health_check_concurrency == 0 {
+        log::error!("--health-check-concurrency must be at least 1.");
+        std::process::exit(1);
+    }
+    if options.passive_failure_threshold == 0 {
+        log::error!("--passive-failure-threshold must be at least 1.");
+        std::process::exit(1);
+    }
+
+    let lb_algorithm = match LoadBalancingAlgorithm::parse(&options.lb_algorithm) {
+        Some(lb_algorithm) => lb_algorithm,
+        None => {
+            log::error!("Unknown load balancing algorithm {}", options.lb_algorithm);
+            std::process::exit(1);
+        }
+    };
+    let response_headers = match headers::HeaderRules::new(
+        options.security_headers,
+        &options.set_response_header,
+        &options.remove_response_header,
+    ) {
+        Ok(rules) => rules,
+        Err(err) => {
+            log::error!("Invalid response header option: {}", err);
+            std::process::exit(1);
+        }
+    };
+    let request_headers = match headers::HeaderRules::new(
+        false,
+        &options.set_request_header,
+        &options.remove_request_header,
+    ) {
+        Ok(rules) => rules,
+        Err(err) => {
+            log::error!("Invalid request header option: {}", err);
             std::process::exit(1);
         }
     };
-    log::info!("Listening for requests on {}", options.bind);
+
+    let trusted_proxies = match &options.trusted_proxies {
+        Some(blocks) => match blocks.split(',').map(cidr::Cidr::parse).collect() {
+            Ok(blocks) => blocks,
+            Err(err) => {
+                log::error!("Invalid --trusted-proxies: {}", err);
+                std::process::exit(1);
+            }
+        },
+        None => Vec::new(),
+    };
+
+    let active_health_check_method =
+        match http::Method::from_bytes(options.active_health_check_method.as_bytes()) {
+            Ok(method) => method,
+            Err(_) => {
+                log::error!(
+                    "Invalid --active-health-check-method {}",
+                    options.active_health_check_method
+                );
+                std::process::exit(1);
+            }
+        };
+
+    let active_health_check_expected_status =
+        match parse_status_set(&options.active_health_check_expected_status) {
+            Ok(statuses) => statuses,
+            Err(err) => {
+                log::error!("Invalid --active-health-check-expected-status: {}", err);
+                std::process::exit(1);
+            }
+        };
+
+    let tls_acceptor = match (&options.tls_cert, &options.tls_key) {
+        (Some(cert_path), Some(key_path)) => match load_tls_acceptor(cert_path, key_path) {
+            Ok(tls_acceptor) => Some(tls_acceptor),
+            Err(err) => {
+                log::error!("{}", err);
+                std::process::exit(1);
+            }
+        },
+        (None, None) => None,
+        _ => {
+            log::error!("--tls-cert and --tls-key must be used together.");
+            std::process::exit(1);
+        }
+    };
+
+    let mut upstreams = Vec::new();
+    for upstream in &options.upstream {
+        match parse_upstream(upstream) {
+            Ok(upstream) => upstreams.push(upstream),
+            Err(err) => {
+                log::error!("{}", err);
+                std::process::exit(1);
+            }
+        }
+    }
+    for backup in &options.backup_upstream {
+        if backup.contains('@') {
+            log::error!("Backup upstream {} can't have a weight", backup);
+            std::process::exit(1);
+        }
+        upstreams.push((backup.clone(), 0));
+    }
+    // Upstreams given on the command line stay put when the upstream file is reloaded
+    let fixed_upstreams = upstreams.clone();
+    if let Some(upstream_file) = &options.upstream_file {
+        match read_upstream_file(upstream_file) {
+            Ok(file_upstreams) => upstreams.extend(file_upstreams),
+            Err(err) => {
+                log::error!("{}", err);
+                std::process::exit(1);
+            }
+        }
+    }
+    if upstreams.is_empty() {
+        log::error!(
+            "At least one upstream server must be specified using the --upstream or \
+             --upstream-file options."
+        );
+        std::process::exit(1);
+    }
+    let mut vhosts: HashMap<String, Vec<(String, usize)>> = HashMap::new();
+    for vhost in &options.vhost {
+        match parse_vhost(vhost) {
+            Ok((host, upstream)) => vhosts.entry(host).or_default().push(upstream),
+            Err(err) => {
+                log::error!("{}", err);
+                std::process::exit(1);
+            }
+        }
+    }
+    let mut routes: Vec<(String, Vec<(String, usize)>)> = Vec::new();
+    for route in &options.route {
+        match parse_route(route) {
+            Ok((prefix, upstreams)) => {
+                match routes.iter_mut().find(|(existing, _)| *existing == prefix) {
+                    Some((_, existing_upstreams)) => existing_upstreams.extend(upstreams),
+                    None => routes.push((prefix, upstreams)),
+                }
+            }
+            Err(err) => {
+                log::error!("{}", err);
+                std::process::exit(1);
+            }
+        }
+    }
+    // Try the most specific prefixes first
+    routes.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+
+    if options.check_config {
+        let all_upstreams: Vec<(String, usize)> = upstreams
+            .iter()
+            .chain(vhosts.values().flatten())
+            .chain(routes.iter().flat_map(|(_, upstreams)| upstreams))
+            .cloned()
+            .collect();
+        let problems = check_addresses(&options, &all_upstreams).await;
+        if !problems.is_empty() {
+            for problem in problems {
+                log::error!("{}", problem);
+            }
+            std::process::exit(1);
+        }
+        log::info!("Configuration OK");
+        std::process::exit(0);
+    }
+
+    // Start listening for connections, on the socket systemd gave us if there is one. Otherwise,
+    // we can make do as long as we can listen on at least one of the --bind addresses.
+    let listeners = match take_systemd_listener() {
+        Some(Ok(listener)) => {
+            log::info!("Listening for requests on socket passed in by systemd");
+            vec![listener]
+        }
+        Some(Err(err)) => {
+            log::error!("Could not use socket passed in by systemd: {}", err);
+            std::process::exit(1);
+        }
+        None => {
+            let mut listeners = Vec::new();
+            for bind in &options.bind {
+                match TcpListener::bind(bind).await {
+                    Ok(listener) => {
+                        log::info!("Listening for requests on {}", bind);
+                        listeners.push(listener);
+                    }
+                    Err(err) => log::warn!("Could not bind to {}: {}", bind, err),
+                }
+            }
+            if listeners.is_empty() {
+                log::error!("Could not bind to any of {:?}", options.bind);
+                std::process::exit(1);
+            }
+            listeners
+        }
+    };
+    let metrics_listener = match &options.metrics_bind {
+        Some(metrics_bind) => match TcpListener::bind(metrics_bind).await {
+            Ok(listener) => {
+                log::info!("Serving metrics on {}", metrics_bind);
+                Some(listener)
+            }
+            Err(err) => {
+                log::error!("Could not bind to {}: {}", metrics_bind, err);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+    let admin_listener = match &options.admin_bind {
+        Some(admin_bind) => match TcpListener::bind(admin_bind).await {
+            Ok(listener) => {
+                log::info!("Serving the admin API on {}", admin_bind);
+                Some(listener)
+            }
+            Err(err) => {
+                log::error!("Could not bind to {}: {}", admin_bind, err);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    let upstream_file = options.upstream_file.clone();
+    // With --resolve-upstream-dns, every pool starts out with whatever its host names resolve to
+    let new_pool = |upstreams: Vec<(String, usize)>| async {
+        let resolved = if options.resolve_upstream_dns {
+            match resolve_upstreams(&upstreams).await {
+                Ok(resolved) => resolved,
+                Err(err) => {
+                    log::error!("{}", err);
+                    std::process::exit(1);
+                }
+            }
+        } else {
+            upstreams.clone()
+        };
+        UpstreamPool::new(upstreams, resolved, options.max_conns_per_upstream)
+    };
+    let default_pool = new_pool(upstreams).await;
+    let mut vhost_pools = HashMap::new();
+    for (host, upstreams) in vhosts {
+        vhost_pools.insert(host, new_pool(upstreams).await);
+    }
+    let mut route_pools = Vec::new();
+    for (prefix, upstreams) in routes {
+        route_pools.push((prefix, new_pool(upstreams).await));
+    }
 
     // Handle incoming connections
+    let (shutdown_sender, shutdown_receiver) = watch::channel(false);
     let state = ProxyState {
-        upstream_addresses: RwLock::new(
-            options
-                .upstream
-                .iter()
-                .map(|address| UpstreamAddress {
-                    address: address.to_string(),
-                    alive: true,
-                })
-                .collect::<Vec<UpstreamAddress>>(),
-        ),
+        upstreams: default_pool,
+        resolve_upstream_dns: options.resolve_upstream_dns,
+        vhosts: vhost_pools,
+        routes: route_pools,
         active_health_check_interval: options.active_health_check_interval,
         active_health_check_path: options.active_health_check_path,
+        active_health_check_method,
+        health_check_concurrency: options.health_check_concurrency,
+        health_check_timeout: match options.health_check_timeout {
+            0 => None,
+            seconds => Some(time::Duration::from_secs(seconds)),
+        },
+        active_health_check_expected_status,
         max_requests_per_minute: options.max_requests_per_minute,
+        rate_limiter: Mutex::new(ratelimit::RateLimiter::new(
+            options.max_requests_per_minute,
+            RATE_LIMIT_WINDOW,
+        )),
+        trusted_proxies,
+        mirror: options.mirror,
+        mirror_slots: match options.max_mirror_requests {
+            0 => None,
+            max_mirror_requests => Some(Arc::new(Semaphore::new(max_mirror_requests))),
+        },
+        events_path: options.events_path,
+        upstream_events: broadcast::channel(EVENTS_CHANNEL_CAPACITY).0,
+        max_connection_duration: match options.max_connection_duration {
+            0 => None,
+            secs => Some(time::Duration::from_secs(secs)),
+        },
+        max_requests_per_connection: match options.max_requests_per_connection {
+            0 => None,
+            requests => Some(requests),
+        },
+        client_idle_timeout: match options.client_idle_timeout {
+            0 => None,
+            secs => Some(time::Duration::from_secs(secs)),
+        },
+        request_deadline: match options.request_deadline {
+            0 => None,
+            secs => Some(time::Duration::from_secs(secs)),
+        },
+        upstream_timeout: match options.upstream_timeout {
+            0 => None,
+            secs => Some(time::Duration::from_secs(secs)),
+        },
+        upstream_connect_timeout: match options.upstream_connect_timeout {
+            0 => None,
+            secs => Some(time::Duration::from_secs(secs)),
+        },
+        slow_request_threshold: match options.slow_request_threshold_ms {
+            0 => None,
+            millis => Some(time::Duration::from_millis(millis)),
+        },
+        metrics: metrics::Metrics::new(options.error_rate_alert),
+        response_headers,
+        request_headers,
+        lb_algorithm,
+        rng: Mutex::new(rand::rngs::StdRng::from_entropy()),
+        connection_pool: pool::ConnectionPool::new(options.max_idle_per_upstream),
+        active_connections: AtomicUsize::new(0),
+        shutting_down: shutdown_receiver,
+        max_retries: options.max_retries,
+        circuit_breaker_threshold: options.circuit_breaker_threshold,
+        circuit_breaker_cooldown: time::Duration::from_secs(options.circuit_breaker_cooldown),
+        passive_failure_threshold: options.passive_failure_threshold,
+        maintenance_mode: AtomicBool::new(options.maintenance),
+        maintenance_retry_after: options.maintenance_retry_after,
+        max_connections_per_ip: options.max_connections_per_ip,
+        connection_slots: match options.max_connections {
+            0 => None,
+            max_connections => Some(Arc::new(Semaphore::new(max_connections))),
+        },
+        reject_excess_connections: options.connection_limit_behavior == "reject",
+        connections_per_ip: Mutex::new(HashMap::new()),
+        tls_acceptor,
+        max_request_body_bytes: match options.max_request_body_bytes {
+            0 => None,
+            bytes => Some(bytes),
+        },
+        max_request_line_bytes: options.max_request_line_bytes,
+        max_header_bytes: options.max_header_bytes,
+        max_response_body_bytes: options.max_response_body_bytes,
+        tcp_nodelay: options.tcp_nodelay == "on",
+        oversized_response: match options.oversized_response.as_str() {
+            "truncate" => response::OversizedBody::Truncate,
+            _ => response::OversizedBody::Reject,
+        },
+        max_conns_per_upstream: options.max_conns_per_upstream,
+        slow_start: match options.slow_start_seconds {
+            0 => None,
+            seconds => Some(time::Duration::from_secs(seconds)),
+        },
+        enable_gzip: options.enable_gzip,
+        response_cache: match options.cache_ttl_seconds {
+            0 => None,
+            seconds => Some(Mutex::new(cache::ResponseCache::new(
+                time::Duration::from_secs(seconds),
+                options.cache_max_entries,
+            ))),
+        },
+        access_log_format: match options.access_log_format.as_str() {
+            "json" => AccessLogFormat::Json,
+            _ => AccessLogFormat::Text,
+        },
+    };
+    let state_arc = Arc::new(state);
+
+    if options.health_check_on_startup {
+        active_health_checks(&state_arc).await;
+        let mut any_alive = false;
+        for pool in state_arc.pools() {
+            any_alive |= pool.addresses.read().await.iter().any(|addr| addr.alive);
+        }
+        // Upstreams that come up later will be found by the next round of health checks
+        if !any_alive {
+            log::warn!("No upstreams passed the startup health check. Starting anyway");
+        }
+    }
+
+    let state_clone = state_arc.clone();
+    tokio::spawn(async move {
+        let mut interval = time::interval(time::Duration::from_secs(
+            state_clone.active_health_check_interval as u64,
+        ));
+        // The first tick completes immediately. Skip it: upstreams are either assumed to be up
+        // until the first interval is up, or (with --health-check-on-startup) were just checked.
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            active_health_checks(&state_clone).await;
+        }
+    });
+
+    if state_arc.max_requests_per_minute > 0 {
+        let state_clone = state_arc.clone();
+        tokio::spawn(async move {
+            prune_rate_limiter(&state_clone).await;
+        });
+    }
+
+    let state_clone = state_arc.clone();
+    tokio::spawn(async move {
+        toggle_maintenance_mode_on_signal(&state_clone).await;
+    });
+
+    if state_arc.resolve_upstream_dns {
+        let state_clone = state_arc.clone();
+        tokio::spawn(async move {
+            resolve_upstreams_periodically(&state_clone).await;
+        });
+    }
+
+    if let Some(upstream_file) = upstream_file {
+        let state_clone = state_arc.clone();
+        tokio::spawn(async move {
+            reload_upstreams_on_signal(&state_clone, &upstream_file, &fixed_upstreams).await;
+        });
+    }
+
+    if let Some(metrics_listener) = metrics_listener {
+        tokio::spawn(admin::serve_metrics(metrics_listener, state_arc.clone()));
+    }
+    if let Some(admin_listener) = admin_listener {
+        tokio::spawn(admin::serve_admin(admin_listener, state_arc.clone()));
+    }
+
+    let accept_loops: Vec<_> = listeners
+        .into_iter()
+        .map(|listener| tokio::spawn(accept_connections(listener, state_arc.clone())))
+        .collect();
+    wait_for_shutdown_signal().await;
+
+    // Stop accepting connections right away, then give the ones we have a chance to finish
+    let _ = shutdown_sender.send(true);
+    for accept_loop in accept_loops {
+        let _ = accept_loop.await;
+    }
+    drain_connections(
+        &state_arc,
+        time::Duration::from_secs(options.shutdown_timeout),
+    )
+    .await;
+}
+
+/// Accepts client connections on one of the listeners, handling each one in its own task, until
+/// we start shutting down.
+async fn accept_connections(listener: TcpListener, state_arc: Arc<ProxyState>) {
+    let mut shutting_down = state_arc.shutting_down.clone();
+    loop {
+        // At the connection limit, leave new connections waiting in the listen backlog until one
+        // of ours closes. (While waiting for a connection, we hold on to the permit it'll use, so
+        // with several listeners, a few permits can be held by listeners rather than connections.)
+        let mut connection_slot = None;
+        if let Some(slots) = &state_arc.connection_slots {
+            if !state_arc.reject_excess_connections {
+                connection_slot = tokio::select! {
+                    permit = slots.clone().acquire_owned() => permit.ok(),
+                    _ = shutting_down.changed() => return,
+                };
+            }
+        }
+        let (mut socket, client_addr) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(error) => {
+                    log::warn!("Failed to accept connection: {}", error);
+                    continue;
+                }
+            },
+            _ = shutting_down.changed() => return,
+        };
+        let client_ip = client_addr.ip().to_string();
+        if state_arc.tcp_nodelay {
+            if let Err(error) = socket.set_nodelay(true) {
+                log::warn!("Failed to set TCP_NODELAY for {}: {}", client_ip, error);
+            }
+        }
+        if let Some(slots) = &state_arc.connection_slots {
+            if state_arc.reject_excess_connections {
+                match slots.clone().try_acquire_owned() {
+                    Ok(permit) => connection_slot = Some(permit),
+                    Err(_) => {
+                        log::warn!(
+                            "Rejecting connection from {}: at --max-connections",
+                            client_ip
+                        );
+                        // A TLS client wouldn't understand a plain-text response
+                        if state_arc.tls_acceptor.is_none() {
+                            tokio::spawn(async move {
+                                let mut response = response::make_http_error(
+                                    http::StatusCode::SERVICE_UNAVAILABLE,
+                                );
+                                response
+                                    .headers_mut()
+                                    .insert("connection", http::HeaderValue::from_static("close"));
+                                let _ = time::timeout(
+                                    time::Duration::from_secs(1),
+                                    response::write_to_stream(&response, &mut socket),
+                                )
+                                .await;
+                            });
+                        }
+                        continue;
+                    }
+                }
+            }
+        }
+        if !claim_ip_connection_slot(&state_arc, &client_ip) {
+            // Dropping the socket closes the connection
+            log::info!(
+                "Refusing connection from {}: too many open connections",
+                client_ip
+            );
+            continue;
+        }
+        // Count the connection before spawning it, so that shutdown can't miss it
+        state_arc.active_connections.fetch_add(1, Ordering::SeqCst);
+        let state = state_arc.clone();
+        tokio::spawn(async move {
+            // (The connection slot is given back when the connection is done with.)
+            let _connection_slot = connection_slot;
+            let _active = ActiveConnection { state: &state };
+            let _ip_slot = IpConnectionSlot {
+                state: &state,
+                client_ip: client_ip.clone(),
+            };
+            match &state.tls_acceptor {
+                Some(tls_acceptor) => {
+                    // A client that hasn't finished its handshake hasn't sent us a request yet,
+                    // so there's nothing to drain; don't let it hold up shutdown
+                    let mut shutting_down = state.shutting_down.clone();
+                    let handshake = tokio::select! {
+                        handshake = tls_acceptor.accept(socket) => handshake,
+                        _ = shutting_down.changed() => return,
+                    };
+                    match handshake {
+                        Ok(tls_stream) => handle_connection(tls_stream, client_addr, &state).await,
+                        Err(error) => {
+                            log::info!("TLS handshake with {} failed: {}", client_ip, error)
+                        }
+                    }
+                }
+                None => handle_connection(socket, client_addr, &state).await,
+            }
+        });
+    }
+}
+
+/// Resolves once we receive SIGTERM or SIGINT.
+async fn wait_for_shutdown_signal() {
+    let mut sigterm = signal(SignalKind::terminate()).expect("Failed to install SIGTERM handler");
+    tokio::select! {
+        _ = sigterm.recv() => log::info!("Received SIGTERM, shutting down"),
+        _ = tokio::signal::ctrl_c() => log::info!("Received SIGINT, shutting down"),
+    }
+}
+
+/// Switches maintenance mode on or off every time we receive SIGUSR1.
+async fn toggle_maintenance_mode_on_signal(state: &ProxyState) {
+    let mut sigusr1 =
+        signal(SignalKind::user_defined1()).expect("Failed to install SIGUSR1 handler");
+    while sigusr1.recv().await.is_some() {
+        // fetch_xor with true flips the flag, returning what it was before
+        if state.maintenance_mode.fetch_xor(true, Ordering::SeqCst) {
+            log::info!("Maintenance mode disabled");
+        } else {
+            log::info!("Maintenance mode enabled; rejecting requests that modify data");
+        }
+    }
+}
+
+/// Re-reads the upstream file every time we receive SIGHUP. Upstreams given with --upstream and
+/// --backup-upstream (`fixed_upstreams`) stay in the list regardless of what the file says.
+async fn reload_upstreams_on_signal(
+    state: &ProxyState,
+    path: &str,
+    fixed_upstreams: &[(String, usize)],
+) {
+    let mut sighup = signal(SignalKind::hangup()).expect("Failed to install SIGHUP handler");
+    while sighup.recv().await.is_some() {
+        let mut upstreams = fixed_upstreams.to_vec();
+        match read_upstream_file(path) {
+            Ok(file_upstreams) => upstreams.extend(file_upstreams),
+            Err(err) => {
+                log::error!("Not reloading upstreams: {}", err);
+                continue;
+            }
+        }
+        if upstreams.is_empty() {
+            log::error!("Not reloading upstreams: {} lists no upstreams", path);
+            continue;
+        }
+        let resolved = if state.resolve_upstream_dns {
+            match resolve_upstreams(&upstreams).await {
+                Ok(resolved) => resolved,
+                Err(err) => {
+                    log::error!("Not reloading upstreams: {}", err);
+                    continue;
+                }
+            }
+        } else {
+            upstreams.clone()
+        };
+        *state.upstreams.configured_upstreams.lock().unwrap() = upstreams;
+        replace_upstreams(state, &state.upstreams, resolved).await;
+    }
+}
+
+/// Expands each upstream given as a host name into one upstream (with the same weight) for every
+/// address the name currently resolves to. Upstreams given as IP addresses (or Unix sockets) are
+/// left alone.
+async fn resolve_upstreams(upstreams: &[(String, usize)]) -> Result<Vec<(String, usize)>, String> {
+    let mut resolved: Vec<(String, usize)> = Vec::new();
+    for (address, weight) in upstreams {
+        if address.parse::<SocketAddr>().is_ok() || stream::unix_socket_path(address).is_some() {
+            resolved.push((address.clone(), *weight));
+            continue;
+        }
+        let addrs: Vec<SocketAddr> = tokio::net::lookup_host(address.as_str())
+            .await
+            .map_err(|err| format!("Could not resolve upstream {}: {}", address, err))?
+            .collect();
+        if addrs.is_empty() {
+            return Err(format!("Upstream {} did not resolve", address));
+        }
+        for addr in addrs {
+            let addr = addr.to_string();
+            // A name can list an address twice, or share it with another upstream
+            if !resolved.iter().any(|(other, _)| *other == addr) {
+                resolved.push((addr, *weight));
+            }
+        }
+    }
+    Ok(resolved)
+}
+
+/// Resolves the upstream host names in every pool again every health check interval, adding
+/// upstreams for new addresses and removing ones that have disappeared from DNS. If a lookup
+/// fails, that pool's upstreams are left as they are until the next try.
+async fn resolve_upstreams_periodically(state: &ProxyState) {
+    let mut interval = time::interval(time::Duration::from_secs(
+        state.active_health_check_interval as u64,
+    ));
+    // We resolved everything at startup
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        for pool in state.pools() {
+            let configured = pool.configured_upstreams.lock().unwrap().clone();
+            let upstreams = match resolve_upstreams(&configured).await {
+                Ok(upstreams) => upstreams,
+                Err(err) => {
+                    log::warn!("Not updating upstreams from DNS: {}", err);
+                    continue;
+                }
+            };
+            let unchanged = {
+                let addresses = pool.addresses.read().await;
+                addresses.len() == upstreams.len()
+                    && upstreams.iter().all(|(address, weight)| {
+                        addresses
+                            .iter()
+                            .any(|addr| addr.address == *address && addr.weight == *weight)
+                    })
+            };
+            if !unchanged {
+                log::info!("Upstream DNS records changed");
+                replace_upstreams(state, pool, upstreams).await;
+            }
+        }
+    }
+}
+
+/// Swaps in a new list of upstreams for `pool`. Upstreams that are still listed keep their
+/// alive/dead state, while new ones start out alive. Requests already in flight to removed
+/// upstreams are left to finish.
+async fn replace_upstreams(
+    state: &ProxyState,
+    pool: &UpstreamPool,
+    upstreams: Vec<(String, usize)>,
+) {
+    let mut addresses = pool.addresses.write().await;
+    let mut hash_ring = pool.hash_ring.write().await;
+    let mut old_addresses: HashMap<String, UpstreamAddress> = addresses
+        .drain(..)
+        .map(|addr| (addr.address.clone(), addr))
+        .collect();
+    let mut added = Vec::new();
+    for (address, weight) in upstreams {
+        if addresses.iter().any(|addr| addr.address == address) {
+            continue;
+        }
+        match old_addresses.remove(&address) {
+            Some(mut addr) => {
+                addr.weight = weight;
+                addresses.push(addr);
+            }
+            None => {
+                hash_ring.add(&address);
+                added.push(address.clone());
+                addresses.push(UpstreamAddress::new(
+                    address,
+                    weight,
+                    state.max_conns_per_upstream,
+                ));
+            }
+        }
+    }
+    let removed: Vec<String> = old_addresses.into_keys().collect();
+    for address in &removed {
+        hash_ring.remove(address);
+        // Another pool may still be using the upstream
+        let mut still_used = false;
+        for other in state.pools().filter(|other| !std::ptr::eq(*other, pool)) {
+            if other
+                .addresses
+                .read()
+                .await
+                .iter()
+                .any(|addr| &addr.address == address)
+            {
+                still_used = true;
+            }
+        }
+        if !still_used {
+            state.connection_pool.forget(address);
+        }
+    }
+    log::info!(
+        "Reloaded upstreams: added {:?}, removed {:?}, {} unchanged",
+        added,
+        removed,
+        addresses.len() - added.len()
+    );
+}
+
+/// Waits for active connections to close, up to `timeout`, and reports how many made it. Whatever
+/// is still open afterwards is closed when the runtime shuts down.
+async fn drain_connections(state: &ProxyState, timeout: time::Duration) {
+    let in_flight = state.active_connections.load(Ordering::SeqCst);
+    log::info!(
+        "Waiting up to {:?} for {} connections to finish",
+        timeout,
+        in_flight
+    );
+    let deadline = Instant::now() + timeout;
+    while state.active_connections.load(Ordering::SeqCst) > 0 && Instant::now() < deadline {
+        time::sleep(time::Duration::from_millis(50)).await;
+    }
+    let remaining = state.active_connections.load(Ordering::SeqCst);
+    log::info!(
+        "Shutdown complete: {} connections drained, {} force-closed",
+        in_flight.saturating_sub(remaining),
+        remaining
+    );
+}
+
+/// Checks every upstream, up to --health-check-concurrency of them at a time, and then marks the
+/// ones whose state changed.
+async fn active_health_checks(state: &Arc<ProxyState>) {
+    log::info!("Starting active health checks....");
+    let mut dead_upstreams: Vec<String> = Vec::new();
+    let mut live_upstreams: Vec<String> = Vec::new();
+    let mut results: Vec<(String, HealthCheckResult)> = Vec::new();
+    // An upstream can be in more than one pool, but it only needs to be checked once
+    let mut upstreams: Vec<(String, bool)> = Vec::new();
+    for pool in state.pools() {
+        for addr in pool.addresses.read().await.iter() {
+            if !upstreams
+                .iter()
+                .any(|(address, _)| *address == addr.address)
+            {
+                upstreams.push((addr.address.clone(), addr.alive));
+            }
+        }
+    }
+    // (No locks are held while the checks run, so requests aren't held up by slow upstreams.)
+    let permits = Arc::new(Semaphore::new(state.health_check_concurrency));
+    let mut checks = Vec::new();
+    for (address, alive) in upstreams {
+        // Waiting for a permit before spawning the check keeps the number running at once capped
+        let permit = permits.clone().acquire_owned().await.unwrap();
+        let state = state.clone();
+        let check = tokio::spawn(async move {
+            let result = check_upstream_health(&state, &address).await;
+            drop(permit);
+            (address, result)
+        });
+        checks.push((alive, check));
+    }
+    for (alive, check) in checks {
+        let (address, result) = check.await.expect("Health check task panicked");
+        if result.alive != alive {
+            if result.alive {
+                live_upstreams.push(address.clone());
+            } else {
+                dead_upstreams.push(address.clone());
+            }
+        }
+        results.push((address, result));
+    }
+    for pool in state.pools() {
+        let mut addresses = pool.addresses.write().await;
+        for (address, result) in &results {
+            // (The upstream may have been removed by a reload in the meantime.)
+            if let Some(addr) = addresses.iter_mut().find(|addr| addr.address == *address) {
+                addr.health_history.record(result.clone());
+            }
+        }
+    }
+    for addr in dead_upstreams {
+        mark_upstream_status(state, addr, false).await;
+    }
+    for addr in live_upstreams {
+        mark_upstream_status(state, addr, true).await;
+    }
+    log::info!("Active health checks complete.");
+}
+
+/// Sends a health check request to one upstream.
+async fn check_upstream_health(state: &ProxyState, address: &str) -> HealthCheckResult {
+    let time = std::time::SystemTime::now();
+    let start = Instant::now();
+    let request = http::Request::builder()
+        .method(state.active_health_check_method.clone())
+        .uri(&state.active_health_check_path)
+        // A socket path isn't much of a host name
+        .header(
+            "Host",
+            match stream::unix_socket_path(address) {
+                Some(_) => "localhost",
+                None => address,
+            },
+        )
+        .body(Vec::new())
+        .unwrap();
+    // An upstream that never answers shouldn't hold up the rest of the health checks
+    let deadline = state.health_check_timeout.map(|timeout| start + timeout);
+    let error = match with_deadline(deadline, stream::UpstreamStream::connect(address)).await {
+        Some(Ok(mut stream)) => {
+            let response = with_deadline(deadline, async {
+                if let Err(e) = request::write_to_stream(&request, &mut stream).await {
+                    log::error!("Failed to write to upstream {}", e);
+                }
+                // (There's no body to read in response to a HEAD request.)
+                response::read_from_stream(&mut stream, request.method()).await
+            })
+            .await;
+            match response {
+                Some(Ok(response)) => {
+                    let status = response.status().as_u16();
+                    if state
+                        .active_health_check_expected_status
+                        .iter()
+                        .any(|statuses| statuses.contains(&status))
+                    {
+                        None
+                    } else {
+                        log::warn!(
+                            "Upstream {} failed health check with status {}",
+                            address,
+                            status
+                        );
+                        Some(format!("Unexpected status {}", status))
+                    }
+                }
+                Some(Err(e)) => {
+                    log::error!("Error reading from upstream {:?}", e);
+                    Some(format!("Error reading response: {:?}", e))
+                }
+                None => {
+                    log::error!(
+                        "Upstream {} timed out responding to a health check. Marking it dead",
+                        address
+                    );
+                    Some("Timed out waiting for a response".to_string())
+                }
+            }
+        }
+        Some(Err(e)) => {
+            log::error!(
+                "Failed to connect to upstream {} {}. Marking it dead",
+                address,
+                e
+            );
+            Some(format!("Failed to connect: {}", e))
+        }
+        None => {
+            log::error!(
+                "Timed out connecting to upstream {} for a health check. Marking it dead",
+                address
+            );
+            Some("Timed out connecting".to_string())
+        }
+    };
+    HealthCheckResult {
+        time,
+        alive: error.is_none(),
+        latency: start.elapsed(),
+        error,
+    }
+}
+
+/// Returns the IP address of the client that sent a request which came in over a connection from
+/// `peer_ip`. If the peer is one of our trusted proxies, that's the last address in
+/// X-Forwarded-For that isn't also a trusted proxy. Otherwise, X-Forwarded-For could say anything,
+/// so it's the peer itself.
+fn real_client_ip(state: &ProxyState, peer_ip: &str, request: &http::Request<Vec<u8>>) -> String {
+    let is_trusted = |ip: IpAddr| state.trusted_proxies.iter().any(|block| block.contains(ip));
+    match peer_ip.parse::<IpAddr>() {
+        Ok(ip) if is_trusted(ip) => {}
+        _ => return peer_ip.to_string(),
+    }
+    let chain: Vec<&str> = request
+        .headers()
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|hop| hop.trim())
+        .collect();
+    let mut client_ip = peer_ip;
+    // Work back from the proxy closest to us, stopping at the first hop we don't trust
+    for hop in chain.iter().rev() {
+        match hop.parse::<IpAddr>() {
+            Ok(ip) => {
+                client_ip = hop;
+                if !is_trusted(ip) {
+                    break;
+                }
+            }
+            // Whatever added this wasn't a proxy we know how to trust
+            Err(_) => break,
+        }
+    }
+    client_ip.to_string()
+}
+
+/// Every minute, forgets about clients that haven't made any requests in the last minute.
+async fn prune_rate_limiter(state: &ProxyState) {
+    let mut interval = time::interval(RATE_LIMIT_WINDOW);
+    loop {
+        interval.tick().await;
+        state
+            .rate_limiter
+            .lock()
+            .unwrap()
+            .prune(std::time::Instant::now());
+    }
+}
+
+/// Returns the number of seconds (rounded up) until `client_ip` may make another request, for the
+/// Retry-After header of 429 responses.
+fn rate_limit_retry_after(state: &ProxyState, client_ip: &str) -> u64 {
+    let remaining = state
+        .rate_limiter
+        .lock()
+        .unwrap()
+        .retry_after(client_ip, std::time::Instant::now());
+    (remaining.as_millis() as u64 + 999) / 1000
+}
+
+/// Counts a request from `client_ip`, returning true if the client has already made as many
+/// requests in the last minute as it's allowed.
+fn is_rate_limited(state: &ProxyState, client_ip: &str) -> bool {
+    if state.max_requests_per_minute == 0 {
+        return false;
+    }
+    !state
+        .rate_limiter
+        .lock()
+        .unwrap()
+        .allow(client_ip, std::time::Instant::now())
+}
+
+/// Picks a live upstream from `pool` for a connection from `client_ip` according to the load
+/// balancing algorithm. The connection is counted against the upstream as soon as it's picked, so
+/// that concurrent callers see it. Upstreams that have no connection slots left are passed over, as
+/// are any in `excluded`.
+async fn get_live_upstream(
+    state: &ProxyState,
+    pool: &UpstreamPool,
+    client_ip: &str,
+    excluded: &HashSet<String>,
+) -> Option<UpstreamConnection> {
+    let addresses = pool.addresses.read().await;
+    let live_addresses = addresses
+        .iter()
+        .filter(|addr| {
+            addr.alive
+                && !addr.drained
+                && !excluded.contains(&addr.address)
+                && addr
+                    .breaker
+                    .lock()
+                    .unwrap()
+                    .allows_requests(state.circuit_breaker_cooldown)
+        })
+        .collect::<Vec<&UpstreamAddress>>();
+    if live_addresses.is_empty() {
+        return None;
+    }
+    // Another caller can take an upstream's last slot between when we pick it and when we try to
+    // take the slot ourselves, in which case we pick again
+    loop {
+        let mut live_addresses = live_addresses.clone();
+        live_addresses.retain(|addr| {
+            addr.connection_slots
+                .as_ref()
+                .map_or(true, |slots| slots.available_permits() > 0)
+        });
+        if live_addresses.is_empty() {
+            log::warn!("Every live upstream already has as many connections as it can take");
+            return None;
+        }
+        if let Some(connection) =
+            pick_upstream(state, pool, &addresses, live_addresses, client_ip).await
+        {
+            return Some(connection);
+        }
+    }
+}
+
+/// Picks one of `live_addresses` (which are some of `addresses`) according to the load balancing
+/// algorithm, returning None if it turned out to have no connection slots left.
+async fn pick_upstream(
+    state: &ProxyState,
+    pool: &UpstreamPool,
+    addresses: &[UpstreamAddress],
+    mut live_addresses: Vec<&UpstreamAddress>,
+    client_ip: &str,
+) -> Option<UpstreamConnection> {
+    // Weight 0 upstreams are a last resort
+    if live_addresses.iter().any(|addr| addr.weight > 0) {
+        live_addresses.retain(|addr| addr.weight > 0);
+    }
+    let upstream_idx = match state.lb_algorithm {
+        LoadBalancingAlgorithm::Random => {
+            let weights: Vec<f64> = live_addresses
+                .iter()
+                .map(|addr| effective_weight(state, addr))
+                .collect();
+            let total_weight: f64 = weights.iter().sum();
+            if total_weight == 0.0 {
+                state.gen_range(0..live_addresses.len())
+            } else {
+                // Pick a point along the combined weights and find whose share it falls in
+                let mut point = state.gen_range(0.0..total_weight);
+                weights
+                    .iter()
+                    .position(|weight| {
+                        if point < *weight {
+                            true
+                        } else {
+                            point -= weight;
+                            false
+                        }
+                    })
+                    // (Rounding can leave the point just past the end.)
+                    .unwrap_or(live_addresses.len() - 1)
+            }
+        }
+        // Every caller takes its own turn, even if several connections arrive at once. Dead
+        // upstreams are skipped since we only count live ones.
+        LoadBalancingAlgorithm::RoundRobin => {
+            pool.round_robin_cursor.fetch_add(1, Ordering::SeqCst) % live_addresses.len()
+        }
+        LoadBalancingAlgorithm::LeastConnections => {
+            let connection_counts: Vec<usize> = live_addresses
+                .iter()
+                .map(|addr| addr.active_connections.load(Ordering::SeqCst))
+                .collect();
+            let fewest = *connection_counts.iter().min().unwrap();
+            let least_loaded: Vec<usize> = (0..live_addresses.len())
+                .filter(|idx| connection_counts[*idx] == fewest)
+                .collect();
+            least_loaded[state.gen_range(0..least_loaded.len())]
+        }
+        // Hash over every upstream rather than just the live ones, so that when an upstream dies,
+        // only its clients move (each to the next live upstream after it)
+        LoadBalancingAlgorithm::IpHash => {
+            let mut hasher = DefaultHasher::new();
+            client_ip.hash(&mut hasher);
+            let start = hasher.finish() as usize % addresses.len();
+            let chosen = (0..addresses.len())
+                .map(|offset| &addresses[(start + offset) % addresses.len()])
+                .find(|addr| live_addresses.iter().any(|live| std::ptr::eq(*live, *addr)))
+                .unwrap();
+            live_addresses
+                .iter()
+                .position(|live| std::ptr::eq(*live, chosen))
+                .unwrap()
+        }
+        LoadBalancingAlgorithm::ConsistentHash => {
+            let hash_ring = pool.hash_ring.read().await;
+            let chosen = hash_ring
+                .get_matching(client_ip, |address| {
+                    live_addresses.iter().any(|live| live.address == address)
+                })
+                .unwrap();
+            live_addresses
+                .iter()
+                .position(|live| live.address == chosen)
+                .unwrap()
+        }
     };
-    let state_arc = Arc::new(state);
+    let upstream = live_addresses[upstream_idx];
+    let slot = match &upstream.connection_slots {
+        Some(slots) => Some(slots.clone().try_acquire_owned().ok()?),
+        None => None,
+    };
+    upstream.breaker.lock().unwrap().on_picked();
+    upstream.active_connections.fetch_add(1, Ordering::SeqCst);
+    Some(UpstreamConnection {
+        address: upstream.address.clone(),
+        active_connections: upstream.active_connections.clone(),
+        slot,
+    })
+}
 
-    let state_clone = state_arc.clone();
-    tokio::spawn(async move {
-        let mut interval = time::interval(time::Duration::from_secs(
-            state_clone.active_health_check_interval as u64,
-        ));
-        loop {
-            interval.tick().await;
-            active_health_checks(&state_clone).await;
+/// Returns an upstream's share of randomly-chosen traffic. An upstream that has just come back from
+/// being dead starts out with a small fraction of its weight, which ramps up to the whole thing
+/// over the slow start period.
+fn effective_weight(state: &ProxyState, addr: &UpstreamAddress) -> f64 {
+    let weight = addr.weight as f64;
+    match (state.slow_start, addr.recovered_at) {
+        (Some(slow_start), Some(recovered_at)) => {
+            let ramp = recovered_at.elapsed().as_secs_f64() / slow_start.as_secs_f64();
+            weight * ramp.clamp(SLOW_START_MIN_FRACTION, 1.0)
         }
-    });
-
-    loop {
-        let (socket, _) = listener.accept().await.unwrap();
-        let state = state_arc.clone();
-        tokio::spawn(async move {
-            handle_connection(socket, &state).await;
-        });
+        _ => weight,
     }
 }
 
-async fn active_health_checks(state: &ProxyState) {
-    log::info!("Starting active health checks....");
-    let mut dead_upstreams: Vec<String> = Vec::new();
-    let mut live_upstreams: Vec<String> = Vec::new();
-    {
-        let addresses = state.upstream_addresses.read().await;
-        for addr in addresses.iter() {
-            let mut is_alive = addr.alive;
-            let request = http::Request::builder()
-                .method(http::Method::GET)
-                .uri(&state.active_health_check_path)
-                .header("Host", &addr.address)
-                .body(Vec::new())
-                .unwrap();
-            match TcpStream::connect(&addr.address).await {
-                Ok(mut stream) => {
-                    if let Err(e) = request::write_to_stream(&request, &mut stream).await {
-                        log::error!("Failed to write to upstream {}", e);
-                        is_alive = false;
-                    }
-                    match response::read_from_stream(&mut stream, &http::Method::GET).await {
-                        Ok(response) => {
-                            is_alive = response.status().as_u16() == 200;
-                        }
-                        Err(e) => {
-                            log::error!("Error reading from upstream {:?}", e);
-                            is_alive = false;
-                        }
-                    }
-                }
-                Err(e) => {
-                    log::error!(
-                        "Failed to connect to upstream {} {}. Marking it dead",
-                        &addr.address,
-                        e
-                    );
-                    is_alive = false;
+/// Marks an upstream alive or dead, in every pool it's in.
+async fn mark_upstream_status(state: &ProxyState, address: String, is_alive: bool) {
+    let mut changed = false;
+    for pool in state.pools() {
+        let mut addresses = pool.addresses.write().await;
+        for addr in addresses.iter_mut() {
+            if addr.address == address {
+                changed |= addr.alive != is_alive;
+                if is_alive && !addr.alive {
+                    addr.recovered_at = Some(Instant::now());
                 }
-            }
-            if is_alive != addr.alive {
+                addr.alive = is_alive;
                 if is_alive {
-                    live_upstreams.push(addr.address.clone());
-                } else {
-                    dead_upstreams.push(addr.address.clone());
+                    // Failures from before it was marked alive don't count any more
+                    addr.consecutive_failures = 0;
                 }
             }
         }
     }
-    for addr in dead_upstreams {
-        mark_upstream_status(state, addr, false).await;
+    if changed && is_alive {
+        if let Some(slow_start) = state.slow_start {
+            log::info!(
+                "Upstream {} recovered; ramping up its traffic over {:?}",
+                address,
+                slow_start
+            );
+        }
     }
-    for addr in live_upstreams {
-        mark_upstream_status(state, addr, true).await;
+    if changed {
+        // It's fine if nobody is subscribed to hear about this
+        let _ = state.upstream_events.send(format!(
+            "{{\"upstream\":\"{}\",\"alive\":{}}}",
+            admin::json_escape(&address),
+            is_alive
+        ));
     }
-    log::info!("Active health checks complete.");
+    log::info!("Upstreams {:?}", state.upstreams.addresses.read().await);
 }
 
-async fn get_live_upstream(state: &ProxyState) -> Option<String> {
-    let mut rng = rand::rngs::StdRng::from_entropy();
-    let addresses = state.upstream_addresses.read().await;
-    let live_addresses = addresses
-        .iter()
-        .filter(|addr| addr.alive)
-        .collect::<Vec<&UpstreamAddress>>();
-    return if live_addresses.is_empty() {
-        None
-    } else {
-        let upstream_idx = rng.gen_range(0..live_addresses.len());
-        Some(live_addresses[upstream_idx].address.clone())
-    };
+/// Counts a failed connection or request to an upstream, marking it dead once
+/// --passive-failure-threshold of them have failed in a row. (Active health checks can still mark
+/// it alive again.)
+async fn record_passive_failure(state: &ProxyState, address: &str) {
+    let mut threshold_reached = false;
+    for pool in state.pools() {
+        let mut addresses = pool.addresses.write().await;
+        for addr in addresses.iter_mut().filter(|addr| addr.address == address) {
+            addr.consecutive_failures += 1;
+            threshold_reached |=
+                addr.alive && addr.consecutive_failures >= state.passive_failure_threshold;
+        }
+    }
+    if threshold_reached {
+        log::warn!(
+            "Upstream {} failed {} times in a row. Marking it dead",
+            address,
+            state.passive_failure_threshold
+        );
+        mark_upstream_status(state, address.to_string(), false).await;
+    }
 }
 
-async fn mark_upstream_status(state: &ProxyState, address: String, is_alive: bool) {
-    let mut addresses = state.upstream_addresses.write().await;
-    for addr in addresses.iter_mut() {
-        if addr.address == address {
-            addr.alive = is_alive;
+/// Notes that a request to an upstream went through, so that earlier failures no longer count as
+/// being in a row.
+async fn record_passive_success(state: &ProxyState, address: &str) {
+    for pool in state.pools() {
+        // There's usually nothing to reset, so only take the write lock if there is
+        let has_failures = pool
+            .addresses
+            .read()
+            .await
+            .iter()
+            .any(|addr| addr.address == address && addr.consecutive_failures > 0);
+        if has_failures {
+            let mut addresses = pool.addresses.write().await;
+            for addr in addresses.iter_mut().filter(|addr| addr.address == address) {
+                addr.consecutive_failures = 0;
+            }
+        }
+    }
+}
+
+/// Feeds whether a request to an upstream succeeded into its circuit breaker (in every pool it's
+/// in).
+async fn record_upstream_result(state: &ProxyState, address: &str, succeeded: bool) {
+    if state.circuit_breaker_threshold == 0 {
+        return;
+    }
+    let mut new_state = None;
+    for pool in state.pools() {
+        let addresses = pool.addresses.read().await;
+        for addr in addresses.iter().filter(|addr| addr.address == address) {
+            let mut breaker = addr.breaker.lock().unwrap();
+            new_state = breaker
+                .record(succeeded, state.circuit_breaker_threshold)
+                .or(new_state);
         }
     }
-    log::info!("Upstreams {:?}", addresses);
+    match new_state {
+        Some(BreakerState::Open(_)) => log::warn!(
+            "Circuit breaker for upstream {} is open; not sending it requests for {:?}",
+            address,
+            state.circuit_breaker_cooldown
+        ),
+        Some(BreakerState::Closed) => log::info!("Circuit breaker for upstream {} closed", address),
+        _ => {}
+    }
 }
 
-async fn connect_to_upstream(state: &ProxyState) -> Result<TcpStream, std::io::Error> {
+/// Connects to a live upstream in `pool`. The stream (and whether it was reused from the pool) is
+/// returned along with the UpstreamConnection that keeps it counted as active.
+async fn connect_to_upstream(
+    state: &ProxyState,
+    pool: &UpstreamPool,
+    client_ip: &str,
+) -> Result<(stream::UpstreamStream, bool, UpstreamConnection), std::io::Error> {
     loop {
-        if let Some(upstream_ip) = get_live_upstream(state).await {
-            match TcpStream::connect(&upstream_ip).await {
-                Ok(stream) => break Ok(stream),
+        if let Some(upstream) = get_live_upstream(state, pool, client_ip, &HashSet::new()).await {
+            let upstream_ip = upstream.address.clone();
+            match open_upstream_stream(state, &upstream_ip).await {
+                Ok((stream, reused)) => break Ok((stream, reused, upstream)),
                 Err(e) => {
                     log::error!("Failed to connect to upstream {}: {}", upstream_ip, e);
-                    mark_upstream_status(state, upstream_ip, false).await;
+                    record_upstream_result(state, &upstream_ip, false).await;
+                    record_passive_failure(state, &upstream_ip).await;
                     continue;
                 }
             }
@@ -232,45 +2369,481 @@ async fn connect_to_upstream(state: &ProxyState) -> Result<TcpStream, std::io::E
     }
 }
 
-async fn send_response(client_conn: &mut TcpStream, response: &http::Response<Vec<u8>>) {
-    let client_ip = client_conn.peer_addr().unwrap().ip().to_string();
+/// Returns a connection to the upstream at `address`, reusing an idle one from the pool if there is
+/// one, along with whether it was reused. (The upstream may still have closed a reused connection
+/// since the pool last checked it.)
+async fn open_upstream_stream(
+    state: &ProxyState,
+    address: &str,
+) -> std::io::Result<(stream::UpstreamStream, bool)> {
+    if let Some(stream) = state.connection_pool.take(address).await {
+        return Ok((stream, true));
+    }
+    connect_upstream_stream(state, address)
+        .await
+        .map(|stream| (stream, false))
+}
+
+/// Opens a new connection to the upstream at `address`. Connecting fails with ErrorKind::TimedOut
+/// if it takes longer than --upstream-connect-timeout.
+async fn connect_upstream_stream(
+    state: &ProxyState,
+    address: &str,
+) -> std::io::Result<stream::UpstreamStream> {
+    let deadline = state
+        .upstream_connect_timeout
+        .map(|timeout| Instant::now() + timeout);
+    let stream = match with_deadline(deadline, stream::UpstreamStream::connect(address)).await {
+        Some(result) => result?,
+        None => return Err(Error::new(ErrorKind::TimedOut, "timed out connecting")),
+    };
+    // (Pooled connections had this done when they were first opened.)
+    if state.tcp_nodelay {
+        if let Err(error) = stream.set_nodelay(true) {
+            log::warn!(
+                "Failed to set TCP_NODELAY for upstream {}: {}",
+                address,
+                error
+            );
+        }
+    }
+    Ok(stream)
+}
+
+/// Returns true if `token` (e.g. "close") is one of the comma-separated options in the Connection
+/// header
+fn has_connection_option(headers: &http::HeaderMap, token: &str) -> bool {
+    headers.get_all("connection").iter().any(|value| {
+        value.to_str().map_or(false, |value| {
+            value
+                .split(',')
+                .any(|option| option.trim().eq_ignore_ascii_case(token))
+        })
+    })
+}
+
+/// Returns true if the client wants its connection kept open after the response to `request`.
+/// HTTP/1.1 connections stay open unless the client sends "Connection: close"; HTTP/1.0 ones are
+/// closed unless it sends "Connection: keep-alive".
+fn client_wants_keep_alive(request: &http::Request<Vec<u8>>) -> bool {
+    if has_connection_option(request.headers(), "close") {
+        false
+    } else if request.version() == http::Version::HTTP_10 {
+        has_connection_option(request.headers(), "keep-alive")
+    } else {
+        true
+    }
+}
+
+/// Sets the Connection header on a response to the client to say whether we're keeping the
+/// connection open. Whatever the upstream sent was about its connection to us, so it's replaced.
+fn set_connection_header(
+    response: &mut http::Response<Vec<u8>>,
+    keep_alive: bool,
+    client_version: http::Version,
+) {
+    let headers = response.headers_mut();
+    headers.remove("keep-alive");
+    if !keep_alive {
+        headers.insert("connection", http::HeaderValue::from_static("close"));
+    } else if client_version == http::Version::HTTP_10 {
+        headers.insert("connection", http::HeaderValue::from_static("keep-alive"));
+    } else {
+        // Keep-alive is the default in HTTP/1.1
+        headers.remove("connection");
+    }
+}
+
+/// Returns true if an upstream connection can be used for another request after this response:
+/// the upstream didn't ask us to close it, and the end of the response body was marked by
+/// Content-Length rather than by the upstream hanging up.
+fn is_upstream_reusable(request_method: &http::Method, response: &http::Response<Vec<u8>>) -> bool {
+    let wants_close = has_connection_option(response.headers(), "close");
+    let has_body = !(request_method == http::Method::HEAD
+        || response.status().as_u16() < 200
+        || response.status() == http::StatusCode::NO_CONTENT
+        || response.status() == http::StatusCode::NOT_MODIFIED);
+    // The rest of a truncated body is still on its way to us
+    let truncated = response.extensions().get::<response::Truncated>().is_some();
+    !wants_close && !truncated && (!has_body || response.headers().contains_key("content-length"))
+}
+
+/// Returns true if a request may change something on the upstream, meaning it should be turned
+/// away in maintenance mode.
+fn is_mutating(method: &http::Method) -> bool {
+    method == http::Method::POST
+        || method == http::Method::PUT
+        || method == http::Method::PATCH
+        || method == http::Method::DELETE
+}
+
+/// Returns true if sending a request more than once has the same effect as sending it once, so
+/// that it's safe to retry.
+fn is_idempotent(method: &http::Method) -> bool {
+    method == http::Method::GET
+        || method == http::Method::HEAD
+        || method == http::Method::PUT
+        || method == http::Method::DELETE
+}
+
+/// Runs `future` to completion, or gives up and returns None once `deadline` has passed.
+async fn with_deadline<F: Future>(deadline: Option<Instant>, future: F) -> Option<F::Output> {
+    match deadline {
+        Some(deadline) => time::timeout_at(deadline, future).await.ok(),
+        None => Some(future.await),
+    }
+}
+
+/// Sends a copy of a request to the mirror upstream, logging (and otherwise ignoring) whatever it
+/// sends back. Nothing that happens here is visible to the client. `slot` (if there's a limit on
+/// mirrored requests) is held until we're done.
+async fn mirror_request(
+    mirror: String,
+    request: http::Request<Vec<u8>>,
+    connect_timeout: Option<time::Duration>,
+    slot: Option<OwnedSemaphorePermit>,
+) {
+    let start = Instant::now();
+    let connect_deadline = connect_timeout.map(|timeout| start + timeout);
+    let mut mirror_conn = match with_deadline(connect_deadline, TcpStream::connect(&mirror)).await {
+        Some(Ok(stream)) => stream,
+        Some(Err(e)) => {
+            log::warn!("Failed to connect to mirror {}: {}", mirror, e);
+            return;
+        }
+        None => {
+            log::warn!("Timed out connecting to mirror {}", mirror);
+            return;
+        }
+    };
+    if let Err(e) = request::write_to_stream(&request, &mut mirror_conn).await {
+        log::warn!("Failed to send request to mirror {}: {}", mirror, e);
+        return;
+    }
+    match response::read_from_stream(&mut mirror_conn, request.method()).await {
+        Ok(response) => log::info!(
+            "Mirror {} responded with {} in {:?}",
+            mirror,
+            response.status().as_u16(),
+            start.elapsed()
+        ),
+        Err(e) => log::warn!("Error reading response from mirror {}: {:?}", mirror, e),
+    }
+    drop(slot);
+}
+
+/// Streams upstream state changes to the client as Server-Sent Events until it disconnects.
+async fn stream_events<S: AsyncRead + AsyncWrite + Unpin>(client_conn: &mut S, state: &ProxyState) {
+    let mut events = state.upstream_events.subscribe();
+    let mut shutting_down = state.shutting_down.clone();
+    let headers = "HTTP/1.1 200 OK\r\n\
+                   Content-Type: text/event-stream\r\n\
+                   Cache-Control: no-cache\r\n\
+                   Connection: keep-alive\r\n\r\n";
+    if let Err(error) = client_conn.write_all(headers.as_bytes()).await {
+        log::warn!("Failed to start events stream: {}", error);
+        return;
+    }
+    let mut buffer = [0_u8; 512];
+    loop {
+        let event = tokio::select! {
+            event = events.recv() => event,
+            // The subscriber isn't supposed to send us anything more, so this is (almost
+            // certainly) the client hanging up
+            _ = client_conn.read(&mut buffer) => {
+                log::debug!("Events subscriber disconnected");
+                return;
+            }
+            _ = shutting_down.changed() => return,
+        };
+        match event {
+            Ok(event) => {
+                let message = format!("data: {}\n\n", event);
+                if let Err(error) = client_conn.write_all(message.as_bytes()).await {
+                    log::debug!("Failed to send event to subscriber: {}", error);
+                    return;
+                }
+            }
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                log::warn!("Events subscriber fell behind and missed {} events", missed);
+            }
+            Err(broadcast::error::RecvError::Closed) => return,
+        }
+    }
+}
+
+/// Sends a response to the client (after applying any header rules), counting it towards
+/// `upstream` (the upstream that was handling the request, if it got that far). `request` is the
+/// request being answered, if we managed to read one.
+async fn send_response<S: AsyncWrite + Unpin>(
+    client_conn: &mut S,
+    client_ip: &str,
+    mut response: http::Response<Vec<u8>>,
+    state: &ProxyState,
+    upstream: Option<&str>,
+    request: Option<&http::Request<Vec<u8>>>,
+) {
+    state.response_headers.apply(response.headers_mut());
+    state.metrics.record_response(response.status(), upstream);
     log::info!(
         "{} <- {}",
         client_ip,
         response::format_response_line(&response)
     );
-    if let Err(error) = response::write_to_stream(&response, client_conn).await {
+    let result = response::write_to_stream(&response, client_conn).await;
+    if state.access_log_format == AccessLogFormat::Json {
+        if let Some(request) = request {
+            println!(
+                "{}",
+                render_access_log_entry(client_ip, request, upstream, response.status())
+            );
+        }
+    }
+    if let Err(error) = result {
         log::warn!("Failed to send response to client: {}", error);
         return;
     }
 }
 
-async fn handle_connection(mut client_conn: TcpStream, state: &ProxyState) {
-    let client_ip = client_conn.peer_addr().unwrap().ip().to_string();
-    log::info!("Connection received from {}", client_ip);
+/// Renders the JSON access log entry for a request we've just answered.
+fn render_access_log_entry(
+    client_ip: &str,
+    request: &http::Request<Vec<u8>>,
+    upstream: Option<&str>,
+    status: http::StatusCode,
+) -> String {
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    let latency = request
+        .extensions()
+        .get::<ReceivedAt>()
+        .map_or(time::Duration::from_secs(0), |received| {
+            received.0.elapsed()
+        });
+    format!(
+        "{{\"timestamp_ms\":{},\"client_ip\":\"{}\",\"method\":\"{}\",\"path\":\"{}\",\
+         \"upstream\":{},\"status\":{},\"latency_ms\":{}}}",
+        timestamp.as_millis(),
+        admin::json_escape(client_ip),
+        admin::json_escape(request.method().as_str()),
+        admin::json_escape(request.uri().path()),
+        match upstream {
+            Some(upstream) => format!("\"{}\"", admin::json_escape(upstream)),
+            None => "null".to_string(),
+        },
+        status.as_u16(),
+        latency.as_millis()
+    )
+}
 
-    // Open a connection to a random destination server
-    let mut upstream_conn = match connect_to_upstream(state).await {
-        Ok(stream) => stream,
-        Err(_error) => {
-            let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-            send_response(&mut client_conn, &response).await;
-            return;
+/// Why forward_request couldn't get a response from an upstream
+#[derive(Debug)]
+enum ForwardError {
+    /// The connection to the upstream broke before it responded, so it has probably gone away
+    LostUpstream,
+    /// Anything else, along with the status to send the client instead: 502 if the upstream sent
+    /// back something we couldn't use, or 504 if the request deadline passed first
+    Status(http::StatusCode),
+}
+
+/// Sends a request to an upstream and reads back its response. If that fails, the error is logged
+/// and returned.
+async fn forward_request(
+    state: &ProxyState,
+    upstream_conn: &mut stream::UpstreamStream,
+    request: &http::Request<Vec<u8>>,
+    request_deadline: Option<Instant>,
+    upstream_ip: &str,
+) -> Result<http::Response<Vec<u8>>, ForwardError> {
+    // Forward the request to the server
+    match with_deadline(
+        request_deadline,
+        request::write_to_stream(request, upstream_conn),
+    )
+    .await
+    {
+        Some(Ok(())) => {}
+        Some(Err(error)) => {
+            log::error!(
+                "Failed to send request to upstream {}: {}",
+                upstream_ip,
+                error
+            );
+            return Err(ForwardError::LostUpstream);
         }
+        None => {
+            log::warn!("Request deadline passed sending request to {}", upstream_ip);
+            return Err(ForwardError::Status(http::StatusCode::GATEWAY_TIMEOUT));
+        }
+    }
+    log::debug!("Forwarded request to server");
+
+    // Read the server's response
+    match with_deadline(
+        request_deadline,
+        response::read_from_stream_with_limit(
+            upstream_conn,
+            request.method(),
+            state.max_response_body_bytes,
+            state.oversized_response,
+        ),
+    )
+    .await
+    {
+        Some(Ok(response)) => {
+            if response.extensions().get::<response::Truncated>().is_some() {
+                log::warn!(
+                    "Response from {} was truncated to {} bytes",
+                    upstream_ip,
+                    state.max_response_body_bytes
+                );
+            }
+            Ok(response)
+        }
+        Some(Err(error)) => {
+            log::error!("Error reading response from server: {:?}", error);
+            match error {
+                response::Error::IncompleteResponse | response::Error::ConnectionError(_) => {
+                    Err(ForwardError::LostUpstream)
+                }
+                _ => Err(ForwardError::Status(http::StatusCode::BAD_GATEWAY)),
+            }
+        }
+        None => {
+            log::warn!(
+                "Request deadline passed waiting for {} to respond",
+                upstream_ip
+            );
+            Err(ForwardError::Status(http::StatusCode::GATEWAY_TIMEOUT))
+        }
+    }
+}
+
+/// Copies bytes in both directions between a client and an upstream that has switched protocols
+/// (e.g. to WebSocket), until both have hung up. When one side finishes sending, the other is told
+/// so by shutting down our side of its connection.
+async fn relay_upgraded_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    client_conn: &mut S,
+    upstream_conn: stream::UpstreamStream,
+) {
+    let (mut client_read, mut client_write) = tokio::io::split(client_conn);
+    let (mut upstream_read, mut upstream_write) = tokio::io::split(upstream_conn);
+    let client_to_upstream = async {
+        let copied = tokio::io::copy(&mut client_read, &mut upstream_write).await;
+        let _ = upstream_write.shutdown().await;
+        copied
+    };
+    let upstream_to_client = async {
+        let copied = tokio::io::copy(&mut upstream_read, &mut client_write).await;
+        let _ = client_write.shutdown().await;
+        copied
     };
-    let upstream_ip = client_conn.peer_addr().unwrap().ip().to_string();
+    match tokio::join!(client_to_upstream, upstream_to_client) {
+        (Ok(sent), Ok(received)) => log::debug!(
+            "Upgraded connection closed after sending {} bytes and receiving {}",
+            sent,
+            received
+        ),
+        (Err(error), _) | (_, Err(error)) => {
+            log::debug!("Upgraded connection closed with error: {}", error)
+        }
+    }
+}
+
+/// Logs a warning about a request if it took longer than the slow request threshold to handle.
+fn log_if_slow(
+    state: &ProxyState,
+    request: &http::Request<Vec<u8>>,
+    upstream_address: &str,
+    elapsed: time::Duration,
+) {
+    if let Some(threshold) = state.slow_request_threshold {
+        if elapsed > threshold {
+            log::warn!(
+                "Slow request: {} {} to upstream {} took {} ms",
+                request.method(),
+                request.uri().path(),
+                upstream_address,
+                elapsed.as_millis()
+            );
+        }
+    }
+}
+
+/// Proxies requests from a client until it hangs up. `client_conn` is either a plain TCP connection
+/// or a TLS stream on top of one, depending on whether TLS is enabled.
+async fn handle_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    mut client_conn: S,
+    client_addr: SocketAddr,
+    state: &ProxyState,
+) {
+    let client_ip = client_addr.ip().to_string();
+    log::info!("Connection received from {}", client_ip);
+    let connection_deadline = state
+        .max_connection_duration
+        .map(|duration| Instant::now() + duration);
+    let mut shutting_down = state.shutting_down.clone();
+
+    // We pick an upstream once the first request has been read, since the host or path may decide
+    // which upstreams can have it, and so that clients that never send anything don't tie up an
+    // upstream connection. (The upstream stops counting this connection as active once `upstream`
+    // is dropped, when we return.)
+    let mut upstream_pool = &state.upstreams;
+    let mut upstream: Option<UpstreamConnection> = None;
+    let mut upstream_address = String::new();
+    // Connection to use for the next request, along with whether it has been used before. This
+    // goes back to the pool after each response if it can, in which case we take another one when
+    // the next request comes in.
+    let mut next_upstream_conn = None;
+    let mut requests_read = 0;
 
     // The client may now send us one or more requests. Keep trying to read requests until the
     // client hangs up or we get an error.
     loop {
-        // Read a request from the client
-        let mut request = match request::read_from_stream(&mut client_conn).await {
+        // Read a request from the client. If the connection has a maximum lifetime, don't wait for
+        // one past the deadline.
+        let idle_deadline = state
+            .client_idle_timeout
+            .map(|timeout| Instant::now() + timeout);
+        let read_result = tokio::select! {
+            read_result = with_deadline(
+                connection_deadline,
+                request::read_from_stream_with_limits(
+                    &mut client_conn,
+                    state.max_request_body_bytes,
+                    state.max_request_line_bytes,
+                    state.max_header_bytes,
+                    idle_deadline,
+                ),
+            ) => read_result,
+            _ = shutting_down.changed() => {
+                log::info!("Closing idle connection from {} to shut down", client_ip);
+                return;
+            }
+        };
+        let read_result = match read_result {
+            Some(read_result) => read_result,
+            None => {
+                log::info!(
+                    "Closing connection from {}: maximum connection duration reached",
+                    client_ip
+                );
+                return;
+            }
+        };
+        let mut request = match read_result {
             Ok(request) => request,
             // Handle case where client closed connection and is no longer sending requests
             Err(request::Error::IncompleteRequest(0)) => {
                 log::debug!("Client finished sending requests. Shutting down connection");
                 return;
             }
+            // A client that hasn't started on another request isn't owed an explanation
+            Err(request::Error::IdleTimeout) => {
+                log::debug!("Closing idle connection from {}", client_ip);
+                return;
+            }
             // Handle I/O error in reading from the client
             Err(request::Error::ConnectionError(io_err)) => {
                 log::info!("Error reading request from client stream: {}", io_err);
@@ -278,22 +2851,197 @@ async fn handle_connection(mut client_conn: TcpStream, state: &ProxyState) {
             }
             Err(error) => {
                 log::debug!("Error parsing request: {:?}", error);
-                let response = response::make_http_error(match error {
+                let response_status = match error {
                     request::Error::IncompleteRequest(_)
                     | request::Error::MalformedRequest(_)
                     | request::Error::InvalidContentLength
                     | request::Error::ContentLengthMismatch => http::StatusCode::BAD_REQUEST,
                     request::Error::RequestBodyTooLarge => http::StatusCode::PAYLOAD_TOO_LARGE,
-                    request::Error::ConnectionError(_) => http::StatusCode::SERVICE_UNAVAILABLE,
-                });
-                send_response(&mut client_conn, &response).await;
+                    request::Error::HeadersTooLarge => {
+                        http::StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE
+                    }
+                    request::Error::RequestTimeout => http::StatusCode::REQUEST_TIMEOUT,
+                    request::Error::IdleTimeout | request::Error::ConnectionError(_) => {
+                        http::StatusCode::SERVICE_UNAVAILABLE
+                    }
+                };
+                let response = response::make_http_error(response_status);
+                // The upstream never saw this request, so don't count the error against it
+                send_response(&mut client_conn, &client_ip, response, state, None, None).await;
+                if response_status == http::StatusCode::PAYLOAD_TOO_LARGE
+                    || response_status == http::StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE
+                    || response_status == http::StatusCode::REQUEST_TIMEOUT
+                {
+                    // We didn't read all of the request, so we can't tell where the next one
+                    // starts
+                    return;
+                }
                 continue;
             }
         };
+        state.metrics.record_request();
+        let request_start = Instant::now();
+        request.extensions_mut().insert(ReceivedAt(request_start));
+        // From here on, the request deadline (if there is one) covers everything it takes to get a
+        // response: connecting to upstreams, sending them the request and reading back what they
+        // send, however many of them we try
+        let request_deadline = state
+            .request_deadline
+            .map(|duration| request_start + duration);
+        let client_version = request.version();
+        // Once the connection has made as many requests as it's allowed, this response is the last
+        // one it gets, just as if the client had asked to close it
+        requests_read += 1;
+        let last_allowed_request = state
+            .max_requests_per_connection
+            .map_or(false, |max_requests| requests_read >= max_requests);
+        let keep_alive = client_wants_keep_alive(&request) && !last_allowed_request;
+        if state.events_path.as_deref() == Some(request.uri().path())
+            && request.method() == http::Method::GET
+        {
+            log::info!("{} subscribed to upstream events", client_ip);
+            stream_events(&mut client_conn, state).await;
+            return;
+        }
+
+        if is_mutating(request.method()) && state.maintenance_mode.load(Ordering::SeqCst) {
+            log::info!(
+                "Rejecting {} request from {}: in maintenance mode",
+                request.method(),
+                client_ip
+            );
+            let mut response = response::make_http_error(http::StatusCode::SERVICE_UNAVAILABLE);
+            response.headers_mut().insert(
+                "retry-after",
+                http::HeaderValue::from(state.maintenance_retry_after),
+            );
+            set_connection_header(&mut response, keep_alive, client_version);
+            send_response(
+                &mut client_conn,
+                &client_ip,
+                response,
+                state,
+                None,
+                Some(&request),
+            )
+            .await;
+            if !keep_alive {
+                return;
+            }
+            continue;
+        }
+
+        // (client_ip is whoever connected to us, which may be a proxy in front of the real client.)
+        let real_client_ip = real_client_ip(state, &client_ip, &request);
+        if is_rate_limited(state, &real_client_ip) {
+            log::info!("Rate limiting request from {}", real_client_ip);
+            state.metrics.record_rate_limited();
+            let mut response = response::make_http_error(http::StatusCode::TOO_MANY_REQUESTS);
+            response.headers_mut().insert(
+                "retry-after",
+                http::HeaderValue::from(rate_limit_retry_after(state, &real_client_ip)),
+            );
+            set_connection_header(&mut response, keep_alive, client_version);
+            send_response(
+                &mut client_conn,
+                &client_ip,
+                response,
+                state,
+                None,
+                Some(&request),
+            )
+            .await;
+            if !keep_alive {
+                return;
+            }
+            continue;
+        }
+
+        // Answer from the cache if we can, without bothering an upstream
+        let cache_key = state
+            .response_cache
+            .as_ref()
+            .and_then(|_| cache::cache_key(&request));
+        if let (Some(cache), Some(key)) = (&state.response_cache, &cache_key) {
+            let cached = cache.lock().unwrap().get(key, std::time::Instant::now());
+            if let Some((mut response, age)) = cached {
+                log::info!(
+                    "{} -> cache: {}",
+                    real_client_ip,
+                    request::format_request_line(&request)
+                );
+                response
+                    .headers_mut()
+                    .insert("age", http::HeaderValue::from(age.as_secs()));
+                if state.enable_gzip && gzip::client_accepts_gzip(&request) {
+                    gzip::compress_response(&mut response);
+                }
+                set_connection_header(&mut response, keep_alive, client_version);
+                send_response(
+                    &mut client_conn,
+                    &client_ip,
+                    response,
+                    state,
+                    None,
+                    Some(&request),
+                )
+                .await;
+                if !keep_alive {
+                    return;
+                }
+                continue;
+            }
+        }
+
+        // Requests for a different host or path than the last one may need a different upstream
+        let pool = state.pool_for_request(&request);
+        if upstream.is_none() || !std::ptr::eq(pool, upstream_pool) {
+            match with_deadline(
+                request_deadline,
+                connect_to_upstream(state, pool, &client_ip),
+            )
+            .await
+            {
+                Some(Ok((upstream_conn, reused, connection))) => {
+                    // Let other clients use our idle connection to the old upstream
+                    if let Some((old_conn, _)) = next_upstream_conn.replace((upstream_conn, reused))
+                    {
+                        state.connection_pool.put(&upstream_address, old_conn);
+                    }
+                    upstream_address = connection.address.clone();
+                    upstream = Some(connection);
+                    upstream_pool = pool;
+                }
+                result => {
+                    let status = if result.is_none() {
+                        log::warn!(
+                            "Request deadline passed connecting to an upstream for {}",
+                            client_ip
+                        );
+                        http::StatusCode::GATEWAY_TIMEOUT
+                    } else {
+                        http::StatusCode::BAD_GATEWAY
+                    };
+                    let mut response = response::make_http_error(status);
+                    set_connection_header(&mut response, false, client_version);
+                    send_response(
+                        &mut client_conn,
+                        &client_ip,
+                        response,
+                        state,
+                        None,
+                        Some(&request),
+                    )
+                    .await;
+                    return;
+                }
+            }
+        }
+
         log::info!(
             "{} -> {}: {}",
-            client_ip,
-            upstream_ip,
+            real_client_ip,
+            upstream_address,
             request::format_request_line(&request)
         );
 
@@ -301,33 +3049,369 @@ async fn handle_connection(mut client_conn: TcpStream, state: &ProxyState) {
         // (We're the ones connecting directly to the upstream server, so without this header, the
         // upstream server will only know our IP, not the client's.)
         request::extend_header_value(&mut request, "x-forwarded-for", &client_ip);
+        // The client's Connection and Keep-Alive headers are about its connection to us. We want
+        // to keep our connection to the upstream open (so it can go back in the pool) no matter
+        // what the client does, so talk plain HTTP/1.1 to the upstream. The exception is a
+        // request to switch protocols (e.g. to WebSocket), which the upstream has to see.
+        let wants_upgrade = has_connection_option(request.headers(), "upgrade")
+            && request.headers().contains_key("upgrade");
+        request.headers_mut().remove("connection");
+        request.headers_mut().remove("keep-alive");
+        if wants_upgrade {
+            request
+                .headers_mut()
+                .insert("connection", http::HeaderValue::from_static("upgrade"));
+        } else {
+            request.headers_mut().remove("upgrade");
+        }
+        *request.version_mut() = http::Version::HTTP_11;
+        // Let the upstream know whether the client is talking to us over HTTPS, and which host it
+        // asked for, so that it can build URLs that work for the client. (Like X-Forwarded-For,
+        // these build up a list when requests go through more than one proxy.)
+        let proto = if state.tls_acceptor.is_some() {
+            "https"
+        } else {
+            "http"
+        };
+        request::extend_header_value(&mut request, "x-forwarded-proto", proto);
+        if let Some(host) = request
+            .headers()
+            .get("host")
+            .and_then(|host| host.to_str().ok())
+            .map(|host| host.to_string())
+        {
+            request::extend_header_value(&mut request, "x-forwarded-host", &host);
+        }
+        // Operators get the last word, even over the headers we added ourselves
+        state.request_headers.apply(request.headers_mut());
 
-        // Forward the request to the server
-        if let Err(error) = request::write_to_stream(&request, &mut upstream_conn).await {
-            log::error!(
-                "Failed to send request to upstream {}: {}",
-                upstream_ip,
-                error
-            );
-            let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-            send_response(&mut client_conn, &response).await;
-            return;
+        if let Some(mirror) = &state.mirror {
+            // A slow mirror shouldn't be able to pile up tasks and sockets without limit, so once
+            // enough copies are waiting on it, new ones are dropped
+            let slot = match &state.mirror_slots {
+                Some(slots) => slots.clone().try_acquire_owned().map(Some),
+                None => Ok(None),
+            };
+            match slot {
+                Ok(slot) => {
+                    tokio::spawn(mirror_request(
+                        mirror.clone(),
+                        request::clone_request(&request),
+                        state.upstream_connect_timeout,
+                        slot,
+                    ));
+                }
+                Err(_) => log::warn!(
+                    "Not mirroring request from {}: too many mirrored requests in flight",
+                    client_ip
+                ),
+            }
         }
-        log::debug!("Forwarded request to server");
 
-        // Read the server's response
-        let response = match response::read_from_stream(&mut upstream_conn, request.method()).await
-        {
-            Ok(response) => response,
-            Err(error) => {
-                log::error!("Error reading response from server: {:?}", error);
-                let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-                send_response(&mut client_conn, &response).await;
-                return;
+        // If the upstream fails an idempotent request, try it again on another one. Requests are
+        // read in full before we forward them, so there's always a complete body to replay. Each
+        // upstream gets the request at most once, so we run out of upstreams to try even if we
+        // never run out of retries.
+        let can_retry = is_idempotent(request.method());
+        let mut retries = 0;
+        let mut tried = HashSet::new();
+        // Whether the last attempt failed because we couldn't reach the upstream at all (as
+        // opposed to it sending back an error)
+        let mut lost_upstream;
+        let result = loop {
+            tried.insert(upstream_address.clone());
+            let upstream_conn = match next_upstream_conn.take() {
+                // A connection we held on to while the client was idle may have been closed by the
+                // upstream in the meantime
+                Some((stream, reused)) if !reused || pool::is_usable(&stream).await => {
+                    Some(Ok((stream, reused)))
+                }
+                _ => {
+                    with_deadline(
+                        request_deadline,
+                        open_upstream_stream(state, &upstream_address),
+                    )
+                    .await
+                }
+            };
+            let upstream_conn = match upstream_conn {
+                Some(Ok(upstream_conn)) => Some(upstream_conn),
+                Some(Err(error)) => {
+                    log::error!(
+                        "Failed to connect to upstream {}: {}",
+                        upstream_address,
+                        error
+                    );
+                    record_upstream_result(state, &upstream_address, false).await;
+                    record_passive_failure(state, &upstream_address).await;
+                    None
+                }
+                // Not getting through to the upstream in time isn't necessarily its fault, and
+                // there's no time left to try another one
+                None => {
+                    log::warn!("Request deadline passed connecting to {}", upstream_address);
+                    lost_upstream = false;
+                    break Err(http::StatusCode::GATEWAY_TIMEOUT);
+                }
+            };
+            let connected = upstream_conn.is_some();
+            lost_upstream = !connected;
+            let mut upstream_timed_out = false;
+            let result = match upstream_conn {
+                Some((mut upstream_conn, mut reused)) => {
+                    state.metrics.record_upstream_request(&upstream_address);
+                    let start = Instant::now();
+                    let upstream_deadline = state.upstream_timeout.map(|timeout| start + timeout);
+                    let result = loop {
+                        let result = with_deadline(
+                            upstream_deadline,
+                            forward_request(
+                                state,
+                                &mut upstream_conn,
+                                &request,
+                                request_deadline,
+                                &upstream_address,
+                            ),
+                        )
+                        .await;
+                        // An upstream closes connections that have been idle for too long, not ones
+                        // it's in the middle of answering, so if a reused connection turns out to
+                        // be closed, the upstream never saw the request. That says nothing about
+                        // the upstream's health, so try once more on a new connection before
+                        // counting it as a failure.
+                        if !reused || !matches!(result, Some(Err(ForwardError::LostUpstream))) {
+                            break result;
+                        }
+                        log::info!(
+                            "Connection to upstream {} had been closed; reconnecting",
+                            upstream_address
+                        );
+                        reused = false;
+                        match with_deadline(
+                            request_deadline,
+                            connect_upstream_stream(state, &upstream_address),
+                        )
+                        .await
+                        {
+                            Some(Ok(stream)) => upstream_conn = stream,
+                            Some(Err(error)) => {
+                                log::error!(
+                                    "Failed to connect to upstream {}: {}",
+                                    upstream_address,
+                                    error
+                                );
+                                break result;
+                            }
+                            None => {
+                                log::warn!(
+                                    "Request deadline passed reconnecting to {}",
+                                    upstream_address
+                                );
+                                break Some(Err(ForwardError::Status(
+                                    http::StatusCode::GATEWAY_TIMEOUT,
+                                )));
+                            }
+                        }
+                    };
+                    let result = match result {
+                        Some(Ok(response)) => Ok(response),
+                        Some(Err(ForwardError::LostUpstream)) => {
+                            lost_upstream = true;
+                            Err(http::StatusCode::BAD_GATEWAY)
+                        }
+                        Some(Err(ForwardError::Status(status))) => Err(status),
+                        None => {
+                            log::error!(
+                                "Upstream {} timed out handling a request",
+                                upstream_address
+                            );
+                            // A hung upstream will likely hang the next request too, so stop
+                            // using it until a health check says it's back
+                            mark_upstream_status(state, upstream_address.clone(), false).await;
+                            upstream_timed_out = true;
+                            Err(http::StatusCode::GATEWAY_TIMEOUT)
+                        }
+                    };
+                    if result.is_ok() {
+                        state
+                            .metrics
+                            .record_upstream_latency(&upstream_address, start.elapsed());
+                        record_passive_success(state, &upstream_address).await;
+                    }
+                    result.map(|response| (response, upstream_conn))
+                }
+                None => Err(http::StatusCode::BAD_GATEWAY),
+            };
+            // (Failing to connect has already been recorded.)
+            if connected {
+                let succeeded = match &result {
+                    Ok((response, _)) => Some(!response.status().is_server_error()),
+                    Err(_) if upstream_timed_out => Some(false),
+                    Err(status) if *status == http::StatusCode::BAD_GATEWAY => Some(false),
+                    // Running out of request deadline isn't the upstream's fault
+                    Err(_) => None,
+                };
+                if let Some(succeeded) = succeeded {
+                    record_upstream_result(state, &upstream_address, succeeded).await;
+                }
+            }
+            // Running out of time isn't the upstream's fault, and there's no time left to retry
+            let failed = match &result {
+                Ok((response, _)) => response.status().is_server_error(),
+                Err(status) => *status == http::StatusCode::BAD_GATEWAY,
+            };
+            // An upstream we couldn't reach has probably gone away, so count that towards marking
+            // it dead straight away. (Failing to connect has already been counted.)
+            if lost_upstream && connected {
+                record_passive_failure(state, &upstream_address).await;
+            }
+            // An idempotent request can go to another upstream instead, whether or not there are
+            // retries left, since this one never got to handle it. It still counts as a retry,
+            // though, so it leaves fewer for the upstreams that do get to handle it.
+            if !failed || !can_retry || (retries >= state.max_retries && !lost_upstream) {
+                break result;
+            }
+            retries += 1;
+            if !lost_upstream && connected {
+                record_passive_failure(state, &upstream_address).await;
+            }
+            match get_live_upstream(state, upstream_pool, &client_ip, &tried).await {
+                Some(next_upstream) => {
+                    if lost_upstream {
+                        log::warn!(
+                            "Resending request from {} to upstream {}",
+                            client_ip,
+                            next_upstream.address
+                        );
+                    } else {
+                        log::warn!(
+                            "Retrying request from {} on upstream {} (retry {} of {})",
+                            client_ip,
+                            next_upstream.address,
+                            retries,
+                            state.max_retries
+                        );
+                    }
+                    upstream_address = next_upstream.address.clone();
+                    upstream = Some(next_upstream);
+                }
+                None => break result,
+            }
+        };
+        let (mut response, upstream_conn) = match result {
+            Ok(result) => result,
+            Err(status) => {
+                let mut response = response::make_http_error(status);
+                // If we lost the upstream, the client's remaining requests can still go to another
+                // one. We only give up on the client once there are none left.
+                let failed_address = upstream_address.clone();
+                // (The next request connects to whichever upstream is picked for it then.)
+                let carry_on = lost_upstream
+                    && keep_alive
+                    && get_live_upstream(state, upstream_pool, &client_ip, &HashSet::new())
+                        .await
+                        .is_some();
+                if carry_on {
+                    upstream = None;
+                }
+                set_connection_header(&mut response, carry_on, client_version);
+                send_response(
+                    &mut client_conn,
+                    &client_ip,
+                    response,
+                    state,
+                    Some(&failed_address),
+                    Some(&request),
+                )
+                .await;
+                log_if_slow(state, &request, &failed_address, request_start.elapsed());
+                if !carry_on {
+                    return;
+                }
+                continue;
             }
         };
+        // Once the upstream has switched protocols, the connection isn't carrying HTTP anymore; all
+        // we can do is pass bytes back and forth until one side hangs up
+        if response.status() == http::StatusCode::SWITCHING_PROTOCOLS {
+            send_response(
+                &mut client_conn,
+                &client_ip,
+                response,
+                state,
+                Some(&upstream_address),
+                Some(&request),
+            )
+            .await;
+            log::info!(
+                "Connection from {} to {} switched protocols",
+                client_ip,
+                upstream_address
+            );
+            relay_upgraded_connection(&mut client_conn, upstream_conn).await;
+            return;
+        }
+
+        if let (Some(cache), Some(key)) = (&state.response_cache, cache_key) {
+            cache
+                .lock()
+                .unwrap()
+                .store(key, &response, std::time::Instant::now());
+        }
+
+        // Let other clients use the upstream connection while this one decides what to do next. If
+        // the pool is full, we hang on to the connection ourselves.
+        if is_upstream_reusable(request.method(), &response) {
+            next_upstream_conn = state
+                .connection_pool
+                .put(&upstream_address, upstream_conn)
+                .map(|upstream_conn| (upstream_conn, true));
+        }
+
+        if state.enable_gzip && gzip::client_accepts_gzip(&request) {
+            gzip::compress_response(&mut response);
+        }
+
+        // If the connection has outlived its maximum duration (or the client doesn't want to keep
+        // it), let the client know that this is the last response it will get on it
+        let connection_expired =
+            connection_deadline.map_or(false, |deadline| Instant::now() >= deadline);
+        set_connection_header(
+            &mut response,
+            keep_alive && !connection_expired,
+            client_version,
+        );
+
         // Forward the response to the client
-        send_response(&mut client_conn, &response).await;
+        send_response(
+            &mut client_conn,
+            &client_ip,
+            response,
+            state,
+            Some(&upstream_address),
+            Some(&request),
+        )
+        .await;
         log::debug!("Forwarded response to client");
+        log_if_slow(state, &request, &upstream_address, request_start.elapsed());
+
+        if connection_expired {
+            log::info!(
+                "Closing connection from {}: maximum connection duration reached",
+                client_ip
+            );
+            return;
+        }
+        if last_allowed_request {
+            log::info!(
+                "Closing connection from {}: maximum requests per connection reached",
+                client_ip
+            );
+            return;
+        }
+        if !keep_alive {
+            log::debug!("Client asked to close the connection. Shutting down connection");
+            return;
+        }
     }
 }
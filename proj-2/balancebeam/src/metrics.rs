@@ -0,0 +1,377 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// How many of the most recent responses the rolling 5xx rate is computed over
+pub const ERROR_RATE_WINDOW: usize = 20;
+/// Don't raise an alert until we've seen at least this many responses; otherwise a single 502
+/// right after startup would count as a 100% error rate
+pub const ERROR_RATE_MIN_SAMPLES: usize = 10;
+
+const STATUS_CLASSES: [&str; 5] = ["1xx", "2xx", "3xx", "4xx", "5xx"];
+
+/// Upper bounds (in seconds) of the upstream latency histogram's buckets. Anything slower only
+/// lands in the implicit +Inf bucket.
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Percentiles of each upstream's latency to report
+const LATENCY_QUANTILES: [f64; 3] = [0.5, 0.9, 0.99];
+
+/// Response counts, broken down by the first digit of the status code.
+#[derive(Debug, Default)]
+pub struct StatusClassCounts {
+    counts: [AtomicUsize; 5],
+}
+
+impl StatusClassCounts {
+    pub fn record(&self, status: http::StatusCode) {
+        let class = (status.as_u16() / 100) as usize;
+        if (1..=5).contains(&class) {
+            self.counts[class - 1].fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Returns how many responses in the given class (1 for 1xx, 2 for 2xx, etc.) have been
+    /// recorded.
+    pub fn get(&self, class: usize) -> usize {
+        self.counts[class - 1].load(Ordering::SeqCst)
+    }
+}
+
+/// How long upstreams took to respond, bucketed the way Prometheus histograms are.
+#[derive(Debug, Default)]
+struct LatencyHistogram {
+    /// Number of responses that took at most each of LATENCY_BUCKETS (but longer than the bucket
+    /// before). These get added up into Prometheus' cumulative counts when rendering.
+    buckets: [usize; LATENCY_BUCKETS.len()],
+    count: usize,
+    sum_seconds: f64,
+}
+
+impl LatencyHistogram {
+    fn record(&mut self, latency: Duration) {
+        let seconds = latency.as_secs_f64();
+        if let Some(bucket) = LATENCY_BUCKETS.iter().position(|bound| seconds <= *bound) {
+            self.buckets[bucket] += 1;
+        }
+        self.count += 1;
+        self.sum_seconds += seconds;
+    }
+
+    /// Estimates the latency (in seconds) that a fraction `quantile` of responses were at least as
+    /// fast as, assuming latencies are spread evenly within each bucket. (This is the same estimate
+    /// Prometheus' histogram_quantile makes.) Anything past the last bucket is reported as its
+    /// bound, and an empty histogram as 0.
+    fn quantile(&self, quantile: f64) -> f64 {
+        if self.count == 0 {
+            return 0.0;
+        }
+        let rank = quantile * self.count as f64;
+        let mut cumulative = 0;
+        let mut lower_bound = 0.0;
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(self.buckets.iter()) {
+            if *count > 0 && (cumulative + count) as f64 >= rank {
+                let fraction = (rank - cumulative as f64) / *count as f64;
+                return lower_bound + (bound - lower_bound) * fraction;
+            }
+            cumulative += count;
+            lower_bound = *bound;
+        }
+        lower_bound
+    }
+}
+
+/// The most recent responses, used to compute the rolling 5xx rate.
+#[derive(Debug, Default)]
+struct ErrorRateWindow {
+    /// Whether each response was a 5xx, oldest first
+    recent: VecDeque<bool>,
+    /// Whether the rate is currently above the alert threshold. Alerts only fire when the rate
+    /// crosses the threshold, not on every response while it stays above it.
+    alerting: bool,
+}
+
+/// Counters describing the responses balancebeam has sent to clients.
+#[derive(Debug)]
+pub struct Metrics {
+    /// Requests read from clients, whether or not they were proxied
+    requests: AtomicUsize,
+    /// Requests turned away with a 429 for exceeding the rate limit
+    rate_limited: AtomicUsize,
+    /// Requests sent to each upstream, including retries
+    upstream_requests: Mutex<HashMap<String, usize>>,
+    upstream_latency: Mutex<LatencyHistogram>,
+    /// The same, broken down by upstream
+    latency_by_upstream: Mutex<HashMap<String, LatencyHistogram>>,
+    /// Responses sent to clients, including errors generated by balancebeam itself
+    responses: StatusClassCounts,
+    /// Responses sent to clients, keyed by the upstream that was handling the request
+    upstream_responses: Mutex<HashMap<String, StatusClassCounts>>,
+    /// Fraction of 5xx responses (0-1) at which to log a warning, if any
+    error_rate_alert: Option<f64>,
+    error_rate_window: Mutex<ErrorRateWindow>,
+    /// Number of times the 5xx rate has crossed the alert threshold
+    error_rate_alerts: AtomicUsize,
+}
+
+impl Metrics {
+    pub fn new(error_rate_alert: Option<f64>) -> Metrics {
+        Metrics {
+            requests: AtomicUsize::new(0),
+            rate_limited: AtomicUsize::new(0),
+            upstream_requests: Mutex::new(HashMap::new()),
+            upstream_latency: Mutex::new(LatencyHistogram::default()),
+            latency_by_upstream: Mutex::new(HashMap::new()),
+            responses: StatusClassCounts::default(),
+            upstream_responses: Mutex::new(HashMap::new()),
+            error_rate_alert,
+            error_rate_window: Mutex::new(ErrorRateWindow::default()),
+            error_rate_alerts: AtomicUsize::new(0),
+        }
+    }
+
+    /// Records a request read from a client.
+    pub fn record_request(&self) {
+        self.requests.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Records a request turned away by the rate limiter.
+    pub fn record_rate_limited(&self) {
+        self.rate_limited.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Records a request being sent to `upstream`.
+    pub fn record_upstream_request(&self, upstream: &str) {
+        *self
+            .upstream_requests
+            .lock()
+            .unwrap()
+            .entry(upstream.to_string())
+            .or_insert(0) += 1;
+    }
+
+    /// Records how long `upstream` took to respond to a request.
+    pub fn record_upstream_latency(&self, upstream: &str, latency: Duration) {
+        self.upstream_latency.lock().unwrap().record(latency);
+        self.latency_by_upstream
+            .lock()
+            .unwrap()
+            .entry(upstream.to_string())
+            .or_default()
+            .record(latency);
+    }
+
+    /// Records a response sent to a client. `upstream` is the upstream that was handling the
+    /// request, or None if the request never made it to one.
+    pub fn record_response(&self, status: http::StatusCode, upstream: Option<&str>) {
+        self.responses.record(status);
+        if let Some(upstream) = upstream {
+            self.upstream_responses
+                .lock()
+                .unwrap()
+                .entry(upstream.to_string())
+                .or_default()
+                .record(status);
+        }
+        if let Some(threshold) = self.error_rate_alert {
+            self.update_error_rate(status.is_server_error(), threshold);
+        }
+    }
+
+    fn update_error_rate(&self, is_error: bool, threshold: f64) {
+        let mut window = self.error_rate_window.lock().unwrap();
+        window.recent.push_back(is_error);
+        if window.recent.len() > ERROR_RATE_WINDOW {
+            window.recent.pop_front();
+        }
+        if window.recent.len() < ERROR_RATE_MIN_SAMPLES {
+            return;
+        }
+        let errors = window.recent.iter().filter(|is_error| **is_error).count();
+        let error_rate = errors as f64 / window.recent.len() as f64;
+        if error_rate >= threshold && !window.alerting {
+            window.alerting = true;
+            self.error_rate_alerts.fetch_add(1, Ordering::SeqCst);
+            log::warn!(
+                "5xx rate is {:.0}% over the last {} responses (alert threshold is {:.0}%)",
+                error_rate * 100.0,
+                window.recent.len(),
+                threshold * 100.0
+            );
+        } else if error_rate < threshold && window.alerting {
+            window.alerting = false;
+            log::info!(
+                "5xx rate has recovered to {:.0}% over the last {} responses",
+                error_rate * 100.0,
+                window.recent.len()
+            );
+        }
+    }
+
+    /// Renders all of the counters in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        out.push_str("# TYPE balancebeam_requests_total counter\n");
+        writeln!(
+            out,
+            "balancebeam_requests_total {}",
+            self.requests.load(Ordering::SeqCst)
+        )
+        .unwrap();
+        out.push_str("# TYPE balancebeam_rate_limited_requests_total counter\n");
+        writeln!(
+            out,
+            "balancebeam_rate_limited_requests_total {}",
+            self.rate_limited.load(Ordering::SeqCst)
+        )
+        .unwrap();
+        out.push_str("# TYPE balancebeam_upstream_requests_total counter\n");
+        let upstream_requests = self.upstream_requests.lock().unwrap();
+        let mut upstreams: Vec<&String> = upstream_requests.keys().collect();
+        upstreams.sort();
+        for upstream in upstreams {
+            writeln!(
+                out,
+                "balancebeam_upstream_requests_total{{upstream=\"{}\"}} {}",
+                upstream, upstream_requests[upstream]
+            )
+            .unwrap();
+        }
+        drop(upstream_requests);
+        self.render_latency(&mut out);
+        out.push_str("# TYPE balancebeam_responses_total counter\n");
+        for (i, class) in STATUS_CLASSES.iter().enumerate() {
+            writeln!(
+                out,
+                "balancebeam_responses_total{{class=\"{}\"}} {}",
+                class,
+                self.responses.get(i + 1)
+            )
+            .unwrap();
+        }
+        out.push_str("# TYPE balancebeam_upstream_responses_total counter\n");
+        let upstream_responses = self.upstream_responses.lock().unwrap();
+        let mut upstreams: Vec<&String> = upstream_responses.keys().collect();
+        upstreams.sort();
+        for upstream in upstreams {
+            for (i, class) in STATUS_CLASSES.iter().enumerate() {
+                writeln!(
+                    out,
+                    "balancebeam_upstream_responses_total{{upstream=\"{}\",class=\"{}\"}} {}",
+                    upstream,
+                    class,
+                    upstream_responses[upstream].get(i + 1)
+                )
+                .unwrap();
+            }
+        }
+        if self.error_rate_alert.is_some() {
+            out.push_str("# TYPE balancebeam_error_rate_alerts_total counter\n");
+            writeln!(
+                out,
+                "balancebeam_error_rate_alerts_total {}",
+                self.error_rate_alerts.load(Ordering::SeqCst)
+            )
+            .unwrap();
+        }
+        out
+    }
+
+    fn render_latency(&self, out: &mut String) {
+        let histogram = self.upstream_latency.lock().unwrap();
+        out.push_str("# TYPE balancebeam_upstream_latency_seconds histogram\n");
+        let mut cumulative = 0;
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets.iter()) {
+            cumulative += count;
+            writeln!(
+                out,
+                "balancebeam_upstream_latency_seconds_bucket{{le=\"{}\"}} {}",
+                bound, cumulative
+            )
+            .unwrap();
+        }
+        writeln!(
+            out,
+            "balancebeam_upstream_latency_seconds_bucket{{le=\"+Inf\"}} {}",
+            histogram.count
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "balancebeam_upstream_latency_seconds_sum {}",
+            histogram.sum_seconds
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "balancebeam_upstream_latency_seconds_count {}",
+            histogram.count
+        )
+        .unwrap();
+        drop(histogram);
+
+        // Per-upstream latencies are reported as percentiles, so that a slow upstream stands out
+        // without having to compare histograms
+        let latency_by_upstream = self.latency_by_upstream.lock().unwrap();
+        out.push_str("# TYPE balancebeam_upstream_latency_by_upstream_seconds summary\n");
+        let mut upstreams: Vec<&String> = latency_by_upstream.keys().collect();
+        upstreams.sort();
+        for upstream in upstreams {
+            let histogram = &latency_by_upstream[upstream];
+            for quantile in LATENCY_QUANTILES.iter() {
+                writeln!(
+                    out,
+                    "balancebeam_upstream_latency_by_upstream_seconds{{upstream=\"{}\",\
+                     quantile=\"{}\"}} {}",
+                    upstream,
+                    quantile,
+                    histogram.quantile(*quantile)
+                )
+                .unwrap();
+            }
+            writeln!(
+                out,
+                "balancebeam_upstream_latency_by_upstream_seconds_sum{{upstream=\"{}\"}} {}",
+                upstream, histogram.sum_seconds
+            )
+            .unwrap();
+            writeln!(
+                out,
+                "balancebeam_upstream_latency_by_upstream_seconds_count{{upstream=\"{}\"}} {}",
+                upstream, histogram.count
+            )
+            .unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_latency_quantiles() {
+        let mut histogram = LatencyHistogram::default();
+        for _ in 0..50 {
+            histogram.record(Duration::from_millis(3));
+        }
+        for _ in 0..40 {
+            histogram.record(Duration::from_millis(80));
+        }
+        for _ in 0..10 {
+            histogram.record(Duration::from_secs(30));
+        }
+        // Half of the responses are in the first bucket, so the median is at its upper bound
+        assert!((histogram.quantile(0.5) - 0.005).abs() < 1e-9);
+        // 90% of responses took at most 0.1s, and the 50-90% range all falls in the 0.05-0.1 bucket
+        assert!((histogram.quantile(0.7) - 0.075).abs() < 1e-9);
+        assert!((histogram.quantile(0.9) - 0.1).abs() < 1e-9);
+        // The slowest responses are past the last bucket
+        assert!((histogram.quantile(0.99) - 10.0).abs() < 1e-9);
+        assert_eq!(LatencyHistogram::default().quantile(0.5), 0.0);
+    }
+}
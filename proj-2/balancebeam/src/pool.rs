@@ -0,0 +1,73 @@
+use crate::stream::UpstreamStream;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tokio::time;
+
+/// Idle keep-alive connections to upstreams, keyed by upstream address, that can be reused for
+/// later requests instead of opening a new connection each time.
+#[derive(Debug)]
+pub struct ConnectionPool {
+    /// Most idle connections we'll hold on to for any one upstream (0 disables pooling)
+    max_idle_per_upstream: usize,
+    idle: Mutex<HashMap<String, Vec<UpstreamStream>>>,
+}
+
+/// Returns true if an idle connection looks like it can still be used: the upstream hasn't closed
+/// it, and hasn't sent anything we weren't expecting.
+pub async fn is_usable(stream: &UpstreamStream) -> bool {
+    let mut buffer = [0_u8; 1];
+    match stream {
+        // A zero timeout still polls peek() once, so this never waits. If there's nothing to read
+        // yet, the connection is idle and open, which is what we want.
+        UpstreamStream::Tcp(stream) => {
+            time::timeout(time::Duration::from_secs(0), stream.peek(&mut buffer))
+                .await
+                .is_err()
+        }
+        // Unix streams can't peek, but if there's anything to read, the connection can't be used
+        // anyway. try_read doesn't wait either.
+        UpstreamStream::Unix(stream) => matches!(
+            stream.try_read(&mut buffer),
+            Err(err) if err.kind() == std::io::ErrorKind::WouldBlock
+        ),
+    }
+}
+
+impl ConnectionPool {
+    pub fn new(max_idle_per_upstream: usize) -> ConnectionPool {
+        ConnectionPool {
+            max_idle_per_upstream,
+            idle: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes an idle connection to `address` out of the pool, if there's a usable one. Connections
+    /// that have gone stale are thrown away.
+    pub async fn take(&self, address: &str) -> Option<UpstreamStream> {
+        loop {
+            let stream = self.idle.lock().unwrap().get_mut(address)?.pop()?;
+            if is_usable(&stream).await {
+                return Some(stream);
+            }
+            log::debug!("Discarding stale pooled connection to {}", address);
+        }
+    }
+
+    /// Closes every idle connection to `address`, e.g. because it's no longer an upstream.
+    pub fn forget(&self, address: &str) {
+        self.idle.lock().unwrap().remove(address);
+    }
+
+    /// Returns a connection to the pool once the response on it has been fully read. If the pool
+    /// for `address` is already full, the connection is handed back instead.
+    pub fn put(&self, address: &str, stream: UpstreamStream) -> Option<UpstreamStream> {
+        let mut idle = self.idle.lock().unwrap();
+        let streams = idle.entry(address.to_string()).or_default();
+        if streams.len() < self.max_idle_per_upstream {
+            streams.push(stream);
+            None
+        } else {
+            Some(stream)
+        }
+    }
+}
@@ -0,0 +1,110 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+/// Limits how many requests each client can make in any window of time (a minute, for
+/// --max-requests-per-minute). Rather than counting requests in fixed windows, which would let a
+/// client send twice the limit right around the boundary between two windows, we remember when
+/// each client's recent requests were made.
+#[derive(Debug)]
+pub struct RateLimiter {
+    limit: usize,
+    window: Duration,
+    /// When each client's requests within the last window were made, oldest first. A client never
+    /// has more than `limit` of these, because requests that get turned away aren't recorded.
+    requests: HashMap<String, VecDeque<Instant>>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: usize, window: Duration) -> RateLimiter {
+        RateLimiter {
+            limit,
+            window,
+            requests: HashMap::new(),
+        }
+    }
+
+    /// Records a request from `client` at `now`, returning false (and not recording it) if the
+    /// client has already made `limit` requests within the window before `now`.
+    pub fn allow(&mut self, client: &str, now: Instant) -> bool {
+        let window = self.window;
+        let times = self.requests.entry(client.to_string()).or_default();
+        while times
+            .front()
+            .map_or(false, |time| now.duration_since(*time) >= window)
+        {
+            times.pop_front();
+        }
+        if times.len() >= self.limit {
+            return false;
+        }
+        times.push_back(now);
+        true
+    }
+
+    /// Returns how long `client` has to wait after `now` before another request would be
+    /// allowed.
+    pub fn retry_after(&self, client: &str, now: Instant) -> Duration {
+        match self.requests.get(client) {
+            // Once the oldest request leaves the window, there's room for another one
+            Some(times) if times.len() >= self.limit => {
+                (times[0] + self.window).saturating_duration_since(now)
+            }
+            _ => Duration::from_secs(0),
+        }
+    }
+
+    /// Forgets clients that haven't made any requests within the window before `now`, so that
+    /// memory use doesn't keep growing with every client we've ever seen.
+    pub fn prune(&mut self, now: Instant) {
+        let window = self.window;
+        self.requests.retain(|_, times| {
+            times
+                .back()
+                .map_or(false, |time| now.duration_since(*time) < window)
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_limit_holds_across_window_boundary() {
+        let start = Instant::now();
+        let mut limiter = RateLimiter::new(3, Duration::from_secs(60));
+        assert!(limiter.allow("client", start));
+        // A burst at the end of one minute...
+        for _ in 0..2 {
+            assert!(limiter.allow("client", start + Duration::from_secs(59)));
+        }
+        assert!(!limiter.allow("client", start + Duration::from_secs(59)));
+        // ...leaves no room at the start of the next one, except for the request that was made a
+        // whole window ago
+        assert!(limiter.allow("client", start + Duration::from_secs(61)));
+        assert!(!limiter.allow("client", start + Duration::from_secs(61)));
+        assert_eq!(
+            limiter.retry_after("client", start + Duration::from_secs(61)),
+            Duration::from_secs(58)
+        );
+        assert!(!limiter.allow("client", start + Duration::from_secs(118)));
+        assert!(limiter.allow("client", start + Duration::from_secs(119)));
+        // Other clients have their own limits
+        assert!(limiter.allow("other", start + Duration::from_secs(61)));
+        assert_eq!(
+            limiter.retry_after("other", start + Duration::from_secs(61)),
+            Duration::from_secs(0)
+        );
+    }
+
+    #[test]
+    fn test_prune_forgets_idle_clients() {
+        let start = Instant::now();
+        let mut limiter = RateLimiter::new(2, Duration::from_secs(60));
+        limiter.allow("idle", start);
+        limiter.allow("active", start + Duration::from_secs(30));
+        limiter.prune(start + Duration::from_secs(60));
+        assert!(!limiter.requests.contains_key("idle"));
+        assert!(limiter.requests.contains_key("active"));
+    }
+}
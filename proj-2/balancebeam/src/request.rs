@@ -1,9 +1,13 @@
+use crate::bufpool;
 use std::cmp::min;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::Instant;
 
-const MAX_HEADERS_SIZE: usize = 8000;
-const MAX_BODY_SIZE: usize = 10000000;
+/// Default limits on the size of the request line and of the headers that follow it
+pub const MAX_REQUEST_LINE_SIZE: usize = 8000;
+pub const MAX_HEADERS_SIZE: usize = 8000;
+/// Default limit on the size of request bodies
+pub const MAX_BODY_SIZE: usize = 10000000;
 const MAX_NUM_HEADERS: usize = 32;
 
 #[derive(Debug)]
@@ -17,8 +21,17 @@ pub enum Error {
     InvalidContentLength,
     /// The Content-Length header does not match the size of the request body that was sent
     ContentLengthMismatch,
-    /// The request body is bigger than MAX_BODY_SIZE
+    /// The request body is bigger than the limit passed to read_from_stream
     RequestBodyTooLarge,
+    /// The request line or headers are longer than the limits passed to
+    /// read_from_stream_with_limits
+    HeadersTooLarge,
+    /// Client didn't start sending a request before the deadline passed to
+    /// read_from_stream_with_limits
+    IdleTimeout,
+    /// Client started sending a request, but didn't finish before the deadline passed to
+    /// read_from_stream_with_limits
+    RequestTimeout,
     /// Encountered an I/O error when reading/writing a TcpStream
     ConnectionError(std::io::Error),
 }
@@ -28,7 +41,7 @@ pub enum Error {
 /// Err(Error) if Content-Length is present but invalid.
 ///
 /// You won't need to touch this function.
-fn get_content_length(request: &http::Request<Vec<u8>>) -> Result<Option<usize>, Error> {
+pub fn get_content_length(request: &http::Request<Vec<u8>>) -> Result<Option<usize>, Error> {
     // Look for content-length header
     if let Some(header_value) = request.headers().get("content-length") {
         // If it exists, parse it as a usize (or return InvalidContentLength if it can't be parsed as such)
@@ -66,6 +79,19 @@ pub fn extend_header_value(
         .insert(name, http::HeaderValue::from_bytes(&new_value).unwrap());
 }
 
+/// Makes a copy of a request (http::Request doesn't implement Clone). This is used to replay the
+/// request against the mirror upstream.
+pub fn clone_request(request: &http::Request<Vec<u8>>) -> http::Request<Vec<u8>> {
+    let mut builder = http::Request::builder()
+        .method(request.method().clone())
+        .uri(request.uri().clone())
+        .version(request.version());
+    for (header_name, header_value) in request.headers() {
+        builder = builder.header(header_name, header_value);
+    }
+    builder.body(request.body().clone()).unwrap()
+}
+
 /// Attempts to parse the data in the supplied buffer as an HTTP request. Returns one of the
 /// following:
 ///
@@ -74,7 +100,7 @@ pub fn extend_header_value(
 /// * If there is data in the buffer that is definitely not a valid HTTP request, returns Err(Error)
 ///
 /// You won't need to touch this function.
-fn parse_request(buffer: &[u8]) -> Result<Option<(http::Request<Vec<u8>>, usize)>, Error> {
+pub fn parse_request(buffer: &[u8]) -> Result<Option<(http::Request<Vec<u8>>, usize)>, Error> {
     let mut headers = [httparse::EMPTY_HEADER; MAX_NUM_HEADERS];
     let mut req = httparse::Request::new(&mut headers);
     let res = req
@@ -85,7 +111,11 @@ fn parse_request(buffer: &[u8]) -> Result<Option<(http::Request<Vec<u8>>, usize)
         let mut request = http::Request::builder()
             .method(req.method.unwrap())
             .uri(req.path.unwrap())
-            .version(http::Version::HTTP_11);
+            .version(if req.version == Some(0) {
+                http::Version::HTTP_10
+            } else {
+                http::Version::HTTP_11
+            });
         for header in req.headers {
             request = request.header(header.name, header.value);
         }
@@ -96,33 +126,106 @@ fn parse_request(buffer: &[u8]) -> Result<Option<(http::Request<Vec<u8>>, usize)
     }
 }
 
+/// Reads some bytes from the stream into `buffer`, giving up once `deadline` (if there is one)
+/// passes. `started` says whether we've already read part of the request, which decides which
+/// timeout error that is.
+pub async fn read_until<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    buffer: &mut [u8],
+    deadline: Option<Instant>,
+    started: bool,
+) -> Result<usize, Error> {
+    let result = match deadline {
+        Some(deadline) => match tokio::time::timeout_at(deadline, stream.read(buffer)).await {
+            Ok(result) => result,
+            Err(_) if started => return Err(Error::RequestTimeout),
+            Err(_) => return Err(Error::IdleTimeout),
+        },
+        None => stream.read(buffer).await,
+    };
+    result.or_else(|err| Err(Error::ConnectionError(err)))
+}
+
+/// Returns Error::HeadersTooLarge if the request line or headers at the start of `buffer` are
+/// longer than allowed. `headers_len` is the length of the whole request line and header block if
+/// we've read all of it, or None if `buffer` only holds the beginning of it.
+pub fn check_header_sizes(
+    buffer: &[u8],
+    headers_len: Option<usize>,
+    max_request_line_size: usize,
+    max_headers_size: usize,
+) -> Result<(), Error> {
+    let line_end = buffer.iter().position(|byte| *byte == b'\n');
+    let line_len = match line_end {
+        Some(end) if end > 0 && buffer[end - 1] == b'\r' => end - 1,
+        Some(end) => end,
+        None => buffer.len(),
+    };
+    if line_len > max_request_line_size {
+        return Err(Error::HeadersTooLarge);
+    }
+    // The headers are everything after the request line, up to (but not including) the blank line
+    // that ends them. If we haven't seen that yet, part of it may be at the end of the buffer.
+    let headers_start = line_end.map_or(buffer.len(), |end| end + 1);
+    let headers_size = match headers_len {
+        Some(headers_len) => headers_len.saturating_sub(headers_start + 2),
+        None => buffer.len().saturating_sub(headers_start + 2),
+    };
+    if headers_size > max_headers_size {
+        return Err(Error::HeadersTooLarge);
+    }
+    Ok(())
+}
+
 /// Reads an HTTP request from the provided stream, waiting until a complete set of headers is sent.
 /// This function only reads the request line and headers; the read_body function can subsequently
 /// be called in order to read the request body (for a POST request).
 ///
-/// Returns Ok(http::Request) if a valid request is received, or Error if not.
+/// Returns Ok(http::Request) if a valid request is received, or Error if not. The sizes of the
+/// request line and headers are checked as they come in, so we never buffer much more than
+/// `max_request_line_size` and `max_headers_size` allow.
 ///
 /// You will need to modify this function in Milestone 2.
-async fn read_headers(stream: &mut TcpStream) -> Result<http::Request<Vec<u8>>, Error> {
+async fn read_headers<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    max_request_line_size: usize,
+    max_headers_size: usize,
+    deadline: Option<Instant>,
+) -> Result<http::Request<Vec<u8>>, Error> {
     // Try reading the headers from the request. We may not receive all the headers in one shot
     // (e.g. we might receive the first few bytes of a request, and then the rest follows later).
     // Try parsing repeatedly until we read a valid HTTP request
-    let mut request_buffer = [0_u8; MAX_HEADERS_SIZE];
-    let mut bytes_read = 0;
+    // (Any buffer this big must break one of the limits, counting line endings, so there's always
+    // room to read more until it does.)
+    let max_buffer_size = max_request_line_size + max_headers_size + 5;
+    let mut request_buffer = bufpool::take();
+    let mut chunk = [0_u8; 1024];
     loop {
-        // Read bytes from the connection into the buffer, starting at position bytes_read
-        let new_bytes = stream
-            .read(&mut request_buffer[bytes_read..])
-            .await
-            .or_else(|err| Err(Error::ConnectionError(err)))?;
+        // Read bytes from the connection into the end of the buffer
+        let chunk_size = min(chunk.len(), max_buffer_size - request_buffer.len());
+        let new_bytes = read_until(
+            stream,
+            &mut chunk[..chunk_size],
+            deadline,
+            !request_buffer.is_empty(),
+        )
+        .await?;
         if new_bytes == 0 {
             // We didn't manage to read a complete request
-            return Err(Error::IncompleteRequest(bytes_read));
+            return Err(Error::IncompleteRequest(request_buffer.len()));
         }
-        bytes_read += new_bytes;
+        request_buffer.extend_from_slice(&chunk[..new_bytes]);
+        let bytes_read = request_buffer.len();
 
         // See if we've read a valid request so far
-        if let Some((mut request, headers_len)) = parse_request(&request_buffer[..bytes_read])? {
+        let parsed = parse_request(&request_buffer)?;
+        check_header_sizes(
+            &request_buffer,
+            parsed.as_ref().map(|(_, headers_len)| *headers_len),
+            max_request_line_size,
+            max_headers_size,
+        )?;
+        if let Some((mut request, headers_len)) = parsed {
             // We've read a complete set of headers. However, if this was a POST request, a request
             // body might have been included as well, and we might have read part of the body out of
             // the stream into header_buffer. We need to add those bytes to the Request body so that
@@ -140,20 +243,19 @@ async fn read_headers(stream: &mut TcpStream) -> Result<http::Request<Vec<u8>>,
 /// returns Ok(()) if successful, or Err(Error) if Content-Length bytes couldn't be read.
 ///
 /// You will need to modify this function in Milestone 2.
-async fn read_body(
-    stream: &mut TcpStream,
+async fn read_body<S: AsyncRead + Unpin>(
+    stream: &mut S,
     request: &mut http::Request<Vec<u8>>,
     content_length: usize,
+    deadline: Option<Instant>,
 ) -> Result<(), Error> {
+    // Read up to 512 bytes at a time. (If the client only sent a small body, then only read that
+    // much.)
+    let mut buffer = bufpool::take();
+    buffer.resize(min(512, content_length), 0);
     // Keep reading data until we read the full body length, or until we hit an error.
     while request.body().len() < content_length {
-        // Read up to 512 bytes at a time. (If the client only sent a small body, then only allocate
-        // space to read that body.)
-        let mut buffer = vec![0_u8; min(512, content_length)];
-        let bytes_read = stream
-            .read(&mut buffer)
-            .await
-            .or_else(|err| Err(Error::ConnectionError(err)))?;
+        let bytes_read = read_until(stream, &mut buffer, deadline, true).await?;
 
         // Make sure the client is still sending us bytes
         if bytes_read == 0 {
@@ -181,18 +283,43 @@ async fn read_body(
 }
 
 /// This function reads and returns an HTTP request from a stream, returning an Error if the client
-/// closes the connection prematurely or sends an invalid request.
+/// closes the connection prematurely or sends an invalid request. Requests with bodies bigger than
+/// `max_body_size` (if there is a limit) are rejected before any of the body is read.
 ///
 /// You will need to modify this function in Milestone 2.
-pub async fn read_from_stream(stream: &mut TcpStream) -> Result<http::Request<Vec<u8>>, Error> {
+pub async fn read_from_stream<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    max_body_size: Option<usize>,
+) -> Result<http::Request<Vec<u8>>, Error> {
+    read_from_stream_with_limits(
+        stream,
+        max_body_size,
+        MAX_REQUEST_LINE_SIZE,
+        MAX_HEADERS_SIZE,
+        None,
+    )
+    .await
+}
+
+/// Like read_from_stream, but with custom limits on the sizes of the request line and headers.
+/// Requests that go over them are rejected with Error::HeadersTooLarge as soon as we've read enough
+/// to tell. If there's a `deadline`, the whole request has to arrive before it.
+pub async fn read_from_stream_with_limits<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    max_body_size: Option<usize>,
+    max_request_line_size: usize,
+    max_headers_size: usize,
+    deadline: Option<Instant>,
+) -> Result<http::Request<Vec<u8>>, Error> {
     // Read headers
-    let mut request = read_headers(stream).await?;
+    let mut request =
+        read_headers(stream, max_request_line_size, max_headers_size, deadline).await?;
     // Read body if the client supplied the Content-Length header (which it does for POST requests)
     if let Some(content_length) = get_content_length(&request)? {
-        if content_length > MAX_BODY_SIZE {
+        if max_body_size.map_or(false, |max_body_size| content_length > max_body_size) {
             return Err(Error::RequestBodyTooLarge);
         } else {
-            read_body(stream, &mut request, content_length).await?;
+            read_body(stream, &mut request, content_length, deadline).await?;
         }
     }
     Ok(request)
@@ -201,25 +328,27 @@ pub async fn read_from_stream(stream: &mut TcpStream) -> Result<http::Request<Ve
 /// This function serializes a request to bytes and writes those bytes to the provided stream.
 ///
 /// You will need to modify this function in Milestone 2.
-pub async fn write_to_stream(
+pub async fn write_to_stream<S: AsyncWrite + Unpin>(
     request: &http::Request<Vec<u8>>,
-    stream: &mut TcpStream,
+    stream: &mut S,
 ) -> Result<(), std::io::Error> {
     stream
-        .write(&format_request_line(request).into_bytes())
+        .write_all(&format_request_line(request).into_bytes())
         .await?;
-    stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
+    stream.write_all(&['\r' as u8, '\n' as u8]).await?; // \r\n
     for (header_name, header_value) in request.headers() {
         stream
-            .write(&format!("{}: ", header_name).as_bytes())
+            .write_all(&format!("{}: ", header_name).as_bytes())
             .await?;
-        stream.write(header_value.as_bytes()).await?;
-        stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
+        stream.write_all(header_value.as_bytes()).await?;
+        stream.write_all(&['\r' as u8, '\n' as u8]).await?; // \r\n
     }
-    stream.write(&['\r' as u8, '\n' as u8]).await?;
+    stream.write_all(&['\r' as u8, '\n' as u8]).await?;
     if request.body().len() > 0 {
-        stream.write(request.body()).await?;
+        stream.write_all(request.body()).await?;
     }
+    // Make sure nothing is left sitting in a buffer (e.g. in a TLS stream)
+    stream.flush().await?;
     Ok(())
 }
 
@@ -1,10 +1,23 @@
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 const MAX_HEADERS_SIZE: usize = 8000;
-const MAX_BODY_SIZE: usize = 10000000;
+pub const MAX_BODY_SIZE: usize = 10000000;
 const MAX_NUM_HEADERS: usize = 32;
 
+/// What to do with a response body that's bigger than we're willing to read
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OversizedBody {
+    /// Fail with Error::ResponseBodyTooLarge
+    Reject,
+    /// Cut the body off at the limit, and mark the response with the Truncated extension
+    Truncate,
+}
+
+/// Marks (as an http::Extensions entry) a response whose body was cut off. The rest of the body is
+/// still waiting to be read on the connection, so the connection can't be used again.
+#[derive(Debug, Clone, Copy)]
+pub struct Truncated;
+
 #[derive(Debug)]
 pub enum Error {
     /// Client hung up before sending a complete request
@@ -15,7 +28,7 @@ pub enum Error {
     InvalidContentLength,
     /// The Content-Length header does not match the size of the request body that was sent
     ContentLengthMismatch,
-    /// The request body is bigger than MAX_BODY_SIZE
+    /// The response body is bigger than the limit it was read with (MAX_BODY_SIZE by default)
     ResponseBodyTooLarge,
     /// Encountered an I/O error when reading/writing a TcpStream
     ConnectionError(std::io::Error),
@@ -80,7 +93,9 @@ fn parse_response(buffer: &[u8]) -> Result<Option<(http::Response<Vec<u8>>, usiz
 /// Returns Ok(http::Response) if a valid response is received, or Error if not.
 ///
 /// You will need to modify this function in Milestone 2.
-async fn read_headers(stream: &mut TcpStream) -> Result<http::Response<Vec<u8>>, Error> {
+async fn read_headers<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> Result<http::Response<Vec<u8>>, Error> {
     // Try reading the headers from the response. We may not receive all the headers in one shot
     // (e.g. we might receive the first few bytes of a response, and then the rest follows later).
     // Try parsing repeatedly until we read a valid HTTP response
@@ -115,9 +130,11 @@ async fn read_headers(stream: &mut TcpStream) -> Result<http::Response<Vec<u8>>,
 /// present, it reads that many bytes; otherwise, it reads bytes until the connection is closed.
 ///
 /// You will need to modify this function in Milestone 2.
-async fn read_body(
-    stream: &mut TcpStream,
+async fn read_body<S: AsyncRead + Unpin>(
+    stream: &mut S,
     response: &mut http::Response<Vec<u8>>,
+    max_body_size: usize,
+    oversized: OversizedBody,
 ) -> Result<(), Error> {
     // The response may or may not supply a Content-Length header. If it provides the header, then
     // we want to read that number of bytes; if it does not, we want to keep reading bytes until
@@ -149,8 +166,13 @@ async fn read_body(
         }
 
         // Make sure server doesn't send more bytes than we allow
-        if response.body().len() + bytes_read > MAX_BODY_SIZE {
-            return Err(Error::ResponseBodyTooLarge);
+        if response.body().len() + bytes_read > max_body_size {
+            if oversized == OversizedBody::Reject {
+                return Err(Error::ResponseBodyTooLarge);
+            }
+            let room = max_body_size - response.body().len();
+            response.body_mut().extend_from_slice(&buffer[..room]);
+            break;
         }
 
         // Append received bytes to the response body
@@ -163,9 +185,20 @@ async fn read_body(
 /// closes the connection prematurely or sends an invalid response.
 ///
 /// You will need to modify this function in Milestone 2.
-pub async fn read_from_stream(
-    stream: &mut TcpStream,
+pub async fn read_from_stream<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    request_method: &http::Method,
+) -> Result<http::Response<Vec<u8>>, Error> {
+    read_from_stream_with_limit(stream, request_method, MAX_BODY_SIZE, OversizedBody::Reject).await
+}
+
+/// Like read_from_stream, but with a custom limit on the size of the response body. If the body is
+/// truncated (rather than rejected), its Content-Length is updated to match what was kept.
+pub async fn read_from_stream_with_limit<S: AsyncRead + Unpin>(
+    stream: &mut S,
     request_method: &http::Method,
+    max_body_size: usize,
+    oversized: OversizedBody,
 ) -> Result<http::Response<Vec<u8>>, Error> {
     let mut response = read_headers(stream).await?;
     // A response may have a body as long as it is not responding to a HEAD request and as long as
@@ -175,7 +208,27 @@ pub async fn read_from_stream(
         || response.status() == http::StatusCode::NO_CONTENT
         || response.status() == http::StatusCode::NOT_MODIFIED)
     {
-        read_body(stream, &mut response).await?;
+        // read_headers may already have read past the limit
+        if response.body().len() > max_body_size {
+            if oversized == OversizedBody::Reject {
+                return Err(Error::ResponseBodyTooLarge);
+            }
+            response.body_mut().truncate(max_body_size);
+        } else {
+            read_body(stream, &mut response, max_body_size, oversized).await?;
+        }
+        // A truncated body always ends up exactly at the limit, but so can a complete one
+        let complete = match get_content_length(&response)? {
+            Some(content_length) => content_length == response.body().len(),
+            None => response.body().len() < max_body_size,
+        };
+        if !complete && oversized == OversizedBody::Truncate {
+            let body_len = response.body().len();
+            response.extensions_mut().insert(Truncated);
+            response
+                .headers_mut()
+                .insert("content-length", http::HeaderValue::from(body_len));
+        }
     }
     Ok(response)
 }
@@ -183,28 +236,42 @@ pub async fn read_from_stream(
 /// This function serializes a response to bytes and writes those bytes to the provided stream.
 ///
 /// You will need to modify this function in Milestone 2.
-pub async fn write_to_stream(
+pub async fn write_to_stream<S: AsyncWrite + Unpin>(
     response: &http::Response<Vec<u8>>,
-    stream: &mut TcpStream,
+    stream: &mut S,
 ) -> Result<(), std::io::Error> {
     stream
-        .write(&format_response_line(response).into_bytes())
+        .write_all(&format_response_line(response).into_bytes())
         .await?;
-    stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
+    stream.write_all(&['\r' as u8, '\n' as u8]).await?; // \r\n
     for (header_name, header_value) in response.headers() {
         stream
-            .write(&format!("{}: ", header_name).as_bytes())
+            .write_all(&format!("{}: ", header_name).as_bytes())
             .await?;
-        stream.write(header_value.as_bytes()).await?;
-        stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
+        stream.write_all(header_value.as_bytes()).await?;
+        stream.write_all(&['\r' as u8, '\n' as u8]).await?; // \r\n
     }
-    stream.write(&['\r' as u8, '\n' as u8]).await?;
+    stream.write_all(&['\r' as u8, '\n' as u8]).await?;
     if response.body().len() > 0 {
-        stream.write(response.body()).await?;
+        stream.write_all(response.body()).await?;
     }
+    // Make sure nothing is left sitting in a buffer (e.g. in a TLS stream)
+    stream.flush().await?;
     Ok(())
 }
 
+/// Makes a copy of a response (http::Response doesn't implement Clone). This is used to answer
+/// requests from the response cache.
+pub fn clone_response(response: &http::Response<Vec<u8>>) -> http::Response<Vec<u8>> {
+    let mut builder = http::Response::builder()
+        .status(response.status())
+        .version(response.version());
+    for (header_name, header_value) in response.headers() {
+        builder = builder.header(header_name, header_value);
+    }
+    builder.body(response.body().clone()).unwrap()
+}
+
 pub fn format_response_line(response: &http::Response<Vec<u8>>) -> String {
     format!(
         "{:?} {} {}",
@@ -0,0 +1,77 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpStream, UnixStream};
+
+/// A connection to an upstream, which is either listening on a TCP port (`host:port`) or on a
+/// Unix-domain socket (`unix:/path/to.sock`).
+#[derive(Debug)]
+pub enum UpstreamStream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+/// Returns the socket path of a `unix:/path/to.sock` upstream address, or None if the upstream is
+/// reached over TCP.
+pub fn unix_socket_path(address: &str) -> Option<&str> {
+    address.strip_prefix("unix:")
+}
+
+impl UpstreamStream {
+    pub async fn connect(address: &str) -> io::Result<UpstreamStream> {
+        match unix_socket_path(address) {
+            Some(path) => Ok(UpstreamStream::Unix(UnixStream::connect(path).await?)),
+            None => Ok(UpstreamStream::Tcp(TcpStream::connect(address).await?)),
+        }
+    }
+
+    /// Sets TCP_NODELAY on a TCP connection. (Unix sockets don't batch up small writes, so
+    /// there's nothing to do for them.)
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        match self {
+            UpstreamStream::Tcp(stream) => stream.set_nodelay(nodelay),
+            UpstreamStream::Unix(_) => Ok(()),
+        }
+    }
+}
+
+impl AsyncRead for UpstreamStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            UpstreamStream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            UpstreamStream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for UpstreamStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            UpstreamStream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            UpstreamStream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            UpstreamStream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            UpstreamStream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            UpstreamStream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            UpstreamStream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
@@ -0,0 +1,3403 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, ErrorServer, RawServer, Server, SlowServer};
+
+use nix::sys::socket::{setsockopt, sockopt};
+use rand::Rng;
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{sleep, timeout};
+
+/// Mirror traffic to a second upstream. The client should only ever see the primary's response,
+/// even though the mirror returns errors.
+#[tokio::test]
+async fn test_mirror() {
+    init_logging();
+    let primary = EchoServer::new().await;
+    let mirror = ErrorServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&primary.address], &["--mirror", &mirror.address]).await;
+
+    log::info!("Sending a request that should be mirrored");
+    let response_text = balancebeam
+        .get("/mirrored")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("GET /mirrored HTTP/1.1"));
+
+    log::info!("Waiting for the mirrored request to be delivered");
+    sleep(Duration::from_millis(500)).await;
+
+    assert_eq!(
+        Box::new(mirror).stop().await,
+        1,
+        "Mirror did not receive the request"
+    );
+    assert_eq!(
+        Box::new(primary).stop().await,
+        1,
+        "Primary did not receive the request"
+    );
+
+    log::info!("All done :)");
+}
+
+/// A mirror that's slow to respond shouldn't pile up requests: once --max-mirror-requests copies
+/// are waiting on it, further ones are dropped (without holding up the primary)
+#[tokio::test]
+async fn test_mirror_limit() {
+    init_logging();
+    let primary = EchoServer::new().await;
+    let mirror = RawServer::with_delay(Duration::from_secs(10), |_| {
+        b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n".to_vec()
+    })
+    .await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&primary.address],
+        &["--mirror", &mirror.address, "--max-mirror-requests", "1"],
+    )
+    .await;
+
+    for i in 0..3 {
+        let path = format!("/mirrored-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+    assert!(balancebeam
+        .wait_for_output(
+            "too many mirrored requests in flight",
+            Duration::from_secs(1)
+        )
+        .await
+        .is_some());
+
+    log::info!("Checking that only the first request was mirrored");
+    sleep(Duration::from_millis(500)).await;
+    assert_eq!(mirror.requests_received(), 1);
+    assert_eq!(Box::new(primary).stop().await, 3);
+    log::info!("All done :)");
+}
+
+/// Subscribe to the upstream events stream, kill an upstream, and make sure we hear about it
+#[tokio::test]
+async fn test_upstream_events_stream() {
+    init_logging();
+    let live = EchoServer::new().await;
+    let doomed = EchoServer::new().await;
+    let doomed_address = doomed.address.clone();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&live.address, &doomed.address],
+        &[
+            "--events-path",
+            "/events",
+            "--active-health-check-interval",
+            "1",
+        ],
+    )
+    .await;
+
+    log::info!("Subscribing to upstream events");
+    let mut subscriber = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Failed to connect to balancebeam");
+    subscriber
+        .write_all(b"GET /events HTTP/1.1\r\nHost: balancebeam\r\n\r\n")
+        .await
+        .unwrap();
+    sleep(Duration::from_millis(200)).await;
+
+    log::info!("Killing an upstream");
+    // (An upstream waits for its open connections before it finishes stopping, so this hangs if the
+    // subscriber's connection is holding one open.)
+    timeout(Duration::from_secs(5), Box::new(doomed).stop())
+        .await
+        .expect("Upstream took too long to stop");
+
+    let expected_event = format!(
+        "data: {{\"upstream\":\"{}\",\"alive\":false}}",
+        doomed_address
+    );
+    let mut received = String::new();
+    let waited = timeout(Duration::from_secs(5), async {
+        let mut buffer = [0_u8; 1024];
+        while !received.contains(&expected_event) {
+            let bytes_read = subscriber.read(&mut buffer).await.unwrap();
+            assert!(bytes_read > 0, "balancebeam closed the events stream");
+            received.push_str(&String::from_utf8_lossy(&buffer[..bytes_read]));
+        }
+    })
+    .await;
+    assert!(
+        waited.is_ok(),
+        "Never received the upstream state change event. Got: {}",
+        received
+    );
+    assert!(received.starts_with("HTTP/1.1 200 OK"));
+    assert!(received.contains("Content-Type: text/event-stream"));
+
+    Box::new(live).stop().await;
+    log::info!("All done :)");
+}
+
+/// Open a keep-alive connection and make sure balancebeam closes it once it has been open for longer
+/// than --max-connection-duration
+#[tokio::test]
+async fn test_max_connection_duration() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--max-connection-duration", "1"]).await;
+
+    let mut client = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Failed to connect to balancebeam");
+    client
+        .write_all(b"GET /long-lived HTTP/1.1\r\nHost: balancebeam\r\n\r\n")
+        .await
+        .unwrap();
+
+    log::info!("Keeping the connection open past the maximum duration");
+    sleep(Duration::from_millis(1500)).await;
+
+    let mut received = Vec::new();
+    let closed = timeout(Duration::from_secs(3), client.read_to_end(&mut received)).await;
+    assert!(
+        closed.is_ok(),
+        "balancebeam did not close the connection after the maximum duration"
+    );
+    let received = String::from_utf8_lossy(&received);
+    assert!(received.starts_with("HTTP/1.1 200 OK"));
+    assert!(received.contains("GET /long-lived HTTP/1.1"));
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Point balancebeam at an upstream that takes longer to respond than the request deadline allows,
+/// and make sure the client gets a 504 once the deadline passes
+#[tokio::test]
+async fn test_request_deadline() {
+    init_logging();
+    let upstream = SlowServer::new(Duration::from_secs(3)).await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--request-deadline",
+            "1",
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+
+    let client = reqwest::Client::new();
+    let response = timeout(
+        Duration::from_secs(5),
+        client
+            .get(&format!("http://{}/slow", balancebeam.address))
+            .send(),
+    )
+    .await
+    .expect("balancebeam did not give up on the slow upstream")
+    .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 504);
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// The request deadline covers every attempt at a request, not each one on its own: with upstreams
+/// that each fail slowly, retrying has to stop (with a 504) once the deadline passes
+#[tokio::test]
+async fn test_request_deadline_across_retries() {
+    init_logging();
+    let slow_failure = |request: &str| {
+        if request.starts_with("GET /slow ") {
+            b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n".to_vec()
+        } else {
+            b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n".to_vec()
+        }
+    };
+    let mut upstreams = Vec::new();
+    for _ in 0..3 {
+        upstreams.push(RawServer::with_delay(Duration::from_millis(1500), slow_failure).await);
+    }
+    let addresses: Vec<&str> = upstreams
+        .iter()
+        .map(|upstream| upstream.address.as_str())
+        .collect();
+    let balancebeam = BalanceBeam::new_with_args(
+        &addresses,
+        &[
+            "--request-deadline",
+            "2",
+            "--max-retries",
+            "2",
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+
+    // Trying all three upstreams would take 4.5 seconds
+    let start = Instant::now();
+    let response = timeout(
+        Duration::from_secs(6),
+        reqwest::Client::new()
+            .get(&format!("http://{}/slow", balancebeam.address))
+            .send(),
+    )
+    .await
+    .expect("balancebeam did not give up on the slow upstreams")
+    .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 504);
+    assert!(
+        start.elapsed() < Duration::from_millis(3000),
+        "balancebeam kept retrying past the request deadline ({:?})",
+        start.elapsed()
+    );
+
+    log::info!("All done :)");
+}
+
+/// Connecting to upstreams comes out of the request deadline too. These upstreams never accept
+/// connections, and their accept queues are full, so connecting to them hangs until we give up.
+#[tokio::test]
+async fn test_request_deadline_while_connecting() {
+    init_logging();
+    let mut upstreams = Vec::new();
+    for _ in 0..2 {
+        let socket = tokio::net::TcpSocket::new_v4().unwrap();
+        socket.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let listener = socket.listen(0).unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let queued = TcpStream::connect(&address).await.unwrap();
+        upstreams.push((listener, queued, address));
+    }
+    let addresses: Vec<&str> = upstreams
+        .iter()
+        .map(|(_, _, address)| address.as_str())
+        .collect();
+    let balancebeam = BalanceBeam::new_with_args(
+        &addresses,
+        &[
+            "--request-deadline",
+            "2",
+            "--max-retries",
+            "1",
+            "--upstream-connect-timeout",
+            "3",
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+
+    // Waiting out the connect timeout on both upstreams would take 6 seconds
+    let start = Instant::now();
+    let response = timeout(
+        Duration::from_secs(8),
+        reqwest::Client::new()
+            .get(&format!("http://{}/", balancebeam.address))
+            .send(),
+    )
+    .await
+    .expect("balancebeam did not give up connecting to the upstreams")
+    .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 504);
+    assert!(
+        start.elapsed() < Duration::from_millis(3000),
+        "balancebeam kept connecting past the request deadline ({:?})",
+        start.elapsed()
+    );
+
+    log::info!("All done :)");
+}
+
+/// An upstream that hangs past --upstream-timeout should get the client a 504 and be marked dead,
+/// so that the next request doesn't wait on it too
+#[tokio::test]
+async fn test_upstream_timeout() {
+    init_logging();
+    let upstream = SlowServer::new(Duration::from_secs(3)).await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--upstream-timeout",
+            "1",
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+
+    let client = reqwest::Client::new();
+    let response = timeout(
+        Duration::from_secs(5),
+        client
+            .get(&format!("http://{}/hangs", balancebeam.address))
+            .send(),
+    )
+    .await
+    .expect("balancebeam did not give up on the hung upstream")
+    .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 504);
+    assert!(balancebeam
+        .wait_for_output("timed out handling a request", Duration::from_secs(1))
+        .await
+        .is_some());
+
+    log::info!("Making sure the hung upstream is no longer used");
+    let response = timeout(
+        Duration::from_secs(2),
+        client
+            .get(&format!("http://{}/after", balancebeam.address))
+            .send(),
+    )
+    .await
+    .expect("balancebeam sent a request to the upstream it marked dead")
+    .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 502);
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// Fetches the metrics endpoint and returns the value of the metric on the line starting with
+/// `metric` (name and labels)
+async fn get_metric(metrics_address: &str, metric: &str) -> usize {
+    let metrics = reqwest::get(&format!("http://{}/metrics", metrics_address))
+        .await
+        .expect("Error fetching metrics")
+        .text()
+        .await
+        .unwrap();
+    metrics
+        .lines()
+        .find_map(|line| line.strip_prefix(metric)?.trim().parse().ok())
+        .unwrap_or_else(|| panic!("Metric {} missing from:\n{}", metric, metrics))
+}
+
+/// Send a mix of successful, bad, and failed requests and make sure they are counted by status
+/// class, and that the error rate alert fires once enough of them are 5xx
+#[tokio::test]
+async fn test_status_class_counters() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let upstream_address = upstream.address.clone();
+    let metrics_address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024..65535));
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--metrics-bind",
+            &metrics_address,
+            "--error-rate-alert",
+            "0.5",
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+
+    log::info!("Sending requests that should succeed");
+    for _ in 0..5 {
+        balancebeam
+            .get("/ok")
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+
+    log::info!("Sending a malformed request");
+    let mut client = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Failed to connect to balancebeam");
+    client.write_all(b"GARBAGE\r\n\r\n").await.unwrap();
+    let mut buffer = [0_u8; 512];
+    let bytes_read = client.read(&mut buffer).await.unwrap();
+    assert!(String::from_utf8_lossy(&buffer[..bytes_read]).starts_with("HTTP/1.1 400"));
+    drop(client);
+
+    assert_eq!(
+        get_metric(&metrics_address, "balancebeam_error_rate_alerts_total").await,
+        0,
+        "Error rate alert fired before any 5xx responses"
+    );
+
+    log::info!("Killing the upstream so that requests fail");
+    Box::new(upstream).stop().await;
+    for _ in 0..8 {
+        balancebeam
+            .get("/fails")
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+
+    let counters = [("2xx", 5), ("3xx", 0), ("4xx", 1), ("5xx", 8)];
+    for (class, expected) in counters.iter() {
+        let metric = format!("balancebeam_responses_total{{class=\"{}\"}}", class);
+        assert_eq!(
+            get_metric(&metrics_address, &metric).await,
+            *expected,
+            "Wrong number of {} responses",
+            class
+        );
+    }
+    // The 400 came from balancebeam and the 502s happened without a live upstream, so only the
+    // successful requests count against the upstream
+    let metric = format!(
+        "balancebeam_upstream_responses_total{{upstream=\"{}\",class=\"2xx\"}}",
+        upstream_address
+    );
+    assert_eq!(get_metric(&metrics_address, &metric).await, 5);
+    assert_eq!(
+        get_metric(&metrics_address, "balancebeam_error_rate_alerts_total").await,
+        1,
+        "Error rate alert did not fire (exactly once)"
+    );
+
+    log::info!("All done :)");
+}
+
+/// Requests forwarded upstream should say which scheme and host the client used, adding to any
+/// X-Forwarded-Proto/X-Forwarded-Host headers earlier proxies set
+#[tokio::test]
+async fn test_forwarded_proto_and_host() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    let client = reqwest::Client::new();
+    let response_text = client
+        .get(&format!("http://{}/first-hop", balancebeam.address))
+        .header("host", "example.com")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+        .text()
+        .await
+        .unwrap();
+    assert!(response_text.contains("x-forwarded-proto: http\n"));
+    assert!(response_text.contains("x-forwarded-host: example.com\n"));
+
+    log::info!("Sending a request that already went through another proxy");
+    let response_text = client
+        .get(&format!("http://{}/second-hop", balancebeam.address))
+        .header("host", "internal.example.com")
+        .header("x-forwarded-proto", "https")
+        .header("x-forwarded-host", "example.com")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+        .text()
+        .await
+        .unwrap();
+    assert!(response_text.contains("x-forwarded-proto: https, http\n"));
+    assert!(response_text.contains("x-forwarded-host: example.com, internal.example.com\n"));
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Turn on the security headers preset, override one of its headers and disable another, and make
+/// sure the response has the right set of headers
+#[tokio::test]
+async fn test_security_headers() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--security-headers",
+            "--set-response-header",
+            "X-Frame-Options=SAMEORIGIN",
+            "--remove-response-header",
+            "Referrer-Policy",
+        ],
+    )
+    .await;
+
+    let response = reqwest::get(&format!("http://{}/secure", balancebeam.address))
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 200);
+    let headers = response.headers();
+    assert_eq!(
+        headers.get("strict-transport-security").unwrap(),
+        "max-age=31536000; includeSubDomains"
+    );
+    assert_eq!(headers.get("x-content-type-options").unwrap(), "nosniff");
+    assert_eq!(
+        headers.get("x-frame-options").unwrap(),
+        "SAMEORIGIN",
+        "--set-response-header did not override the preset"
+    );
+    assert!(
+        headers.get("referrer-policy").is_none(),
+        "--remove-response-header did not disable the preset header"
+    );
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// With round-robin selection, requests should be spread exactly evenly across the upstreams
+#[tokio::test]
+async fn test_round_robin() {
+    init_logging();
+    let mut upstreams = Vec::new();
+    for _ in 0..3 {
+        upstreams.push(EchoServer::new().await);
+    }
+    let upstream_addresses: Vec<&str> = upstreams
+        .iter()
+        .map(|upstream| upstream.address.as_str())
+        .collect();
+    let balancebeam =
+        BalanceBeam::new_with_args(&upstream_addresses, &["--lb-algorithm", "round-robin"]).await;
+
+    for i in 0..9 {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    for upstream in upstreams {
+        assert_eq!(
+            Box::new(upstream).stop().await,
+            3,
+            "Round-robin did not distribute requests evenly"
+        );
+    }
+    log::info!("All done :)");
+}
+
+/// Starts balancebeam in front of a slow upstream, sends a request, and then sends SIGTERM while
+/// the request is still in flight. Returns whether the request succeeded along with balancebeam's
+/// final shutdown log line.
+async fn shutdown_with_request_in_flight(
+    upstream_delay: Duration,
+    shutdown_timeout: &str,
+) -> (bool, String) {
+    let upstream = SlowServer::new(upstream_delay).await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--shutdown-timeout", shutdown_timeout],
+    )
+    .await;
+
+    let url = format!("http://{}/in-flight", balancebeam.address);
+    let request = tokio::spawn(async move { reqwest::get(&url).await });
+    sleep(Duration::from_millis(500)).await;
+
+    log::info!("Sending SIGTERM to balancebeam");
+    balancebeam.send_signal(nix::sys::signal::Signal::SIGTERM);
+    let succeeded = match request.await.unwrap() {
+        Ok(response) => response.status().as_u16() == 200,
+        Err(_) => false,
+    };
+    let summary = balancebeam
+        .wait_for_output("Shutdown complete", Duration::from_secs(10))
+        .await
+        .expect("balancebeam never finished shutting down");
+    assert!(
+        balancebeam.wait_for_exit().await.success(),
+        "balancebeam did not exit cleanly after shutting down"
+    );
+
+    Box::new(upstream).stop().await;
+    (succeeded, summary)
+}
+
+/// A request that finishes within the shutdown timeout should be allowed to complete
+#[tokio::test]
+async fn test_shutdown_drains_connections() {
+    init_logging();
+    let (succeeded, summary) = shutdown_with_request_in_flight(Duration::from_secs(2), "5").await;
+    assert!(succeeded, "In-flight request was not allowed to finish");
+    assert!(
+        summary.contains("1 connections drained, 0 force-closed"),
+        "Unexpected shutdown summary: {}",
+        summary
+    );
+    log::info!("All done :)");
+}
+
+/// A request that is still running when the shutdown timeout passes should be cut off
+#[tokio::test]
+async fn test_shutdown_force_closes_connections() {
+    init_logging();
+    let (succeeded, summary) = shutdown_with_request_in_flight(Duration::from_secs(5), "1").await;
+    assert!(
+        !succeeded,
+        "Request should have been cut off by the shutdown"
+    );
+    assert!(
+        summary.contains("0 connections drained, 1 force-closed"),
+        "Unexpected shutdown summary: {}",
+        summary
+    );
+    log::info!("All done :)");
+}
+
+/// Tie up one upstream with a long-lived connection and make sure least-connections sends new
+/// connections to the other one
+#[tokio::test]
+async fn test_least_connections() {
+    init_logging();
+    let first = EchoServer::new().await;
+    let second = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&first.address, &second.address],
+        &[
+            "--lb-algorithm",
+            "least-connections",
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+
+    // A client connection counts against its upstream from its first request until it closes, so
+    // this one keeps an upstream busy while it stays open
+    log::info!("Opening a connection that stays open after its first request");
+    let mut busy_client = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Failed to connect to balancebeam");
+    let (response, closed) = send_raw_request(&mut busy_client, &raw_get("/busy")).await;
+    assert!(response.starts_with("HTTP/1.1 200"), "Got: {}", response);
+    assert!(!closed);
+
+    // balancebeam closes each of these connections (and stops counting it) before we see it close,
+    // so the next one never finds the other upstream busy
+    for i in 0..4 {
+        let mut client = TcpStream::connect(&balancebeam.address).await.unwrap();
+        let request = format!(
+            "GET /request-{} HTTP/1.1\r\nHost: balancebeam\r\nConnection: close\r\n\r\n",
+            i
+        );
+        let (response, closed) = send_raw_request(&mut client, &request).await;
+        assert!(response.starts_with("HTTP/1.1 200"), "Got: {}", response);
+        assert!(closed);
+    }
+
+    drop(busy_client);
+    let mut request_counts = vec![Box::new(first).stop().await, Box::new(second).stop().await];
+    request_counts.sort_unstable();
+    assert_eq!(
+        request_counts,
+        vec![1, 4],
+        "Requests were sent to the upstream that was already busy"
+    );
+    log::info!("All done :)");
+}
+
+/// Send a few hundred requests to upstreams weighted 3:1:0 and make sure the traffic is split in
+/// roughly that ratio
+#[tokio::test]
+async fn test_weighted_upstreams() {
+    init_logging();
+    let heavy = EchoServer::new().await;
+    let light = EchoServer::new().await;
+    let standby = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[
+            &format!("{}@3", heavy.address),
+            &format!("{}@1", light.address),
+            &format!("{}@0", standby.address),
+        ],
+        // (Health checks would add requests of their own, including to the standby upstream.)
+        &["--active-health-check-interval", "60"],
+    )
+    .await;
+
+    // Each request is on a connection of its own, since a connection's requests all go to the same
+    // upstream
+    let n_requests = 400;
+    let sent = timeout(Duration::from_secs(30), async {
+        for i in 0..n_requests {
+            let mut client = TcpStream::connect(&balancebeam.address).await.unwrap();
+            let request = format!(
+                "GET /request-{} HTTP/1.1\r\nHost: balancebeam\r\nConnection: close\r\n\r\n",
+                i
+            );
+            let (response, _) = send_raw_request(&mut client, &request).await;
+            assert!(response.starts_with("HTTP/1.1 200"), "Got: {}", response);
+        }
+    })
+    .await;
+    assert!(sent.is_ok(), "Requests took too long");
+
+    let stop_all = async {
+        (
+            Box::new(heavy).stop().await,
+            Box::new(light).stop().await,
+            Box::new(standby).stop().await,
+        )
+    };
+    let (heavy_count, light_count, standby_count) = timeout(Duration::from_secs(5), stop_all)
+        .await
+        .expect("Upstreams took too long to stop");
+    log::info!(
+        "Requests received: heavy {}, light {}, standby {}",
+        heavy_count,
+        light_count,
+        standby_count
+    );
+    assert_eq!(heavy_count + light_count, n_requests);
+    assert_eq!(
+        standby_count, 0,
+        "Upstream with weight 0 got traffic while others were alive"
+    );
+    // Expect 300 and 100 requests; this leaves more than four standard deviations of slack
+    assert!(
+        (heavy_count as i64 - 300).abs() < 40,
+        "Traffic was not split according to the weights"
+    );
+    log::info!("All done :)");
+}
+
+/// Hand balancebeam a listening socket the way systemd socket activation does, and make sure it
+/// accepts connections on that socket
+#[tokio::test]
+async fn test_systemd_socket_activation() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("Failed to bind listener");
+    let balancebeam = BalanceBeam::new_with_listener(&[&upstream.address], listener).await;
+
+    let response_text = balancebeam
+        .get("/activated")
+        .await
+        .expect("Error sending request to balancebeam on the passed-in socket");
+    assert!(response_text.contains("GET /activated HTTP/1.1"));
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Requests from separate client connections should share one pooled upstream connection
+#[tokio::test]
+async fn test_connection_pool_reuses_connections() {
+    init_logging();
+    let upstream =
+        RawServer::new(|_| b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok".to_vec()).await;
+    let balancebeam = BalanceBeam::new_with_args(&[&upstream.address], &[]).await;
+
+    for i in 0..5 {
+        let response_text = balancebeam
+            .get(&format!("/pooled-{}", i))
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response_text, "ok");
+    }
+    assert_eq!(
+        upstream.connections_accepted(),
+        1,
+        "balancebeam did not reuse its upstream connection"
+    );
+    log::info!("All done :)");
+}
+
+/// Upstream connections that the upstream asked to close must not be pooled
+#[tokio::test]
+async fn test_connection_pool_respects_connection_close() {
+    init_logging();
+    let upstream = RawServer::new(|_| {
+        b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok".to_vec()
+    })
+    .await;
+    let balancebeam = BalanceBeam::new_with_args(&[&upstream.address], &[]).await;
+
+    for i in 0..5 {
+        let response_text = balancebeam
+            .get(&format!("/not-pooled-{}", i))
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response_text, "ok");
+    }
+    assert_eq!(
+        upstream.connections_accepted(),
+        5,
+        "balancebeam reused an upstream connection marked Connection: close"
+    );
+    log::info!("All done :)");
+}
+
+/// Idempotent requests that get a 5xx from their upstream should be retried on another one
+#[tokio::test]
+async fn test_retries_idempotent_requests() {
+    init_logging();
+    let failing = ErrorServer::new().await;
+    let working = EchoServer::new().await;
+    // With round-robin, the first connection goes to the failing upstream
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&failing.address, &working.address],
+        &[
+            "--lb-algorithm",
+            "round-robin",
+            "--max-retries",
+            "1",
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+
+    for i in 0..3 {
+        let path = format!("/retried-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(
+            response_text.contains(&format!("GET {} HTTP/1.1", path)),
+            "Request was not retried on the working upstream"
+        );
+    }
+
+    assert_eq!(Box::new(failing).stop().await, 1);
+    assert_eq!(Box::new(working).stop().await, 3);
+    log::info!("All done :)");
+}
+
+/// Retries should only go to upstreams that haven't had the request yet, however many retries are
+/// allowed
+#[tokio::test]
+async fn test_retries_skip_upstreams_already_tried() {
+    init_logging();
+    let failing_1 = ErrorServer::new().await;
+    let failing_2 = ErrorServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&failing_1.address, &failing_2.address],
+        &["--max-retries", "5", "--active-health-check-interval", "60"],
+    )
+    .await;
+
+    let status = reqwest::get(&format!("http://{}/retried", balancebeam.address))
+        .await
+        .expect("Error sending request to balancebeam")
+        .status()
+        .as_u16();
+    assert!(status >= 500, "Got a {} from two failing upstreams", status);
+
+    assert_eq!(Box::new(failing_1).stop().await, 1);
+    assert_eq!(Box::new(failing_2).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// POST requests aren't idempotent, so they must never be sent twice
+#[tokio::test]
+async fn test_does_not_retry_post() {
+    init_logging();
+    let failing = ErrorServer::new().await;
+    let working = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&failing.address, &working.address],
+        &[
+            "--lb-algorithm",
+            "round-robin",
+            "--max-retries",
+            "1",
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+
+    let response_text = balancebeam
+        .post("/not-retried", "some data")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(
+        !response_text.contains("POST /not-retried"),
+        "POST request was retried on another upstream"
+    );
+
+    assert_eq!(Box::new(failing).stop().await, 1);
+    assert_eq!(Box::new(working).stop().await, 0);
+    log::info!("All done :)");
+}
+
+/// In maintenance mode (toggled with SIGUSR1), requests that modify data should get a 503 while
+/// reads are still proxied
+#[tokio::test]
+async fn test_maintenance_mode() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--maintenance-retry-after", "120"])
+            .await;
+    let client = reqwest::Client::new();
+    let url = format!("http://{}/maintenance", balancebeam.address);
+
+    log::info!("Sending a POST before maintenance mode");
+    let response = client.post(&url).body("data").send().await.unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+
+    log::info!("Enabling maintenance mode");
+    balancebeam.send_signal(nix::sys::signal::Signal::SIGUSR1);
+    balancebeam
+        .wait_for_output("Maintenance mode enabled", Duration::from_secs(5))
+        .await
+        .expect("balancebeam did not enable maintenance mode");
+
+    let response = client.post(&url).body("data").send().await.unwrap();
+    assert_eq!(response.status().as_u16(), 503);
+    assert_eq!(response.headers()["retry-after"], "120");
+    let response = client.get(&url).send().await.unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    assert!(response.text().await.unwrap().contains("GET /maintenance"));
+
+    log::info!("Disabling maintenance mode");
+    balancebeam.send_signal(nix::sys::signal::Signal::SIGUSR1);
+    balancebeam
+        .wait_for_output("Maintenance mode disabled", Duration::from_secs(5))
+        .await
+        .expect("balancebeam did not disable maintenance mode");
+    let response = client.post(&url).body("data").send().await.unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+
+    assert_eq!(Box::new(upstream).stop().await, 3);
+    log::info!("All done :)");
+}
+
+/// With ip-hash selection, every request from the same client should go to the same upstream
+#[tokio::test]
+async fn test_ip_hash() {
+    init_logging();
+    let mut upstreams = Vec::new();
+    for _ in 0..3 {
+        upstreams.push(EchoServer::new().await);
+    }
+    let upstream_addresses: Vec<&str> = upstreams
+        .iter()
+        .map(|upstream| upstream.address.as_str())
+        .collect();
+    let balancebeam =
+        BalanceBeam::new_with_args(&upstream_addresses, &["--lb-algorithm", "ip-hash"]).await;
+
+    for i in 0..10 {
+        let path = format!("/sticky-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    let mut request_counts = Vec::new();
+    for upstream in upstreams {
+        request_counts.push(Box::new(upstream).stop().await);
+    }
+    request_counts.sort();
+    assert_eq!(
+        request_counts,
+        vec![0, 0, 10],
+        "Requests from one client were spread across upstreams"
+    );
+    log::info!("All done :)");
+}
+
+/// Connections from one IP beyond --max-connections-per-ip should be closed right away, without
+/// affecting other IPs
+#[tokio::test]
+async fn test_max_connections_per_ip() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--max-connections-per-ip", "2"]).await;
+
+    let mut allowed = Vec::new();
+    for _ in 0..2 {
+        allowed.push(TcpStream::connect(&balancebeam.address).await.unwrap());
+        // Give balancebeam a chance to accept the connection before the next one arrives
+        sleep(Duration::from_millis(100)).await;
+    }
+    let mut excess = TcpStream::connect(&balancebeam.address).await.unwrap();
+    let (response, closed) = send_raw_request(&mut excess, &raw_get("/excess")).await;
+    assert!(
+        response.is_empty() && closed,
+        "balancebeam accepted more connections than --max-connections-per-ip"
+    );
+
+    log::info!("Connecting from another IP");
+    let socket = tokio::net::TcpSocket::new_v4().unwrap();
+    socket.bind("127.0.0.2:0".parse().unwrap()).unwrap();
+    let mut other_ip = socket
+        .connect(balancebeam.address.parse().unwrap())
+        .await
+        .unwrap();
+    let (response, _) = send_raw_request(&mut other_ip, &raw_get("/other-ip")).await;
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+
+    for (i, connection) in allowed.iter_mut().enumerate() {
+        let (response, _) =
+            send_raw_request(connection, &raw_get(&format!("/allowed-{}", i))).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+    }
+
+    log::info!("Closing one connection to free up a slot");
+    drop(allowed.pop());
+    sleep(Duration::from_millis(100)).await;
+    let mut replacement = TcpStream::connect(&balancebeam.address).await.unwrap();
+    let (response, _) = send_raw_request(&mut replacement, &raw_get("/replacement")).await;
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+
+    assert_eq!(Box::new(upstream).stop().await, 4);
+    log::info!("All done :)");
+}
+
+/// Active health checks should count an upstream as alive if it returns any of the expected
+/// statuses, not just 200
+#[tokio::test]
+async fn test_active_health_check_expected_status() {
+    init_logging();
+    let upstream =
+        RawServer::new(|_| b"HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n".to_vec()).await;
+    let expected = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--active-health-check-interval",
+            "1",
+            "--active-health-check-expected-status",
+            "200,204-206",
+        ],
+    )
+    .await;
+    let default = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--active-health-check-interval", "1"],
+    )
+    .await;
+
+    log::info!("Waiting for health checks to run...");
+    sleep(Duration::from_millis(2500)).await;
+
+    let client = reqwest::Client::new();
+    let response = client
+        .get(&format!("http://{}/", expected.address))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(
+        response.status().as_u16(),
+        204,
+        "Upstream returning an expected status was marked dead"
+    );
+    let response = client
+        .get(&format!("http://{}/", default.address))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(
+        response.status().as_u16(),
+        502,
+        "Upstream returning 204 passed the default health check"
+    );
+    log::info!("All done :)");
+}
+
+/// With --tls-cert and --tls-key, clients should be able to talk to balancebeam over HTTPS, and
+/// the upstream should be told that the client used HTTPS
+#[tokio::test]
+async fn test_tls_termination() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let tls_dir = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/tls");
+    let cert_path = format!("{}/cert.pem", tls_dir);
+    let key_path = format!("{}/key.pem", tls_dir);
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--tls-cert", &cert_path, "--tls-key", &key_path],
+    )
+    .await;
+
+    // The test certificate is self-signed
+    let client = reqwest::Client::builder()
+        .danger_accept_invalid_certs(true)
+        .build()
+        .unwrap();
+    let response_text = client
+        .get(&format!("https://{}/secure", balancebeam.address))
+        .send()
+        .await
+        .expect("Error sending HTTPS request to balancebeam")
+        .text()
+        .await
+        .unwrap();
+    assert!(response_text.contains("GET /secure HTTP/1.1"));
+    assert!(
+        response_text.contains("x-forwarded-proto: https"),
+        "Upstream was not told that the client used HTTPS"
+    );
+
+    log::info!("Making sure plaintext HTTP is rejected");
+    assert!(balancebeam.get("/insecure").await.is_err());
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// --check-config should validate the options and exit without starting the proxy, failing with a
+/// description of the problem if an address is bad
+#[tokio::test]
+async fn test_check_config() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(&[&upstream.address], &["--check-config"]).await;
+    assert!(balancebeam
+        .wait_for_output("Configuration OK", Duration::from_secs(1))
+        .await
+        .is_some());
+    assert!(balancebeam.wait_for_exit().await.success());
+
+    log::info!("Checking a configuration with a bad upstream address");
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address, "not-an-address"], &["--check-config"])
+            .await;
+    assert!(
+        balancebeam
+            .wait_for_output(
+                "Invalid upstream address not-an-address",
+                Duration::from_secs(1)
+            )
+            .await
+            .is_some(),
+        "balancebeam did not explain what was wrong with the configuration"
+    );
+    assert!(!balancebeam.wait_for_exit().await.success());
+    assert_eq!(
+        Box::new(upstream).stop().await,
+        0,
+        "Checking the configuration should not have sent requests upstream"
+    );
+    log::info!("All done :)");
+}
+
+/// On SIGHUP, balancebeam should re-read --upstream-file and start using the new upstream list
+#[tokio::test]
+async fn test_reload_upstream_file() {
+    init_logging();
+    let old_upstream = EchoServer::new().await;
+    let new_upstream = EchoServer::new().await;
+    let upstream_file = std::env::temp_dir().join(format!(
+        "balancebeam-upstreams-{}",
+        rand::thread_rng().gen::<u32>()
+    ));
+    std::fs::write(&upstream_file, format!("{}\n", old_upstream.address)).unwrap();
+    let balancebeam =
+        BalanceBeam::new_with_args(&[], &["--upstream-file", upstream_file.to_str().unwrap()])
+            .await;
+    balancebeam
+        .get("/before-reload")
+        .await
+        .expect("Error sending request to balancebeam");
+
+    log::info!("Replacing the upstream and sending SIGHUP");
+    std::fs::write(
+        &upstream_file,
+        format!("# Moved to a new server\n{}@2\n", new_upstream.address),
+    )
+    .unwrap();
+    balancebeam.send_signal(nix::sys::signal::Signal::SIGHUP);
+    let summary = balancebeam
+        .wait_for_output("Reloaded upstreams", Duration::from_secs(2))
+        .await
+        .expect("balancebeam did not reload its upstreams");
+    assert!(
+        summary.contains(&new_upstream.address) && summary.contains(&old_upstream.address),
+        "Reload summary should list the added and removed upstreams: {}",
+        summary
+    );
+    for _ in 0..5 {
+        balancebeam
+            .get("/after-reload")
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+    std::fs::remove_file(&upstream_file).unwrap();
+
+    assert_eq!(Box::new(old_upstream).stop().await, 1);
+    assert_eq!(Box::new(new_upstream).stop().await, 5);
+    log::info!("All done :)");
+}
+
+/// Requests should be counted in total, per upstream and when rate limited, and upstream response
+/// times should go into the latency histogram
+#[tokio::test]
+async fn test_request_metrics() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let metrics_address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024..65535));
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--metrics-bind",
+            &metrics_address,
+            "--max-requests-per-minute",
+            "2",
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+
+    for _ in 0..3 {
+        balancebeam
+            .get("/counted")
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+
+    assert_eq!(
+        get_metric(&metrics_address, "balancebeam_requests_total").await,
+        3
+    );
+    assert_eq!(
+        get_metric(&metrics_address, "balancebeam_rate_limited_requests_total").await,
+        1
+    );
+    let metric = format!(
+        "balancebeam_upstream_requests_total{{upstream=\"{}\"}}",
+        upstream.address
+    );
+    assert_eq!(get_metric(&metrics_address, &metric).await, 2);
+    assert_eq!(
+        get_metric(
+            &metrics_address,
+            "balancebeam_upstream_latency_seconds_bucket{le=\"+Inf\"}"
+        )
+        .await,
+        2
+    );
+    assert_eq!(
+        get_metric(
+            &metrics_address,
+            "balancebeam_upstream_latency_seconds_count"
+        )
+        .await,
+        2
+    );
+
+    log::info!("Making sure the metrics server only answers GET /metrics");
+    let response = reqwest::get(&format!("http://{}/other", metrics_address))
+        .await
+        .expect("Error fetching from the metrics server");
+    assert_eq!(response.status().as_u16(), 404);
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Returns a 200 response whose body is `body_len` bytes long.
+fn large_body_response(body_len: usize) -> Vec<u8> {
+    let mut response =
+        format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body_len).into_bytes();
+    response.resize(response.len() + body_len, b'x');
+    response
+}
+
+/// Response bodies over --max-response-body-bytes should get a 502, or be cut off at the limit
+/// with --oversized-response truncate
+#[tokio::test]
+async fn test_max_response_body_bytes() {
+    init_logging();
+    let upstream = RawServer::new(|_| large_body_response(50000)).await;
+    let rejecting = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--max-response-body-bytes",
+            "1000",
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+    let response = reqwest::get(&format!("http://{}/big", rejecting.address))
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 502);
+
+    log::info!("Checking that oversized bodies can be truncated instead");
+    let truncating = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--max-response-body-bytes",
+            "1000",
+            "--oversized-response",
+            "truncate",
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+    let response = reqwest::get(&format!("http://{}/big", truncating.address))
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(response.bytes().await.unwrap().len(), 1000);
+    assert!(truncating
+        .wait_for_output("was truncated to 1000 bytes", Duration::from_secs(1))
+        .await
+        .is_some());
+
+    log::info!("Checking that bodies within the limit are untouched");
+    let small_upstream = RawServer::new(|_| large_body_response(1000)).await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&small_upstream.address],
+        &["--max-response-body-bytes", "1000"],
+    )
+    .await;
+    assert_eq!(balancebeam.get("/small").await.unwrap().len(), 1000);
+    log::info!("All done :)");
+}
+
+/// Requests with bodies over --max-request-body-bytes should get a 413 as soon as their headers
+/// arrive, without balancebeam waiting for (or buffering) the body
+#[tokio::test]
+async fn test_max_request_body_bytes() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--max-request-body-bytes",
+            "100",
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+
+    let mut client = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Failed to connect to balancebeam");
+    client
+        .write_all(b"POST /upload HTTP/1.1\r\nHost: localhost\r\nContent-Length: 1000000\r\n\r\n")
+        .await
+        .unwrap();
+    let mut buffer = [0_u8; 512];
+    let bytes_read = timeout(Duration::from_secs(2), client.read(&mut buffer))
+        .await
+        .expect("balancebeam waited for the body of an oversized request")
+        .unwrap();
+    assert!(String::from_utf8_lossy(&buffer[..bytes_read]).starts_with("HTTP/1.1 413"));
+    drop(client);
+
+    log::info!("Sending a request with a body within the limit");
+    let response_text = balancebeam
+        .post("/small", "just a few bytes")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("just a few bytes"));
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// The admin API should report an upstream's recent health check results, in order
+#[tokio::test]
+async fn test_upstream_health_history() {
+    init_logging();
+    // Alternates between answering with a 200 and a 500
+    let responses = AtomicUsize::new(0);
+    let upstream = RawServer::new(move |_| {
+        if responses.fetch_add(1, Ordering::SeqCst) % 2 == 0 {
+            b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_vec()
+        } else {
+            b"HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                .to_vec()
+        }
+    })
+    .await;
+    let admin_address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024..65535));
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--active-health-check-interval",
+            "1",
+            "--admin-bind",
+            &admin_address,
+        ],
+    )
+    .await;
+    // BalanceBeam::new_with_args already waited a second; wait for a few more checks
+    sleep(Duration::from_millis(3500)).await;
+
+    let history = reqwest::get(&format!(
+        "http://{}/upstreams/{}/history",
+        admin_address, upstream.address
+    ))
+    .await
+    .expect("Error fetching upstream history")
+    .text()
+    .await
+    .unwrap();
+    let results: Vec<&str> = history
+        .split("\"history\":[")
+        .nth(1)
+        .expect("Response is missing the history")
+        .split("{\"timestamp_ms\"")
+        .skip(1)
+        .collect();
+    assert!(
+        results.len() >= 3,
+        "Too few health checks recorded: {}",
+        history
+    );
+    for (i, result) in results.iter().enumerate() {
+        if i % 2 == 0 {
+            assert!(result.contains("\"alive\":true,"), "{}", history);
+            assert!(result.contains("\"error\":null"), "{}", history);
+        } else {
+            assert!(result.contains("\"alive\":false,"), "{}", history);
+            assert!(result.contains("Unexpected status 500"), "{}", history);
+        }
+    }
+
+    log::info!("Checking that unknown upstreams get a 404");
+    let response = reqwest::get(&format!(
+        "http://{}/upstreams/127.0.0.1:1/history",
+        admin_address
+    ))
+    .await
+    .expect("Error fetching upstream history");
+    assert_eq!(response.status().as_u16(), 404);
+    drop(balancebeam);
+    log::info!("All done :)");
+}
+
+/// Sends a raw request and reads the response, returning the text received and whether balancebeam
+/// closed the connection afterwards (or before the request could be sent)
+async fn send_raw_request(connection: &mut TcpStream, request: &str) -> (String, bool) {
+    if connection.write_all(request.as_bytes()).await.is_err() {
+        return (String::new(), true);
+    }
+    let mut received = String::new();
+    let mut buffer = [0_u8; 1024];
+    loop {
+        match timeout(Duration::from_millis(500), connection.read(&mut buffer)).await {
+            Ok(Ok(0)) | Ok(Err(_)) => return (received, true),
+            Ok(Ok(bytes_read)) => {
+                received.push_str(&String::from_utf8_lossy(&buffer[..bytes_read]))
+            }
+            // Nothing more coming, but the connection is still open
+            Err(_) => return (received, false),
+        }
+    }
+}
+
+/// A minimal GET request for `path`, for use with send_raw_request
+fn raw_get(path: &str) -> String {
+    format!("GET {} HTTP/1.1\r\nHost: balancebeam\r\n\r\n", path)
+}
+
+/// balancebeam should close client connections after one response when the client sends
+/// "Connection: close" or speaks HTTP/1.0 without asking for keep-alive, and keep them open
+/// otherwise
+#[tokio::test]
+async fn test_client_keep_alive() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    log::info!("Sending an HTTP/1.1 request with Connection: close");
+    let mut client = TcpStream::connect(&balancebeam.address).await.unwrap();
+    let (response, closed) = send_raw_request(
+        &mut client,
+        "GET /close HTTP/1.1\r\nHost: balancebeam\r\nConnection: close\r\n\r\n",
+    )
+    .await;
+    assert!(response.starts_with("HTTP/1.1 200"), "Got: {}", response);
+    assert!(
+        response.to_ascii_lowercase().contains("connection: close"),
+        "Response doesn't say the connection is closing: {}",
+        response
+    );
+    assert!(closed, "balancebeam kept the connection open");
+    // The upstream shouldn't be asked to close its connection too (the echoed request is in the
+    // response body)
+    let echoed_request = response.splitn(2, "\r\n\r\n").nth(1).unwrap_or("");
+    assert!(
+        !echoed_request.to_ascii_lowercase().contains("connection:"),
+        "Client's Connection header was forwarded: {}",
+        echoed_request
+    );
+
+    log::info!("Sending an HTTP/1.0 request");
+    let mut client = TcpStream::connect(&balancebeam.address).await.unwrap();
+    let (response, closed) = send_raw_request(&mut client, "GET /old HTTP/1.0\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 200"), "Got: {}", response);
+    assert!(
+        response.contains("GET /old HTTP/1.1"),
+        "Request wasn't forwarded as HTTP/1.1: {}",
+        response
+    );
+    assert!(closed, "balancebeam kept an HTTP/1.0 connection open");
+
+    log::info!("Sending HTTP/1.0 requests with Connection: keep-alive");
+    let mut client = TcpStream::connect(&balancebeam.address).await.unwrap();
+    for _ in 0..2 {
+        let (response, closed) = send_raw_request(
+            &mut client,
+            "GET /kept HTTP/1.0\r\nConnection: keep-alive\r\n\r\n",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 200"), "Got: {}", response);
+        assert!(
+            response
+                .to_ascii_lowercase()
+                .contains("connection: keep-alive"),
+            "Response doesn't say the connection is staying open: {}",
+            response
+        );
+        assert!(!closed, "balancebeam closed a keep-alive connection");
+    }
+
+    log::info!("Sending HTTP/1.1 requests without a Connection header");
+    let mut client = TcpStream::connect(&balancebeam.address).await.unwrap();
+    for _ in 0..2 {
+        let (response, closed) = send_raw_request(
+            &mut client,
+            "GET /default HTTP/1.1\r\nHost: balancebeam\r\n\r\n",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 200"), "Got: {}", response);
+        assert!(!closed, "balancebeam closed an HTTP/1.1 connection");
+    }
+
+    log::info!("All done :)");
+}
+
+/// Requests should go to the upstream for their Host header if it was given one with --vhost, and
+/// to the regular upstreams otherwise, even when one connection asks for several hosts
+#[tokio::test]
+async fn test_vhosts() {
+    init_logging();
+    let default_upstream = EchoServer::new().await;
+    let first_vhost = EchoServer::new().await;
+    let second_vhost = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&default_upstream.address],
+        &[
+            "--vhost",
+            &format!("first.example={}", first_vhost.address),
+            "--vhost",
+            &format!("Second.Example={}", second_vhost.address),
+        ],
+    )
+    .await;
+
+    log::info!("Sending requests for each host over one connection");
+    let mut client = TcpStream::connect(&balancebeam.address).await.unwrap();
+    for host in &[
+        "first.example",
+        "second.example:1100",
+        "other.example",
+        "FIRST.example",
+    ] {
+        let (response, _) = send_raw_request(
+            &mut client,
+            &format!("GET /vhost HTTP/1.1\r\nHost: {}\r\n\r\n", host),
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 200"), "Got: {}", response);
+    }
+
+    log::info!("Sending a request through the regular client");
+    balancebeam
+        .get("/no-vhost")
+        .await
+        .expect("Error sending request to balancebeam");
+
+    assert_eq!(
+        Box::new(first_vhost).stop().await,
+        2,
+        "first.example didn't get its requests"
+    );
+    assert_eq!(
+        Box::new(second_vhost).stop().await,
+        1,
+        "second.example didn't get its request"
+    );
+    assert_eq!(
+        Box::new(default_upstream).stop().await,
+        2,
+        "Requests for other hosts didn't go to the default upstream"
+    );
+    log::info!("All done :)");
+}
+
+/// After --circuit-breaker-threshold failures in a row, an upstream shouldn't get any requests
+/// until the cooldown is over, and then it should only get one probe
+#[tokio::test]
+async fn test_circuit_breaker() {
+    init_logging();
+    let upstream = ErrorServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--circuit-breaker-threshold",
+            "2",
+            "--circuit-breaker-cooldown",
+            "1",
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+
+    log::info!("Sending requests until the circuit breaker trips");
+    for _ in 0..2 {
+        let response_text = balancebeam
+            .get("/failing")
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(!response_text.contains("502"), "Got: {}", response_text);
+    }
+    let response_text = balancebeam
+        .get("/failing")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(
+        response_text.contains("502"),
+        "Request went through an open circuit breaker: {}",
+        response_text
+    );
+
+    log::info!("Waiting for the cooldown, then sending a probe that fails");
+    sleep(Duration::from_millis(1500)).await;
+    let response_text = balancebeam
+        .get("/probe")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(!response_text.contains("502"), "Got: {}", response_text);
+    let response_text = balancebeam
+        .get("/failing")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(
+        response_text.contains("502"),
+        "Circuit breaker didn't open again after the probe failed: {}",
+        response_text
+    );
+
+    assert_eq!(
+        Box::new(upstream).stop().await,
+        3,
+        "Upstream got the wrong number of requests"
+    );
+    log::info!("All done :)");
+}
+
+/// An upstream shouldn't be marked dead until --passive-failure-threshold connections to it have
+/// failed in a row
+#[tokio::test]
+async fn test_passive_failure_threshold() {
+    init_logging();
+    // Nothing is listening here once the listener is dropped
+    let dead_address = TcpListener::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .to_string();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&dead_address, &upstream.address],
+        &[
+            "--lb-algorithm",
+            "round-robin",
+            "--passive-failure-threshold",
+            "3",
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+
+    // With round-robin, every request tries the dead upstream first and then falls back to the
+    // live one
+    let threshold_message = format!("Upstream {} failed 3 times in a row", dead_address);
+    for i in 0..3 {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+        let marked_dead = balancebeam
+            .wait_for_output(&threshold_message, Duration::from_millis(200))
+            .await
+            .is_some();
+        assert_eq!(
+            marked_dead,
+            i == 2,
+            "Upstream marked dead after {} failures",
+            i + 1
+        );
+    }
+
+    assert_eq!(
+        Box::new(upstream).stop().await,
+        3,
+        "Live upstream didn't get every request"
+    );
+    log::info!("All done :)");
+}
+
+/// Requests slower than --slow-request-threshold-ms should be logged as slow, and faster ones
+/// shouldn't
+#[tokio::test]
+async fn test_slow_request_logging() {
+    init_logging();
+    let fast_upstream = EchoServer::new().await;
+    let slow_upstream = SlowServer::new(Duration::from_millis(300)).await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&fast_upstream.address],
+        &[
+            "--slow-request-threshold-ms",
+            "150",
+            "--vhost",
+            &format!("slow.example={}", slow_upstream.address),
+        ],
+    )
+    .await;
+
+    log::info!("Sending a fast request and a slow one");
+    let mut client = TcpStream::connect(&balancebeam.address).await.unwrap();
+    let (response, _) = send_raw_request(
+        &mut client,
+        "GET /fast HTTP/1.1\r\nHost: fast.example\r\n\r\n",
+    )
+    .await;
+    assert!(response.starts_with("HTTP/1.1 200"), "Got: {}", response);
+    let (response, _) = send_raw_request(
+        &mut client,
+        "GET /slow HTTP/1.1\r\nHost: slow.example\r\n\r\n",
+    )
+    .await;
+    assert!(response.starts_with("HTTP/1.1 200"), "Got: {}", response);
+
+    let slow_line = balancebeam
+        .wait_for_output("Slow request: GET /slow", Duration::from_secs(1))
+        .await
+        .expect("Slow request wasn't logged");
+    assert!(
+        slow_line.contains(&slow_upstream.address),
+        "Slow request log doesn't name the upstream: {}",
+        slow_line
+    );
+    assert!(
+        balancebeam
+            .wait_for_output("Slow request: GET /fast", Duration::from_millis(100))
+            .await
+            .is_none(),
+        "Fast request was logged as slow"
+    );
+    log::info!("All done :)");
+}
+
+/// Requests should go to the upstreams for the longest --route prefix that matches their path, and
+/// to the regular upstreams if none does
+#[tokio::test]
+async fn test_path_prefix_routes() {
+    init_logging();
+    let default_upstream = EchoServer::new().await;
+    let api_upstream = EchoServer::new().await;
+    let admin_upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&default_upstream.address],
+        &[
+            "--route",
+            &format!("/api={}", api_upstream.address),
+            "--route",
+            &format!("/api/admin={}", admin_upstream.address),
+        ],
+    )
+    .await;
+
+    for path in &["/api", "/api/users", "/api/admin/users", "/apiary", "/"] {
+        let response_text = balancebeam
+            .get(path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    assert_eq!(
+        Box::new(api_upstream).stop().await,
+        2,
+        "/api upstream didn't get the requests under /api"
+    );
+    assert_eq!(
+        Box::new(admin_upstream).stop().await,
+        1,
+        "Longest prefix didn't win"
+    );
+    assert_eq!(
+        Box::new(default_upstream).stop().await,
+        2,
+        "Requests matching no route didn't go to the default upstream"
+    );
+    log::info!("All done :)");
+}
+
+/// 429 responses should say how many seconds are left until the rate limiting window resets
+#[tokio::test]
+async fn test_rate_limit_retry_after() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, Some(1)).await;
+
+    let client = reqwest::Client::new();
+    let url = format!("http://{}/limited", balancebeam.address);
+    let response = client.get(&url).send().await.unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    let response = client.get(&url).send().await.unwrap();
+    assert_eq!(response.status().as_u16(), 429);
+    let retry_after = response
+        .headers()
+        .get("retry-after")
+        .expect("429 response has no Retry-After header")
+        .to_str()
+        .unwrap()
+        .parse::<u64>()
+        .expect("Retry-After isn't a number of seconds");
+    assert!(
+        retry_after > 0 && retry_after <= 60,
+        "Retry-After {} is outside the rate limiting window",
+        retry_after
+    );
+    log::info!("All done :)");
+}
+
+/// Sends a GET with the given X-Forwarded-For header, returning the response status and body
+async fn get_forwarded_for(balancebeam: &BalanceBeam, forwarded_for: &str) -> (u16, String) {
+    let response = reqwest::Client::new()
+        .get(&format!("http://{}/forwarded", balancebeam.address))
+        .header("x-forwarded-for", forwarded_for)
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    let status = response.status().as_u16();
+    (status, response.text().await.unwrap())
+}
+
+/// Requests from --trusted-proxies should be rate limited by the client IP in X-Forwarded-For, and
+/// requests from anywhere else by the connection's IP, whatever X-Forwarded-For says
+#[tokio::test]
+async fn test_trusted_proxies() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let trusting = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--max-requests-per-minute",
+            "1",
+            "--trusted-proxies",
+            "127.0.0.0/8,10.0.0.1",
+        ],
+    )
+    .await;
+
+    log::info!("Sending requests through trusted proxies for two different clients");
+    let (status, body) = get_forwarded_for(&trusting, "203.0.113.1, 10.0.0.1").await;
+    assert_eq!(status, 200);
+    // We still add the proxy we got the request from to the chain
+    assert!(
+        body.contains("x-forwarded-for: 203.0.113.1, 10.0.0.1, 127.0.0.1"),
+        "Got: {}",
+        body
+    );
+    assert_eq!(get_forwarded_for(&trusting, "203.0.113.2").await.0, 200);
+    assert_eq!(
+        get_forwarded_for(&trusting, "203.0.113.1").await.0,
+        429,
+        "Client from X-Forwarded-For wasn't rate limited"
+    );
+
+    log::info!("Making sure untrusted peers can't pick their own client IP");
+    let untrusting = BalanceBeam::new(&[&upstream.address], None, Some(1)).await;
+    assert_eq!(get_forwarded_for(&untrusting, "203.0.113.3").await.0, 200);
+    assert_eq!(
+        get_forwarded_for(&untrusting, "203.0.113.4").await.0,
+        429,
+        "Spoofed X-Forwarded-For got around the rate limit"
+    );
+    log::info!("All done :)");
+}
+
+/// --active-health-check-method should be used for health checks, and a HEAD response shouldn't be
+/// expected to have a body
+#[tokio::test]
+async fn test_active_health_check_method() {
+    init_logging();
+    let heads = Arc::new(AtomicUsize::new(0));
+    let upstream_heads = heads.clone();
+    let upstream = RawServer::new(move |request| {
+        if request.starts_with("HEAD ") {
+            upstream_heads.fetch_add(1, Ordering::SeqCst);
+            b"HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\n".to_vec()
+        } else {
+            b"HTTP/1.1 405 Method Not Allowed\r\nContent-Length: 0\r\n\r\n".to_vec()
+        }
+    })
+    .await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--active-health-check-interval",
+            "1",
+            "--active-health-check-method",
+            "HEAD",
+        ],
+    )
+    .await;
+
+    log::info!("Waiting for health checks to run...");
+    sleep(Duration::from_millis(2500)).await;
+    assert!(
+        heads.load(Ordering::SeqCst) >= 2,
+        "Health checks weren't sent with HEAD"
+    );
+
+    let response = reqwest::Client::new()
+        .get(&format!("http://{}/", balancebeam.address))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(
+        response.status().as_u16(),
+        405,
+        "Upstream passing HEAD health checks was marked dead"
+    );
+    log::info!("All done :)");
+}
+
+/// Health checks of slow upstreams should run side by side, so that one round takes about as long
+/// as the slowest upstream rather than all of them added up.
+#[tokio::test]
+async fn test_concurrent_health_checks() {
+    init_logging();
+    let mut upstreams = Vec::new();
+    for _ in 0..4 {
+        upstreams.push(SlowServer::new(Duration::from_secs(1)).await);
+    }
+    let upstream_addresses: Vec<&str> = upstreams
+        .iter()
+        .map(|upstream| upstream.address.as_str())
+        .collect();
+    let balancebeam = BalanceBeam::new_with_args(
+        &upstream_addresses,
+        &[
+            "--active-health-check-interval",
+            "1",
+            "--health-check-concurrency",
+            "4",
+        ],
+    )
+    .await;
+
+    // The first round starts after one interval and should take about a second; checking the
+    // upstreams one at a time would take four
+    log::info!("Waiting for a round of health checks to finish...");
+    assert!(
+        balancebeam
+            .wait_for_output("Active health checks complete", Duration::from_secs(3))
+            .await
+            .is_some(),
+        "Health checks didn't run concurrently"
+    );
+
+    for upstream in upstreams {
+        Box::new(upstream).stop().await;
+    }
+    log::info!("All done :)");
+}
+
+/// With --resolve-upstream-dns, an upstream given as a host name should be replaced by the
+/// addresses it resolves to
+#[tokio::test]
+async fn test_resolve_upstream_dns() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let port = upstream.address.rsplit_once(':').unwrap().1.to_string();
+    let admin_address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024..65535));
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&format!("localhost:{}", port)],
+        &["--resolve-upstream-dns", "--admin-bind", &admin_address],
+    )
+    .await;
+
+    log::info!("Checking that localhost was expanded into 127.0.0.1");
+    let response = reqwest::get(&format!(
+        "http://{}/upstreams/127.0.0.1:{}/history",
+        admin_address, port
+    ))
+    .await
+    .expect("Error fetching upstream history");
+    assert_eq!(response.status().as_u16(), 200);
+    let response = reqwest::get(&format!(
+        "http://{}/upstreams/localhost:{}/history",
+        admin_address, port
+    ))
+    .await
+    .expect("Error fetching upstream history");
+    assert_eq!(response.status().as_u16(), 404);
+
+    log::info!("Sending a request through the resolved upstream");
+    let response_text = balancebeam
+        .get("/resolved")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("GET /resolved HTTP/1.1"));
+    assert!(Box::new(upstream).stop().await >= 1);
+    log::info!("All done :)");
+}
+
+/// --resolve-upstream-dns applies to the upstreams for --route and --vhost too, not just the
+/// default pool
+#[tokio::test]
+async fn test_resolve_upstream_dns_for_routes() {
+    init_logging();
+    let default_upstream = EchoServer::new().await;
+    let api_upstream = EchoServer::new().await;
+    let api_port = api_upstream.address.rsplit_once(':').unwrap().1.to_string();
+    let admin_address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024..65535));
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&default_upstream.address],
+        &[
+            "--resolve-upstream-dns",
+            "--route",
+            &format!("/api=localhost:{}", api_port),
+            "--admin-bind",
+            &admin_address,
+        ],
+    )
+    .await;
+
+    log::info!("Checking that the route's localhost was expanded into 127.0.0.1");
+    for (address, status) in &[
+        (format!("127.0.0.1:{}", api_port), 200),
+        (format!("localhost:{}", api_port), 404),
+    ] {
+        let response = reqwest::get(&format!(
+            "http://{}/upstreams/{}/history",
+            admin_address, address
+        ))
+        .await
+        .expect("Error fetching upstream history");
+        assert_eq!(response.status().as_u16(), *status, "for {}", address);
+    }
+
+    log::info!("Sending a request through the route's resolved upstream");
+    let response_text = balancebeam
+        .get("/api/resolved")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("GET /api/resolved HTTP/1.1"));
+    assert!(Box::new(api_upstream).stop().await >= 1);
+    assert_eq!(Box::new(default_upstream).stop().await, 0);
+    log::info!("All done :)");
+}
+
+/// Upstreams given as unix:/path should be proxied to (and health checked) over the Unix socket
+#[tokio::test]
+async fn test_unix_socket_upstream() {
+    init_logging();
+    // Answers with the request line, so we can tell the request made it through
+    let upstream = RawServer::new_unix(|request| {
+        let request_line = request.lines().next().unwrap_or("");
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+            request_line.len(),
+            request_line
+        )
+        .into_bytes()
+    })
+    .await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&format!("unix:{}", upstream.address)],
+        &["--active-health-check-interval", "1"],
+    )
+    .await;
+
+    log::info!("Waiting for a health check to run...");
+    sleep(Duration::from_millis(1500)).await;
+    let health_checks = upstream.requests_received();
+    assert!(
+        health_checks >= 1,
+        "Upstream wasn't health checked over its socket"
+    );
+
+    log::info!("Sending requests through the Unix socket upstream");
+    for i in 0..3 {
+        let path = format!("/unix/{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response_text, format!("GET {} HTTP/1.1", path));
+    }
+    assert!(upstream.requests_received() >= health_checks + 3);
+
+    let _ = std::fs::remove_file(&upstream.address);
+    log::info!("All done :)");
+}
+
+/// Once an upstream answers with 101 Switching Protocols, balancebeam should pass bytes through in
+/// both directions without trying to parse them as HTTP
+#[tokio::test]
+async fn test_upgrade_passthrough() {
+    init_logging();
+    // Switches protocols when asked to upgrade to websocket, and echoes everything after that
+    let upstream = RawServer::new(|request| {
+        let request = request.to_lowercase();
+        if request.contains("\r\nconnection: upgrade\r\n")
+            && request.contains("\r\nupgrade: websocket\r\n")
+        {
+            b"HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\n\
+              Upgrade: websocket\r\n\r\n"
+                .to_vec()
+        } else {
+            b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_vec()
+        }
+    })
+    .await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    let mut stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+    let (response, closed) = send_raw_request(
+        &mut stream,
+        "GET /chat HTTP/1.1\r\nHost: example.com\r\nConnection: Upgrade\r\n\
+         Upgrade: websocket\r\n\r\n",
+    )
+    .await;
+    assert!(!closed, "Connection closed after upgrading");
+    assert!(
+        response.starts_with("HTTP/1.1 101"),
+        "Upgrade wasn't accepted: {}",
+        response
+    );
+    assert!(response.to_lowercase().contains("upgrade: websocket"));
+
+    log::info!("Sending data over the upgraded connection");
+    for message in &["hello", "not an HTTP request\r\n\r\n", "goodbye"] {
+        stream.write_all(message.as_bytes()).await.unwrap();
+        let mut buffer = vec![0_u8; message.len()];
+        timeout(Duration::from_secs(1), stream.read_exact(&mut buffer))
+            .await
+            .expect("Timed out waiting for echoed data")
+            .unwrap();
+        assert_eq!(String::from_utf8_lossy(&buffer), *message);
+    }
+    log::info!("All done :)");
+}
+
+/// With --access-log-format json, each request should be logged as a JSON object
+#[tokio::test]
+async fn test_json_access_log() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--access-log-format", "json"]).await;
+
+    let response_text = balancebeam
+        .get("/logged?query=1")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("GET /logged?query=1 HTTP/1.1"));
+
+    let entry = balancebeam
+        .wait_for_output("\"path\":\"/logged\"", Duration::from_secs(1))
+        .await
+        .expect("Request wasn't written to the JSON access log");
+    let entry = &entry[entry.find('{').unwrap()..];
+    assert!(entry.starts_with("{\"timestamp_ms\":"), "{}", entry);
+    assert!(entry.contains("\"client_ip\":\"127.0.0.1\""), "{}", entry);
+    assert!(entry.contains("\"method\":\"GET\""), "{}", entry);
+    assert!(
+        entry.contains(&format!("\"upstream\":\"{}\"", upstream.address)),
+        "{}",
+        entry
+    );
+    assert!(entry.contains("\"status\":200"), "{}", entry);
+    assert!(entry.contains("\"latency_ms\":"), "{}", entry);
+    assert!(entry.ends_with('}'), "{}", entry);
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// An upstream that accepts health checks but never answers them should be marked dead once
+/// --health-check-timeout runs out, rather than holding up health checks indefinitely
+#[tokio::test]
+async fn test_health_check_timeout() {
+    init_logging();
+    // Far longer than the health check timeout, so that there's no mistaking one for the other
+    let upstream = SlowServer::new(Duration::from_secs(30)).await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--active-health-check-interval",
+            "1",
+            "--health-check-timeout",
+            "1",
+        ],
+    )
+    .await;
+
+    // (The first health check starts a second after balancebeam does and times out a second after
+    // that, but leave plenty of slack for a busy machine.)
+    log::info!("Waiting for the health check to time out...");
+    assert!(
+        balancebeam
+            .wait_for_output(
+                "timed out responding to a health check. Marking it dead",
+                Duration::from_secs(10)
+            )
+            .await
+            .is_some(),
+        "Health check didn't time out"
+    );
+    assert!(balancebeam
+        .wait_for_output("Active health checks complete", Duration::from_secs(5))
+        .await
+        .is_some());
+
+    log::info!("Checking that the upstream was marked dead");
+    let response = reqwest::Client::new()
+        .get(&format!("http://{}/", balancebeam.address))
+        .timeout(Duration::from_secs(5))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 502);
+    log::info!("All done :)");
+}
+
+/// No upstream should ever be sent more than --max-conns-per-upstream connections at once. Once
+/// every upstream is full, further connections are turned away.
+#[tokio::test]
+async fn test_max_conns_per_upstream() {
+    init_logging();
+    let ok = || b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n".to_vec();
+    let upstream_1 = RawServer::with_delay(Duration::from_millis(500), move |_| ok()).await;
+    let upstream_2 = RawServer::with_delay(Duration::from_millis(500), move |_| ok()).await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream_1.address, &upstream_2.address],
+        &["--max-conns-per-upstream", "2"],
+    )
+    .await;
+
+    log::info!("Sending 6 requests at once to 2 upstreams that can take 2 each");
+    let mut requests = Vec::new();
+    for i in 0..6 {
+        let address = balancebeam.address.clone();
+        requests.push(tokio::spawn(async move {
+            // A fresh client for each request, so that each uses its own connection
+            reqwest::Client::new()
+                .get(&format!("http://{}/stress/{}", address, i))
+                .send()
+                .await
+                .expect("Error sending request to balancebeam")
+                .status()
+                .as_u16()
+        }));
+    }
+    let mut statuses = Vec::new();
+    for request in requests {
+        statuses.push(request.await.unwrap());
+    }
+    statuses.sort_unstable();
+    assert_eq!(statuses, vec![200, 200, 200, 200, 502, 502]);
+    assert_eq!(upstream_1.most_requests_in_progress(), 2);
+    assert_eq!(upstream_2.most_requests_in_progress(), 2);
+
+    log::info!("Checking that the upstreams' slots were given back");
+    for i in 0..4 {
+        let response_text = balancebeam.get(&format!("/after/{}", i)).await;
+        assert!(response_text.is_ok());
+    }
+    log::info!("All done :)");
+}
+
+/// With --enable-gzip, compressible responses should be gzipped for clients that accept it, and
+/// left alone for everyone else
+#[tokio::test]
+async fn test_gzip_responses() {
+    init_logging();
+    let body = "balancebeam compresses repetitive text very well. ".repeat(100);
+    let upstream_body = body.clone();
+    let upstream = RawServer::new(move |request| {
+        // Responses that already have an encoding shouldn't be compressed again
+        let encoding = if request.starts_with("GET /encoded ") {
+            "Content-Encoding: identity\r\n"
+        } else {
+            ""
+        };
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; charset=utf-8\r\n{}\
+             Content-Length: {}\r\n\r\n{}",
+            encoding,
+            upstream_body.len(),
+            upstream_body
+        )
+        .into_bytes()
+    })
+    .await;
+    let balancebeam = BalanceBeam::new_with_args(&[&upstream.address], &["--enable-gzip"]).await;
+    let client = reqwest::Client::new();
+
+    log::info!("Requesting a gzipped response");
+    let response = client
+        .get(&format!("http://{}/text", balancebeam.address))
+        .header("Accept-Encoding", "gzip")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.headers().get("content-encoding").unwrap(), "gzip");
+    let compressed = response.bytes().await.unwrap();
+    assert!(
+        compressed.len() < body.len(),
+        "Compressed body ({} bytes) isn't smaller than the original ({} bytes)",
+        compressed.len(),
+        body.len()
+    );
+    let mut decompressed = String::new();
+    // (std::io::Read, since the tokio extension traits are imported here too)
+    std::io::Read::read_to_string(
+        &mut flate2::read::GzDecoder::new(&compressed[..]),
+        &mut decompressed,
+    )
+    .expect("Response isn't valid gzip");
+    assert_eq!(decompressed, body);
+
+    log::info!("Checking that clients that don't accept gzip get the plain body");
+    let response = client
+        .get(&format!("http://{}/text", balancebeam.address))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response.headers().get("content-encoding").is_none());
+    assert_eq!(response.text().await.unwrap(), body);
+
+    log::info!("Checking that already-encoded responses aren't compressed again");
+    let response = client
+        .get(&format!("http://{}/encoded", balancebeam.address))
+        .header("Accept-Encoding", "gzip")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(
+        response.headers().get("content-encoding").unwrap(),
+        "identity"
+    );
+    assert_eq!(response.text().await.unwrap(), body);
+    log::info!("All done :)");
+}
+
+/// An upstream that has just recovered should start out with much less traffic than one that has
+/// been alive all along
+#[tokio::test]
+async fn test_slow_start() {
+    init_logging();
+    // Both upstreams count only the test's own requests, not health checks
+    let healthy_requests = Arc::new(AtomicUsize::new(0));
+    let upstream_requests = healthy_requests.clone();
+    let healthy_upstream = RawServer::new(move |request| {
+        if request.starts_with("GET /slow-start/") {
+            upstream_requests.fetch_add(1, Ordering::SeqCst);
+        }
+        b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n".to_vec()
+    })
+    .await;
+    let recovered = Arc::new(AtomicBool::new(false));
+    let recovering_requests = Arc::new(AtomicUsize::new(0));
+    let (upstream_recovered, upstream_requests) = (recovered.clone(), recovering_requests.clone());
+    // Fails health checks until `recovered` is set
+    let recovering_upstream = RawServer::new(move |request| {
+        if request.starts_with("GET /slow-start/") {
+            upstream_requests.fetch_add(1, Ordering::SeqCst);
+            b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n".to_vec()
+        } else if upstream_recovered.load(Ordering::SeqCst) {
+            b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n".to_vec()
+        } else {
+            b"HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\n\r\n".to_vec()
+        }
+    })
+    .await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&healthy_upstream.address, &recovering_upstream.address],
+        &[
+            "--active-health-check-interval",
+            "1",
+            "--active-health-check-path",
+            "/health",
+            "--slow-start-seconds",
+            "60",
+        ],
+    )
+    .await;
+
+    log::info!("Waiting for the failing upstream to be marked dead...");
+    assert!(balancebeam
+        .wait_for_output("Active health checks complete", Duration::from_secs(2))
+        .await
+        .is_some());
+    recovered.store(true, Ordering::SeqCst);
+    assert!(
+        balancebeam
+            .wait_for_output(
+                &format!("Upstream {} recovered", recovering_upstream.address),
+                Duration::from_secs(2)
+            )
+            .await
+            .is_some(),
+        "Upstream wasn't marked alive again"
+    );
+
+    log::info!("Sending requests right after it recovered");
+    for i in 0..100 {
+        balancebeam
+            .get(&format!("/slow-start/{}", i))
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+    let recovering_count = recovering_requests.load(Ordering::SeqCst);
+    let healthy_count = healthy_requests.load(Ordering::SeqCst);
+    log::info!(
+        "Recovered upstream got {} requests, healthy one got {}",
+        recovering_count,
+        healthy_count
+    );
+    assert_eq!(recovering_count + healthy_count, 100);
+    assert!(
+        recovering_count < 30,
+        "Recovered upstream got {} of 100 requests",
+        recovering_count
+    );
+    log::info!("All done :)");
+}
+
+/// --set-request-header and --remove-request-header should rewrite requests on their way to the
+/// upstream, and the response equivalents should rewrite what comes back. Setting replaces any
+/// existing value, and names are case-insensitive.
+#[tokio::test]
+async fn test_header_rewriting() {
+    init_logging();
+    // Sends back the request it got (lowercased), along with headers for balancebeam to rewrite
+    let upstream = RawServer::new(|request| {
+        let body = request.to_lowercase();
+        format!(
+            "HTTP/1.1 200 OK\r\nX-Upstream: original\r\nX-Powered-By: stub\r\n\
+             Content-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        )
+        .into_bytes()
+    })
+    .await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--set-request-header",
+            "X-Added=request",
+            "--set-request-header",
+            "x-client-value=overwritten",
+            "--remove-request-header",
+            "X-SECRET",
+            "--set-response-header",
+            "X-Served-By=balancebeam",
+            "--set-response-header",
+            "x-upstream=rewritten",
+            "--remove-response-header",
+            "x-powered-by",
+        ],
+    )
+    .await;
+
+    let response = reqwest::Client::new()
+        .get(&format!("http://{}/headers", balancebeam.address))
+        .header("X-Client-Value", "original")
+        .header("X-Secret", "hunter2")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+
+    log::info!("Checking the response headers");
+    let headers = response.headers();
+    assert_eq!(headers.get("x-served-by").unwrap(), "balancebeam");
+    assert_eq!(
+        headers.get_all("x-upstream").iter().collect::<Vec<_>>(),
+        vec!["rewritten"]
+    );
+    assert!(headers.get("x-powered-by").is_none());
+
+    log::info!("Checking the request headers the upstream saw");
+    let request = response.text().await.unwrap();
+    assert!(request.contains("\r\nx-added: request\r\n"), "{}", request);
+    assert!(
+        request.contains("\r\nx-client-value: overwritten\r\n"),
+        "{}",
+        request
+    );
+    assert!(!request.contains("x-client-value: original"), "{}", request);
+    assert!(!request.contains("x-secret"), "{}", request);
+    log::info!("All done :)");
+}
+
+/// balancebeam should serve requests on every --bind address it can listen on, and only warn
+/// about the ones it can't
+#[tokio::test]
+async fn test_multiple_bind_addresses() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let second_address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024..65535));
+    // Something is already listening here, so binding to it will fail
+    let taken = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let taken_address = taken.local_addr().unwrap().to_string();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--bind", &second_address, "--bind", &taken_address],
+    )
+    .await;
+    assert!(
+        balancebeam
+            .wait_for_output(
+                &format!("Could not bind to {}", taken_address),
+                Duration::from_secs(1)
+            )
+            .await
+            .is_some(),
+        "Failing to bind wasn't logged"
+    );
+
+    for address in &[&balancebeam.address, &second_address] {
+        log::info!("Sending a request to {}", address);
+        let response_text = reqwest::get(&format!("http://{}/multi-bind", address))
+            .await
+            .expect("Error sending request to balancebeam")
+            .text()
+            .await
+            .unwrap();
+        assert!(response_text.contains("GET /multi-bind HTTP/1.1"));
+    }
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    log::info!("All done :)");
+}
+
+/// With --connection-limit-behavior reject, connections past --max-connections should get a 503
+/// straight away, and there should be room again once a connection closes
+#[tokio::test]
+async fn test_max_connections_reject() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--max-connections",
+            "2",
+            "--connection-limit-behavior",
+            "reject",
+        ],
+    )
+    .await;
+
+    log::info!("Opening as many connections as allowed");
+    let first = TcpStream::connect(&balancebeam.address).await.unwrap();
+    let _second = TcpStream::connect(&balancebeam.address).await.unwrap();
+    sleep(Duration::from_millis(200)).await;
+
+    log::info!("Checking that one more connection is turned away");
+    let mut excess = TcpStream::connect(&balancebeam.address).await.unwrap();
+    let mut response = String::new();
+    timeout(Duration::from_secs(1), excess.read_to_string(&mut response))
+        .await
+        .expect("Excess connection wasn't closed")
+        .unwrap();
+    assert!(response.starts_with("HTTP/1.1 503"), "{}", response);
+
+    log::info!("Closing a connection to make room for another");
+    drop(first);
+    sleep(Duration::from_millis(200)).await;
+    let mut client = TcpStream::connect(&balancebeam.address).await.unwrap();
+    let (response, _) = send_raw_request(
+        &mut client,
+        "GET /room HTTP/1.1\r\nHost: balancebeam\r\nConnection: close\r\n\r\n",
+    )
+    .await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    log::info!("All done :)");
+}
+
+/// By default, connections past --max-connections should wait to be handled until another
+/// connection closes
+#[tokio::test]
+async fn test_max_connections_wait() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--max-connections", "1"]).await;
+
+    let mut first = TcpStream::connect(&balancebeam.address).await.unwrap();
+    let (response, closed) = send_raw_request(
+        &mut first,
+        "GET /first HTTP/1.1\r\nHost: balancebeam\r\n\r\n",
+    )
+    .await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    assert!(!closed);
+
+    log::info!("Checking that a second connection is left waiting");
+    let mut second = TcpStream::connect(&balancebeam.address).await.unwrap();
+    let (response, closed) = send_raw_request(
+        &mut second,
+        "GET /second HTTP/1.1\r\nHost: balancebeam\r\nConnection: close\r\n\r\n",
+    )
+    .await;
+    assert_eq!(response, "", "Connection over the limit was handled");
+    assert!(!closed);
+
+    log::info!("Closing the first connection, which should let the second one through");
+    drop(first);
+    let mut response = String::new();
+    timeout(Duration::from_secs(2), second.read_to_string(&mut response))
+        .await
+        .expect("Waiting connection was never handled")
+        .unwrap();
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    assert!(response.contains("GET /second HTTP/1.1"));
+    log::info!("All done :)");
+}
+
+/// With --cache-ttl-seconds, repeat GETs should be answered from the cache until the TTL runs out
+#[tokio::test]
+async fn test_response_cache() {
+    init_logging();
+    // Answers with the request line, and says the response can be cached
+    let upstream = RawServer::new(|request| {
+        let request_line = request.lines().next().unwrap_or("");
+        format!(
+            "HTTP/1.1 200 OK\r\nCache-Control: max-age=60\r\nContent-Length: {}\r\n\r\n{}",
+            request_line.len(),
+            request_line
+        )
+        .into_bytes()
+    })
+    .await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--cache-ttl-seconds", "1"]).await;
+    let client = reqwest::Client::new();
+    let get = |path: &str| {
+        client
+            .get(&format!("http://{}{}", balancebeam.address, path))
+            .send()
+    };
+
+    log::info!("First request for a path should go to the upstream");
+    let response = get("/cached").await.expect("Error sending request");
+    assert!(response.headers().get("age").is_none());
+    let first_body = response.text().await.unwrap();
+    assert!(first_body.contains("GET /cached HTTP/1.1"));
+
+    log::info!("Repeating it should be a cache hit");
+    let response = get("/cached").await.expect("Error sending request");
+    assert_eq!(response.headers().get("age").unwrap(), "0");
+    assert_eq!(response.text().await.unwrap(), first_body);
+
+    log::info!("Requests with cookies shouldn't be answered from the cache");
+    let response = client
+        .get(&format!("http://{}/cached", balancebeam.address))
+        .header("Cookie", "session=1")
+        .send()
+        .await
+        .expect("Error sending request");
+    assert!(response.headers().get("age").is_none());
+
+    log::info!("A different path should be a miss");
+    let response = get("/cached?other").await.expect("Error sending request");
+    assert!(response.headers().get("age").is_none());
+    assert!(response
+        .text()
+        .await
+        .unwrap()
+        .contains("GET /cached?other HTTP/1.1"));
+
+    log::info!("Once the TTL is up, the upstream should be asked again");
+    sleep(Duration::from_millis(1200)).await;
+    let response = get("/cached").await.expect("Error sending request");
+    assert!(response.headers().get("age").is_none());
+
+    assert_eq!(Box::new(upstream).stop().await, 4);
+    log::info!("All done :)");
+}
+
+/// The request log should name the upstream a request was sent to, not the client
+#[tokio::test]
+async fn test_logs_upstream_address() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    log::info!("Sending a request");
+    balancebeam
+        .get("/logged")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(
+        balancebeam
+            .wait_for_output(
+                &format!("-> {}: GET /logged", upstream.address),
+                Duration::from_secs(2)
+            )
+            .await
+            .is_some(),
+        "balancebeam didn't log the address of the upstream it connected to"
+    );
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// If the upstream a client's connection has been using goes away, the client's remaining requests
+/// should go to another upstream instead of the connection being torn down
+#[tokio::test]
+async fn test_reconnect_after_upstream_dies() {
+    init_logging();
+    let first = EchoServer::new().await;
+    let second = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&first.address, &second.address],
+        &[
+            "--lb-algorithm",
+            "round-robin",
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+
+    log::info!("Sending the first request, which should go to the first upstream");
+    let mut client = TcpStream::connect(&balancebeam.address).await.unwrap();
+    let (response, closed) = send_raw_request(
+        &mut client,
+        "GET /first HTTP/1.1\r\nHost: balancebeam\r\n\r\n",
+    )
+    .await;
+    assert!(response.starts_with("HTTP/1.1 200"), "Got: {}", response);
+    assert!(!closed);
+
+    log::info!("Killing the first upstream");
+    assert_eq!(Box::new(first).stop().await, 1);
+
+    log::info!("Sending the second request on the same connection");
+    let (response, closed) = send_raw_request(
+        &mut client,
+        "GET /second HTTP/1.1\r\nHost: balancebeam\r\n\r\n",
+    )
+    .await;
+    assert!(response.starts_with("HTTP/1.1 200"), "Got: {}", response);
+    assert!(
+        response.contains("GET /second HTTP/1.1"),
+        "Got: {}",
+        response
+    );
+    assert!(
+        !closed,
+        "balancebeam closed the connection after its upstream died"
+    );
+
+    assert_eq!(Box::new(second).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// An upstream closing a connection that balancebeam kept open while the client was idle isn't a
+/// sign that the upstream is down, so it shouldn't get marked dead (or the request get a 502)
+#[tokio::test]
+async fn test_upstream_closes_idle_connection() {
+    init_logging();
+    let upstream = RawServer::with_idle_timeout(Duration::from_secs(1), |_| {
+        b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok".to_vec()
+    })
+    .await;
+    // Without a pool, the client's connection holds on to its upstream connection itself
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--max-idle-per-upstream",
+            "0",
+            "--passive-failure-threshold",
+            "1",
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+
+    let mut client = TcpStream::connect(&balancebeam.address).await.unwrap();
+    let (response, _) = send_raw_request(&mut client, &raw_get("/first")).await;
+    assert!(response.starts_with("HTTP/1.1 200"), "Got: {}", response);
+
+    log::info!("Waiting for the upstream to close the idle connection");
+    sleep(Duration::from_secs(2)).await;
+    let (response, _) = send_raw_request(&mut client, &raw_get("/second")).await;
+    assert!(response.starts_with("HTTP/1.1 200"), "Got: {}", response);
+
+    log::info!("Checking that the upstream is still considered alive");
+    let response_text = balancebeam
+        .get("/third")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response_text, "ok");
+    assert_eq!(upstream.requests_received(), 3);
+    log::info!("All done :)");
+}
+
+/// With --health-check-on-startup, upstreams that are already down shouldn't get any requests
+#[tokio::test]
+async fn test_health_check_on_startup() {
+    init_logging();
+    // Nothing is listening here once the listener is dropped
+    let dead_address = TcpListener::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .to_string();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&dead_address, &upstream.address],
+        &[
+            "--health-check-on-startup",
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+
+    log::info!("Sending requests, which should all go to the live upstream");
+    for i in 0..5 {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+    // (The startup health check's own failure is logged as "Failed to connect to upstream <address>
+    // <error>. Marking it dead", without the colon.)
+    assert!(
+        balancebeam
+            .wait_for_output(
+                &format!("Failed to connect to upstream {}:", dead_address),
+                Duration::from_millis(200)
+            )
+            .await
+            .is_none(),
+        "balancebeam tried to send a request to an upstream that was down at startup"
+    );
+
+    // (The live upstream also got the startup health check.)
+    assert_eq!(Box::new(upstream).stop().await, 6);
+    log::info!("All done :)");
+}
+
+/// Upstreams drained through the admin API shouldn't get new connections, even once they pass
+/// health checks, until they're enabled again
+#[tokio::test]
+async fn test_admin_drain_upstream() {
+    init_logging();
+    let first = EchoServer::new().await;
+    let second = EchoServer::new().await;
+    let admin_address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024..65535));
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&first.address, &second.address],
+        &[
+            "--lb-algorithm",
+            "round-robin",
+            "--active-health-check-interval",
+            "1",
+            "--admin-bind",
+            &admin_address,
+        ],
+    )
+    .await;
+    let client = reqwest::Client::new();
+
+    log::info!("Draining the first upstream");
+    let response = client
+        .post(&format!(
+            "http://{}/upstreams/{}/drain",
+            admin_address, first.address
+        ))
+        .send()
+        .await
+        .expect("Error sending request to the admin API");
+    assert_eq!(response.status().as_u16(), 200);
+    assert!(response.text().await.unwrap().contains("\"drained\":true"));
+    let statuses = reqwest::get(&format!("http://{}/upstreams", admin_address))
+        .await
+        .expect("Error fetching upstream statuses")
+        .text()
+        .await
+        .unwrap();
+    // (A health check may already have marked it alive again, so only its drained flag is certain.)
+    let status_of = |address: &str| {
+        statuses
+            .split("},{")
+            .find(|status| status.contains(&format!("\"upstream\":\"{}\"", address)))
+            .unwrap_or_else(|| panic!("{} is missing from {}", address, statuses))
+            .to_string()
+    };
+    assert!(status_of(&first.address).contains("\"drained\":true"));
+    assert!(status_of(&second.address).contains("\"drained\":false"));
+
+    log::info!("Waiting for a health check, then sending requests");
+    sleep(Duration::from_millis(1500)).await;
+    for i in 0..4 {
+        balancebeam
+            .get(&format!("/drained-{}", i))
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+    assert!(
+        balancebeam
+            .wait_for_output(
+                &format!("-> {}: GET /drained-", first.address),
+                Duration::from_millis(200)
+            )
+            .await
+            .is_none(),
+        "A drained upstream got a request"
+    );
+
+    log::info!("Enabling the first upstream again");
+    let response = client
+        .post(&format!(
+            "http://{}/upstreams/{}/enable",
+            admin_address, first.address
+        ))
+        .send()
+        .await
+        .expect("Error sending request to the admin API");
+    assert_eq!(response.status().as_u16(), 200);
+    for i in 0..2 {
+        balancebeam
+            .get(&format!("/enabled-{}", i))
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+    assert!(
+        balancebeam
+            .wait_for_output(
+                &format!("-> {}: GET /enabled-", first.address),
+                Duration::from_secs(1)
+            )
+            .await
+            .is_some(),
+        "The upstream didn't get requests again after being enabled"
+    );
+
+    log::info!("Checking that unknown upstreams get a 404");
+    let response = client
+        .post(&format!(
+            "http://{}/upstreams/127.0.0.1:1/drain",
+            admin_address
+        ))
+        .send()
+        .await
+        .expect("Error sending request to the admin API");
+    assert_eq!(response.status().as_u16(), 404);
+
+    drop(balancebeam);
+    log::info!("All done :)");
+}
+
+/// Request lines and headers over --max-request-line-bytes and --max-header-bytes should get a 431,
+/// and the connection should be closed
+#[tokio::test]
+async fn test_max_header_bytes() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--max-request-line-bytes",
+            "100",
+            "--max-header-bytes",
+            "900",
+        ],
+    )
+    .await;
+
+    log::info!("Sending a request with headers under the limit");
+    let mut client = TcpStream::connect(&balancebeam.address).await.unwrap();
+    let (response, closed) = send_raw_request(
+        &mut client,
+        &format!(
+            "GET /small HTTP/1.1\r\nHost: balancebeam\r\nX-Padding: {}\r\n\r\n",
+            "a".repeat(500)
+        ),
+    )
+    .await;
+    assert!(response.starts_with("HTTP/1.1 200"), "Got: {}", response);
+    assert!(!closed);
+
+    log::info!("Sending an oversized header");
+    // (If balancebeam closes the connection without reading everything we sent, the kernel may
+    // reset it and throw away the 431 before we read it. 1024 bytes should all be read at once.)
+    let request = format!(
+        "GET /big HTTP/1.1\r\nHost: balancebeam\r\nX-Padding: {}\r\n\r\n",
+        "a".repeat(971)
+    );
+    assert_eq!(request.len(), 1024);
+    let mut client = TcpStream::connect(&balancebeam.address).await.unwrap();
+    let (response, closed) = send_raw_request(&mut client, &request).await;
+    assert!(response.starts_with("HTTP/1.1 431"), "Got: {}", response);
+    assert!(closed, "balancebeam didn't close the connection");
+
+    log::info!("Sending an oversized request line");
+    let mut client = TcpStream::connect(&balancebeam.address).await.unwrap();
+    let (response, closed) = send_raw_request(
+        &mut client,
+        &format!(
+            "GET /{} HTTP/1.1\r\nHost: balancebeam\r\n\r\n",
+            "a".repeat(200)
+        ),
+    )
+    .await;
+    assert!(response.starts_with("HTTP/1.1 431"), "Got: {}", response);
+    assert!(closed, "balancebeam didn't close the connection");
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// Connecting to an upstream that never answers should give up after --upstream-connect-timeout
+/// and move on to another upstream
+#[tokio::test]
+async fn test_upstream_connect_timeout() {
+    init_logging();
+    // Nothing routes packets to this address, so connecting to it hangs until we give up
+    let black_hole = "10.255.255.1:80";
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[black_hole, &upstream.address],
+        &[
+            "--lb-algorithm",
+            "round-robin",
+            "--upstream-connect-timeout",
+            "1",
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+
+    log::info!("Sending a request, which should try the unreachable upstream first");
+    let response_text = timeout(Duration::from_secs(3), balancebeam.get("/connect-timeout"))
+        .await
+        .expect("balancebeam didn't give up connecting to the unreachable upstream")
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("GET /connect-timeout HTTP/1.1"));
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// Each upstream's latency percentiles should be reported separately, so that a slow upstream
+/// stands out
+#[tokio::test]
+async fn test_latency_by_upstream() {
+    init_logging();
+    let fast = EchoServer::new().await;
+    let slow = SlowServer::new(Duration::from_millis(300)).await;
+    let metrics_address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024..65535));
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&fast.address, &slow.address],
+        &[
+            "--lb-algorithm",
+            "round-robin",
+            "--metrics-bind",
+            &metrics_address,
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+
+    // Each request gets a connection of its own, so round-robin alternates between the upstreams
+    for _ in 0..6 {
+        balancebeam
+            .get("/timed")
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+
+    let metrics = reqwest::get(&format!("http://{}/metrics", metrics_address))
+        .await
+        .expect("Error fetching metrics")
+        .text()
+        .await
+        .unwrap();
+    let percentile = |upstream: &str, quantile: &str| -> f64 {
+        let metric = format!(
+            "balancebeam_upstream_latency_by_upstream_seconds{{upstream=\"{}\",quantile=\"{}\"}}",
+            upstream, quantile
+        );
+        metrics
+            .lines()
+            .find_map(|line| line.strip_prefix(&metric)?.trim().parse().ok())
+            .unwrap_or_else(|| panic!("Metric {} missing from:\n{}", metric, metrics))
+    };
+    for quantile in &["0.5", "0.9", "0.99"] {
+        assert!(percentile(&fast.address, quantile) < 0.1, "{}", metrics);
+        assert!(percentile(&slow.address, quantile) >= 0.25, "{}", metrics);
+    }
+    let count = format!(
+        "balancebeam_upstream_latency_by_upstream_seconds_count{{upstream=\"{}\"}}",
+        slow.address
+    );
+    assert_eq!(get_metric(&metrics_address, &count).await, 3);
+
+    Box::new(fast).stop().await;
+    Box::new(slow).stop().await;
+    log::info!("All done :)");
+}
+
+/// --backup-upstream servers should only get requests while every primary upstream is dead
+#[tokio::test]
+async fn test_backup_upstream() {
+    init_logging();
+    // Nothing is listening here until the primary comes up
+    let primary_address = TcpListener::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .to_string();
+    let backup = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&primary_address],
+        &[
+            "--backup-upstream",
+            &backup.address,
+            "--health-check-on-startup",
+            "--active-health-check-interval",
+            "1",
+        ],
+    )
+    .await;
+
+    log::info!("Sending requests while the primary is down");
+    for i in 0..3 {
+        let path = format!("/primary-down-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+        assert!(balancebeam
+            .wait_for_output(
+                &format!("-> {}: GET {}", backup.address, path),
+                Duration::from_secs(1)
+            )
+            .await
+            .is_some());
+    }
+
+    log::info!("Starting the primary and waiting for a health check to notice");
+    let primary = EchoServer::new_at_address(primary_address.clone()).await;
+    sleep(Duration::from_millis(2500)).await;
+    for i in 0..3 {
+        let path = format!("/primary-up-{}", i);
+        balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(
+            balancebeam
+                .wait_for_output(
+                    &format!("-> {}: GET {}", primary_address, path),
+                    Duration::from_secs(1)
+                )
+                .await
+                .is_some(),
+            "Request didn't go back to the primary once it recovered"
+        );
+    }
+
+    Box::new(primary).stop().await;
+    Box::new(backup).stop().await;
+    log::info!("All done :)");
+}
+
+/// With --max-requests-per-connection, balancebeam should close a connection once it has answered
+/// that many requests on it, telling the client so in the last response
+#[tokio::test]
+async fn test_max_requests_per_connection() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--max-requests-per-connection", "2"],
+    )
+    .await;
+
+    let mut client = TcpStream::connect(&balancebeam.address).await.unwrap();
+    for i in 0..2 {
+        log::info!("Sending request {} on the connection", i + 1);
+        let (response, closed) = send_raw_request(
+            &mut client,
+            &format!("GET /request-{} HTTP/1.1\r\nHost: balancebeam\r\n\r\n", i),
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 200"), "Got: {}", response);
+        let last = i == 1;
+        assert_eq!(
+            response.to_lowercase().contains("connection: close"),
+            last,
+            "Got: {}",
+            response
+        );
+        assert_eq!(closed, last, "Connection closed after {} requests", i + 1);
+    }
+
+    log::info!("Sending a request on a new connection");
+    let response_text = balancebeam
+        .get("/new-connection")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("GET /new-connection HTTP/1.1"));
+
+    assert_eq!(Box::new(upstream).stop().await, 3);
+    log::info!("All done :)");
+}
+
+/// Connections that don't send a request within --client-idle-timeout should be closed: silently if
+/// the client never started one, and with a 408 if it only sent part of one
+#[tokio::test]
+async fn test_client_idle_timeout() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--client-idle-timeout", "1"]).await;
+
+    log::info!("Connecting and sending nothing");
+    let mut client = TcpStream::connect(&balancebeam.address).await.unwrap();
+    let mut buffer = [0_u8; 512];
+    let bytes_read = timeout(Duration::from_secs(3), client.read(&mut buffer))
+        .await
+        .expect("balancebeam didn't close the idle connection")
+        .unwrap();
+    assert_eq!(
+        bytes_read, 0,
+        "balancebeam sent something to an idle client"
+    );
+
+    log::info!("Sending part of a request and stopping");
+    let mut client = TcpStream::connect(&balancebeam.address).await.unwrap();
+    client
+        .write_all(b"GET /partial HTTP/1.1\r\nHost: balance")
+        .await
+        .unwrap();
+    let mut received = Vec::new();
+    timeout(Duration::from_secs(3), client.read_to_end(&mut received))
+        .await
+        .expect("balancebeam didn't close the connection with a partial request")
+        .unwrap();
+    let received = String::from_utf8_lossy(&received);
+    assert!(received.starts_with("HTTP/1.1 408"), "Got: {}", received);
+
+    log::info!("Checking that a prompt request still goes through");
+    let response_text = balancebeam
+        .get("/prompt")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("GET /prompt HTTP/1.1"));
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// Clients that reset their connections right after connecting shouldn't take anything down with
+/// them. (balancebeam takes each client's address from accept() rather than asking the socket for
+/// it later, when it might be gone.)
+#[tokio::test]
+async fn test_client_disconnects_immediately() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    log::info!("Connecting and resetting connections straight away");
+    for _ in 0..10 {
+        let client = TcpStream::connect(&balancebeam.address).await.unwrap();
+        // Lingering for zero seconds makes closing the socket send a reset
+        setsockopt(
+            client.as_raw_fd(),
+            sockopt::Linger,
+            &nix::libc::linger {
+                l_onoff: 1,
+                l_linger: 0,
+            },
+        )
+        .unwrap();
+        drop(client);
+    }
+    sleep(Duration::from_millis(200)).await;
+
+    log::info!("Checking that balancebeam still serves requests");
+    let response_text = balancebeam
+        .get("/still-alive")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("GET /still-alive HTTP/1.1"));
+    assert!(
+        balancebeam
+            .wait_for_output("panicked", Duration::from_millis(200))
+            .await
+            .is_none(),
+        "balancebeam panicked handling a connection that was reset"
+    );
+
+    log::info!("All done :)");
+}
+
+/// Make sure requests still go through with TCP_NODELAY turned off (it's on by default, which the
+/// other tests cover).
+#[tokio::test]
+async fn test_tcp_nodelay_off() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--tcp-nodelay", "off"]).await;
+
+    for path in ["/first", "/second"].iter() {
+        let response_text = balancebeam
+            .get(path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+    assert!(
+        balancebeam
+            .wait_for_output("Failed to set TCP_NODELAY", Duration::from_millis(100))
+            .await
+            .is_none(),
+        "balancebeam shouldn't touch TCP_NODELAY when it's turned off"
+    );
+
+    log::info!("All done :)");
+}
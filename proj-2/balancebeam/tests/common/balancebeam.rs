@@ -1,13 +1,17 @@
 use rand::Rng;
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use tokio::io::{AsyncBufReadExt, BufReader};
 use tokio::process::{Child, Command};
-use tokio::time::sleep;
+use tokio::time::{sleep, Instant};
 
 pub struct BalanceBeam {
     #[allow(dead_code)]
     child: Child, // process is killed when dropped (Command::kill_on_drop)
     pub address: String,
+    /// Every line balancebeam has printed so far
+    output: Arc<Mutex<Vec<String>>>,
 }
 
 impl BalanceBeam {
@@ -24,6 +28,22 @@ impl BalanceBeam {
         active_health_check_interval: Option<usize>,
         max_requests_per_minute: Option<usize>,
     ) -> BalanceBeam {
+        let mut args = Vec::new();
+        if let Some(active_health_check_interval) = active_health_check_interval {
+            args.push("--active-health-check-interval".to_string());
+            args.push(active_health_check_interval.to_string());
+        }
+        if let Some(max_requests_per_minute) = max_requests_per_minute {
+            args.push("--max-requests-per-minute".to_string());
+            args.push(max_requests_per_minute.to_string());
+        }
+        let args: Vec<&str> = args.iter().map(|arg| arg.as_str()).collect();
+        BalanceBeam::new_with_args(upstreams, &args).await
+    }
+
+    /// Starts balancebeam with the given upstreams, passing any extra command-line arguments
+    /// through as-is.
+    pub async fn new_with_args(upstreams: &[&str], extra_args: &[&str]) -> BalanceBeam {
         let mut rng = rand::thread_rng();
         let address = format!("127.0.0.1:{}", rng.gen_range(1024..65535));
         let mut cmd = Command::new(BalanceBeam::target_bin_path());
@@ -31,15 +51,54 @@ impl BalanceBeam {
         for upstream in upstreams {
             cmd.arg("--upstream").arg(upstream);
         }
-        if let Some(active_health_check_interval) = active_health_check_interval {
-            cmd.arg("--active-health-check-interval")
-                .arg(active_health_check_interval.to_string());
+        cmd.args(extra_args);
+        BalanceBeam::spawn(cmd, address).await
+    }
+
+    /// Starts balancebeam with the given upstreams, handing it an already-bound listening socket
+    /// the way systemd socket activation does (as fd 3, announced in LISTEN_FDS).
+    #[allow(dead_code)]
+    pub async fn new_with_listener(
+        upstreams: &[&str],
+        listener: std::net::TcpListener,
+    ) -> BalanceBeam {
+        let address = listener.local_addr().unwrap().to_string();
+        let mut cmd = Command::new(BalanceBeam::target_bin_path());
+        for upstream in upstreams {
+            cmd.arg("--upstream").arg(upstream);
         }
-        if let Some(max_requests_per_minute) = max_requests_per_minute {
-            cmd.arg("--max-requests-per-minute")
-                .arg(max_requests_per_minute.to_string());
+        cmd.env("LISTEN_FDS", "1");
+        let fd = listener.as_raw_fd();
+        unsafe {
+            cmd.pre_exec(move || {
+                // Move the socket to fd 3, which (unlike the original) isn't close-on-exec
+                let result = if fd == 3 {
+                    nix::libc::fcntl(fd, nix::libc::F_SETFD, 0)
+                } else {
+                    nix::libc::dup2(fd, 3)
+                };
+                if result < 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
         }
+        // Our copy of the socket is closed once the child has started
+        BalanceBeam::spawn(cmd, address).await
+    }
+
+    async fn spawn(mut cmd: Command, address: String) -> BalanceBeam {
         cmd.kill_on_drop(true);
+        // kill_on_drop only helps if we get dropped. If a hung test gets killed instead, take
+        // balancebeam down with it rather than leaving it running.
+        unsafe {
+            cmd.pre_exec(|| {
+                if nix::libc::prctl(nix::libc::PR_SET_PDEATHSIG, nix::libc::SIGKILL) < 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
         cmd.stdout(std::process::Stdio::piped());
         cmd.stderr(std::process::Stdio::piped());
         let mut child = cmd.spawn().expect(&format!(
@@ -50,10 +109,12 @@ impl BalanceBeam {
         // Print output from the child. We want to intercept and log this output (instead of letting
         // the child inherit stderr and print directly to the terminal) so that the output can be
         // suppressed if the test passes and displayed if it fails.
+        let output = Arc::new(Mutex::new(Vec::new()));
         let stdout = child
             .stdout
             .take()
             .expect("Child process somehow missing stdout pipe!");
+        let stdout_output = output.clone();
         tokio::spawn(async move {
             let mut stdout_reader = BufReader::new(stdout).lines();
             while let Some(line) = stdout_reader
@@ -62,12 +123,14 @@ impl BalanceBeam {
                 .expect("I/O error reading from child stdout")
             {
                 println!("Balancebeam output: {}", line);
+                stdout_output.lock().unwrap().push(line);
             }
         });
         let stderr = child
             .stderr
             .take()
             .expect("Child process somehow missing stderr pipe!");
+        let stderr_output = output.clone();
         tokio::spawn(async move {
             let mut stderr_reader = BufReader::new(stderr).lines();
             while let Some(line) = stderr_reader
@@ -76,12 +139,55 @@ impl BalanceBeam {
                 .expect("I/O error reading from child stderr")
             {
                 println!("Balancebeam output: {}", line);
+                stderr_output.lock().unwrap().push(line);
             }
         });
 
         // Hack: wait for executable to start running
         sleep(Duration::from_secs(1)).await;
-        BalanceBeam { child, address }
+        BalanceBeam {
+            child,
+            address,
+            output,
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn send_signal(&self, signal: nix::sys::signal::Signal) {
+        let pid =
+            nix::unistd::Pid::from_raw(self.child.id().expect("balancebeam already exited") as i32);
+        nix::sys::signal::kill(pid, signal).expect("Failed to signal balancebeam");
+    }
+
+    /// Waits up to `timeout` for balancebeam to print a line containing `text`, returning that
+    /// line if it does.
+    #[allow(dead_code)]
+    pub async fn wait_for_output(&self, text: &str, timeout: Duration) -> Option<String> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(line) = self
+                .output
+                .lock()
+                .unwrap()
+                .iter()
+                .find(|line| line.contains(text))
+            {
+                return Some(line.clone());
+            }
+            if Instant::now() >= deadline {
+                return None;
+            }
+            sleep(Duration::from_millis(50)).await;
+        }
+    }
+
+    /// Waits for balancebeam to exit on its own, returning its exit status.
+    #[allow(dead_code)]
+    pub async fn wait_for_exit(mut self) -> std::process::ExitStatus {
+        self.child
+            .wait()
+            .await
+            .expect("Failed to wait for balancebeam to exit")
     }
 
     #[allow(dead_code)]
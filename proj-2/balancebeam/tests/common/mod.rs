@@ -1,14 +1,18 @@
 mod balancebeam;
 mod echo_server;
 mod error_server;
+mod raw_server;
 mod server;
+mod slow_server;
 
 use std::sync;
 
 pub use balancebeam::BalanceBeam;
 pub use echo_server::EchoServer;
 pub use error_server::ErrorServer;
+pub use raw_server::RawServer;
 pub use server::Server;
+pub use slow_server::SlowServer;
 
 static INIT_TESTS: sync::Once = sync::Once::new();
 
@@ -0,0 +1,231 @@
+use crate::common::server::Server;
+use async_trait::async_trait;
+use rand::Rng;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, UnixListener};
+
+/// Comes up with the raw bytes to send in response to a request, given the request's text
+/// (headers and body).
+type Handler = dyn Fn(&str) -> Vec<u8> + Send + Sync;
+
+#[derive(Debug, Default)]
+struct ServerState {
+    connections_accepted: AtomicUsize,
+    requests_received: AtomicUsize,
+    requests_in_progress: AtomicUsize,
+    most_requests_in_progress: AtomicUsize,
+}
+
+/// A bare-bones upstream that answers each request with whatever bytes its handler returns, for
+/// tests that need exact control over responses (or responses hyper won't send). Connections are
+/// kept open for more requests unless a response says `Connection: close`, and once a response
+/// says `101 Switching Protocols`, everything sent over the connection is echoed back.
+pub struct RawServer {
+    accept_task: tokio::task::JoinHandle<()>,
+    pub address: String,
+    state: Arc<ServerState>,
+}
+
+/// Returns the length of the first request in `received` (its headers plus a body of
+/// Content-Length bytes), or None if it hasn't all arrived yet.
+fn request_length(received: &[u8]) -> Option<usize> {
+    let headers_len = received.windows(4).position(|w| w == b"\r\n\r\n")? + 4;
+    let headers = String::from_utf8_lossy(&received[..headers_len]).to_lowercase();
+    let body_len = headers
+        .lines()
+        .find_map(|line| line.strip_prefix("content-length:"))
+        .and_then(|len| len.trim().parse::<usize>().ok())
+        .unwrap_or(0);
+    if received.len() < headers_len + body_len {
+        return None;
+    }
+    Some(headers_len + body_len)
+}
+
+async fn serve_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    handler: Arc<Handler>,
+    state: Arc<ServerState>,
+    delay: Duration,
+    idle_timeout: Option<Duration>,
+) {
+    state.connections_accepted.fetch_add(1, Ordering::SeqCst);
+    let mut received = Vec::new();
+    let mut buffer = [0_u8; 4096];
+    loop {
+        let request_len = loop {
+            if let Some(request_len) = request_length(&received) {
+                break request_len;
+            }
+            let read = stream.read(&mut buffer);
+            let read_result = match idle_timeout {
+                // Only a connection that's between requests counts as idle
+                Some(idle_timeout) if received.is_empty() => {
+                    match tokio::time::timeout(idle_timeout, read).await {
+                        Ok(read_result) => read_result,
+                        Err(_) => return,
+                    }
+                }
+                _ => read.await,
+            };
+            match read_result {
+                Ok(0) | Err(_) => return,
+                Ok(bytes_read) => received.extend_from_slice(&buffer[..bytes_read]),
+            }
+        };
+        let request: Vec<u8> = received.drain(..request_len).collect();
+        state.requests_received.fetch_add(1, Ordering::SeqCst);
+        let in_progress = state.requests_in_progress.fetch_add(1, Ordering::SeqCst) + 1;
+        state
+            .most_requests_in_progress
+            .fetch_max(in_progress, Ordering::SeqCst);
+        tokio::time::sleep(delay).await;
+        state.requests_in_progress.fetch_sub(1, Ordering::SeqCst);
+
+        let response = handler(&String::from_utf8_lossy(&request));
+        // balancebeam may hang up before reading all of this
+        if stream.write_all(&response).await.is_err() {
+            return;
+        }
+        let response = String::from_utf8_lossy(&response).to_lowercase();
+        let headers = response.split("\r\n\r\n").next().unwrap_or("");
+        if headers.starts_with("http/1.1 101 ") {
+            stream.write_all(&received).await.ok();
+            while let Ok(bytes_read) = stream.read(&mut buffer).await {
+                if bytes_read == 0 || stream.write_all(&buffer[..bytes_read]).await.is_err() {
+                    return;
+                }
+            }
+            return;
+        }
+        if headers.contains("\r\nconnection: close") {
+            return;
+        }
+    }
+}
+
+impl RawServer {
+    /// Starts a server on a random local port, answering every request using `handler`.
+    #[allow(dead_code)]
+    pub async fn new<F>(handler: F) -> RawServer
+    where
+        F: Fn(&str) -> Vec<u8> + Send + Sync + 'static,
+    {
+        RawServer::with_delay(Duration::from_secs(0), handler).await
+    }
+
+    /// Like new(), but waits for `delay` before answering each request.
+    #[allow(dead_code)]
+    pub async fn with_delay<F>(delay: Duration, handler: F) -> RawServer
+    where
+        F: Fn(&str) -> Vec<u8> + Send + Sync + 'static,
+    {
+        RawServer::start(delay, None, handler).await
+    }
+
+    /// Like new(), but hangs up on connections that go `idle_timeout` without a request, the way
+    /// most real servers eventually do.
+    #[allow(dead_code)]
+    pub async fn with_idle_timeout<F>(idle_timeout: Duration, handler: F) -> RawServer
+    where
+        F: Fn(&str) -> Vec<u8> + Send + Sync + 'static,
+    {
+        RawServer::start(Duration::from_secs(0), Some(idle_timeout), handler).await
+    }
+
+    async fn start<F>(delay: Duration, idle_timeout: Option<Duration>, handler: F) -> RawServer
+    where
+        F: Fn(&str) -> Vec<u8> + Send + Sync + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let handler: Arc<Handler> = Arc::new(handler);
+        let state = Arc::new(ServerState::default());
+        let task_state = state.clone();
+        let accept_task = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(serve_connection(
+                    stream,
+                    handler.clone(),
+                    task_state.clone(),
+                    delay,
+                    idle_timeout,
+                ));
+            }
+        });
+        RawServer {
+            accept_task,
+            address,
+            state,
+        }
+    }
+
+    /// Like new(), but listens on a Unix socket at a random path, which becomes the address.
+    #[allow(dead_code)]
+    pub async fn new_unix<F>(handler: F) -> RawServer
+    where
+        F: Fn(&str) -> Vec<u8> + Send + Sync + 'static,
+    {
+        let path = std::env::temp_dir().join(format!(
+            "balancebeam-test-{}.sock",
+            rand::thread_rng().gen::<u32>()
+        ));
+        let listener = UnixListener::bind(&path).unwrap();
+        let handler: Arc<Handler> = Arc::new(handler);
+        let state = Arc::new(ServerState::default());
+        let task_state = state.clone();
+        let accept_task = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(serve_connection(
+                    stream,
+                    handler.clone(),
+                    task_state.clone(),
+                    Duration::from_secs(0),
+                    None,
+                ));
+            }
+        });
+        RawServer {
+            accept_task,
+            address: path.to_str().unwrap().to_string(),
+            state,
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn connections_accepted(&self) -> usize {
+        self.state.connections_accepted.load(Ordering::SeqCst)
+    }
+
+    #[allow(dead_code)]
+    pub fn requests_received(&self) -> usize {
+        self.state.requests_received.load(Ordering::SeqCst)
+    }
+
+    /// The most requests this server has been working on at once
+    #[allow(dead_code)]
+    pub fn most_requests_in_progress(&self) -> usize {
+        self.state.most_requests_in_progress.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for RawServer {
+    fn drop(&mut self) {
+        // Connections that are already open stay open, but no new ones are accepted
+        self.accept_task.abort();
+    }
+}
+
+#[async_trait]
+impl Server for RawServer {
+    async fn stop(self: Box<Self>) -> usize {
+        self.requests_received()
+    }
+
+    fn address(&self) -> String {
+        self.address.clone()
+    }
+}
@@ -1,28 +1,177 @@
 use std::env;
 use std::fs::File;
-use std::io::{BufRead, BufReader};
+use std::io::{self, BufRead, BufReader, Read};
+use std::ops::AddAssign;
 use std::process;
 
+const FILES0_FROM_FLAG: &str = "--files0-from=";
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+struct Counts {
+    lines: usize,
+    words: usize,
+    chars: usize,
+}
+
+impl AddAssign for Counts {
+    fn add_assign(&mut self, other: Counts) {
+        self.lines += other.lines;
+        self.words += other.words;
+        self.chars += other.chars;
+    }
+}
+
+/// Counts the lines, words and characters read from the supplied reader.
+fn count<R: BufRead>(reader: R) -> io::Result<Counts> {
+    let mut counts = Counts::default();
+    for line in reader.lines() {
+        let line = line?;
+        counts.lines += 1;
+        counts.words += line.split_whitespace().count();
+        counts.chars += line.chars().count();
+    }
+    counts.chars += counts.lines; // Include newline to character count
+    Ok(counts)
+}
+
+fn count_file(filename: &str) -> io::Result<Counts> {
+    count(BufReader::new(File::open(filename)?))
+}
+
+/// Splits a NUL-separated list of filenames (as produced by `find -print0`). Empty entries, such
+/// as the one following a trailing NUL, are skipped.
+fn parse_files0(data: &[u8]) -> Vec<String> {
+    data.split(|byte| *byte == 0)
+        .filter(|name| !name.is_empty())
+        .map(|name| String::from_utf8_lossy(name).into_owned())
+        .collect()
+}
+
+/// Reads the NUL-separated filename list from `source`, where `-` means stdin.
+fn read_files0_from(source: &str) -> io::Result<Vec<String>> {
+    let mut data = Vec::new();
+    if source == "-" {
+        io::stdin().read_to_end(&mut data)?;
+    } else {
+        File::open(source)?.read_to_end(&mut data)?;
+    }
+    Ok(parse_files0(&data))
+}
+
+fn print_counts(counts: &Counts, name: Option<&str>) {
+    print!(
+        "Lines: {} Words: {} Chars: {}",
+        counts.lines, counts.words, counts.chars
+    );
+    match name {
+        Some(name) => println!(" {}", name),
+        None => println!(),
+    }
+}
+
 fn main() {
     let args: Vec<String> = env::args().collect();
     if args.len() < 2 {
         println!("Too few arguments.");
         process::exit(1);
     }
-    let mut line_count = 0;
-    let mut word_count = 0;
-    let mut char_count = 0;
-    let filename = &args[1];
-    let file = File::open(filename).expect("Unable to open file");
-    for line in BufReader::new(file).lines() {
-        line_count += 1;
-        let line = line.unwrap();
-        word_count += line.split_whitespace().count();
-        char_count += line.chars().count();
-    }
-    char_count += line_count; // Include newline to character count
-    println!(
-        "Lines: {} Words: {} Chars: {}",
-        line_count, word_count, char_count
-    );
+
+    let mut filenames = Vec::new();
+    for arg in &args[1..] {
+        if let Some(source) = arg.strip_prefix(FILES0_FROM_FLAG) {
+            match read_files0_from(source) {
+                Ok(names) => filenames.extend(names),
+                Err(err) => {
+                    println!("Unable to read file list {}: {}", source, err);
+                    process::exit(1);
+                }
+            }
+        } else {
+            filenames.push(arg.clone());
+        }
+    }
+
+    if filenames.len() == 1 {
+        match count_file(&filenames[0]) {
+            Ok(counts) => print_counts(&counts, None),
+            Err(err) => {
+                println!("{}: {}", filenames[0], err);
+                process::exit(1);
+            }
+        }
+        return;
+    }
+
+    let mut total = Counts::default();
+    let mut had_error = false;
+    for filename in &filenames {
+        match count_file(filename) {
+            Ok(counts) => {
+                print_counts(&counts, Some(filename));
+                total += counts;
+            }
+            Err(err) => {
+                println!("{}: {}", filename, err);
+                had_error = true;
+            }
+        }
+    }
+    print_counts(&total, Some("total"));
+    if had_error {
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_count() {
+        let counts = count("hello world\nfoo\n".as_bytes()).unwrap();
+        assert_eq!(
+            counts,
+            Counts {
+                lines: 2,
+                words: 3,
+                chars: 16
+            }
+        );
+    }
+
+    #[test]
+    fn test_files0_from() {
+        let dir = env::temp_dir().join(format!("rwc-files0-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let plain = dir.join("plain.txt");
+        let spaced = dir.join("with space.txt");
+        fs::write(&plain, "one two\nthree\n").unwrap();
+        fs::write(&spaced, "four\n").unwrap();
+        let list = dir.join("list");
+        let mut list_data = Vec::new();
+        for path in &[&plain, &spaced] {
+            list_data.extend_from_slice(path.to_str().unwrap().as_bytes());
+            list_data.push(0);
+        }
+        fs::write(&list, &list_data).unwrap();
+
+        let filenames = read_files0_from(list.to_str().unwrap()).unwrap();
+        assert_eq!(filenames.len(), 2);
+        assert!(filenames[1].ends_with("with space.txt"));
+
+        let mut total = Counts::default();
+        for filename in &filenames {
+            total += count_file(filename).unwrap();
+        }
+        assert_eq!(
+            total,
+            Counts {
+                lines: 3,
+                words: 4,
+                chars: 19
+            }
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
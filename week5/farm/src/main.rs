@@ -1,6 +1,9 @@
+// u32::is_multiple_of is newer than the toolchain this assignment targets
+#![allow(unknown_lints, clippy::manual_is_multiple_of)]
+
 use std::collections::VecDeque;
 use std::sync::{Arc, Mutex};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use std::{env, process, thread};
 
 /// Determines whether a number is prime. This function is taken from CS 110 factor.py.
@@ -18,16 +21,41 @@ fn is_prime(num: u32) -> bool {
     true
 }
 
-/// Determines the prime factors of a number and prints them to stdout. This function is taken
-/// from CS 110 factor.py.
+/// The prime factorization of a single input number.
+#[derive(Debug, Clone, PartialEq)]
+struct FactorResult {
+    /// Position of the number in the input, so results can be printed in input order
+    index: usize,
+    num: u32,
+    factors: Vec<u32>,
+    time: Duration,
+}
+
+impl FactorResult {
+    fn print(&self) {
+        let factors_str = self
+            .factors
+            .iter()
+            .map(|f| f.to_string())
+            .collect::<Vec<String>>()
+            .join(" * ");
+        println!("{} = {} [time: {:?}]", self.num, factors_str, self.time);
+    }
+}
+
+/// Determines the prime factors of a number. This function is taken from CS 110 factor.py.
 ///
 /// You don't need to read or understand this code.
-fn factor_number(num: u32) {
+fn factor_number(index: usize, num: u32) -> FactorResult {
     let start = Instant::now();
 
     if num == 1 || is_prime(num) {
-        println!("{} = {} [time: {:?}]", num, num, start.elapsed());
-        return;
+        return FactorResult {
+            index,
+            num,
+            factors: vec![num],
+            time: start.elapsed(),
+        };
     }
 
     let mut factors = Vec::new();
@@ -39,27 +67,70 @@ fn factor_number(num: u32) {
         }
     }
     factors.sort_unstable();
-    let factors_str = factors
-        .into_iter()
-        .map(|f| f.to_string())
-        .collect::<Vec<String>>()
-        .join(" * ");
-    println!("{} = {} [time: {:?}]", num, factors_str, start.elapsed());
+    FactorResult {
+        index,
+        num,
+        factors,
+        time: start.elapsed(),
+    }
 }
 
-/// Returns a list of numbers supplied via argv.
-#[allow(dead_code)]
-fn get_input_numbers() -> VecDeque<u32> {
+/// The order in which results are printed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SortBy {
+    Input,
+    Time,
+    FactorCount,
+}
+
+impl SortBy {
+    fn parse(value: &str) -> Option<SortBy> {
+        match value {
+            "input" => Some(SortBy::Input),
+            "time" => Some(SortBy::Time),
+            "factor-count" => Some(SortBy::FactorCount),
+            _ => None,
+        }
+    }
+}
+
+/// Sorts results into the requested order. Ties are broken by input order, so the output never
+/// depends on which thread happened to finish first.
+fn sort_results(results: &mut [FactorResult], sort_by: SortBy) {
+    match sort_by {
+        SortBy::Input => results.sort_by_key(|result| result.index),
+        SortBy::Time => results.sort_by_key(|result| (result.time, result.index)),
+        SortBy::FactorCount => results.sort_by_key(|result| (result.factors.len(), result.index)),
+    }
+}
+
+fn exit_with_usage() -> ! {
+    println!("Usage: farm [--sort-by input|time|factor-count] <numbers...>");
+    process::exit(1);
+}
+
+/// Returns the requested sort order along with the list of numbers supplied via argv.
+fn get_input_numbers() -> (SortBy, VecDeque<(usize, u32)>) {
+    let mut sort_by = SortBy::Input;
     let mut numbers = VecDeque::new();
-    for arg in env::args().skip(1) {
-        if let Ok(val) = arg.parse::<u32>() {
-            numbers.push_back(val);
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let sort_value = if arg == "--sort-by" {
+            Some(args.next().unwrap_or_else(|| exit_with_usage()))
+        } else {
+            arg.strip_prefix("--sort-by=")
+                .map(|value| value.to_string())
+        };
+        if let Some(value) = sort_value {
+            sort_by = SortBy::parse(&value).unwrap_or_else(|| exit_with_usage());
+        } else if let Ok(val) = arg.parse::<u32>() {
+            numbers.push_back((numbers.len(), val));
         } else {
             println!("{} is not a valid number", arg);
             process::exit(1);
         }
     }
-    numbers
+    (sort_by, numbers)
 }
 
 fn main() {
@@ -68,31 +139,101 @@ fn main() {
     let start = Instant::now();
 
     // TODO: call get_input_numbers() and store a queue of numbers to factor
-    let input_numbers = get_input_numbers();
-    let nums: Arc<Mutex<VecDeque<u32>>> = Arc::new(Mutex::new(input_numbers));
+    let (sort_by, input_numbers) = get_input_numbers();
+    let nums: Arc<Mutex<VecDeque<(usize, u32)>>> = Arc::new(Mutex::new(input_numbers));
 
     // TODO: spawn `num_threads` threads, each of which pops numbers off the queue and calls
     // factor_number() until the queue is empty
     let mut threads = Vec::new();
     for _ in 0..num_threads {
         let nums_ref = nums.clone();
-        threads.push(thread::spawn(move || loop {
-            let n;
-            {
-                let mut remaining_nums = nums_ref.lock().unwrap();
-                n = remaining_nums.pop_front();
-            }
-            match n {
-                Some(n) => factor_number(n),
-                None => break,
+        threads.push(thread::spawn(move || {
+            let mut results = Vec::new();
+            loop {
+                let n;
+                {
+                    let mut remaining_nums = nums_ref.lock().unwrap();
+                    n = remaining_nums.pop_front();
+                }
+                match n {
+                    Some((index, n)) => results.push(factor_number(index, n)),
+                    None => break,
+                }
             }
+            results
         }))
     }
 
     // TODO: join all the threads you created
+    let mut results = Vec::new();
     for handle in threads {
-        handle.join().expect("Panic occurred in thread!");
+        results.extend(handle.join().expect("Panic occurred in thread!"));
+    }
+
+    sort_results(&mut results, sort_by);
+    for result in &results {
+        result.print();
     }
 
     println!("Total execution time: {:?}", start.elapsed());
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn fixed_results() -> Vec<FactorResult> {
+        // Pretend the numbers finished in an order unrelated to the input
+        let inputs = [12, 7, 30, 1];
+        let times = [30, 10, 20, 5];
+        let mut results: Vec<FactorResult> = inputs
+            .iter()
+            .enumerate()
+            .map(|(index, num)| FactorResult {
+                time: Duration::from_millis(times[index]),
+                ..factor_number(index, *num)
+            })
+            .collect();
+        results.reverse();
+        results
+    }
+
+    fn nums(results: &[FactorResult]) -> Vec<u32> {
+        results.iter().map(|result| result.num).collect()
+    }
+
+    #[test]
+    fn test_factor_number() {
+        assert_eq!(factor_number(0, 12).factors, vec![2, 2, 3]);
+        assert_eq!(factor_number(0, 7).factors, vec![7]);
+    }
+
+    #[test]
+    fn test_sort_by_input() {
+        let mut results = fixed_results();
+        sort_results(&mut results, SortBy::Input);
+        assert_eq!(nums(&results), vec![12, 7, 30, 1]);
+    }
+
+    #[test]
+    fn test_sort_by_time() {
+        let mut results = fixed_results();
+        sort_results(&mut results, SortBy::Time);
+        assert_eq!(nums(&results), vec![1, 7, 30, 12]);
+    }
+
+    #[test]
+    fn test_sort_by_factor_count() {
+        let mut results = fixed_results();
+        sort_results(&mut results, SortBy::FactorCount);
+        // 7 and 1 have a single factor, 12 and 30 have three; ties keep input order
+        assert_eq!(nums(&results), vec![7, 1, 12, 30]);
+    }
+
+    #[test]
+    fn test_parse_sort_by() {
+        assert_eq!(SortBy::parse("time"), Some(SortBy::Time));
+        assert_eq!(SortBy::parse("factor-count"), Some(SortBy::FactorCount));
+        assert_eq!(SortBy::parse("random"), None);
+    }
+}
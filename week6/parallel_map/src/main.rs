@@ -1,6 +1,6 @@
 use std::{thread, time};
 
-struct ChannelMessage<T: Send + 'static> {
+struct ChannelMessage<T> {
     index: usize,
     item: T,
 }
@@ -49,6 +49,56 @@ where
     output_vec
 }
 
+/// Like parallel_map, but borrows the input instead of taking ownership of it, and also passes
+/// each element's index to `f`. The results are returned in the same order as the input. At least
+/// one thread is always used, even if `num_threads` is 0.
+fn par_map_indexed<T, U, F>(input: &[T], num_threads: usize, f: F) -> Vec<U>
+where
+    F: Fn(usize, &T) -> U + Sync,
+    T: Sync,
+    U: Send,
+{
+    let mut output_vec: Vec<Option<U>> = Vec::with_capacity(input.len());
+    output_vec.resize_with(input.len(), || None);
+    // The threads only need to be told which element to work on next, since they can all see the
+    // input slice
+    let (index_sender, index_receiver) = crossbeam_channel::unbounded::<usize>();
+    let (result_sender, result_receiver) = crossbeam_channel::unbounded::<ChannelMessage<U>>();
+    // Scoped threads are guaranteed to finish before the scope ends, which is what lets them
+    // borrow `input` and `f`
+    thread::scope(|scope| {
+        for _ in 0..num_threads.max(1) {
+            let index_receiver = index_receiver.clone();
+            let result_sender = result_sender.clone();
+            let f = &f;
+            scope.spawn(move || {
+                while let Ok(index) = index_receiver.recv() {
+                    result_sender
+                        .send(ChannelMessage {
+                            index,
+                            item: f(index, &input[index]),
+                        })
+                        .expect("Tried sending result to channel, but failed");
+                }
+            });
+        }
+        drop(result_sender);
+        for index in 0..input.len() {
+            index_sender
+                .send(index)
+                .expect("Tried sending input to channel, but failed");
+        }
+        drop(index_sender);
+        while let Ok(result) = result_receiver.recv() {
+            output_vec[result.index] = Some(result.item);
+        }
+    });
+    output_vec
+        .into_iter()
+        .map(|item| item.expect("Missing result for an element"))
+        .collect()
+}
+
 fn main() {
     let v = vec![6, 7, 8, 9, 10, 1, 2, 3, 4, 5, 12, 18, 11, 5, 20];
     let squares = parallel_map(v, 16, |num| {
@@ -57,4 +107,37 @@ fn main() {
         num * num
     });
     println!("squares: {:?}", squares);
+    let scaled = par_map_indexed(&squares, 4, |i, square| i * square);
+    println!("squares multiplied by their indices: {:?}", scaled);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_par_map_indexed() {
+        let v: Vec<usize> = (0..50).map(|i| 50 - i).collect();
+        let products = par_map_indexed(&v, 8, |i, num| {
+            // Finish out of order, so that the results have to be put back in order
+            thread::sleep(time::Duration::from_millis((*num % 5) as u64));
+            i * num
+        });
+        let expected: Vec<usize> = v.iter().enumerate().map(|(i, num)| i * num).collect();
+        assert_eq!(products, expected);
+        // The input was only borrowed
+        assert_eq!(v.len(), 50);
+    }
+
+    #[test]
+    fn test_par_map_indexed_empty() {
+        let v: Vec<u32> = Vec::new();
+        assert_eq!(par_map_indexed(&v, 4, |_, num| *num), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn test_par_map_indexed_no_threads() {
+        let v = vec![1, 2, 3];
+        assert_eq!(par_map_indexed(&v, 0, |i, num| i + num), vec![1, 3, 5]);
+    }
 }